@@ -0,0 +1,43 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use promql_parser::parser;
+
+const QUERIES: [(&str, &str); 4] = [
+    ("selector", r#"http_requests_total{job="api", code!="500"}"#),
+    (
+        "aggregation",
+        r#"sum by (job) (rate(http_requests_total{job="api"}[5m] offset 1h))"#,
+    ),
+    (
+        "binary",
+        r#"sum by (job) (rate(http_requests_total{code=~"5.."}[5m])) / on (job) group_left () sum by (job) (rate(http_requests_total[5m]))"#,
+    ),
+    (
+        "subquery",
+        r#"max_over_time(histogram_quantile(0.9, sum by (le) (rate(foo_bucket[5m])))[1h:1m] @ end())"#,
+    ),
+];
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for (name, query) in QUERIES {
+        group.bench_function(name, |b| b.iter(|| parser::parse(black_box(query))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
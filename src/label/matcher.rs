@@ -17,6 +17,8 @@ use std::hash::{Hash, Hasher};
 
 use regex::Regex;
 
+use crate::label::METRIC_NAME;
+use crate::parser::lex::{is_label, is_valid_in_quotes};
 use crate::parser::token::{token_display, TokenId, T_EQL, T_EQL_REGEX, T_NEQ, T_NEQ_REGEX};
 use crate::util::join_vector;
 
@@ -76,12 +78,14 @@ impl serde::Serialize for MatchOp {
 }
 
 // Matcher models the matching of a label.
+// The fields are serialized in the order of the Prometheus `parse_query` API,
+// i.e. `{"name":"job","type":"=","value":"api"}`.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "ser", derive(serde::Serialize))]
 pub struct Matcher {
+    pub name: String,
     #[cfg_attr(feature = "ser", serde(rename = "type"))]
     pub op: MatchOp,
-    pub name: String,
     pub value: String,
 }
 
@@ -94,6 +98,15 @@ impl Matcher {
         }
     }
 
+    /// same as [`Matcher::new`], but the name must be a valid label name,
+    /// like `job` or `__name__`, not `1bad` or `a:b`.
+    pub fn try_new(op: MatchOp, name: &str, value: &str) -> Result<Self, String> {
+        if !is_label(name) {
+            return Err(format!("invalid label name '{name}'"));
+        }
+        Ok(Self::new(op, name, value))
+    }
+
     /// matches returns whether the matcher matches the given string value.
     pub fn is_match(&self, s: &str) -> bool {
         match &self.op {
@@ -104,16 +117,41 @@ impl Matcher {
         }
     }
 
+    /// same as [`Matcher::is_match`], but the label value is optional. An absent label
+    /// (`None`) is treated as the empty string like Prometheus does, so `foo!="bar"`
+    /// matches series without the `foo` label while `foo="bar"` does not.
+    pub fn matches_opt(&self, s: Option<&str>) -> bool {
+        self.is_match(s.unwrap_or_default())
+    }
+
+    /// whether the matcher matches the empty string, i.e. it also selects series
+    /// that do not have the label at all, like `=~".*"`, `!~".+"`, `!="a"` or `=""`.
+    pub fn matches_empty(&self) -> bool {
+        self.is_match("")
+    }
+
     // Go and Rust handle the repeat pattern differently
     // in Go the following is valid: `aaa{bbb}ccc`
     // in Rust {bbb} is seen as an invalid repeat and must be ecaped \{bbb}
     // This escapes the opening { if its not followed by valid repeat pattern (e.g. 4,6).
-    fn try_parse_re(re: &str) -> Result<Regex, String> {
+    pub(crate) fn try_parse_re(re: &str) -> Result<Regex, String> {
         Regex::new(re)
             .or_else(|_| Regex::new(&try_escape_for_repeat_re(re)))
             .map_err(|_| format!("illegal regex for {re}",))
     }
 
+    /// create a `=~` matcher like the parser does, reporting how the regex is
+    /// interpreted, see [`RegexInfo`].
+    pub fn new_regex_validated(name: &str, value: &str) -> Result<(Matcher, RegexInfo), String> {
+        let re = Matcher::try_parse_re(value)?;
+        let info = RegexInfo {
+            escaped: re.as_str() != value,
+            pattern: re.as_str().to_string(),
+            anchored_pattern: format!("^(?:{})$", re.as_str()),
+        };
+        Ok((Matcher::new(MatchOp::Re(re), name, value), info))
+    }
+
     pub fn new_matcher(id: TokenId, name: String, value: String) -> Result<Matcher, String> {
         let op = Self::find_matcher_op(id, &value)?;
         op.map(|op| Matcher::new(op, name.as_str(), value.as_str()))
@@ -131,12 +169,43 @@ impl Matcher {
     }
 }
 
+/// How the value of a regex matcher is interpreted, see [`Matcher::new_regex_validated`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexInfo {
+    /// whether `{` not starting a repetition was escaped, which Go accepts
+    /// as literal but Rust does not, e.g. `aaa{bbb}ccc`
+    pub escaped: bool,
+    /// the pattern compiled into the matcher, after escaping. Like the matchers
+    /// the parser builds, see [`Matcher::is_match`], it is not anchored.
+    pub pattern: String,
+    /// the same pattern anchored to the whole label value, the way Prometheus
+    /// evaluates it, e.g. `^(?:a.*)$` for `a.*`
+    pub anchored_pattern: String,
+}
+
 impl fmt::Display for Matcher {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}{}\"{}\"", self.name, self.op, self.value)
+        write!(f, "{}{}", self.name, self.op)?;
+        write_quoted(f, &self.value)
     }
 }
 
+/// quote the value, which is the text between the quotes as written in the query,
+/// escapes included, e.g. `it\'s` for `'it\'s'`. The first quote symbol the value is
+/// valid within is used, so that the value parses back the same.
+///
+/// NOTE: a parsed value is always valid within the quotes it was written in, but a
+/// value built programmatically may be valid within none of them, e.g. one with all
+/// of `"`, `'` and `` ` ``. Such a value can't be written in a query, it is rendered
+/// between double quotes as is, which doesn't parse back.
+pub(crate) fn write_quoted(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    let symbol = ['"', '\'', '`']
+        .into_iter()
+        .find(|&symbol| is_valid_in_quotes(value, symbol))
+        .unwrap_or('"');
+    write!(f, "{symbol}{value}{symbol}")
+}
+
 // Go and Rust handle the repeat pattern differently
 // in Go the following is valid: `aaa{bbb}ccc`
 // in Rust {bbb} is seen as an invalid repeat and must be ecaped \{bbb}
@@ -256,7 +325,7 @@ impl Matchers {
     }
 
     /// Vector selectors must either specify a name or at least one label
-    /// matcher that does not match the empty string.
+    /// matcher that does not match the empty string, see [`Matcher::matches_empty`].
     ///
     /// The following expression is illegal:
     /// {job=~".*"} # Bad!
@@ -266,7 +335,16 @@ impl Matchers {
                 .matchers
                 .iter()
                 .chain(self.or_matchers.iter().flatten())
-                .all(|m| m.is_match(""))
+                .all(|m| m.matches_empty())
+    }
+
+    /// whether the matchers are a single `__name__` equal matcher and nothing else,
+    /// e.g. `{__name__="foo"}`, which selects the same series as `foo`.
+    pub fn is_name_only(&self) -> bool {
+        match self.matchers.as_slice() {
+            [m] => self.or_matchers.is_empty() && m.op == MatchOp::Equal && m.name == METRIC_NAME,
+            _ => false,
+        }
     }
 
     /// find the matcher's value whose name equals the specified name. This function
@@ -279,6 +357,38 @@ impl Matchers {
             .map(|m| m.value.clone())
     }
 
+    /// rewrite the value of every matcher, including the ones of the `or` groups,
+    /// e.g. to redact label values. The regex of `=~` and `!~` matchers is compiled
+    /// again from the new value, an invalid one is an error and leaves the matchers
+    /// untouched.
+    pub fn map_values<F: FnMut(&str) -> String>(&mut self, mut f: F) -> Result<(), String> {
+        let mut rewritten = vec![];
+        for m in self
+            .matchers
+            .iter()
+            .chain(self.or_matchers.iter().flatten())
+        {
+            let value = f(&m.value);
+            let re = match &m.op {
+                MatchOp::Re(_) | MatchOp::NotRe(_) => Some(Matcher::try_parse_re(&value)?),
+                _ => None,
+            };
+            rewritten.push((value, re));
+        }
+
+        let all = self
+            .matchers
+            .iter_mut()
+            .chain(self.or_matchers.iter_mut().flatten());
+        for (m, (value, re)) in all.zip(rewritten) {
+            if let (MatchOp::Re(old) | MatchOp::NotRe(old), Some(re)) = (&mut m.op, re) {
+                *old = re;
+            }
+            m.value = value;
+        }
+        Ok(())
+    }
+
     /// find matchers whose name equals the specified name
     pub fn find_matchers(&self, name: &str) -> Vec<Matcher> {
         self.matchers
@@ -288,6 +398,79 @@ impl Matchers {
             .cloned()
             .collect()
     }
+
+    /// the `or` groups of the matchers without cloning them, a selector without `or`
+    /// has a single group. The simple matchers, if any, are shared by every group.
+    pub fn iter_or_groups(&self) -> impl Iterator<Item = Vec<&Matcher>> + '_ {
+        let single_group = self.or_matchers.is_empty().then_some(&[][..]);
+        single_group
+            .into_iter()
+            .chain(self.or_matchers.iter().map(Vec::as_slice))
+            .map(|group| self.matchers.iter().chain(group).collect())
+    }
+
+    /// like the [`Display`](fmt::Display) of the matchers, but renders at most `max`
+    /// matchers followed by `, …(+N more)`, which is friendly to the logs.
+    pub fn to_string_truncated(&self, max: usize) -> String {
+        let total = if self.or_matchers.is_empty() {
+            self.matchers.len()
+        } else {
+            self.or_matchers.iter().map(Vec::len).sum()
+        };
+        if total <= max {
+            return self.to_string();
+        }
+
+        let rendered = if self.or_matchers.is_empty() {
+            // sorted like Display, before keeping the first ones
+            let mut sorted: Vec<&Matcher> = self.matchers.iter().collect();
+            sorted.sort_by_cached_key(|m| m.to_string());
+            join_vector(&sorted[..max], ",", false)
+        } else {
+            let mut left = max;
+            let groups: Vec<String> = self
+                .or_matchers
+                .iter()
+                .map_while(|group| {
+                    if left == 0 {
+                        return None;
+                    }
+                    let n = left.min(group.len());
+                    left -= n;
+                    Some(join_vector(&group[..n], ", ", false))
+                })
+                .collect();
+            groups.join(" or ")
+        };
+
+        let more = total - max;
+        if rendered.is_empty() {
+            format!("…(+{more} more)")
+        } else {
+            format!("{rendered}, …(+{more} more)")
+        }
+    }
+
+    /// remove the exact duplicate matchers, in the simple matchers and within
+    /// each `or` group respectively, keeping the first occurrence.
+    pub fn dedup(&mut self) {
+        dedup_matchers(&mut self.matchers);
+        for group in &mut self.or_matchers {
+            dedup_matchers(group);
+        }
+    }
+}
+
+/// keep the first occurrence of each matcher. A selector only has a few of them,
+/// so they are compared linearly instead of being hashed.
+fn dedup_matchers(matchers: &mut Vec<Matcher>) {
+    let mut deduped: Vec<Matcher> = Vec::with_capacity(matchers.len());
+    for m in matchers.drain(..) {
+        if !deduped.contains(&m) {
+            deduped.push(m);
+        }
+    }
+    *matchers = deduped;
 }
 
 impl fmt::Display for Matchers {
@@ -599,6 +782,275 @@ mod tests {
         assert_eq!(4, ms.len());
     }
 
+    #[test]
+    fn test_matchers_dedup() {
+        let job = Matcher::new(MatchOp::Equal, "job", "api");
+        let env = Matcher::new(MatchOp::NotEqual, "env", "dev");
+
+        let mut matchers = Matchers::new(vec![job.clone(), job.clone()]);
+        matchers.dedup();
+        assert_eq!(matchers, Matchers::one(job.clone()));
+
+        let mut matchers = Matchers::new(vec![
+            job.clone(),
+            env.clone(),
+            Matcher::new(MatchOp::Equal, "job", "web"),
+            job.clone(),
+            env.clone(),
+        ]);
+        matchers.dedup();
+        assert_eq!(
+            matchers,
+            Matchers::new(vec![
+                job.clone(),
+                env.clone(),
+                Matcher::new(MatchOp::Equal, "job", "web"),
+            ])
+        );
+
+        // the groups are deduplicated on their own
+        let mut matchers = Matchers::empty().with_or_matchers(vec![
+            vec![job.clone(), job.clone(), env.clone()],
+            vec![job.clone()],
+        ]);
+        matchers.dedup();
+        assert_eq!(
+            matchers,
+            Matchers::empty()
+                .with_or_matchers(vec![vec![job.clone(), env.clone()], vec![job.clone()]])
+        );
+
+        let mut matchers = match crate::parser::parse(r#"{job="api",job="api"}"#).unwrap() {
+            crate::parser::Expr::VectorSelector(vs) => vs.matchers,
+            _ => unreachable!(),
+        };
+        matchers.dedup();
+        assert_eq!(matchers, Matchers::one(job));
+        assert_eq!(r#"job="api""#, matchers.to_string());
+    }
+
+    #[test]
+    fn test_new_regex_validated() {
+        let (matcher, info) = Matcher::new_regex_validated("foo", "aaa{bbb}ccc").unwrap();
+        assert!(info.escaped);
+        assert_eq!(r"aaa\{bbb}ccc", info.pattern);
+        assert_eq!(r"^(?:aaa\{bbb}ccc)$", info.anchored_pattern);
+        assert_eq!("aaa{bbb}ccc", matcher.value);
+        assert!(matcher.is_match("aaa{bbb}ccc"));
+
+        let (matcher, info) = Matcher::new_regex_validated("foo", "a.*").unwrap();
+        assert!(!info.escaped);
+        assert_eq!("a.*", info.pattern);
+        assert_eq!("^(?:a.*)$", info.anchored_pattern);
+        assert_eq!(
+            matcher,
+            Matcher::new_matcher(T_EQL_REGEX, "foo".into(), "a.*".into()).unwrap()
+        );
+
+        let (matcher, info) = Matcher::new_regex_validated("foo", "a{1,3}").unwrap();
+        assert!(!info.escaped);
+        assert_eq!("a{1,3}", info.pattern);
+        assert_eq!(
+            matcher,
+            Matcher::new_matcher(T_EQL_REGEX, "foo".into(), "a{1,3}".into()).unwrap()
+        );
+
+        assert_eq!(
+            Err("illegal regex for (".into()),
+            Matcher::new_regex_validated("foo", "(")
+        );
+    }
+
+    #[test]
+    fn test_iter_or_groups() {
+        let a = Matcher::new(MatchOp::Equal, "a", "1");
+        let b = Matcher::new(MatchOp::Equal, "b", "2");
+        let c = Matcher::new(MatchOp::NotEqual, "c", "3");
+
+        let matchers = match crate::parser::parse(r#"{a="1" or b="2"}"#).unwrap() {
+            crate::parser::Expr::VectorSelector(vs) => vs.matchers,
+            _ => unreachable!(),
+        };
+        let groups: Vec<_> = matchers.iter_or_groups().collect();
+        assert_eq!(groups, vec![vec![&a], vec![&b]]);
+        assert!(std::ptr::eq(groups[0][0], &matchers.or_matchers[0][0]));
+
+        let matchers = Matchers::new(vec![a.clone(), c.clone()]);
+        let groups: Vec<_> = matchers.iter_or_groups().collect();
+        assert_eq!(groups, vec![vec![&a, &c]]);
+
+        let matchers =
+            Matchers::one(c.clone()).with_or_matchers(vec![vec![a.clone()], vec![b.clone()]]);
+        let groups: Vec<_> = matchers.iter_or_groups().collect();
+        assert_eq!(groups, vec![vec![&c, &a], vec![&c, &b]]);
+
+        assert_eq!(1, Matchers::empty().iter_or_groups().count());
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert_eq!(
+            Ok(Matcher::new(MatchOp::Equal, "job", "api")),
+            Matcher::try_new(MatchOp::Equal, "job", "api")
+        );
+        assert!(Matcher::try_new(MatchOp::NotEqual, crate::label::METRIC_NAME, "up").is_ok());
+        assert!(Matcher::try_new(MatchOp::Equal, "_a1", "").is_ok());
+
+        for name in ["1bad", "a:b", "", "a-b", "job "] {
+            assert_eq!(
+                Err(format!("invalid label name '{name}'")),
+                Matcher::try_new(MatchOp::Equal, name, "x")
+            );
+        }
+    }
+
+    #[test]
+    fn test_display_quotes() {
+        let cases = vec![
+            (r#"{a="x"}"#, r#"{a="x"}"#),
+            (r#"{a="x\"y"}"#, r#"{a="x\"y"}"#),
+            (r#"{a='x"y'}"#, r#"{a='x"y'}"#),
+            (r"{a='it\'s'}", r"{a='it\'s'}"),
+            (r#"{a=`x"y'z`}"#, r#"{a=`x"y'z`}"#),
+            (r#"{a="x\\y\n"}"#, r#"{a="x\\y\n"}"#),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+            assert_eq!(
+                expr,
+                crate::parser::parse(&expr.to_string()).unwrap(),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_name_only() {
+        let name = Matcher::new(MatchOp::Equal, METRIC_NAME, "foo");
+        let job = Matcher::new(MatchOp::Equal, "job", "x");
+
+        assert!(Matchers::one(name.clone()).is_name_only());
+
+        assert!(!Matchers::empty().is_name_only());
+        assert!(!Matchers::new(vec![name.clone(), job.clone()]).is_name_only());
+        assert!(!Matchers::one(job.clone()).is_name_only());
+        assert!(!Matchers::one(Matcher::new(MatchOp::NotEqual, METRIC_NAME, "foo")).is_name_only());
+        assert!(!Matchers::empty()
+            .append(name.clone())
+            .append_or(job)
+            .is_name_only());
+    }
+
+    #[test]
+    fn test_map_values() {
+        let mut matchers = Matchers::empty()
+            .append(Matcher::new(MatchOp::Equal, "a", "x"))
+            .append_or(Matcher::new(MatchOp::Equal, "b", "y"));
+        matchers.map_values(|v| v.to_uppercase()).unwrap();
+        assert_eq!(r#"a="X" or b="Y""#, matchers.to_string());
+
+        let mut matchers = Matchers::new(vec![
+            Matcher::new_matcher(T_EQL_REGEX, "a".into(), "x.*".into()).unwrap(),
+            Matcher::new_matcher(T_NEQ_REGEX, "b".into(), "y".into()).unwrap(),
+        ]);
+        matchers.map_values(|v| v.to_uppercase()).unwrap();
+        assert_eq!(r#"a=~"X.*",b!~"Y""#, matchers.to_string());
+        assert!(matchers.matchers[0].is_match("XZ"));
+        assert!(!matchers.matchers[0].is_match("xz"));
+        assert!(matchers.matchers[1].is_match("y"));
+
+        let mut matchers =
+            Matchers::one(Matcher::new_matcher(T_EQL_REGEX, "a".into(), "x".into()).unwrap());
+        assert_eq!(
+            Err(String::from("illegal regex for (")),
+            matchers.map_values(|_| String::from("("))
+        );
+
+        // a failure leaves all the matchers as they were
+        let mut matchers = Matchers::new(vec![
+            Matcher::new(MatchOp::Equal, "a", "x"),
+            Matcher::new_matcher(T_EQL_REGEX, "b".into(), "y".into()).unwrap(),
+        ]);
+        let before = matchers.clone();
+        assert!(matchers
+            .map_values(|v| if v == "y" {
+                "(".into()
+            } else {
+                v.to_uppercase()
+            })
+            .is_err());
+        assert_eq!(before, matchers);
+        assert_eq!(r#"a="x",b=~"y""#, matchers.to_string());
+    }
+
+    #[test]
+    fn test_to_string_truncated() {
+        let matchers = Matchers::new(
+            ["e", "d", "c", "b", "a"]
+                .into_iter()
+                .map(|name| Matcher::new(MatchOp::Equal, name, "1"))
+                .collect(),
+        );
+        assert_eq!(
+            r#"a="1",b="1", …(+3 more)"#,
+            matchers.to_string_truncated(2)
+        );
+        assert_eq!("…(+5 more)", matchers.to_string_truncated(0));
+        assert_eq!(matchers.to_string(), matchers.to_string_truncated(5));
+
+        let matchers = match crate::parser::parse(r#"{a="1", b="2" or c="3" or d="4"}"#).unwrap() {
+            crate::parser::Expr::VectorSelector(vs) => vs.matchers,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            r#"a="1", b="2" or c="3", …(+1 more)"#,
+            matchers.to_string_truncated(3)
+        );
+        assert_eq!(r#"a="1", …(+3 more)"#, matchers.to_string_truncated(1));
+    }
+
+    #[test]
+    fn test_matches_opt() {
+        let ne = Matcher::new(MatchOp::NotEqual, "foo", "bar");
+        assert!(ne.matches_opt(None));
+        assert!(ne.matches_opt(Some("baz")));
+        assert!(!ne.matches_opt(Some("bar")));
+
+        let eq = Matcher::new(MatchOp::Equal, "foo", "bar");
+        assert!(!eq.matches_opt(None));
+        assert!(eq.matches_opt(Some("bar")));
+
+        let nre = Matcher::new_matcher(T_NEQ_REGEX, "foo".into(), "b.*".into()).unwrap();
+        assert!(nre.matches_opt(None));
+        assert!(!nre.matches_opt(Some("bar")));
+    }
+
+    #[test]
+    fn test_matches_empty() {
+        let cases = vec![
+            (T_EQL_REGEX, ".*", true),
+            (T_NEQ_REGEX, ".+", true),
+            (T_NEQ, "a", true),
+            (T_EQL, "", true),
+            (T_EQL_REGEX, ".+", false),
+            (T_NEQ_REGEX, ".*", false),
+            (T_NEQ, "", false),
+            (T_EQL, "a", false),
+        ];
+
+        for (id, value, expected) in cases {
+            let matcher = Matcher::new_matcher(id, "x".into(), value.into()).unwrap();
+            assert_eq!(expected, matcher.matches_empty(), "{matcher}");
+            assert_eq!(
+                expected,
+                Matchers::one(matcher.clone()).is_empty_matchers(),
+                "{matcher}"
+            );
+        }
+    }
+
     #[test]
     fn test_convert_re() {
         assert_eq!(try_escape_for_repeat_re("abc{}"), r"abc\{}");
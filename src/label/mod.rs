@@ -18,7 +18,8 @@ use std::collections::HashSet;
 use std::fmt;
 
 mod matcher;
-pub use matcher::{MatchOp, Matcher, Matchers};
+pub(crate) use matcher::write_quoted;
+pub use matcher::{MatchOp, Matcher, Matchers, RegexInfo};
 
 /// "__name__"
 pub const METRIC_NAME: &str = "__name__";
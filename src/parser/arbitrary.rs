@@ -0,0 +1,285 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Arbitrary valid expressions for fuzzing, enabled by the `arbitrary` feature.
+//!
+//! Every node is built with the same constructors and checks as the parser, and
+//! falls back to a plain literal or selector if the checks fail, so the generated
+//! expressions are always type-valid and parse back from their string form.
+
+use ::arbitrary::{Arbitrary, Result, Unstructured};
+use std::time::Duration;
+
+use crate::label::{Labels, MatchOp, Matcher, Matchers};
+use crate::parser::ast::check_ast;
+use crate::parser::function::get_function;
+use crate::parser::token::{
+    TokenId, TokenType, T_ADD, T_ATAN2, T_AVG, T_BOTTOMK, T_COUNT, T_COUNT_VALUES, T_DIV, T_EQLC,
+    T_GROUP, T_GTE, T_GTR, T_LAND, T_LOR, T_LSS, T_LTE, T_LUNLESS, T_MAX, T_MIN, T_MOD, T_MUL,
+    T_NEQ, T_POW, T_QUANTILE, T_STDDEV, T_STDVAR, T_SUB, T_SUM, T_TOPK,
+};
+use crate::parser::value::ValueType;
+use crate::parser::{Expr, FunctionArgs, LabelModifier, Offset};
+
+const MAX_DEPTH: usize = 4;
+
+const METRIC_NAMES: &[&str] = &["foo", "bar", "up", "http_requests_total"];
+const LABEL_NAMES: &[&str] = &["job", "instance", "env"];
+const LABEL_VALUES: &[&str] = &["api", "web", "prod", "a.*"];
+
+const BINARY_OPERATORS: &[TokenId] = &[
+    T_ADD, T_SUB, T_MUL, T_DIV, T_MOD, T_POW, T_ATAN2, T_EQLC, T_NEQ, T_GTR, T_GTE, T_LSS, T_LTE,
+    T_LAND, T_LOR, T_LUNLESS,
+];
+const AGGREGATORS: &[TokenId] = &[
+    T_SUM,
+    T_AVG,
+    T_MIN,
+    T_MAX,
+    T_COUNT,
+    T_GROUP,
+    T_STDDEV,
+    T_STDVAR,
+    T_TOPK,
+    T_BOTTOMK,
+    T_QUANTILE,
+    T_COUNT_VALUES,
+];
+const VECTOR_FUNCTIONS: &[&str] = &[
+    "abs",
+    "absent",
+    "ceil",
+    "clamp",
+    "clamp_max",
+    "day_of_week",
+    "delta",
+    "histogram_quantile",
+    "increase",
+    "label_replace",
+    "max_over_time",
+    "predict_linear",
+    "quantile_over_time",
+    "rate",
+    "round",
+    "sort",
+    "timestamp",
+    "vector",
+];
+const SCALAR_FUNCTIONS: &[&str] = &["pi", "scalar", "time"];
+
+impl<'a> Arbitrary<'a> for Expr {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let value_type = *u.choose(&[
+            ValueType::Vector,
+            ValueType::Scalar,
+            ValueType::Matrix,
+            ValueType::String,
+        ])?;
+        arbitrary_expr(u, value_type, MAX_DEPTH)
+    }
+}
+
+/// an expression of the given value type, nested at most `depth` levels.
+fn arbitrary_expr(u: &mut Unstructured, value_type: ValueType, depth: usize) -> Result<Expr> {
+    if depth == 0 || u.is_empty() {
+        return leaf(u, value_type);
+    }
+
+    let depth = depth - 1;
+    let built = match value_type {
+        ValueType::Vector => match u.int_in_range(0..=5)? {
+            0 => return leaf(u, value_type),
+            1 => arbitrary_unary(u, value_type, depth)?,
+            2 => {
+                let lhs_type = *u.choose(&[ValueType::Vector, ValueType::Scalar])?;
+                arbitrary_binary(u, lhs_type, ValueType::Vector, depth)?
+            }
+            3 => Expr::new_paren_expr(arbitrary_expr(u, value_type, depth)?),
+            4 => arbitrary_aggregate(u, depth)?,
+            _ => arbitrary_call(u, VECTOR_FUNCTIONS, depth)?,
+        },
+        ValueType::Scalar => match u.int_in_range(0..=4)? {
+            0 => return leaf(u, value_type),
+            1 => arbitrary_unary(u, value_type, depth)?,
+            2 => arbitrary_binary(u, ValueType::Scalar, ValueType::Scalar, depth)?,
+            3 => Expr::new_paren_expr(arbitrary_expr(u, value_type, depth)?),
+            _ => arbitrary_call(u, SCALAR_FUNCTIONS, depth)?,
+        },
+        ValueType::Matrix => match u.int_in_range(0..=1)? {
+            0 => return leaf(u, value_type),
+            _ => {
+                let expr = parenthesize(arbitrary_expr(u, ValueType::Vector, depth)?);
+                let range = arbitrary_duration(u)?;
+                let step = if u.arbitrary()? {
+                    Some(arbitrary_duration(u)?)
+                } else {
+                    None
+                };
+                Expr::new_subquery_expr(expr, range, step)
+                    .and_then(|expr| arbitrary_offset(u, expr))
+            }
+        },
+        ValueType::String => return leaf(u, value_type),
+    };
+
+    // invalid combinations, like comparisons between scalars without bool,
+    // are replaced by a leaf of the same type.
+    match built.and_then(check_ast) {
+        Ok(expr) if expr.value_type() == value_type => Ok(expr),
+        _ => leaf(u, value_type),
+    }
+}
+
+fn leaf(u: &mut Unstructured, value_type: ValueType) -> Result<Expr> {
+    let expr = match value_type {
+        ValueType::Vector => {
+            let vs = arbitrary_selector(u)?;
+            arbitrary_offset(u, vs)
+        }
+        ValueType::Matrix => {
+            let range = arbitrary_duration(u)?;
+            Expr::new_matrix_selector(arbitrary_selector(u)?, range)
+                .and_then(|expr| arbitrary_offset(u, expr))
+        }
+        ValueType::Scalar => Ok(Expr::from(f64::from(u.arbitrary::<u16>()?))),
+        ValueType::String => Ok(Expr::from(*u.choose(LABEL_VALUES)?)),
+    };
+    // leaves are always valid
+    Ok(expr.expect("arbitrary leaf"))
+}
+
+fn arbitrary_selector(u: &mut Unstructured) -> Result<Expr> {
+    let name = *u.choose(METRIC_NAMES)?;
+    let mut matchers = Matchers::empty();
+    for _ in 0..u.int_in_range(0..=2)? {
+        let op = match u.int_in_range(0..=1)? {
+            0 => MatchOp::Equal,
+            _ => MatchOp::NotEqual,
+        };
+        let matcher = Matcher::new(op, u.choose(LABEL_NAMES)?, u.choose(LABEL_VALUES)?);
+        matchers = matchers.append(matcher);
+    }
+    Ok(Expr::new_vector_selector(Some(name.into()), matchers).expect("arbitrary selector"))
+}
+
+fn arbitrary_duration(u: &mut Unstructured) -> Result<Duration> {
+    Ok(Duration::from_secs(u.int_in_range(1..=3600)?))
+}
+
+fn arbitrary_offset(u: &mut Unstructured, expr: Expr) -> std::result::Result<Expr, String> {
+    match u.int_in_range(0..=2) {
+        Ok(1) => expr.offset_expr(Offset::Pos(Duration::from_secs(60))),
+        Ok(2) => expr.offset_expr(Offset::Neg(Duration::from_secs(60))),
+        _ => Ok(expr),
+    }
+}
+
+/// operands which are operations themselves are wrapped in parentheses, so that
+/// the rendered expression parses back to the same tree.
+fn parenthesize(expr: Expr) -> Expr {
+    let is_operation = match &expr {
+        Expr::Binary(_) | Expr::Unary(_) => true,
+        Expr::NumberLiteral(nl) => nl.val.is_sign_negative(),
+        _ => false,
+    };
+    if is_operation {
+        Expr::new_paren_expr(expr).expect("paren")
+    } else {
+        expr
+    }
+}
+
+fn arbitrary_unary(
+    u: &mut Unstructured,
+    value_type: ValueType,
+    depth: usize,
+) -> Result<std::result::Result<Expr, String>> {
+    let expr = parenthesize(arbitrary_expr(u, value_type, depth)?);
+    Ok(Expr::new_unary_expr(expr))
+}
+
+fn arbitrary_binary(
+    u: &mut Unstructured,
+    lhs_type: ValueType,
+    rhs_type: ValueType,
+    depth: usize,
+) -> Result<std::result::Result<Expr, String>> {
+    let op = *u.choose(BINARY_OPERATORS)?;
+    let lhs = parenthesize(arbitrary_expr(u, lhs_type, depth)?);
+    let rhs = parenthesize(arbitrary_expr(u, rhs_type, depth)?);
+    Ok(Expr::new_binary_expr(lhs, op, None, rhs))
+}
+
+fn arbitrary_aggregate(
+    u: &mut Unstructured,
+    depth: usize,
+) -> Result<std::result::Result<Expr, String>> {
+    let op = *u.choose(AGGREGATORS)?;
+    let expr = arbitrary_expr(u, ValueType::Vector, depth)?;
+    let args = if TokenType::new(op).is_aggregator_with_param() {
+        let param_type = if op == T_COUNT_VALUES {
+            ValueType::String
+        } else {
+            ValueType::Scalar
+        };
+        FunctionArgs::new_args(arbitrary_expr(u, param_type, depth)?).append_args(expr)
+    } else {
+        FunctionArgs::new_args(expr)
+    };
+
+    let labels = Labels::new(vec![*u.choose(LABEL_NAMES)?]);
+    let modifier = match u.int_in_range(0..=2)? {
+        1 => Some(LabelModifier::Include(labels)),
+        2 => Some(LabelModifier::Exclude(labels)),
+        _ => None,
+    };
+    Ok(Expr::new_aggregate_expr(op, modifier, args))
+}
+
+fn arbitrary_call(
+    u: &mut Unstructured,
+    names: &[&str],
+    depth: usize,
+) -> Result<std::result::Result<Expr, String>> {
+    let func = get_function(u.choose(names)?).expect("registered function");
+    let mut args = FunctionArgs::empty_args();
+    for arg_type in func.arg_types.clone() {
+        args = args.append_args(arbitrary_expr(u, arg_type, depth)?);
+    }
+    Ok(Expr::new_call(func, args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn test_arbitrary_expr() {
+        for seed in 0..64u32 {
+            let bytes: Vec<u8> = (0..512u32)
+                .map(|i| (i.wrapping_mul(31) ^ seed.wrapping_mul(2654435761)) as u8)
+                .collect();
+            let mut u = Unstructured::new(&bytes);
+            let expr = Expr::arbitrary(&mut u).unwrap();
+
+            // the generated expression passes the checks of the parser again, the
+            // matchers are generated in any order, but rendered sorted
+            let rendered = expr.to_string();
+            let parsed = parser::parse(&rendered).unwrap_or_else(|e| panic!("{rendered}: {e}"));
+            assert_eq!(rendered, parsed.to_string());
+            assert!(check_ast(expr).is_ok(), "{rendered}");
+        }
+    }
+}
@@ -12,16 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::label::{Labels, Matchers, METRIC_NAME};
+use crate::label::{write_quoted, Labels, MatchOp, Matcher, Matchers, METRIC_NAME};
+use crate::parser::errors;
+use crate::parser::function::{get_function, FunctionId};
+use crate::parser::lex::{is_label, is_metric_name};
+use crate::parser::lint::{self, Lint};
 use crate::parser::token::{
-    self, token_display, T_BOTTOMK, T_COUNT_VALUES, T_END, T_QUANTILE, T_START, T_TOPK,
+    self, token_display, T_ADD, T_ATAN2, T_BOTTOMK, T_COUNT_VALUES, T_DIV, T_END, T_EQLC, T_GTE,
+    T_GTR, T_LAND, T_LOR, T_LSS, T_LTE, T_MOD, T_MUL, T_NEQ, T_POW, T_QUANTILE, T_START, T_SUB,
+    T_TOPK,
 };
 use crate::parser::token::{Token, TokenId, TokenType};
 use crate::parser::value::ValueType;
-use crate::parser::{indent, Function, FunctionArgs, Prettier, MAX_CHARACTERS_PER_LINE};
-use crate::util::display_duration;
+use crate::parser::{
+    indent, Function, FunctionArgs, Prettier, PrettyConfig, PrettyStyle, MAX_CHARACTERS_PER_LINE,
+};
+use crate::util::{
+    display_duration, percent_encode, walk_expr_with_path, ExprPathVisitor, PathSegment,
+};
 use chrono::{DateTime, Utc};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Write};
+use std::hash::{DefaultHasher, Hasher};
 use std::ops::Neg;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
@@ -67,6 +79,41 @@ impl LabelModifier {
     pub fn exclude(ls: Vec<&str>) -> Self {
         Self::Exclude(Labels::new(ls))
     }
+
+    /// the listed labels as a set, regardless of include or exclude.
+    pub fn as_set(&self) -> HashSet<&str> {
+        self.labels().labels.iter().map(String::as_str).collect()
+    }
+
+    /// create an Include modifier from a set of labels, labels are sorted
+    /// to keep the result deterministic.
+    pub fn from_include_set<S: AsRef<str>>(set: &HashSet<S>) -> Self {
+        Self::include(sorted_labels(set))
+    }
+
+    /// create an Exclude modifier from a set of labels, labels are sorted
+    /// to keep the result deterministic.
+    pub fn from_exclude_set<S: AsRef<str>>(set: &HashSet<S>) -> Self {
+        Self::exclude(sorted_labels(set))
+    }
+}
+
+fn sorted_labels<S: AsRef<str>>(set: &HashSet<S>) -> Vec<&str> {
+    let mut labels: Vec<&str> = set.iter().map(|l| l.as_ref()).collect();
+    labels.sort_unstable();
+    labels
+}
+
+/// The labels kept in the result of an expression by its outermost aggregation,
+/// see [`Expr::labels_required_by_grouping`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupingResult {
+    /// only the listed labels are kept
+    By(Vec<String>),
+    /// all the labels except the listed ones, and the metric name, are kept
+    Without(Vec<String>),
+    /// the labels of the input series are kept as is
+    All,
 }
 
 /// The label list provided with the group_left or group_right modifier contains
@@ -114,28 +161,26 @@ pub struct BinModifier {
     pub return_bool: bool,
 }
 
+/// each part of the modifier is rendered with a single leading space, so that it
+/// can directly follow the operator, e.g. ` bool on (job) group_left (instance)`.
 impl fmt::Display for BinModifier {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut s = String::from(self.bool_str());
+        if self.return_bool {
+            write!(f, " bool")?;
+        }
 
         if let Some(matching) = &self.matching {
             match matching {
-                LabelModifier::Include(ls) => write!(s, "on ({ls}) ")?,
-                LabelModifier::Exclude(ls) if !ls.is_empty() => write!(s, "ignoring ({ls}) ")?,
+                LabelModifier::Include(ls) => write!(f, " on ({ls})")?,
+                LabelModifier::Exclude(ls) if !ls.is_empty() => write!(f, " ignoring ({ls})")?,
                 _ => (),
             }
         }
 
         match &self.card {
-            VectorMatchCardinality::ManyToOne(ls) => write!(s, "group_left ({ls}) ")?,
-            VectorMatchCardinality::OneToMany(ls) => write!(s, "group_right ({ls}) ")?,
-            _ => (),
-        }
-
-        if s.trim().is_empty() {
-            write!(f, "")
-        } else {
-            write!(f, " {}", s.trim_end()) // there is a leading space here
+            VectorMatchCardinality::ManyToOne(ls) => write!(f, " group_left ({ls})"),
+            VectorMatchCardinality::OneToMany(ls) => write!(f, " group_right ({ls})"),
+            _ => Ok(()),
         }
     }
 }
@@ -329,6 +374,13 @@ pub enum Offset {
 }
 
 impl Offset {
+    /// whether the offset shifts nothing, in either direction.
+    pub(crate) fn is_zero(&self) -> bool {
+        match self {
+            Self::Pos(dur) | Self::Neg(dur) => dur.is_zero(),
+        }
+    }
+
     #[cfg(feature = "ser")]
     pub(crate) fn as_millis(&self) -> i128 {
         match self {
@@ -353,8 +405,9 @@ impl Offset {
 impl fmt::Display for Offset {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Offset::Pos(dur) => write!(f, "{}", display_duration(dur)),
-            Offset::Neg(dur) => write!(f, "-{}", display_duration(dur)),
+            // a negative zero offset is the same as a zero offset, never render `-0s`
+            Offset::Neg(dur) if !dur.is_zero() => write!(f, "-{}", display_duration(dur)),
+            Offset::Pos(dur) | Offset::Neg(dur) => write!(f, "{}", display_duration(dur)),
         }
     }
 }
@@ -363,7 +416,8 @@ impl fmt::Display for Offset {
 pub enum AtModifier {
     Start,
     End,
-    /// at can be earlier than UNIX_EPOCH
+    /// at can be earlier than UNIX_EPOCH. Timestamps are rounded to the nearest
+    /// millisecond, so `@ 3.3335` and `@ 3.334` are equal, see `test_at_modifier_rounding`.
     At(SystemTime),
 }
 
@@ -376,7 +430,11 @@ impl fmt::Display for AtModifier {
                 let d = time
                     .duration_since(SystemTime::UNIX_EPOCH)
                     .unwrap_or(Duration::ZERO); // This should not happen
-                write!(f, "@ {:.3}", d.as_secs() as f64)
+
+                // the precision of the formatter decides the decimals, 3 by default,
+                // see Expr::to_string_with_at_precision
+                let precision = f.precision().unwrap_or(3);
+                write!(f, "@ {:.*}", precision, d.as_millis() as f64 / 1000.0)
             }
         }
     }
@@ -419,7 +477,7 @@ impl TryFrom<Expr> for AtModifier {
     fn try_from(ex: Expr) -> Result<Self, Self::Error> {
         match ex {
             Expr::NumberLiteral(nl) => AtModifier::try_from(nl),
-            _ => Err("invalid float value after @ modifier".into()),
+            _ => Err(errors::INVALID_AT_FLOAT.into()),
         }
     }
 }
@@ -466,6 +524,38 @@ pub struct EvalStmt {
     pub lookback_delta: Duration,
 }
 
+impl EvalStmt {
+    /// create the statement of a range query, checking the range is valid: the end
+    /// must not be before the start, the interval and the lookback delta must not be
+    /// zero, and the expression must evaluate to a scalar or an instant vector.
+    pub fn try_new_range(
+        expr: Expr,
+        start: SystemTime,
+        end: SystemTime,
+        interval: Duration,
+        lookback_delta: Duration,
+    ) -> Result<Self, String> {
+        if end < start {
+            return Err(errors::END_BEFORE_START.into());
+        }
+        if interval.is_zero() {
+            return Err(errors::ZERO_STEP.into());
+        }
+        if lookback_delta.is_zero() {
+            return Err(errors::ZERO_LOOKBACK_DELTA.into());
+        }
+        expr.check_query_type("range")?;
+
+        Ok(Self {
+            expr,
+            start,
+            end,
+            interval,
+            lookback_delta,
+        })
+    }
+}
+
 impl fmt::Display for EvalStmt {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -523,11 +613,11 @@ impl fmt::Display for AggregateExpr {
 
         write!(f, "(")?;
         if let Some(param) = &self.param {
-            write!(f, "{param}, ")?;
+            fmt::Display::fmt(param, f)?;
+            write!(f, ", ")?;
         }
-        write!(f, "{})", self.expr)?;
-
-        Ok(())
+        fmt::Display::fmt(&self.expr, f)?;
+        write!(f, ")")
     }
 }
 
@@ -551,7 +641,8 @@ pub struct UnaryExpr {
 
 impl fmt::Display for UnaryExpr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "-{}", self.expr)
+        write!(f, "-")?;
+        fmt::Display::fmt(&self.expr, f)
     }
 }
 
@@ -636,13 +727,9 @@ impl BinaryExpr {
 
 impl fmt::Display for BinaryExpr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{} {} {}",
-            self.lhs,
-            self.get_op_matching_string(),
-            self.rhs
-        )
+        fmt::Display::fmt(&self.lhs, f)?;
+        write!(f, " {} ", self.get_op_matching_string())?;
+        fmt::Display::fmt(&self.rhs, f)
     }
 }
 
@@ -666,7 +753,9 @@ pub struct ParenExpr {
 
 impl fmt::Display for ParenExpr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "({})", self.expr)
+        write!(f, "(")?;
+        fmt::Display::fmt(&self.expr, f)?;
+        write!(f, ")")
     }
 }
 
@@ -708,29 +797,34 @@ pub struct SubqueryExpr {
 }
 
 impl SubqueryExpr {
-    fn get_time_suffix_string(&self) -> String {
+    /// the suffix after the inner expression, e.g. `[10m:] offset 5m`. A missing
+    /// step and a zero one, which the parser rejects, both render as the empty step.
+    fn fmt_time_suffix(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let step = match &self.step {
-            Some(step) => display_duration(step),
-            None => String::from(""),
+            Some(step) if !step.is_zero() => display_duration(step),
+            _ => String::from(""),
         };
-        let range = display_duration(&self.range);
+        write!(f, "[{}:{step}]", display_duration(&self.range))?;
+        write_at_and_offset(f, &self.at, &self.offset)
+    }
 
-        let mut s = format!("[{range}:{step}]");
+    fn get_time_suffix_string(&self) -> String {
+        struct TimeSuffix<'a>(&'a SubqueryExpr);
 
-        if let Some(at) = &self.at {
-            write!(s, " {at}").unwrap();
+        impl fmt::Display for TimeSuffix<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt_time_suffix(f)
+            }
         }
 
-        if let Some(offset) = &self.offset {
-            write!(s, " offset {offset}").unwrap();
-        }
-        s
+        TimeSuffix(self).to_string()
     }
 }
 
 impl fmt::Display for SubqueryExpr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}{}", self.expr, self.get_time_suffix_string())
+        fmt::Display::fmt(&self.expr, f)?;
+        self.fmt_time_suffix(f)
     }
 }
 
@@ -753,8 +847,42 @@ impl NumberLiteral {
     pub fn new(val: f64) -> Self {
         Self { val }
     }
+
+    /// renders the number with the given format, Inf and NaN are rendered
+    /// the same way as Display does.
+    pub fn format(&self, format: NumberFormat) -> String {
+        if self.val.is_infinite() || self.val.is_nan() {
+            return self.to_string();
+        }
+        match format {
+            NumberFormat::Default => self.to_string(),
+            NumberFormat::Fixed(n) => format!("{:.n$}", self.val),
+            NumberFormat::Exponent => format!("{:e}", self.val),
+        }
+    }
+
+    /// the strict IEEE 754 comparison of the values, under which `NaN` is not equal
+    /// to anything, itself included. Unlike this, `==` treats two `NaN` literals as
+    /// equal, so that ASTs containing `NaN` compare equal to themselves.
+    pub fn ieee_eq(&self, other: &Self) -> bool {
+        self.val == other.val
+    }
+}
+
+/// how [NumberLiteral::format] renders a number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumberFormat {
+    /// same as Display, e.g. `0.005`
+    #[default]
+    Default,
+    /// fixed number of decimals, e.g. `0.005000` with 6 decimals
+    Fixed(usize),
+    /// always in exponent form, e.g. `5e-3`
+    Exponent,
 }
 
+/// `NaN` literals are equal to each other, unlike in IEEE 754, see [`NumberLiteral::ieee_eq`]
+/// for the strict comparison. `0` and `-0` are equal.
 impl PartialEq for NumberLiteral {
     fn eq(&self, other: &Self) -> bool {
         self.val == other.val || self.val.is_nan() && other.val.is_nan()
@@ -845,6 +973,85 @@ impl VectorSelector {
             at: None,
         }
     }
+
+    /// whether both selectors select the same series, comparing the name and the
+    /// matchers but neither the offset nor the `@` modifier. The order of the simple
+    /// matchers doesn't matter, like in their rendering, e.g. `foo{a="1",b="2"}` and
+    /// `foo{b="2",a="1"}` are the same.
+    pub fn matchers_eq(&self, other: &VectorSelector) -> bool {
+        // a selector only has a few matchers, so they are counted linearly
+        let count = |matchers: &[Matcher], m: &Matcher| matchers.iter().filter(|n| *n == m).count();
+        let (lhs, rhs) = (&self.matchers.matchers, &other.matchers.matchers);
+        self.name == other.name
+            && self.matchers.or_matchers == other.matchers.or_matchers
+            && lhs.len() == rhs.len()
+            && lhs.iter().all(|m| count(lhs, m) == count(rhs, m))
+    }
+
+    /// whether the selector can select series of the metric, either by its name, e.g.
+    /// `foo{job="a"}`, or by a `__name__` matcher, e.g. `{__name__=~"foo|bar"}`.
+    pub fn matches_name(&self, name: &str) -> bool {
+        if let Some(n) = &self.name {
+            return n == name;
+        }
+        self.matchers
+            .matchers
+            .iter()
+            .chain(self.matchers.or_matchers.iter().flatten())
+            .filter(|m| m.name == METRIC_NAME)
+            .any(|m| match &m.op {
+                // the regex compiled by the parser is reused, see `Matcher::is_match`
+                MatchOp::Equal | MatchOp::Re(_) => m.is_match(name),
+                MatchOp::NotEqual | MatchOp::NotRe(_) => false,
+            })
+    }
+
+    /// a crude score of how selective the selector is, i.e. the higher the fewer
+    /// series it likely selects, for query planning. The metric name and equality
+    /// matchers weigh the most, then regex matchers, then the negated ones. With
+    /// or-groups, only the least selective group counts.
+    pub fn selectivity_score(&self) -> u32 {
+        fn score(matchers: &[Matcher]) -> u32 {
+            matchers
+                .iter()
+                .map(|m| match m.op {
+                    MatchOp::Equal => 4,
+                    MatchOp::Re(_) => 2,
+                    MatchOp::NotEqual | MatchOp::NotRe(_) => 1,
+                })
+                .sum()
+        }
+
+        let name = if self.name.is_some() { 4 } else { 0 };
+        let or_groups = self
+            .matchers
+            .or_matchers
+            .iter()
+            .map(|group| score(group))
+            .min()
+            .unwrap_or(0);
+        name + score(&self.matchers.matchers) + or_groups
+    }
+
+    /// the selector without offset and `@` modifier, which is the `match[]`
+    /// argument of the Prometheus series API, e.g. `foo{job="a"}`.
+    pub fn to_prometheus_match_arg(&self) -> String {
+        let vs = VectorSelector {
+            offset: None,
+            at: None,
+            ..self.clone()
+        };
+        vs.to_string()
+    }
+
+    /// `match[]=` followed by the percent-encoded [match argument](VectorSelector::to_prometheus_match_arg),
+    /// ready to be appended to the query of a `/api/v1/series` URL.
+    pub fn to_series_query(&self) -> String {
+        format!(
+            "match[]={}",
+            percent_encode(&self.to_prometheus_match_arg())
+        )
+    }
 }
 
 impl Default for VectorSelector {
@@ -903,22 +1110,78 @@ impl Neg for VectorSelector {
     }
 }
 
-impl fmt::Display for VectorSelector {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(name) = &self.name {
-            write!(f, "{name}")?;
-        }
-        let matchers = &self.matchers.to_string();
-        if !matchers.is_empty() {
-            write!(f, "{{{matchers}}}")?;
+/// render the name and the matchers of a selector. Names which aren't valid
+/// identifiers are quoted inside the braces, e.g. `{"http.requests",job="api"}`.
+fn write_name_and_matchers(
+    f: &mut fmt::Formatter,
+    name: &Option<String>,
+    matchers: &Matchers,
+) -> fmt::Result {
+    match name {
+        // the quoted name only applies to the `or` group it is written in
+        Some(name) if !is_metric_name(name) && !matchers.or_matchers.is_empty() => {
+            write!(f, "{{")?;
+            for (idx, group) in matchers.or_matchers.iter().enumerate() {
+                if idx > 0 {
+                    write!(f, " or ")?;
+                }
+                write_quoted(f, name)?;
+                for m in group {
+                    write!(f, ", {m}")?;
+                }
+            }
+            write!(f, "}}")
         }
-        if let Some(at) = &self.at {
-            write!(f, " {at}")?;
+        Some(name) if !is_metric_name(name) => {
+            let matchers = matchers.to_string();
+            write!(f, "{{")?;
+            write_quoted(f, name)?;
+            if !matchers.is_empty() {
+                write!(f, ",{matchers}")?;
+            }
+            write!(f, "}}")
         }
-        if let Some(offset) = &self.offset {
-            write!(f, " offset {offset}")?;
+        _ => {
+            if let Some(name) = name {
+                write!(f, "{name}")?;
+            }
+            let matchers = matchers.to_string();
+            if !matchers.is_empty() {
+                write!(f, "{{{matchers}}}")?;
+            }
+            Ok(())
         }
-        Ok(())
+    }
+}
+
+/// the offset to render, if any. A zero offset changes nothing, and the parser
+/// rejects it anyway, so it is left out like a missing one.
+fn rendered_offset(offset: &Option<Offset>) -> Option<&Offset> {
+    offset.as_ref().filter(|offset| !offset.is_zero())
+}
+
+/// render the `@` and `offset` modifiers shared by selectors and subqueries, each
+/// with a leading space, e.g. ` @ 100.000 offset 5m`. The formatter is passed down
+/// so that its precision applies to the timestamp of `@`.
+fn write_at_and_offset(
+    f: &mut fmt::Formatter,
+    at: &Option<AtModifier>,
+    offset: &Option<Offset>,
+) -> fmt::Result {
+    if let Some(at) = at {
+        write!(f, " ")?;
+        fmt::Display::fmt(at, f)?;
+    }
+    if let Some(offset) = rendered_offset(offset) {
+        write!(f, " offset {offset}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for VectorSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_name_and_matchers(f, &self.name, &self.matchers)?;
+        write_at_and_offset(f, &self.at, &self.offset)
     }
 }
 
@@ -942,26 +1205,10 @@ pub struct MatrixSelector {
 
 impl fmt::Display for MatrixSelector {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(name) = &self.vs.name {
-            write!(f, "{name}")?;
-        }
-
-        let matchers = &self.vs.matchers.to_string();
-        if !matchers.is_empty() {
-            write!(f, "{{{matchers}}}")?;
-        }
+        write_name_and_matchers(f, &self.vs.name, &self.vs.matchers)?;
 
         write!(f, "[{}]", display_duration(&self.range))?;
-
-        if let Some(at) = &self.vs.at {
-            write!(f, " {at}")?;
-        }
-
-        if let Some(offset) = &self.vs.offset {
-            write!(f, " offset {offset}")?;
-        }
-
-        Ok(())
+        write_at_and_offset(f, &self.vs.at, &self.vs.offset)
     }
 }
 
@@ -1018,9 +1265,57 @@ pub struct Call {
     pub args: FunctionArgs,
 }
 
+impl Call {
+    /// start building a call to `func`, the arguments are checked against
+    /// the signature of `func` as they are added.
+    pub fn builder(func: Function) -> CallBuilder {
+        CallBuilder {
+            func,
+            args: FunctionArgs::empty_args(),
+            err: None,
+        }
+    }
+}
+
+/// Builder of [Call] created by [Call::builder], which validates the count
+/// and the types of the arguments the same way as the parser does.
+#[derive(Debug, Clone)]
+pub struct CallBuilder {
+    func: Function,
+    args: FunctionArgs,
+    err: Option<String>,
+}
+
+impl CallBuilder {
+    /// append an argument. The first invalid argument is remembered and
+    /// reported by [CallBuilder::build].
+    pub fn arg(mut self, arg: Expr) -> Self {
+        if self.err.is_none() {
+            if let Err(e) = check_call_arg(&self.func, self.args.len(), &arg) {
+                self.err = Some(e);
+            }
+        }
+        self.args = self.args.append_args(arg);
+        self
+    }
+
+    pub fn build(self) -> Result<Call, String> {
+        if let Some(err) = self.err {
+            return Err(err);
+        }
+        check_call_args_len(&self.func, self.args.len())?;
+        Ok(Call {
+            func: self.func,
+            args: self.args,
+        })
+    }
+}
+
 impl fmt::Display for Call {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}({})", self.func.name, self.args)
+        write!(f, "{}(", self.func.name)?;
+        fmt::Display::fmt(&self.args, f)?;
+        write!(f, ")")
     }
 }
 
@@ -1061,6 +1356,34 @@ impl PartialEq for Extension {
 
 impl Eq for Extension {}
 
+/// A built-in [ExtensionExpr] attaching a comment to the wrapped expression,
+/// which is its only child.
+#[cfg(feature = "comment-extension")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommentExtension {
+    pub expr: Expr,
+    pub comment: String,
+}
+
+#[cfg(feature = "comment-extension")]
+impl ExtensionExpr for CommentExtension {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        "comment"
+    }
+
+    fn value_type(&self) -> ValueType {
+        self.expr.value_type()
+    }
+
+    fn children(&self) -> &[Expr] {
+        std::slice::from_ref(&self.expr)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "ser", derive(serde::Serialize))]
 #[cfg_attr(feature = "ser", serde(tag = "type", rename_all = "camelCase"))]
@@ -1118,8 +1441,8 @@ impl Expr {
 
     pub(crate) fn new_unary_expr(expr: Expr) -> Result<Self, String> {
         match expr {
-            Expr::StringLiteral(_) => Err("unary expression only allowed on expressions of type scalar or vector, got: string".into()),
-            Expr::MatrixSelector(_) => Err("unary expression only allowed on expressions of type scalar or vector, got: matrix".into()),
+            Expr::StringLiteral(_) => Err(errors::UNARY_ON_STRING.into()),
+            Expr::MatrixSelector(_) => Err(errors::UNARY_ON_MATRIX.into()),
             _ => Ok(-expr),
         }
     }
@@ -1151,20 +1474,20 @@ impl Expr {
         match expr {
             Expr::VectorSelector(VectorSelector {
                 offset: Some(_), ..
-            }) => Err("no offset modifiers allowed before range".into()),
+            }) => Err(errors::OFFSET_BEFORE_RANGE.into()),
             Expr::VectorSelector(VectorSelector { at: Some(_), .. }) => {
-                Err("no @ modifiers allowed before range".into())
+                Err(errors::AT_BEFORE_RANGE.into())
             }
             Expr::VectorSelector(vs) => {
                 let ms = Expr::MatrixSelector(MatrixSelector { vs, range });
                 Ok(ms)
             }
-            _ => Err("ranges only allowed for vector selectors".into()),
+            _ => Err(errors::RANGE_ON_NON_SELECTOR.into()),
         }
     }
 
     pub(crate) fn at_expr(self, at: AtModifier) -> Result<Self, String> {
-        let already_set_err = Err("@ <timestamp> may not be set multiple times".into());
+        let already_set_err = Err(errors::AT_SET_TWICE.into());
         match self {
             Expr::VectorSelector(mut vs) => match vs.at {
                 None => {
@@ -1187,15 +1510,13 @@ impl Expr {
                 }
                 Some(_) => already_set_err,
             },
-            _ => {
-                Err("@ modifier must be preceded by an vector selector or matrix selector or a subquery".into())
-            }
+            _ => Err(errors::AT_ON_NON_SELECTOR.into()),
         }
     }
 
     /// set offset field for specified Expr, but CAN ONLY be set once.
     pub(crate) fn offset_expr(self, offset: Offset) -> Result<Self, String> {
-        let already_set_err = Err("offset may not be set multiple times".into());
+        let already_set_err = Err(errors::OFFSET_SET_TWICE.into());
         match self {
             Expr::VectorSelector(mut vs) => match vs.offset {
                 None => {
@@ -1218,9 +1539,7 @@ impl Expr {
                 }
                 Some(_) => already_set_err,
             },
-            _ => {
-                Err("offset modifier must be preceded by an vector selector or matrix selector or a subquery".into())
-            }
+            _ => Err(errors::OFFSET_ON_NON_SELECTOR.into()),
         }
     }
 
@@ -1275,10 +1594,7 @@ impl Expr {
                 param,
                 modifier,
             })),
-            None => Err(
-                "aggregate operation needs a single instant vector parameter, but found none"
-                    .into(),
-            ),
+            None => Err(errors::AGGREGATE_WITHOUT_EXPR.into()),
         }
     }
 
@@ -1306,6 +1622,46 @@ impl Expr {
         }
     }
 
+    /// whether the expression is a binary comparison, like `foo > 0.9` or `a == bool b`.
+    pub fn is_binary_comparison(&self) -> bool {
+        matches!(self, Expr::Binary(ex) if ex.op.is_comparison_operator())
+    }
+
+    /// the constant of a binary comparison against a number literal, like `0.9` of
+    /// `error_rate > 0.9` or `0.9 < error_rate`, which is usually the threshold of
+    /// an alerting rule. The right side is preferred if both are literals.
+    pub fn comparison_threshold(&self) -> Option<f64> {
+        match self {
+            Expr::Binary(ex) if ex.op.is_comparison_operator() => {
+                ex.rhs.scalar_value().or_else(|| ex.lhs.scalar_value())
+            }
+            _ => None,
+        }
+    }
+
+    /// check the expression can be evaluated as an instant query, whose result
+    /// must be a scalar or an instant vector, not a string or a range vector.
+    pub fn check_instant_query(&self) -> Result<(), String> {
+        self.check_query_type("instant")
+    }
+
+    /// the type check of [`Expr::check_instant_query`], shared with the range queries
+    /// of [`EvalStmt::try_new_range`].
+    fn check_query_type(&self, kind: &str) -> Result<(), String> {
+        match self.value_type() {
+            ValueType::Scalar | ValueType::Vector => Ok(()),
+            vt => Err(format!(
+                "invalid expression type '{vt}' for {kind} query, must be scalar or vector"
+            )),
+        }
+    }
+
+    /// whether the expression evaluates to an instant vector, like what most dashboard
+    /// panels expect. Queries like `scalar(up)` or `1` return a scalar instead.
+    pub fn expects_vector_result(&self) -> bool {
+        self.value_type() == ValueType::Vector
+    }
+
     /// only Some if expr is [Expr::NumberLiteral]
     pub(crate) fn scalar_value(&self) -> Option<f64> {
         match self {
@@ -1317,545 +1673,2456 @@ impl Expr {
     pub fn prettify(&self) -> String {
         self.pretty(0, MAX_CHARACTERS_PER_LINE)
     }
-}
 
-impl From<String> for Expr {
-    fn from(val: String) -> Self {
-        Expr::StringLiteral(StringLiteral { val })
+    /// same as [`Expr::prettify`], but with the layout of the given config.
+    pub fn prettify_with(&self, config: &PrettyConfig) -> String {
+        match config.style {
+            PrettyStyle::Default => self.prettify(),
+            PrettyStyle::OperatorColumn => self.pretty_operator_column(0, MAX_CHARACTERS_PER_LINE),
+        }
     }
-}
 
-impl From<&str> for Expr {
-    fn from(s: &str) -> Self {
-        Expr::StringLiteral(StringLiteral { val: s.into() })
+    /// the binary operators, also the ones in parentheses, are laid out in a column,
+    /// the other nodes are prettified as usual.
+    fn pretty_operator_column(&self, level: usize, max: usize) -> String {
+        // the indentation takes up part of the line
+        let avail = max.saturating_sub(indent(level).len());
+        match self {
+            Expr::Binary(ex) if self.needs_split(avail) => {
+                // flatten the chain of operators of the same precedence, like `a + b - c`
+                let mut operands = vec![&*ex.rhs];
+                let mut ops = vec![ex.get_op_matching_string()];
+                let mut lhs = &*ex.lhs;
+                while let Expr::Binary(inner) = lhs {
+                    if inner.op.precedence() != ex.op.precedence() {
+                        break;
+                    }
+                    operands.push(&*inner.rhs);
+                    ops.push(inner.get_op_matching_string());
+                    lhs = &*inner.lhs;
+                }
+                operands.push(lhs);
+                operands.reverse();
+                ops.reverse();
+
+                let width = ops.iter().map(|op| op.len()).max().unwrap_or(0);
+                let column = " ".repeat(width + 1);
+                let mut lines = Vec::with_capacity(operands.len());
+                for (i, operand) in operands.into_iter().enumerate() {
+                    let prefix = match i {
+                        0 => column.clone(),
+                        _ => format!("{:>width$} ", ops[i - 1]),
+                    };
+                    // the lines of a multi-line operand are aligned with its first line,
+                    // so they are shifted by the operator column
+                    let operand = operand
+                        .pretty_operator_column(0, avail.saturating_sub(column.len()))
+                        .replace('\n', &format!("\n{}{column}", indent(level)));
+                    lines.push(format!("{}{prefix}{operand}", indent(level)));
+                }
+                lines.join("\n")
+            }
+            Expr::Paren(ex) if self.needs_split(avail) => format!(
+                "{}(\n{}\n{})",
+                indent(level),
+                ex.expr.pretty_operator_column(level + 1, max),
+                indent(level)
+            ),
+            _ => self.pretty(level, max),
+        }
     }
-}
 
-impl From<f64> for Expr {
-    fn from(val: f64) -> Self {
-        Expr::NumberLiteral(NumberLiteral { val })
+    /// the strongest canonical rendering of the expression, which sorts and dedups the labels,
+    /// orders the operands of commutative `+` and `*` and only keeps the parentheses
+    /// required by the precedence of the operators, e.g. `a + b` for `(b) + a`.
+    /// The result parses to an expression equivalent to the original one.
+    pub fn to_string_sorted_everything(&self) -> String {
+        self.clone().into_sorted_everything().to_string()
     }
-}
 
-/// directly create an Expr::VectorSelector from instant vector
-///
-/// # Examples
-///
-/// Basic usage:
-///
-/// ``` rust
-/// use promql_parser::label::Matchers;
-/// use promql_parser::parser::{Expr, VectorSelector};
-///
-/// let name = String::from("foo");
-/// let vs = VectorSelector::new(Some(name), Matchers::empty());
-///
-/// assert_eq!(Expr::VectorSelector(vs), Expr::from(VectorSelector::from("foo")));
-/// ```
-impl From<VectorSelector> for Expr {
-    fn from(vs: VectorSelector) -> Self {
-        Expr::VectorSelector(vs)
+    /// feed the canonical structure of the expression into the hasher, so that queries
+    /// with the same [canonical rendering](Expr::to_string_sorted_everything) like
+    /// `b + a` and `(a) + b` have the same hash, e.g. for cache keys. The nodes are
+    /// hashed directly instead of being rendered, and the operands of commutative
+    /// operators are ordered by their own hash.
+    pub fn hash_canonical<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Expr::Paren(ex) => ex.expr.hash_canonical(state),
+            Expr::Aggregate(ex) => {
+                state.write_u8(0);
+                state.write_u8(ex.op.id());
+                match &ex.modifier {
+                    Some(LabelModifier::Include(ls)) if !ls.is_empty() => {
+                        state.write_u8(1);
+                        hash_sorted_labels(ls, state);
+                    }
+                    Some(LabelModifier::Exclude(ls)) => {
+                        state.write_u8(2);
+                        hash_sorted_labels(ls, state);
+                    }
+                    _ => state.write_u8(0),
+                }
+                match &ex.param {
+                    Some(param) => {
+                        state.write_u8(1);
+                        param.hash_canonical(state);
+                    }
+                    None => state.write_u8(0),
+                }
+                ex.expr.hash_canonical(state);
+            }
+            Expr::Unary(ex) => {
+                state.write_u8(1);
+                ex.expr.hash_canonical(state);
+            }
+            Expr::Binary(ex) => {
+                state.write_u8(2);
+                state.write_u8(ex.op.id());
+                if let Some(modifier) = &ex.modifier {
+                    state.write_u8(modifier.return_bool as u8);
+                    match &modifier.matching {
+                        Some(LabelModifier::Include(ls)) => {
+                            state.write_u8(1);
+                            hash_sorted_labels(ls, state);
+                        }
+                        Some(LabelModifier::Exclude(ls)) if !ls.is_empty() => {
+                            state.write_u8(2);
+                            hash_sorted_labels(ls, state);
+                        }
+                        _ => state.write_u8(0),
+                    }
+                    match &modifier.card {
+                        VectorMatchCardinality::ManyToOne(ls) => {
+                            state.write_u8(1);
+                            hash_sorted_labels(ls, state);
+                        }
+                        VectorMatchCardinality::OneToMany(ls) => {
+                            state.write_u8(2);
+                            hash_sorted_labels(ls, state);
+                        }
+                        _ => state.write_u8(0),
+                    }
+                } else {
+                    // same as a modifier with nothing to render
+                    state.write_u8(0);
+                    state.write_u8(0);
+                    state.write_u8(0);
+                }
+                if is_commutative(ex) {
+                    let mut operands = [canonical_hash(&ex.lhs), canonical_hash(&ex.rhs)];
+                    operands.sort_unstable();
+                    state.write_u64(operands[0]);
+                    state.write_u64(operands[1]);
+                } else {
+                    ex.lhs.hash_canonical(state);
+                    ex.rhs.hash_canonical(state);
+                }
+            }
+            Expr::Subquery(ex) => {
+                state.write_u8(3);
+                ex.expr.hash_canonical(state);
+                state.write_u128(ex.range.as_nanos());
+                match ex.step {
+                    Some(step) => {
+                        state.write_u8(1);
+                        state.write_u128(step.as_nanos());
+                    }
+                    None => state.write_u8(0),
+                }
+                hash_offset_and_at(&ex.offset, &ex.at, state);
+            }
+            Expr::NumberLiteral(nl) => {
+                state.write_u8(4);
+                // all the NaNs are rendered the same
+                let val = if nl.val.is_nan() { f64::NAN } else { nl.val };
+                state.write_u64(val.to_bits());
+            }
+            Expr::StringLiteral(sl) => {
+                state.write_u8(5);
+                state.write(sl.val.as_bytes());
+                state.write_u8(0xff);
+            }
+            Expr::VectorSelector(vs) => {
+                state.write_u8(6);
+                hash_vector_selector(vs, state);
+            }
+            Expr::MatrixSelector(ms) => {
+                state.write_u8(7);
+                hash_vector_selector(&ms.vs, state);
+                state.write_u128(ms.range.as_nanos());
+            }
+            Expr::Call(ex) => {
+                state.write_u8(8);
+                state.write(ex.func.name.as_bytes());
+                state.write_u8(0xff);
+                state.write_usize(ex.args.len());
+                for arg in &ex.args.args {
+                    arg.hash_canonical(state);
+                }
+            }
+            Expr::Extension(ext) => {
+                // extensions are opaque, so their rendering is the only structure
+                state.write_u8(9);
+                // writing to a hasher never fails
+                let _ = write!(HashWriter(state), "{ext:?}");
+                state.write_u8(0xff);
+            }
+        }
     }
-}
 
-impl Neg for Expr {
-    type Output = Self;
-
-    fn neg(self) -> Self::Output {
+    fn into_sorted_everything(self) -> Expr {
         match self {
-            Expr::NumberLiteral(nl) => Expr::NumberLiteral(-nl),
-            _ => Expr::Unary(UnaryExpr {
-                expr: Box::new(self),
-            }),
+            Expr::Paren(ex) => ex.expr.into_sorted_everything(),
+            Expr::Aggregate(mut ex) => {
+                ex.expr = Box::new(ex.expr.into_sorted_everything());
+                ex.param = ex.param.map(|p| Box::new(p.into_sorted_everything()));
+                if let Some(modifier) = &mut ex.modifier {
+                    sort_modifier_labels(modifier);
+                }
+                Expr::Aggregate(ex)
+            }
+            Expr::Unary(ex) => match ex.expr.into_sorted_everything() {
+                expr @ (Expr::Binary(_) | Expr::Unary(_)) => -parenthesize(expr),
+                expr => -expr,
+            },
+            Expr::Binary(mut ex) => {
+                let commutative = is_commutative(&ex);
+                let mut lhs = ex.lhs.into_sorted_everything();
+                let mut rhs = ex.rhs.into_sorted_everything();
+                if let Some(modifier) = &mut ex.modifier {
+                    if let Some(matching) = &mut modifier.matching {
+                        sort_modifier_labels(matching);
+                    }
+                    if let VectorMatchCardinality::ManyToOne(labels)
+                    | VectorMatchCardinality::OneToMany(labels) = &mut modifier.card
+                    {
+                        sort_labels(labels);
+                    }
+                }
+                if commutative && rhs.to_string() < lhs.to_string() {
+                    std::mem::swap(&mut lhs, &mut rhs);
+                }
+                ex.lhs = Box::new(paren_operand(lhs, &ex.op, true));
+                ex.rhs = Box::new(paren_operand(rhs, &ex.op, false));
+                Expr::Binary(ex)
+            }
+            Expr::Subquery(mut ex) => {
+                let expr = ex.expr.into_sorted_everything();
+                ex.expr = Box::new(match expr {
+                    Expr::Binary(_) | Expr::Unary(_) => parenthesize(expr),
+                    Expr::NumberLiteral(ref nl) if nl.val.is_sign_negative() => parenthesize(expr),
+                    _ => expr,
+                });
+                Expr::Subquery(ex)
+            }
+            Expr::Call(mut ex) => {
+                ex.args.args = ex
+                    .args
+                    .args
+                    .into_iter()
+                    .map(|arg| Box::new(arg.into_sorted_everything()))
+                    .collect();
+                Expr::Call(ex)
+            }
+            expr => expr,
         }
     }
-}
 
-impl fmt::Display for Expr {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Expr::Aggregate(ex) => write!(f, "{ex}"),
-            Expr::Unary(ex) => write!(f, "{ex}"),
-            Expr::Binary(ex) => write!(f, "{ex}"),
-            Expr::Paren(ex) => write!(f, "{ex}"),
-            Expr::Subquery(ex) => write!(f, "{ex}"),
-            Expr::NumberLiteral(ex) => write!(f, "{ex}"),
-            Expr::StringLiteral(ex) => write!(f, "{ex}"),
-            Expr::VectorSelector(ex) => write!(f, "{ex}"),
-            Expr::MatrixSelector(ex) => write!(f, "{ex}"),
-            Expr::Call(ex) => write!(f, "{ex}"),
-            Expr::Extension(ext) => write!(f, "{ext:?}"),
+    /// whether both expressions are equal, except for the order of the simple matchers
+    /// of the selectors, which is lost in the rendering, see [`VectorSelector::matchers_eq`].
+    /// So an expression parsed from its own rendering is the same as the original one.
+    pub fn eq_ignoring_matcher_order(&self, other: &Expr) -> bool {
+        let eq = |lhs: &Expr, rhs: &Expr| lhs.eq_ignoring_matcher_order(rhs);
+        let vs_eq = |lhs: &VectorSelector, rhs: &VectorSelector| {
+            lhs.matchers_eq(rhs) && lhs.offset == rhs.offset && lhs.at == rhs.at
+        };
+        match (self, other) {
+            (Expr::VectorSelector(lhs), Expr::VectorSelector(rhs)) => vs_eq(lhs, rhs),
+            (Expr::MatrixSelector(lhs), Expr::MatrixSelector(rhs)) => {
+                lhs.range == rhs.range && vs_eq(&lhs.vs, &rhs.vs)
+            }
+            (Expr::Aggregate(lhs), Expr::Aggregate(rhs)) => {
+                lhs.op == rhs.op
+                    && lhs.modifier == rhs.modifier
+                    && match (&lhs.param, &rhs.param) {
+                        (Some(l), Some(r)) => eq(l, r),
+                        (l, r) => l.is_none() && r.is_none(),
+                    }
+                    && eq(&lhs.expr, &rhs.expr)
+            }
+            (Expr::Unary(lhs), Expr::Unary(rhs)) => eq(&lhs.expr, &rhs.expr),
+            (Expr::Binary(lhs), Expr::Binary(rhs)) => {
+                lhs.op == rhs.op
+                    && lhs.modifier == rhs.modifier
+                    && eq(&lhs.lhs, &rhs.lhs)
+                    && eq(&lhs.rhs, &rhs.rhs)
+            }
+            (Expr::Paren(lhs), Expr::Paren(rhs)) => eq(&lhs.expr, &rhs.expr),
+            (Expr::Subquery(lhs), Expr::Subquery(rhs)) => {
+                lhs.range == rhs.range
+                    && lhs.step == rhs.step
+                    && lhs.offset == rhs.offset
+                    && lhs.at == rhs.at
+                    && eq(&lhs.expr, &rhs.expr)
+            }
+            (Expr::Call(lhs), Expr::Call(rhs)) => {
+                lhs.func == rhs.func
+                    && lhs.args.len() == rhs.args.len()
+                    && lhs
+                        .args
+                        .args
+                        .iter()
+                        .zip(&rhs.args.args)
+                        .all(|(l, r)| eq(l, r))
+            }
+            // literals have no matchers, and extensions are opaque
+            _ => self == other,
         }
     }
-}
 
-impl Prettier for Expr {
-    fn pretty(&self, level: usize, max: usize) -> String {
-        match self {
-            Expr::Aggregate(ex) => ex.pretty(level, max),
-            Expr::Unary(ex) => ex.pretty(level, max),
-            Expr::Binary(ex) => ex.pretty(level, max),
-            Expr::Paren(ex) => ex.pretty(level, max),
-            Expr::Subquery(ex) => ex.pretty(level, max),
-            Expr::NumberLiteral(ex) => ex.pretty(level, max),
-            Expr::StringLiteral(ex) => ex.pretty(level, max),
-            Expr::VectorSelector(ex) => ex.pretty(level, max),
-            Expr::MatrixSelector(ex) => ex.pretty(level, max),
-            Expr::Call(ex) => ex.pretty(level, max),
-            Expr::Extension(ext) => format!("{ext:?}"),
+    /// same as `to_string`, but renders the timestamps of `@` modifiers with `n` decimals
+    /// instead of the default 3, e.g. `foo @ 100` with 0 decimals.
+    pub fn to_string_with_at_precision(&self, n: usize) -> String {
+        format!("{self:.n$}")
+    }
+
+    // downcasts to the node of each variant, None for the other variants.
+
+    pub fn as_aggregate(&self) -> Option<&AggregateExpr> {
+        match self {
+            Expr::Aggregate(ex) => Some(ex),
+            _ => None,
         }
     }
-}
 
-/// check_ast checks the validity of the provided AST. This includes type checking.
-/// Recursively check correct typing for child nodes and raise errors in case of bad typing.
-pub(crate) fn check_ast(expr: Expr) -> Result<Expr, String> {
-    match expr {
-        Expr::Binary(ex) => check_ast_for_binary_expr(ex),
-        Expr::Aggregate(ex) => check_ast_for_aggregate_expr(ex),
-        Expr::Call(ex) => check_ast_for_call(ex),
-        Expr::Unary(ex) => check_ast_for_unary(ex),
-        Expr::Subquery(ex) => check_ast_for_subquery(ex),
-        Expr::VectorSelector(ex) => check_ast_for_vector_selector(ex),
-        Expr::Paren(_) => Ok(expr),
-        Expr::NumberLiteral(_) => Ok(expr),
-        Expr::StringLiteral(_) => Ok(expr),
-        Expr::MatrixSelector(_) => Ok(expr),
-        Expr::Extension(_) => Ok(expr),
+    pub fn as_unary(&self) -> Option<&UnaryExpr> {
+        match self {
+            Expr::Unary(ex) => Some(ex),
+            _ => None,
+        }
     }
-}
 
-fn expect_type(
-    expected: ValueType,
-    actual: Option<ValueType>,
-    context: &str,
-) -> Result<bool, String> {
-    match actual {
-        Some(actual) => {
-            if actual == expected {
-                Ok(true)
-            } else {
-                Err(format!(
-                    "expected type {expected} in {context}, got {actual}"
-                ))
-            }
+    pub fn as_binary(&self) -> Option<&BinaryExpr> {
+        match self {
+            Expr::Binary(ex) => Some(ex),
+            _ => None,
         }
-        None => Err(format!("expected type {expected} in {context}, got None")),
     }
-}
 
-/// the original logic is redundant in prometheus, and the following coding blocks
-/// have been optimized for readability, but all logic SHOULD be covered.
-fn check_ast_for_binary_expr(mut ex: BinaryExpr) -> Result<Expr, String> {
-    if !ex.op.is_operator() {
-        return Err(format!(
-            "binary expression does not support operator '{}'",
-            ex.op
-        ));
+    pub fn as_paren(&self) -> Option<&ParenExpr> {
+        match self {
+            Expr::Paren(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    if ex.return_bool() && !ex.op.is_comparison_operator() {
-        return Err("bool modifier can only be used on comparison operators".into());
+    pub fn as_subquery(&self) -> Option<&SubqueryExpr> {
+        match self {
+            Expr::Subquery(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    if ex.op.is_comparison_operator()
-        && ex.lhs.value_type() == ValueType::Scalar
-        && ex.rhs.value_type() == ValueType::Scalar
-        && !ex.return_bool()
-    {
-        return Err("comparisons between scalars must use BOOL modifier".into());
+    pub fn as_number_literal(&self) -> Option<&NumberLiteral> {
+        match self {
+            Expr::NumberLiteral(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    // For `on` matching, a label can only appear in one of the lists.
-    // Every time series of the result vector must be uniquely identifiable.
-    if ex.is_matching_on() && ex.is_labels_joint() {
-        if let Some(labels) = ex.intersect_labels() {
-            if let Some(label) = labels.first() {
-                return Err(format!(
-                    "label '{label}' must not occur in ON and GROUP clause at once"
-                ));
-            }
-        };
+    pub fn as_string_literal(&self) -> Option<&StringLiteral> {
+        match self {
+            Expr::StringLiteral(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    if ex.op.is_set_operator() {
-        if ex.lhs.value_type() == ValueType::Scalar || ex.rhs.value_type() == ValueType::Scalar {
-            return Err(format!(
-                "set operator '{}' not allowed in binary scalar expression",
-                ex.op
-            ));
+    pub fn as_vector_selector(&self) -> Option<&VectorSelector> {
+        match self {
+            Expr::VectorSelector(ex) => Some(ex),
+            _ => None,
         }
+    }
 
-        if ex.lhs.value_type() == ValueType::Vector && ex.rhs.value_type() == ValueType::Vector {
-            if let Some(ref modifier) = ex.modifier {
-                if matches!(modifier.card, VectorMatchCardinality::OneToMany(_))
-                    || matches!(modifier.card, VectorMatchCardinality::ManyToOne(_))
-                {
-                    return Err(format!("no grouping allowed for '{}' operation", ex.op));
-                }
-            };
+    pub fn as_matrix_selector(&self) -> Option<&MatrixSelector> {
+        match self {
+            Expr::MatrixSelector(ex) => Some(ex),
+            _ => None,
         }
+    }
 
-        match &mut ex.modifier {
-            Some(modifier) => {
-                if modifier.card == VectorMatchCardinality::OneToOne {
-                    modifier.card = VectorMatchCardinality::ManyToMany;
-                }
-            }
-            None => {
-                ex.modifier =
-                    Some(BinModifier::default().with_card(VectorMatchCardinality::ManyToMany));
-            }
+    pub fn as_call(&self) -> Option<&Call> {
+        match self {
+            Expr::Call(ex) => Some(ex),
+            _ => None,
         }
     }
 
-    if ex.lhs.value_type() != ValueType::Scalar && ex.lhs.value_type() != ValueType::Vector {
-        return Err("binary expression must contain only scalar and instant vector types".into());
+    /// wrap the expression in a [CommentExtension] with the given comment.
+    #[cfg(feature = "comment-extension")]
+    pub fn with_comment(self, comment: impl Into<String>) -> Expr {
+        Expr::Extension(Extension {
+            expr: Arc::new(CommentExtension {
+                expr: self,
+                comment: comment.into(),
+            }),
+        })
     }
-    if ex.rhs.value_type() != ValueType::Scalar && ex.rhs.value_type() != ValueType::Vector {
-        return Err("binary expression must contain only scalar and instant vector types".into());
+
+    pub fn as_extension(&self) -> Option<&Extension> {
+        match self {
+            Expr::Extension(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    if (ex.lhs.value_type() != ValueType::Vector || ex.rhs.value_type() != ValueType::Vector)
-        && ex.is_matching_labels_not_empty()
-    {
-        return Err("vector matching only allowed between vectors".into());
+    pub fn as_aggregate_mut(&mut self) -> Option<&mut AggregateExpr> {
+        match self {
+            Expr::Aggregate(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    Ok(Expr::Binary(ex))
-}
+    pub fn as_unary_mut(&mut self) -> Option<&mut UnaryExpr> {
+        match self {
+            Expr::Unary(ex) => Some(ex),
+            _ => None,
+        }
+    }
 
-fn check_ast_for_aggregate_expr(ex: AggregateExpr) -> Result<Expr, String> {
-    if !ex.op.is_aggregator() {
-        return Err(format!(
-            "aggregation operator expected in aggregation expression but got '{}'",
-            ex.op
-        ));
+    pub fn as_binary_mut(&mut self) -> Option<&mut BinaryExpr> {
+        match self {
+            Expr::Binary(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    expect_type(
-        ValueType::Vector,
-        Some(ex.expr.value_type()),
-        "aggregation expression",
-    )?;
+    pub fn as_paren_mut(&mut self) -> Option<&mut ParenExpr> {
+        match self {
+            Expr::Paren(ex) => Some(ex),
+            _ => None,
+        }
+    }
 
-    if matches!(ex.op.id(), T_TOPK | T_BOTTOMK | T_QUANTILE) {
-        expect_type(
-            ValueType::Scalar,
-            ex.param.as_ref().map(|ex| ex.value_type()),
-            "aggregation expression",
-        )?;
+    pub fn as_subquery_mut(&mut self) -> Option<&mut SubqueryExpr> {
+        match self {
+            Expr::Subquery(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    if ex.op.id() == T_COUNT_VALUES {
-        expect_type(
-            ValueType::String,
-            ex.param.as_ref().map(|ex| ex.value_type()),
-            "aggregation expression",
-        )?;
+    pub fn as_number_literal_mut(&mut self) -> Option<&mut NumberLiteral> {
+        match self {
+            Expr::NumberLiteral(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    Ok(Expr::Aggregate(ex))
-}
+    pub fn as_string_literal_mut(&mut self) -> Option<&mut StringLiteral> {
+        match self {
+            Expr::StringLiteral(ex) => Some(ex),
+            _ => None,
+        }
+    }
 
-fn check_ast_for_call(ex: Call) -> Result<Expr, String> {
-    let expected_args_len = ex.func.arg_types.len();
-    let name = ex.func.name;
-    let actual_args_len = ex.args.len();
+    pub fn as_vector_selector_mut(&mut self) -> Option<&mut VectorSelector> {
+        match self {
+            Expr::VectorSelector(ex) => Some(ex),
+            _ => None,
+        }
+    }
 
-    if ex.func.variadic {
-        let expected_args_len_without_default = expected_args_len - 1;
-        if expected_args_len_without_default > actual_args_len {
-            return Err(format!(
-                "expected at least {expected_args_len_without_default} argument(s) in call to '{name}', got {actual_args_len}"
-            ));
+    pub fn as_matrix_selector_mut(&mut self) -> Option<&mut MatrixSelector> {
+        match self {
+            Expr::MatrixSelector(ex) => Some(ex),
+            _ => None,
         }
+    }
 
-        // `label_join` do not have a maximum arguments threshold.
-        // this hard code SHOULD be careful if new functions are supported by Prometheus.
-        if actual_args_len > expected_args_len && name.ne("label_join") {
-            return Err(format!(
-                "expected at most {expected_args_len} argument(s) in call to '{name}', got {actual_args_len}"
-            ));
+    pub fn as_call_mut(&mut self) -> Option<&mut Call> {
+        match self {
+            Expr::Call(ex) => Some(ex),
+            _ => None,
         }
     }
 
-    if !ex.func.variadic && expected_args_len != actual_args_len {
-        return Err(format!(
-            "expected {expected_args_len} argument(s) in call to '{name}', got {actual_args_len}"
-        ));
+    pub fn as_extension_mut(&mut self) -> Option<&mut Extension> {
+        match self {
+            Expr::Extension(ex) => Some(ex),
+            _ => None,
+        }
     }
 
-    // special cases from https://prometheus.io/docs/prometheus/latest/querying/functions
-    if name.eq("exp") {
-        if let Some(val) = ex.args.first().and_then(|ex| ex.scalar_value()) {
-            if val.is_nan() || val.is_infinite() {
-                return Ok(Expr::Call(ex));
-            }
+    /// consume the expression into the inner [`AggregateExpr`], see [`Expr::into_binary`].
+    pub fn into_aggregate(self) -> Result<AggregateExpr, Box<Expr>> {
+        match self {
+            Expr::Aggregate(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
         }
-    } else if name.eq("ln") || name.eq("log2") || name.eq("log10") {
-        if let Some(val) = ex.args.first().and_then(|ex| ex.scalar_value()) {
-            if val.is_nan() || val.is_infinite() || val <= 0.0 {
-                return Ok(Expr::Call(ex));
-            }
+    }
+
+    /// consume the expression into the inner [`UnaryExpr`], see [`Expr::into_binary`].
+    pub fn into_unary(self) -> Result<UnaryExpr, Box<Expr>> {
+        match self {
+            Expr::Unary(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
         }
     }
 
-    for (mut idx, actual_arg) in ex.args.args.iter().enumerate() {
-        // this only happens when function args are variadic
-        if idx >= ex.func.arg_types.len() {
-            idx = ex.func.arg_types.len() - 1;
+    /// consume the expression and return the inner [`BinaryExpr`], or give the
+    /// expression back boxed if it is another variant, which avoids cloning on matches.
+    pub fn into_binary(self) -> Result<BinaryExpr, Box<Expr>> {
+        match self {
+            Expr::Binary(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
         }
+    }
 
-        expect_type(
-            ex.func.arg_types[idx],
-            Some(actual_arg.value_type()),
-            &format!("call to function '{name}'"),
-        )?;
+    /// consume the expression into the inner [`ParenExpr`], see [`Expr::into_binary`].
+    pub fn into_paren(self) -> Result<ParenExpr, Box<Expr>> {
+        match self {
+            Expr::Paren(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
+        }
     }
 
-    Ok(Expr::Call(ex))
-}
+    /// consume the expression into the inner [`SubqueryExpr`], see [`Expr::into_binary`].
+    pub fn into_subquery(self) -> Result<SubqueryExpr, Box<Expr>> {
+        match self {
+            Expr::Subquery(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
+        }
+    }
 
-fn check_ast_for_unary(ex: UnaryExpr) -> Result<Expr, String> {
-    let value_type = ex.expr.value_type();
-    if value_type != ValueType::Scalar && value_type != ValueType::Vector {
-        return Err(format!(
-            "unary expression only allowed on expressions of type scalar or vector, got {value_type}"
-        ));
+    /// consume the expression into the inner [`NumberLiteral`], see [`Expr::into_binary`].
+    pub fn into_number_literal(self) -> Result<NumberLiteral, Box<Expr>> {
+        match self {
+            Expr::NumberLiteral(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
+        }
     }
 
-    Ok(Expr::Unary(ex))
-}
+    /// consume the expression into the inner [`StringLiteral`], see [`Expr::into_binary`].
+    pub fn into_string_literal(self) -> Result<StringLiteral, Box<Expr>> {
+        match self {
+            Expr::StringLiteral(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
+        }
+    }
 
-fn check_ast_for_subquery(ex: SubqueryExpr) -> Result<Expr, String> {
-    let value_type = ex.expr.value_type();
-    if value_type != ValueType::Vector {
-        return Err(format!(
-            "subquery is only allowed on vector, got {value_type} instead"
-        ));
+    /// consume the expression into the inner [`VectorSelector`], see [`Expr::into_binary`].
+    pub fn into_vector_selector(self) -> Result<VectorSelector, Box<Expr>> {
+        match self {
+            Expr::VectorSelector(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
+        }
     }
 
-    Ok(Expr::Subquery(ex))
-}
+    /// consume the expression into the inner [`MatrixSelector`], see [`Expr::into_binary`].
+    pub fn into_matrix_selector(self) -> Result<MatrixSelector, Box<Expr>> {
+        match self {
+            Expr::MatrixSelector(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
+        }
+    }
 
-fn check_ast_for_vector_selector(ex: VectorSelector) -> Result<Expr, String> {
-    match ex.name {
-        Some(ref name) => match ex.matchers.find_matcher_value(METRIC_NAME) {
-            Some(val) => Err(format!(
-                "metric name must not be set twice: '{}' or '{}'",
-                name, val
-            )),
-            None => Ok(Expr::VectorSelector(ex)),
-        },
-        None if ex.matchers.is_empty_matchers() => {
-            // When name is None, a vector selector must contain at least one non-empty matcher
-            // to prevent implicit selection of all metrics (e.g. by a typo).
-            Err("vector selector must contain at least one non-empty matcher".into())
+    /// consume the expression into the inner [`Call`], see [`Expr::into_binary`].
+    pub fn into_call(self) -> Result<Call, Box<Expr>> {
+        match self {
+            Expr::Call(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
         }
-        _ => Ok(Expr::VectorSelector(ex)),
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::label::{MatchOp, Matcher};
+    /// consume the expression into the inner [`Extension`], see [`Expr::into_binary`].
+    pub fn into_extension(self) -> Result<Extension, Box<Expr>> {
+        match self {
+            Expr::Extension(ex) => Ok(ex),
+            _ => Err(Box::new(self)),
+        }
+    }
 
-    #[test]
-    fn test_valid_at_modifier() {
-        let cases = vec![
-            // tuple: (seconds, elapsed milliseconds before or after UNIX_EPOCH)
-            (0.0, 0),
-            (1000.3, 1000300),    // after UNIX_EPOCH
-            (1000.9, 1000900),    // after UNIX_EPOCH
-            (1000.9991, 1000999), // after UNIX_EPOCH
-            (1000.9999, 1001000), // after UNIX_EPOCH
-            (-1000.3, 1000300),   // before UNIX_EPOCH
-            (-1000.9, 1000900),   // before UNIX_EPOCH
-        ];
+    /// direct sub-expressions of this node, in the order they appear in the query.
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Aggregate(ex) => {
+                let mut children: Vec<&Expr> = Vec::with_capacity(2);
+                if let Some(param) = &ex.param {
+                    children.push(param);
+                }
+                children.push(&ex.expr);
+                children
+            }
+            Expr::Unary(ex) => vec![&*ex.expr],
+            Expr::Binary(ex) => vec![&*ex.lhs, &*ex.rhs],
+            Expr::Paren(ex) => vec![&*ex.expr],
+            Expr::Subquery(ex) => vec![&*ex.expr],
+            Expr::Call(ex) => ex.args.args.iter().map(|arg| &**arg).collect(),
+            Expr::Extension(ex) => ex.expr.children().iter().collect(),
+            Expr::NumberLiteral(_)
+            | Expr::StringLiteral(_)
+            | Expr::VectorSelector(_)
+            | Expr::MatrixSelector(_) => vec![],
+        }
+    }
 
-        for (secs, elapsed) in cases {
-            match AtModifier::try_from(secs).unwrap() {
-                AtModifier::At(st) => {
-                    if secs.is_sign_positive() || secs == 0.0 {
-                        assert_eq!(
-                            elapsed,
-                            st.duration_since(SystemTime::UNIX_EPOCH)
-                                .unwrap()
-                                .as_millis()
-                        )
-                    } else if secs.is_sign_negative() {
-                        assert_eq!(
-                            elapsed,
-                            SystemTime::UNIX_EPOCH
-                                .duration_since(st)
-                                .unwrap()
-                                .as_millis()
-                        )
-                    }
+    /// the `@` modifier governing the evaluation of the expression, looking through
+    /// parentheses, e.g. `@ 100` for `((foo + bar)[5m:] @ 100)`. Selectors without
+    /// their own `@` inside a subquery are evaluated at the `@` of the subquery,
+    /// which is reported for the subquery, not for its children.
+    pub fn effective_at(&self) -> Option<&AtModifier> {
+        match self {
+            Expr::VectorSelector(vs) => vs.at.as_ref(),
+            Expr::MatrixSelector(ms) => ms.vs.at.as_ref(),
+            Expr::Subquery(sq) => sq.at.as_ref(),
+            Expr::Paren(ex) => ex.expr.effective_at(),
+            _ => None,
+        }
+    }
+
+    /// the number of binary and unary operators in the expression.
+    pub fn operator_count(&self) -> usize {
+        let mut count = 0;
+        self.for_each_node(&mut |expr| {
+            if matches!(expr, Expr::Binary(_) | Expr::Unary(_)) {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    /// the number of operands of the longest chain of binary operators of the same
+    /// precedence, e.g. 4 for `a + b - c + d`, and 0 without any binary operator.
+    /// Parentheses break the chains. Long chains, usually machine generated, nest
+    /// deeply and are slow to process.
+    pub fn max_binary_chain_length(&self) -> usize {
+        let mut max = 0;
+        self.visit_binary_chains(None, &mut max);
+        max
+    }
+
+    /// the length of a chain is only computed from its root, i.e. the binary expression
+    /// whose parent isn't a binary expression of the same precedence.
+    fn visit_binary_chains(&self, parent_precedence: Option<u8>, max: &mut usize) {
+        match self {
+            Expr::Binary(ex) => {
+                let precedence = ex.op.precedence();
+                if precedence != parent_precedence {
+                    *max = (*max).max(self.binary_chain_length(precedence));
+                }
+                ex.lhs.visit_binary_chains(precedence, max);
+                ex.rhs.visit_binary_chains(precedence, max);
+            }
+            _ => {
+                for child in self.children() {
+                    child.visit_binary_chains(None, max);
                 }
-                _ => panic!(),
             }
         }
+    }
 
-        assert_eq!(
-            AtModifier::try_from(Expr::from(1.0)),
-            AtModifier::try_from(1.0),
-        );
+    fn binary_chain_length(&self, precedence: Option<u8>) -> usize {
+        match self {
+            Expr::Binary(ex) if ex.op.precedence() == precedence => {
+                ex.lhs.binary_chain_length(precedence) + ex.rhs.binary_chain_length(precedence)
+            }
+            _ => 1,
+        }
     }
 
-    #[test]
-    fn test_invalid_at_modifier() {
-        let cases = vec![
-            f64::MAX,
-            f64::MIN,
-            f64::NAN,
-            f64::INFINITY,
-            f64::NEG_INFINITY,
-        ];
+    /// the operator and the operands, from left to right, of the chain of the same
+    /// associative operator at the top, i.e. `+`, `*`, `and` or `or`, e.g. `+` and
+    /// `[a, b, c, d]` for `a + b + c + d`. The chain stops at parentheses and at
+    /// operators with other modifiers, like `a + b + on (job) c` whose operands are
+    /// `a + b` and `c`. None if the top isn't such an operator.
+    pub fn flatten_associative(&self) -> Option<(TokenType, Vec<&Expr>)> {
+        let ex = match self {
+            Expr::Binary(ex) if matches!(ex.op.id(), T_ADD | T_MUL | T_LAND | T_LOR) => ex,
+            _ => return None,
+        };
 
-        for secs in cases {
-            assert!(AtModifier::try_from(secs).is_err())
+        let mut operands = vec![];
+        self.collect_associative_operands(ex, &mut operands);
+        Some((ex.op, operands))
+    }
+
+    fn collect_associative_operands<'a>(&'a self, top: &BinaryExpr, operands: &mut Vec<&'a Expr>) {
+        match self {
+            Expr::Binary(ex) if ex.op == top.op && ex.modifier == top.modifier => {
+                ex.lhs.collect_associative_operands(top, operands);
+                ex.rhs.collect_associative_operands(top, operands);
+            }
+            _ => operands.push(self),
         }
+    }
 
-        assert_eq!(
-            AtModifier::try_from(token::T_ADD),
-            Err("invalid @ modifier preprocessor '+', START or END is valid.".into())
-        );
+    /// the number of label matchers of all the selectors, including the ones of `or`
+    /// groups, without collecting them. The metric name, e.g. `foo` of `foo{a="b"}`,
+    /// is not counted unless written as a `__name__` matcher.
+    pub fn matcher_count(&self) -> usize {
+        let mut count = 0;
+        self.for_each_selector(&mut |vs| {
+            count += vs.matchers.matchers.len()
+                + vs.matchers.or_matchers.iter().map(Vec::len).sum::<usize>();
+        });
+        count
+    }
 
-        assert_eq!(
-            AtModifier::try_from(Expr::from("string literal")),
-            Err("invalid float value after @ modifier".into())
-        );
+    /// the number of function calls in the expression, aggregations excluded.
+    pub fn function_call_count(&self) -> usize {
+        let mut count = 0;
+        self.for_each_node(&mut |expr| {
+            if matches!(expr, Expr::Call(_)) {
+                count += 1;
+            }
+        });
+        count
     }
 
-    #[test]
-    fn test_binary_labels() {
-        assert_eq!(
-            &Labels::new(vec!["foo", "bar"]),
-            LabelModifier::Include(Labels::new(vec!["foo", "bar"])).labels()
-        );
+    /// the names of the called functions in post-order, i.e. the arguments before the
+    /// call, e.g. `[rate, histogram_quantile]` for `histogram_quantile(0.9, rate(x[5m]))`.
+    /// A function called several times is listed several times.
+    pub fn function_names(&self) -> Vec<String> {
+        self.view().function_names().map(String::from).collect()
+    }
 
-        assert_eq!(
-            &Labels::new(vec!["foo", "bar"]),
-            LabelModifier::Exclude(Labels::new(vec!["foo", "bar"])).labels()
-        );
+    /// the calls in post-order, shared by [`Expr::function_names`] and [`QueryView`].
+    fn collect_calls<'a>(&'a self, calls: &mut Vec<&'a Call>) {
+        for child in self.children() {
+            child.collect_calls(calls);
+        }
+        if let Expr::Call(call) = self {
+            calls.push(call);
+        }
+    }
 
-        assert_eq!(
-            &Labels::new(vec!["foo", "bar"]),
-            VectorMatchCardinality::OneToMany(Labels::new(vec!["foo", "bar"]))
-                .labels()
-                .unwrap()
-        );
+    /// the selectors, in pre-order, which have neither a metric name nor a `__name__`
+    /// matcher, like `{job="api"}`, so they may select the series of every metric.
+    pub fn nameless_selectors(&self) -> Vec<&VectorSelector> {
+        let mut selectors = vec![];
+        self.for_each_selector(&mut |vs| {
+            let has_name_matcher = vs
+                .matchers
+                .matchers
+                .iter()
+                .chain(vs.matchers.or_matchers.iter().flatten())
+                .any(|m| m.name == METRIC_NAME);
+            if vs.name.is_none() && !has_name_matcher {
+                selectors.push(vs);
+            }
+        });
+        selectors
+    }
 
-        assert_eq!(
-            &Labels::new(vec!["foo", "bar"]),
-            VectorMatchCardinality::ManyToOne(Labels::new(vec!["foo", "bar"]))
-                .labels()
-                .unwrap()
-        );
+    /// every subquery of the expression in pre-order, including the expression itself.
+    pub fn subqueries(&self) -> Vec<&SubqueryExpr> {
+        let mut subqueries = vec![];
+        self.for_each_node(&mut |expr| {
+            if let Expr::Subquery(sq) = expr {
+                subqueries.push(sq);
+            }
+        });
+        subqueries
+    }
 
-        assert_eq!(VectorMatchCardinality::OneToOne.labels(), None);
-        assert_eq!(VectorMatchCardinality::ManyToMany.labels(), None);
+    /// every duration literal of the expression in pre-order: the ranges of matrix
+    /// selectors, the ranges and steps of subqueries, and the durations of offsets,
+    /// e.g. `[5m, 1m]` for `rate(foo[5m:1m])`. Negative offsets report their magnitude.
+    pub fn collect_durations(&self) -> Vec<Duration> {
+        fn offset_duration(offset: &Option<Offset>) -> Option<Duration> {
+            offset.as_ref().map(|offset| match offset {
+                Offset::Pos(dur) | Offset::Neg(dur) => *dur,
+            })
+        }
+
+        let mut durations = vec![];
+        self.for_each_node(&mut |expr| match expr {
+            Expr::VectorSelector(vs) => durations.extend(offset_duration(&vs.offset)),
+            Expr::MatrixSelector(ms) => {
+                durations.push(ms.range);
+                durations.extend(offset_duration(&ms.vs.offset));
+            }
+            Expr::Subquery(sq) => {
+                durations.push(sq.range);
+                durations.extend(sq.step);
+                durations.extend(offset_duration(&sq.offset));
+            }
+            _ => (),
+        });
+        durations
     }
 
-    #[test]
-    fn test_neg() {
-        assert_eq!(
-            -VectorSelector::from("foo"),
-            UnaryExpr {
-                expr: Box::new(Expr::from(VectorSelector::from("foo")))
+    /// the first node, in pre-order, which matches the predicate, including the
+    /// expression itself.
+    pub fn find<F: Fn(&Expr) -> bool>(&self, f: F) -> Option<&Expr> {
+        self.find_node(&f)
+    }
+
+    fn find_node<F: Fn(&Expr) -> bool>(&self, f: &F) -> Option<&Expr> {
+        if f(self) {
+            return Some(self);
+        }
+        self.children()
+            .into_iter()
+            .find_map(|child| child.find_node(f))
+    }
+
+    /// mutable direct sub-expressions of this node. Unlike [Expr::children],
+    /// the children of extensions are not included, since they are immutable.
+    pub(crate) fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Expr::Aggregate(ex) => {
+                let mut children: Vec<&mut Expr> = Vec::with_capacity(2);
+                if let Some(param) = &mut ex.param {
+                    children.push(param);
+                }
+                children.push(&mut ex.expr);
+                children
             }
-        )
+            Expr::Unary(ex) => vec![&mut *ex.expr],
+            Expr::Binary(ex) => vec![&mut *ex.lhs, &mut *ex.rhs],
+            Expr::Paren(ex) => vec![&mut *ex.expr],
+            Expr::Subquery(ex) => vec![&mut *ex.expr],
+            Expr::Call(ex) => ex.args.args.iter_mut().map(|arg| &mut **arg).collect(),
+            Expr::Extension(_)
+            | Expr::NumberLiteral(_)
+            | Expr::StringLiteral(_)
+            | Expr::VectorSelector(_)
+            | Expr::MatrixSelector(_) => vec![],
+        }
     }
 
-    #[test]
-    fn test_scalar_value() {
-        assert_eq!(Some(1.0), Expr::from(1.0).scalar_value());
-        assert_eq!(None, Expr::from("1.0").scalar_value());
+    /// mutable variant of [Expr::for_each_node], see [Expr::children_mut].
+    pub(crate) fn for_each_node_mut<F: FnMut(&mut Expr)>(&mut self, f: &mut F) {
+        f(self);
+        for child in self.children_mut() {
+            child.for_each_node_mut(f);
+        }
     }
 
-    #[test]
-    fn test_at_expr() {
-        assert_eq!(
-            "@ <timestamp> may not be set multiple times",
-            Expr::from(VectorSelector::from("foo"))
-                .at_expr(AtModifier::try_from(1.0).unwrap())
-                .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-                .unwrap_err()
-        );
+    /// remove all the `@` modifiers of selectors and subqueries, offsets are kept.
+    pub fn strip_at_modifiers(&mut self) {
+        self.for_each_node_mut(&mut |expr| match expr {
+            Expr::VectorSelector(vs) => vs.at = None,
+            Expr::MatrixSelector(ms) => ms.vs.at = None,
+            Expr::Subquery(sq) => sq.at = None,
+            _ => (),
+        });
+    }
 
-        assert_eq!(
-            "@ <timestamp> may not be set multiple times",
-            Expr::new_matrix_selector(
-                Expr::from(VectorSelector::from("foo")),
-                Duration::from_secs(1),
-            )
-            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-            .unwrap_err()
-        );
+    /// resolve `@ start()` and `@ end()` into the absolute timestamps of the evaluation
+    /// range, e.g. `foo @ start()` into `foo @ 100.000` for a range starting at 100s.
+    /// When every selector has an `@` modifier, the expression is then
+    /// [absolute time](Expr::is_absolute_time).
+    pub fn replace_at_with_absolute(&mut self, start: SystemTime, end: SystemTime) {
+        self.for_each_node_mut(&mut |expr| {
+            let at = match expr {
+                Expr::VectorSelector(vs) => &mut vs.at,
+                Expr::MatrixSelector(ms) => &mut ms.vs.at,
+                Expr::Subquery(sq) => &mut sq.at,
+                _ => return,
+            };
+            match at {
+                Some(AtModifier::Start) => *at = Some(AtModifier::At(start)),
+                Some(AtModifier::End) => *at = Some(AtModifier::At(end)),
+                _ => (),
+            }
+        });
+    }
 
-        assert_eq!(
-            "@ <timestamp> may not be set multiple times",
-            Expr::new_subquery_expr(
-                Expr::from(VectorSelector::from("foo")),
-                Duration::from_secs(1),
-                None,
-            )
-            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
-            .unwrap_err()
-        )
+    /// remove all the offsets of selectors and subqueries, `@` modifiers are kept.
+    pub fn strip_offsets(&mut self) {
+        self.for_each_node_mut(&mut |expr| match expr {
+            Expr::VectorSelector(vs) => vs.offset = None,
+            Expr::MatrixSelector(ms) => ms.vs.offset = None,
+            Expr::Subquery(sq) => sq.offset = None,
+            _ => (),
+        });
     }
 
-    #[test]
-    fn test_offset_expr() {
-        assert_eq!(
-            "offset may not be set multiple times",
-            Expr::from(VectorSelector::from("foo"))
-                .offset_expr(Offset::Pos(Duration::from_secs(1000)))
-                .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-                .unwrap_err()
-        );
+    /// normalize the rendering of the number literals. The parser already turns the
+    /// different notations into the same value, e.g. `08`, `0x8`, `8.` and `.8e1` are
+    /// all rendered as `8`, and parsing the rendered numbers again yields the same
+    /// values. On top of that, `-0` is turned into `0`.
+    pub fn normalize_number_format(&mut self) {
+        self.for_each_node_mut(&mut |expr| {
+            if let Expr::NumberLiteral(nl) = expr {
+                if nl.val == 0.0 {
+                    nl.val = 0.0;
+                }
+            }
+        });
+    }
 
-        assert_eq!(
-            "offset may not be set multiple times",
-            Expr::new_matrix_selector(
-                Expr::from(VectorSelector::from("foo")),
-                Duration::from_secs(1),
-            )
-            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-            .unwrap_err()
-        );
+    /// replace every offset of selectors and subqueries with the result of `f`,
+    /// e.g. to shift all of them by the same delta. Missing offsets are untouched.
+    pub fn rewrite_offsets<F: FnMut(&Offset) -> Offset>(&mut self, mut f: F) {
+        self.for_each_node_mut(&mut |expr| {
+            let offset = match expr {
+                Expr::VectorSelector(vs) => &mut vs.offset,
+                Expr::MatrixSelector(ms) => &mut ms.vs.offset,
+                Expr::Subquery(sq) => &mut sq.offset,
+                _ => return,
+            };
+            if let Some(offset) = offset {
+                *offset = f(offset);
+            }
+        });
+    }
 
-        assert_eq!(
-            "offset may not be set multiple times",
-            Expr::new_subquery_expr(
-                Expr::from(VectorSelector::from("foo")),
-                Duration::from_secs(1),
-                None,
-            )
-            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
-            .unwrap_err()
-        );
+    /// rebuild the expression bottom-up: the children are fully folded before `f` is
+    /// called with their parent, so `f` sees the already transformed children. This is
+    /// the natural shape of optimizations like [Expr::fold_constants]. The children of
+    /// [Expr::Extension] are opaque and left untouched.
+    pub fn fold_post<F>(self, mut f: F) -> Result<Expr, String>
+    where
+        F: FnMut(Expr) -> Result<Expr, String>,
+    {
+        self.fold_post_with(&mut f)
     }
 
-    #[test]
-    fn test_expr_to_string() {
-        let mut cases = vec![
-            ("1", "1"),
-            ("- 1", "-1"),
-            ("+ 1", "1"),
-            ("Inf", "Inf"),
-            ("inf", "Inf"),
-            ("+Inf", "Inf"),
-            ("- Inf", "-Inf"),
-            (".5", "0.5"),
-            ("5.", "5"),
+    fn fold_post_with<F>(self, f: &mut F) -> Result<Expr, String>
+    where
+        F: FnMut(Expr) -> Result<Expr, String>,
+    {
+        let expr = match self {
+            Expr::Aggregate(mut ex) => {
+                ex.expr = Box::new(ex.expr.fold_post_with(f)?);
+                if let Some(param) = ex.param {
+                    ex.param = Some(Box::new(param.fold_post_with(f)?));
+                }
+                Expr::Aggregate(ex)
+            }
+            Expr::Unary(mut ex) => {
+                ex.expr = Box::new(ex.expr.fold_post_with(f)?);
+                Expr::Unary(ex)
+            }
+            Expr::Binary(mut ex) => {
+                ex.lhs = Box::new(ex.lhs.fold_post_with(f)?);
+                ex.rhs = Box::new(ex.rhs.fold_post_with(f)?);
+                Expr::Binary(ex)
+            }
+            Expr::Paren(mut ex) => {
+                ex.expr = Box::new(ex.expr.fold_post_with(f)?);
+                Expr::Paren(ex)
+            }
+            Expr::Subquery(mut ex) => {
+                ex.expr = Box::new(ex.expr.fold_post_with(f)?);
+                Expr::Subquery(ex)
+            }
+            Expr::Call(mut ex) => {
+                ex.args.args = ex
+                    .args
+                    .args
+                    .into_iter()
+                    .map(|arg| arg.fold_post_with(f).map(Box::new))
+                    .collect::<Result<_, _>>()?;
+                Expr::Call(ex)
+            }
+            expr => expr,
+        };
+        f(expr)
+    }
+
+    /// evaluate the operations between number literals, e.g. `foo * 120` for
+    /// `foo * (2 * 60)`. Comparisons are only folded with `bool`, resulting in
+    /// 1 or 0, and parentheses around a number literal are removed.
+    pub fn fold_constants(self) -> Result<Expr, String> {
+        self.fold_post(|expr| match expr {
+            Expr::Unary(ex) => match *ex.expr {
+                Expr::NumberLiteral(nl) => Ok(Expr::NumberLiteral(-nl)),
+                expr => Ok(Expr::Unary(UnaryExpr {
+                    expr: Box::new(expr),
+                })),
+            },
+            Expr::Paren(ex) => match *ex.expr {
+                expr @ Expr::NumberLiteral(_) => Ok(expr),
+                expr => Ok(Expr::Paren(ParenExpr {
+                    expr: Box::new(expr),
+                })),
+            },
+            Expr::Binary(mut ex) => {
+                let folded = match (ex.lhs.scalar_value(), ex.rhs.scalar_value()) {
+                    (Some(lhs), Some(rhs)) => {
+                        fold_scalar_binary(ex.op.id(), lhs, rhs, ex.return_bool())
+                    }
+                    _ => None,
+                };
+                if let Some(val) = folded {
+                    return Ok(Expr::from(val));
+                }
+                // `-2 ^ foo` is `-(2 ^ foo)`, keep the parentheses of a negative base
+                if ex.op.id() == T_POW && ex.lhs.scalar_value().is_some_and(f64::is_sign_negative) {
+                    ex.lhs = Box::new(parenthesize(*ex.lhs));
+                }
+                Ok(Expr::Binary(ex))
+            }
+            expr => Ok(expr),
+        })
+    }
+
+    /// set the step of every subquery, also the ones relying on the default
+    /// evaluation interval, e.g. `foo[5m:10s]` for `foo[5m:]`.
+    pub fn replace_subquery_steps(&mut self, step: Duration) {
+        self.for_each_node_mut(&mut |expr| {
+            if let Expr::Subquery(sq) = expr {
+                sq.step = Some(step);
+            }
+        });
+    }
+
+    /// raise the ranges of matrix selectors and subqueries shorter than `min` to `min`,
+    /// e.g. `rate(foo[1m])` for `rate(foo[10s])` with a minimum of 1m, like to cover
+    /// at least a few scrape intervals. Steps and offsets are left untouched.
+    pub fn clamp_ranges(&mut self, min: Duration) {
+        self.for_each_node_mut(&mut |expr| match expr {
+            Expr::MatrixSelector(ms) => ms.range = ms.range.max(min),
+            Expr::Subquery(sq) => sq.range = sq.range.max(min),
+            _ => (),
+        });
+    }
+
+    /// replace the value of every string literal with the result of `f`, e.g. to
+    /// substitute the placeholders of a templated query. Like the parsed values, the
+    /// results are kept as is, with escape sequences not interpreted. The query must
+    /// parse first, so placeholders can only be used where any string is accepted,
+    /// e.g. not as the label names of `label_join()`, which are validated.
+    pub fn map_string_literals<F: FnMut(&str) -> String>(&mut self, mut f: F) {
+        self.for_each_node_mut(&mut |expr| {
+            if let Expr::StringLiteral(sl) = expr {
+                sl.val = f(&sl.val);
+            }
+        });
+    }
+
+    /// rename the called functions according to `map`, from old name to new name,
+    /// e.g. to migrate `holt_winters` to `double_exponential_smoothing`. The new
+    /// functions are looked up again, and every node is checked again like the parser
+    /// does, so both the arguments and the return type must fit, e.g. `sum(scalar(x))`
+    /// is rejected. The expression is left untouched if any of them fails.
+    pub fn rename_functions(&mut self, map: &HashMap<String, String>) -> Result<(), String> {
+        let mut funcs = HashMap::with_capacity(map.len());
+        for (from, to) in map {
+            let func =
+                get_function(to).ok_or_else(|| format!("unknown function with name '{to}'"))?;
+            funcs.insert(from.as_str(), func);
+        }
+
+        let renamed = self.clone().fold_post(|expr| match expr {
+            Expr::Call(mut call) => {
+                if let Some(func) = funcs.get(call.func.name) {
+                    call.func = func.clone();
+                }
+                check_ast(Expr::Call(call))
+            }
+            expr => check_ast(expr),
+        })?;
+        *self = renamed;
+        Ok(())
+    }
+
+    /// replace the `__name__` matchers of all selectors with the result of `f`, e.g.
+    /// `{__name__=~"new_.*"}` for `{__name__=~"old_.*"}` when migrating metric names.
+    /// Matchers for which `f` returns None are kept, and so are the names of selectors
+    /// like `old_foo`. The regex of the new `=~` and `!~` matchers is compiled again from
+    /// their value, the expression is left untouched if any of them is invalid.
+    pub fn rewrite_name_matchers<F: FnMut(&Matcher) -> Option<Matcher>>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), String> {
+        let mut expr = self.clone();
+        let mut rewritten = Ok(());
+        expr.for_each_node_mut(&mut |expr| {
+            let matchers = match expr {
+                Expr::VectorSelector(vs) => &mut vs.matchers,
+                Expr::MatrixSelector(ms) => &mut ms.vs.matchers,
+                _ => return,
+            };
+            let matchers = matchers
+                .matchers
+                .iter_mut()
+                .chain(matchers.or_matchers.iter_mut().flatten())
+                .filter(|m| m.name == METRIC_NAME);
+            for m in matchers {
+                if rewritten.is_err() {
+                    return;
+                }
+                if let Some(mut new) = f(m) {
+                    if let MatchOp::Re(re) | MatchOp::NotRe(re) = &mut new.op {
+                        match Matcher::try_parse_re(&new.value) {
+                            Ok(compiled) => *re = compiled,
+                            Err(e) => rewritten = Err(e),
+                        }
+                    }
+                    *m = new;
+                }
+            }
+        });
+        rewritten?;
+
+        *self = expr;
+        Ok(())
+    }
+
+    /// whether any selector in the expression uses a regex matcher, i.e. `=~` or `!~`.
+    pub fn has_regex_matcher(&self) -> bool {
+        let mut found = false;
+        self.for_each_selector(&mut |vs| {
+            found |= vs
+                .matchers
+                .matchers
+                .iter()
+                .chain(vs.matchers.or_matchers.iter().flatten())
+                .any(|m| matches!(m.op, MatchOp::Re(_) | MatchOp::NotRe(_)));
+        });
+        found
+    }
+
+    /// a heuristic cost of evaluating the expression, meant for ranking queries
+    /// against each other rather than as an absolute measure. The score adds up:
+    ///
+    /// - 1 for every node of the AST,
+    /// - 2 for every label matcher, plus another 10 if it is a regex matcher,
+    /// - 1 for every minute of the range of a matrix selector,
+    /// - 20 for every subquery, plus 1 for every step it evaluates, with a step
+    ///   of 1m assumed if none is given.
+    pub fn cost_estimate(&self) -> u64 {
+        const NODE_COST: u64 = 1;
+        const MATCHER_COST: u64 = 2;
+        const REGEX_COST: u64 = 10;
+        const SUBQUERY_COST: u64 = 20;
+        const DEFAULT_STEP: Duration = Duration::from_secs(60);
+
+        let mut cost: u64 = 0;
+        self.for_each_node(&mut |expr| {
+            cost = cost.saturating_add(NODE_COST);
+            let (vs, range) = match expr {
+                Expr::VectorSelector(vs) => (vs, None),
+                Expr::MatrixSelector(ms) => (&ms.vs, Some(ms.range)),
+                Expr::Subquery(sq) => {
+                    let step = sq.step.filter(|s| !s.is_zero()).unwrap_or(DEFAULT_STEP);
+                    // a step under 1ms can only be built programmatically, count it as 1ms
+                    let steps = sq.range.as_millis() / step.as_millis().max(1);
+                    let steps = u64::try_from(steps).unwrap_or(u64::MAX);
+                    cost = cost.saturating_add(SUBQUERY_COST).saturating_add(steps);
+                    return;
+                }
+                _ => return,
+            };
+            for m in vs
+                .matchers
+                .matchers
+                .iter()
+                .chain(vs.matchers.or_matchers.iter().flatten())
+            {
+                cost = cost.saturating_add(MATCHER_COST);
+                if matches!(m.op, MatchOp::Re(_) | MatchOp::NotRe(_)) {
+                    cost = cost.saturating_add(REGEX_COST);
+                }
+            }
+            if let Some(range) = range {
+                cost = cost.saturating_add(range.as_secs() / 60);
+            }
+        });
+        cost
+    }
+
+    /// whether any selector of the expression selects the metric, see
+    /// [`VectorSelector::matches_name`]. A recording rule whose expression references
+    /// its own output name, like `foo:bar` for `rate(foo:bar[5m])`, feeds on itself.
+    pub fn references_name(&self, name: &str) -> bool {
+        let mut found = false;
+        self.for_each_selector(&mut |vs| found |= vs.matches_name(name));
+        found
+    }
+
+    /// visit every node of the AST in pre-order.
+    pub(crate) fn for_each_node<'a, F: FnMut(&'a Expr)>(&'a self, f: &mut F) {
+        f(self);
+        for child in self.children() {
+            child.for_each_node(f);
+        }
+    }
+
+    /// visit every vector selector, including the ones of matrix selectors.
+    pub(crate) fn for_each_selector<'a, F: FnMut(&'a VectorSelector)>(&'a self, f: &mut F) {
+        self.for_each_node(&mut |expr| match expr {
+            Expr::VectorSelector(vs) => f(vs),
+            Expr::MatrixSelector(ms) => f(&ms.vs),
+            _ => (),
+        });
+    }
+
+    /// whether the result of the expression doesn't depend on the evaluation step, which
+    /// means it only needs to be evaluated once for a range query. This is the case for
+    /// literals and for selectors and subqueries with an `@` modifier, as long as no function
+    /// relying on the evaluation timestamp is called, see [`Function::is_at_modifier_unsafe`].
+    pub fn is_step_invariant(&self) -> bool {
+        match self {
+            Expr::VectorSelector(vs) => vs.at.is_some(),
+            Expr::MatrixSelector(ms) => ms.vs.at.is_some(),
+            Expr::Subquery(ex) => ex.at.is_some(),
+            Expr::NumberLiteral(_) | Expr::StringLiteral(_) => true,
+            Expr::Call(ex) => {
+                !ex.func.is_at_modifier_unsafe()
+                    && ex.args.args.iter().all(|arg| arg.is_step_invariant())
+            }
+            // the semantics of an extension is unknown
+            Expr::Extension(_) => false,
+            Expr::Aggregate(_) | Expr::Unary(_) | Expr::Binary(_) | Expr::Paren(_) => self
+                .children()
+                .into_iter()
+                .all(|child| child.is_step_invariant()),
+        }
+    }
+
+    /// whether the result of the expression is the same whenever and at whichever
+    /// timestamp it is evaluated, which is the case if every selector and subquery is
+    /// pinned by an absolute `@` modifier, directly or by an enclosing subquery, and
+    /// the functions reading the evaluation timestamp, like `time()`, `timestamp()`
+    /// or `predict_linear()`, see [`Function::is_at_modifier_unsafe`], are only called
+    /// in a pinned subquery. The [date functions](Function::is_date_time) only read it
+    /// without argument, e.g. `day_of_week(foo @ 100)` is pure. `@ start()` and
+    /// `@ end()` depend on the query range, so they are not pure.
+    pub fn is_pure(&self) -> bool {
+        self.is_pure_at(false)
+    }
+
+    fn is_pure_at(&self, pinned: bool) -> bool {
+        let at_is_pure = |at: &Option<AtModifier>| match at {
+            Some(AtModifier::At(_)) => true,
+            Some(AtModifier::Start) | Some(AtModifier::End) => false,
+            None => pinned,
+        };
+        match self {
+            Expr::VectorSelector(vs) => at_is_pure(&vs.at),
+            Expr::MatrixSelector(ms) => at_is_pure(&ms.vs.at),
+            Expr::Subquery(sq) => at_is_pure(&sq.at) && sq.expr.is_pure_at(true),
+            Expr::Call(call) if call.func.is_date_time() && !call.args.is_empty() => {
+                call.args.args.iter().all(|arg| arg.is_pure_at(pinned))
+            }
+            Expr::Call(call) if call.func.is_at_modifier_unsafe() => {
+                pinned && call.args.args.iter().all(|arg| arg.is_pure_at(pinned))
+            }
+            // the semantics of an extension is unknown
+            Expr::Extension(_) => false,
+            _ => self
+                .children()
+                .into_iter()
+                .all(|child| child.is_pure_at(pinned)),
+        }
+    }
+
+    /// the labels preserved by the outermost aggregation of the expression, e.g.
+    /// `By([a, b])` for `sum by (a, b) (x)`. The expression is `All` if it is not
+    /// an aggregation or the aggregation keeps the input labels, like `topk`.
+    pub fn labels_required_by_grouping(&self) -> GroupingResult {
+        let ex = match self {
+            Expr::Paren(ex) => return ex.expr.labels_required_by_grouping(),
+            Expr::Aggregate(ex) if !matches!(ex.op.id(), T_TOPK | T_BOTTOMK) => ex,
+            _ => return GroupingResult::All,
+        };
+
+        let mut labels: Vec<String> = ex
+            .modifier
+            .as_ref()
+            .map(|m| m.labels().labels.clone())
+            .unwrap_or_default();
+        let without = matches!(ex.modifier, Some(LabelModifier::Exclude(_)));
+
+        // count_values adds the label named by its parameter
+        if ex.op.id() == T_COUNT_VALUES {
+            if let Some(Expr::StringLiteral(StringLiteral { val })) = ex.param.as_deref() {
+                if without {
+                    labels.retain(|l| l != val);
+                } else if !labels.contains(val) {
+                    labels.push(val.clone());
+                }
+            }
+        }
+
+        if without {
+            GroupingResult::Without(labels)
+        } else {
+            GroupingResult::By(labels)
+        }
+    }
+
+    /// whether the result of the expression never changes, so it can be cached forever.
+    /// Besides being [step invariant](Expr::is_step_invariant), every `@` modifier in the
+    /// expression must be an absolute timestamp, `@ start()` and `@ end()` are relative
+    /// to the query range.
+    pub fn is_absolute_time(&self) -> bool {
+        if !self.is_step_invariant() {
+            return false;
+        }
+
+        let mut absolute = true;
+        self.for_each_node(&mut |expr| {
+            let at = match expr {
+                Expr::VectorSelector(vs) => &vs.at,
+                Expr::MatrixSelector(ms) => &ms.vs.at,
+                Expr::Subquery(sq) => &sq.at,
+                _ => return,
+            };
+            if matches!(at, Some(AtModifier::Start) | Some(AtModifier::End)) {
+                absolute = false;
+            }
+        });
+        absolute
+    }
+
+    /// heuristic warnings about parts of the expression which are valid,
+    /// but likely to be mistakes, like `a + on(__name__) b`.
+    pub fn lints(&self) -> Vec<Lint> {
+        lint::lint(self)
+    }
+
+    /// the lints about `histogram_quantile()` mixing up classic and native histograms,
+    /// like aggregating the `_bucket` series of a classic histogram without `le`.
+    pub fn validate_native_histogram_usage(&self) -> Vec<Lint> {
+        lint::lint_histograms(self)
+    }
+
+    /// traverse the AST in depth-first order, passing the path from the root to each
+    /// node to the visitor, see [`walk_expr_with_path`].
+    pub fn walk_paths<V: ExprPathVisitor>(&self, visitor: &mut V) -> Result<bool, V::Error> {
+        walk_expr_with_path(visitor, self)
+    }
+
+    /// the node reached by following the path from this node, like the paths passed
+    /// by [`Expr::walk_paths`], e.g. `foo[5m]` for `[BinaryLhs, CallArg(0)]` in
+    /// `rate(foo[5m]) + 1`. None if any segment does not lead to a child.
+    pub fn subtree_at_path(&self, path: &[PathSegment]) -> Option<&Expr> {
+        path.iter().try_fold(self, |expr, segment| {
+            PathSegment::children(expr)
+                .into_iter()
+                .find(|(s, _)| s == segment)
+                .map(|(_, child)| child)
+        })
+    }
+
+    /// a read-only view of the expression, whose accessors borrow from the AST
+    /// instead of cloning into owned strings, see [`QueryView`].
+    pub fn view(&self) -> QueryView<'_> {
+        QueryView::new(self)
+    }
+
+    /// the query with the values of the label matchers and the number literals replaced
+    /// by `?`, e.g. `foo{job=?} + ?` for `foo{job="a"} + 1`, to group queries which only
+    /// differ by their literals, like the fingerprint of a SQL query.
+    pub fn shape_signature(&self) -> String {
+        // rendered as a quoted string, which is then replaced by the placeholder
+        const MARKER: &str = "\u{0}";
+
+        fn mark_values(matchers: &mut Matchers) {
+            for m in matchers
+                .matchers
+                .iter_mut()
+                .chain(matchers.or_matchers.iter_mut().flatten())
+            {
+                m.value = String::from(MARKER);
+            }
+        }
+
+        let mut expr = self.clone();
+        expr.for_each_node_mut(&mut |node| match node {
+            Expr::NumberLiteral(_) => *node = Expr::from(MARKER),
+            Expr::VectorSelector(vs) => mark_values(&mut vs.matchers),
+            Expr::MatrixSelector(ms) => mark_values(&mut ms.vs.matchers),
+            _ => (),
+        });
+        expr.to_string().replace(&format!("\"{MARKER}\""), "?")
+    }
+
+    /// a compact and deterministic Lisp-like dump of the AST for snapshot tests, which
+    /// unlike [Debug](fmt::Debug) is stable across refactors of the node types,
+    /// e.g. `(binary + (selector foo) (num 1))` for `foo + 1`.
+    pub fn to_sexpr(&self) -> String {
+        let mut s = String::new();
+        self.write_sexpr(&mut s);
+        s
+    }
+
+    fn write_sexpr(&self, s: &mut String) {
+        fn write_selector(s: &mut String, vs: &VectorSelector) {
+            if let Some(name) = &vs.name {
+                write!(s, " {name}").unwrap();
+            }
+            if !vs.matchers.matchers.is_empty() || !vs.matchers.or_matchers.is_empty() {
+                write!(s, " {{{}}}", vs.matchers).unwrap();
+            }
+        }
+        fn write_modifiers(s: &mut String, at: &Option<AtModifier>, offset: &Option<Offset>) {
+            if let Some(at) = at {
+                write!(s, " ({at})").unwrap();
+            }
+            if let Some(offset) = offset {
+                write!(s, " (offset {offset})").unwrap();
+            }
+        }
+
+        match self {
+            Expr::Aggregate(ex) => {
+                write!(s, "(aggregate {}", ex.get_op_string().trim_end()).unwrap();
+                if let Some(param) = &ex.param {
+                    s.push(' ');
+                    param.write_sexpr(s);
+                }
+                s.push(' ');
+                ex.expr.write_sexpr(s);
+            }
+            Expr::Unary(ex) => {
+                s.push_str("(unary - ");
+                ex.expr.write_sexpr(s);
+            }
+            Expr::Binary(ex) => {
+                write!(s, "(binary {} ", ex.get_op_matching_string()).unwrap();
+                ex.lhs.write_sexpr(s);
+                s.push(' ');
+                ex.rhs.write_sexpr(s);
+            }
+            Expr::Paren(ex) => {
+                s.push_str("(paren ");
+                ex.expr.write_sexpr(s);
+            }
+            Expr::Subquery(ex) => {
+                s.push_str("(subquery ");
+                ex.expr.write_sexpr(s);
+                write!(s, " {}", display_duration(&ex.range)).unwrap();
+                if let Some(step) = &ex.step {
+                    write!(s, " {}", display_duration(step)).unwrap();
+                }
+                write_modifiers(s, &ex.at, &ex.offset);
+            }
+            Expr::NumberLiteral(ex) => write!(s, "(num {ex}").unwrap(),
+            Expr::StringLiteral(ex) => write!(s, "(str {ex}").unwrap(),
+            Expr::VectorSelector(ex) => {
+                s.push_str("(selector");
+                write_selector(s, ex);
+                write_modifiers(s, &ex.at, &ex.offset);
+            }
+            Expr::MatrixSelector(ex) => {
+                s.push_str("(matrix");
+                write_selector(s, &ex.vs);
+                write!(s, " {}", display_duration(&ex.range)).unwrap();
+                write_modifiers(s, &ex.vs.at, &ex.vs.offset);
+            }
+            Expr::Call(ex) => {
+                write!(s, "(call {}", ex.func.name).unwrap();
+                for arg in &ex.args.args {
+                    s.push(' ');
+                    arg.write_sexpr(s);
+                }
+            }
+            Expr::Extension(ex) => {
+                write!(s, "(extension {}", ex.expr.name()).unwrap();
+                for child in ex.expr.children() {
+                    s.push(' ');
+                    child.write_sexpr(s);
+                }
+            }
+        }
+        s.push(')');
+    }
+
+    /// render the AST in Graphviz DOT format, one node per expression, which is
+    /// handy for debugging complex queries, e.g. `dot -Tsvg`.
+    pub fn to_dot(&self) -> String {
+        let mut s = String::from("digraph promql {\n");
+        let mut next_id = 0;
+        self.write_dot_node(&mut s, &mut next_id);
+        s.push('}');
+        s
+    }
+
+    fn write_dot_node(&self, s: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+
+        let label = self.dot_label().replace('\\', "\\\\").replace('"', "\\\"");
+        writeln!(s, "  n{id} [label=\"{label}\"];").unwrap();
+        for child in self.children() {
+            let child_id = child.write_dot_node(s, next_id);
+            writeln!(s, "  n{id} -> n{child_id};").unwrap();
+        }
+        id
+    }
+
+    fn dot_label(&self) -> String {
+        match self {
+            Expr::Aggregate(ex) => format!("Aggregate: {}", ex.get_op_string().trim_end()),
+            Expr::Unary(_) => String::from("Unary: -"),
+            Expr::Binary(ex) => format!("Binary: {}", ex.get_op_matching_string()),
+            Expr::Paren(_) => String::from("Paren"),
+            Expr::Subquery(ex) => format!("Subquery: {}", ex.get_time_suffix_string()),
+            Expr::NumberLiteral(ex) => format!("NumberLiteral: {ex}"),
+            Expr::StringLiteral(ex) => format!("StringLiteral: {ex}"),
+            Expr::VectorSelector(ex) => format!("VectorSelector: {ex}"),
+            Expr::MatrixSelector(ex) => format!("MatrixSelector: {ex}"),
+            Expr::Call(ex) => format!("Call: {}", ex.func.name),
+            Expr::Extension(ex) => format!("Extension: {}", ex.expr.name()),
+        }
+    }
+}
+
+/// A zero-copy view over a parsed [Expr] for read-heavy analysis. The selectors and
+/// the calls are gathered once, then every accessor yields `&str` borrowed from the
+/// AST, the selectors in pre-order and the calls in post-order like
+/// [`Expr::function_names`]. Duplicates are kept, e.g. `foo + foo` yields `foo` twice.
+#[derive(Debug, Clone)]
+pub struct QueryView<'a> {
+    expr: &'a Expr,
+    selectors: Vec<&'a VectorSelector>,
+    calls: Vec<&'a Call>,
+}
+
+impl<'a> QueryView<'a> {
+    pub fn new(expr: &'a Expr) -> Self {
+        let mut selectors = vec![];
+        expr.for_each_node(&mut |node| match node {
+            Expr::VectorSelector(vs) => selectors.push(vs),
+            Expr::MatrixSelector(ms) => selectors.push(&ms.vs),
+            _ => (),
+        });
+        let mut calls = vec![];
+        expr.collect_calls(&mut calls);
+        Self {
+            expr,
+            selectors,
+            calls,
+        }
+    }
+
+    pub fn expr(&self) -> &'a Expr {
+        self.expr
+    }
+
+    /// every vector selector, including the ones of matrix selectors.
+    pub fn selectors(&self) -> impl Iterator<Item = &'a VectorSelector> + '_ {
+        self.selectors.iter().copied()
+    }
+
+    /// the metric names of the selectors, either `foo` or `{__name__="foo"}`.
+    pub fn metric_names(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.selectors().filter_map(|vs| {
+            vs.name.as_deref().or_else(|| {
+                vs.matchers
+                    .matchers
+                    .iter()
+                    .find(|m| m.name == METRIC_NAME && m.op == MatchOp::Equal)
+                    .map(|m| m.value.as_str())
+            })
+        })
+    }
+
+    /// the label names of the matchers of the selectors, except `__name__`.
+    pub fn label_names(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.selectors()
+            .flat_map(|vs| {
+                vs.matchers
+                    .matchers
+                    .iter()
+                    .chain(vs.matchers.or_matchers.iter().flatten())
+            })
+            .map(|m| m.name.as_str())
+            .filter(|name| *name != METRIC_NAME)
+    }
+
+    /// the names of the called functions, in post-order like [`Expr::function_names`].
+    pub fn function_names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.calls.iter().map(|call| call.func.name)
+    }
+}
+
+impl From<String> for Expr {
+    fn from(val: String) -> Self {
+        Expr::StringLiteral(StringLiteral { val })
+    }
+}
+
+impl From<&str> for Expr {
+    fn from(s: &str) -> Self {
+        Expr::StringLiteral(StringLiteral { val: s.into() })
+    }
+}
+
+impl From<f64> for Expr {
+    fn from(val: f64) -> Self {
+        Expr::NumberLiteral(NumberLiteral { val })
+    }
+}
+
+/// directly create an Expr::VectorSelector from instant vector
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ``` rust
+/// use promql_parser::label::Matchers;
+/// use promql_parser::parser::{Expr, VectorSelector};
+///
+/// let name = String::from("foo");
+/// let vs = VectorSelector::new(Some(name), Matchers::empty());
+///
+/// assert_eq!(Expr::VectorSelector(vs), Expr::from(VectorSelector::from("foo")));
+/// ```
+impl From<VectorSelector> for Expr {
+    fn from(vs: VectorSelector) -> Self {
+        Expr::VectorSelector(vs)
+    }
+}
+
+fn parenthesize(expr: Expr) -> Expr {
+    Expr::Paren(ParenExpr {
+        expr: Box::new(expr),
+    })
+}
+
+/// the result of the binary operation between two numbers, None if it can't be folded.
+fn fold_scalar_binary(op: TokenId, lhs: f64, rhs: f64, return_bool: bool) -> Option<f64> {
+    let from_bool = |b: bool| if b { 1.0 } else { 0.0 };
+    let val = match op {
+        T_ADD => lhs + rhs,
+        T_SUB => lhs - rhs,
+        T_MUL => lhs * rhs,
+        T_DIV => lhs / rhs,
+        T_MOD => lhs % rhs,
+        T_POW => lhs.powf(rhs),
+        T_ATAN2 => lhs.atan2(rhs),
+        T_EQLC if return_bool => from_bool(lhs == rhs),
+        T_NEQ if return_bool => from_bool(lhs != rhs),
+        T_GTR if return_bool => from_bool(lhs > rhs),
+        T_GTE if return_bool => from_bool(lhs >= rhs),
+        T_LSS if return_bool => from_bool(lhs < rhs),
+        T_LTE if return_bool => from_bool(lhs <= rhs),
+        _ => return None,
+    };
+    Some(val)
+}
+
+fn sort_modifier_labels(modifier: &mut LabelModifier) {
+    match modifier {
+        LabelModifier::Include(labels) | LabelModifier::Exclude(labels) => sort_labels(labels),
+    }
+}
+
+/// sort the labels and remove the duplicates, e.g. `on (a, b)` for `on (b, a, a)`.
+fn sort_labels(labels: &mut Labels) {
+    labels.labels.sort();
+    labels.labels.dedup();
+}
+
+/// feeds strings written with `write!` into a hasher, see [`Expr::hash_canonical`].
+struct HashWriter<'a, H>(&'a mut H);
+
+impl<H: Hasher> fmt::Write for HashWriter<'_, H> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// the [canonical hash](Expr::hash_canonical) of a single expression, used to
+/// order the operands of commutative operators.
+fn canonical_hash(expr: &Expr) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    expr.hash_canonical(&mut hasher);
+    hasher.finish()
+}
+
+/// hash the labels sorted and deduped, like the canonical rendering.
+fn hash_sorted_labels<H: Hasher>(labels: &Labels, state: &mut H) {
+    let mut labels: Vec<&str> = labels.labels.iter().map(String::as_str).collect();
+    labels.sort_unstable();
+    labels.dedup();
+    state.write_usize(labels.len());
+    for label in labels {
+        state.write(label.as_bytes());
+        state.write_u8(0xff);
+    }
+}
+
+/// hash the name and the matchers of the selector. The simple matchers are rendered
+/// sorted, so they are ordered by their own hash, while the `or` groups keep their order.
+fn hash_vector_selector<H: Hasher>(vs: &VectorSelector, state: &mut H) {
+    match &vs.name {
+        Some(name) => {
+            state.write_u8(1);
+            state.write(name.as_bytes());
+            state.write_u8(0xff);
+        }
+        None => state.write_u8(0),
+    }
+
+    let mut matchers: Vec<u64> = vs
+        .matchers
+        .matchers
+        .iter()
+        .map(|m| {
+            let mut hasher = DefaultHasher::new();
+            hash_matcher(m, &mut hasher);
+            hasher.finish()
+        })
+        .collect();
+    matchers.sort_unstable();
+    state.write_usize(matchers.len());
+    for m in matchers {
+        state.write_u64(m);
+    }
+
+    state.write_usize(vs.matchers.or_matchers.len());
+    for group in &vs.matchers.or_matchers {
+        state.write_usize(group.len());
+        for m in group {
+            hash_matcher(m, state);
+        }
+    }
+
+    hash_offset_and_at(&vs.offset, &vs.at, state);
+}
+
+/// hash the name, the operator and the value of the matcher.
+fn hash_matcher<H: Hasher>(m: &Matcher, state: &mut H) {
+    state.write(m.name.as_bytes());
+    state.write_u8(0xff);
+    state.write_u8(match m.op {
+        MatchOp::Equal => 0,
+        MatchOp::NotEqual => 1,
+        MatchOp::Re(_) => 2,
+        MatchOp::NotRe(_) => 3,
+    });
+    state.write(m.value.as_bytes());
+    state.write_u8(0xff);
+}
+
+/// hash the offset and the `@` modifier like they are rendered.
+fn hash_offset_and_at<H: Hasher>(offset: &Option<Offset>, at: &Option<AtModifier>, state: &mut H) {
+    match rendered_offset(offset) {
+        Some(Offset::Pos(d)) => {
+            state.write_u8(1);
+            state.write_u128(d.as_nanos());
+        }
+        Some(Offset::Neg(d)) => {
+            state.write_u8(2);
+            state.write_u128(d.as_nanos());
+        }
+        None => state.write_u8(0),
+    }
+    match at {
+        Some(AtModifier::Start) => state.write_u8(1),
+        Some(AtModifier::End) => state.write_u8(2),
+        Some(AtModifier::At(t)) => {
+            state.write_u8(3);
+            match t.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(d) => state.write_i128(d.as_nanos() as i128),
+                Err(e) => state.write_i128(-(e.duration().as_nanos() as i128)),
+            }
+        }
+        None => state.write_u8(0),
+    }
+}
+
+/// `+` and `*` give the same result with swapped operands, as long as the labels
+/// of the result don't depend on the side, i.e. without explicit matching.
+fn is_commutative(ex: &BinaryExpr) -> bool {
+    let plain_matching = match &ex.modifier {
+        None => true,
+        Some(modifier) => {
+            !modifier.return_bool
+                && matches!(
+                    modifier.card,
+                    VectorMatchCardinality::OneToOne | VectorMatchCardinality::ManyToMany
+                )
+                && match &modifier.matching {
+                    None => true,
+                    Some(LabelModifier::Exclude(labels)) => labels.is_empty(),
+                    Some(LabelModifier::Include(_)) => false,
+                }
+        }
+    };
+    matches!(ex.op.id(), T_ADD | T_MUL) && plain_matching
+}
+
+/// wraps the operand of the binary operator in parentheses if it would be parsed
+/// differently without them.
+fn paren_operand(operand: Expr, op: &TokenType, is_lhs: bool) -> Expr {
+    let right_assoc = op.id() == T_POW;
+    let needs_paren = match &operand {
+        Expr::Binary(child) => match (child.op.precedence(), op.precedence()) {
+            (Some(child_prec), Some(prec)) => {
+                child_prec < prec || (child_prec == prec && is_lhs == right_assoc)
+            }
+            _ => true,
+        },
+        // `-a ^ b` is parsed as `-(a ^ b)`
+        Expr::Unary(_) => is_lhs && right_assoc,
+        Expr::NumberLiteral(nl) => nl.val.is_sign_negative() && is_lhs && right_assoc,
+        _ => false,
+    };
+    if needs_paren {
+        parenthesize(operand)
+    } else {
+        operand
+    }
+}
+
+impl Neg for Expr {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        match self {
+            Expr::NumberLiteral(nl) => Expr::NumberLiteral(-nl),
+            _ => Expr::Unary(UnaryExpr {
+                expr: Box::new(self),
+            }),
+        }
+    }
+}
+
+/// The formatter is passed down to the children as is, so that the precision,
+/// like `{:.6}`, applies to the timestamps of all the `@` modifiers.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Aggregate(ex) => fmt::Display::fmt(ex, f),
+            Expr::Unary(ex) => fmt::Display::fmt(ex, f),
+            Expr::Binary(ex) => fmt::Display::fmt(ex, f),
+            Expr::Paren(ex) => fmt::Display::fmt(ex, f),
+            Expr::Subquery(ex) => fmt::Display::fmt(ex, f),
+            Expr::NumberLiteral(ex) => write!(f, "{ex}"),
+            Expr::StringLiteral(ex) => write!(f, "{ex}"),
+            Expr::VectorSelector(ex) => fmt::Display::fmt(ex, f),
+            Expr::MatrixSelector(ex) => fmt::Display::fmt(ex, f),
+            Expr::Call(ex) => fmt::Display::fmt(ex, f),
+            Expr::Extension(ext) => write!(f, "{ext:?}"),
+        }
+    }
+}
+
+impl Prettier for Expr {
+    fn pretty(&self, level: usize, max: usize) -> String {
+        match self {
+            Expr::Aggregate(ex) => ex.pretty(level, max),
+            Expr::Unary(ex) => ex.pretty(level, max),
+            Expr::Binary(ex) => ex.pretty(level, max),
+            Expr::Paren(ex) => ex.pretty(level, max),
+            Expr::Subquery(ex) => ex.pretty(level, max),
+            Expr::NumberLiteral(ex) => ex.pretty(level, max),
+            Expr::StringLiteral(ex) => ex.pretty(level, max),
+            Expr::VectorSelector(ex) => ex.pretty(level, max),
+            Expr::MatrixSelector(ex) => ex.pretty(level, max),
+            Expr::Call(ex) => ex.pretty(level, max),
+            Expr::Extension(ext) => format!("{ext:?}"),
+        }
+    }
+}
+
+/// check_ast checks the validity of the provided AST. This includes type checking.
+/// Recursively check correct typing for child nodes and raise errors in case of bad typing.
+pub(crate) fn check_ast(expr: Expr) -> Result<Expr, String> {
+    match expr {
+        Expr::Binary(ex) => check_ast_for_binary_expr(ex),
+        Expr::Aggregate(ex) => check_ast_for_aggregate_expr(ex),
+        Expr::Call(ex) => check_ast_for_call(ex),
+        Expr::Unary(ex) => check_ast_for_unary(ex),
+        Expr::Subquery(ex) => check_ast_for_subquery(ex),
+        Expr::VectorSelector(ex) => check_ast_for_vector_selector(ex),
+        Expr::Paren(_) => Ok(expr),
+        Expr::NumberLiteral(_) => Ok(expr),
+        Expr::StringLiteral(_) => Ok(expr),
+        Expr::MatrixSelector(_) => Ok(expr),
+        Expr::Extension(_) => Ok(expr),
+    }
+}
+
+fn expect_type(
+    expected: ValueType,
+    actual: Option<ValueType>,
+    context: &str,
+) -> Result<bool, String> {
+    match actual {
+        Some(actual) => {
+            if actual == expected {
+                Ok(true)
+            } else {
+                Err(format!(
+                    "expected type {expected} in {context}, got {actual}"
+                ))
+            }
+        }
+        None => Err(format!("expected type {expected} in {context}, got None")),
+    }
+}
+
+/// the original logic is redundant in prometheus, and the following coding blocks
+/// have been optimized for readability, but all logic SHOULD be covered.
+fn check_ast_for_binary_expr(mut ex: BinaryExpr) -> Result<Expr, String> {
+    if !ex.op.is_operator() {
+        return Err(format!(
+            "binary expression does not support operator '{}'",
+            ex.op
+        ));
+    }
+
+    if ex.return_bool() && !ex.op.is_comparison_operator() {
+        return Err(errors::BOOL_ON_NON_COMPARISON.into());
+    }
+
+    if ex.op.is_comparison_operator()
+        && ex.lhs.value_type() == ValueType::Scalar
+        && ex.rhs.value_type() == ValueType::Scalar
+        && !ex.return_bool()
+    {
+        return Err(errors::SCALAR_COMPARISON_WITHOUT_BOOL.into());
+    }
+
+    // For `on` matching, a label can only appear in one of the lists.
+    // Every time series of the result vector must be uniquely identifiable.
+    if ex.is_matching_on() && ex.is_labels_joint() {
+        if let Some(labels) = ex.intersect_labels() {
+            if let Some(label) = labels.first() {
+                return Err(format!(
+                    "label '{label}' must not occur in ON and GROUP clause at once"
+                ));
+            }
+        };
+    }
+
+    ValueType::binary_result(ex.lhs.value_type(), ex.rhs.value_type(), ex.op.id())?;
+
+    if ex.op.is_set_operator() {
+        if ex.lhs.value_type() == ValueType::Vector && ex.rhs.value_type() == ValueType::Vector {
+            if let Some(ref modifier) = ex.modifier {
+                if matches!(modifier.card, VectorMatchCardinality::OneToMany(_))
+                    || matches!(modifier.card, VectorMatchCardinality::ManyToOne(_))
+                {
+                    return Err(format!("no grouping allowed for '{}' operation", ex.op));
+                }
+            };
+        }
+
+        match &mut ex.modifier {
+            Some(modifier) => {
+                if modifier.card == VectorMatchCardinality::OneToOne {
+                    modifier.card = VectorMatchCardinality::ManyToMany;
+                }
+            }
+            None => {
+                ex.modifier =
+                    Some(BinModifier::default().with_card(VectorMatchCardinality::ManyToMany));
+            }
+        }
+    }
+
+    if (ex.lhs.value_type() != ValueType::Vector || ex.rhs.value_type() != ValueType::Vector)
+        && ex.is_matching_labels_not_empty()
+    {
+        return Err(errors::VECTOR_MATCHING_ON_NON_VECTORS.into());
+    }
+
+    Ok(Expr::Binary(ex))
+}
+
+fn check_ast_for_aggregate_expr(ex: AggregateExpr) -> Result<Expr, String> {
+    if !ex.op.is_aggregator() {
+        return Err(format!(
+            "aggregation operator expected in aggregation expression but got '{}'",
+            ex.op
+        ));
+    }
+
+    expect_type(
+        ValueType::Vector,
+        Some(ex.expr.value_type()),
+        "aggregation expression",
+    )?;
+
+    if matches!(ex.op.id(), T_TOPK | T_BOTTOMK | T_QUANTILE) {
+        expect_type(
+            ValueType::Scalar,
+            ex.param.as_ref().map(|ex| ex.value_type()),
+            "aggregation expression",
+        )?;
+    }
+
+    if ex.op.id() == T_COUNT_VALUES {
+        expect_type(
+            ValueType::String,
+            ex.param.as_ref().map(|ex| ex.value_type()),
+            "aggregation expression",
+        )?;
+    }
+
+    Ok(Expr::Aggregate(ex))
+}
+
+fn check_ast_for_call(ex: Call) -> Result<Expr, String> {
+    check_call_args(&ex.func, &ex.args)?;
+    Ok(Expr::Call(ex))
+}
+
+fn check_call_args(func: &Function, args: &FunctionArgs) -> Result<(), String> {
+    check_call_args_len(func, args.len())?;
+
+    for (idx, actual_arg) in args.args.iter().enumerate() {
+        check_call_arg(func, idx, actual_arg)?;
+    }
+    Ok(())
+}
+
+fn check_call_args_len(func: &Function, actual_args_len: usize) -> Result<(), String> {
+    let expected_args_len = func.arg_types.len();
+    let name = func.name;
+
+    if func.variadic {
+        let expected_args_len_without_default = expected_args_len - 1;
+        if expected_args_len_without_default > actual_args_len {
+            return Err(format!(
+                "expected at least {expected_args_len_without_default} argument(s) in call to '{name}', got {actual_args_len}"
+            ));
+        }
+
+        // `label_join` do not have a maximum arguments threshold.
+        // this hard code SHOULD be careful if new functions are supported by Prometheus.
+        if actual_args_len > expected_args_len && func.id() != Some(FunctionId::LabelJoin) {
+            return Err(format!(
+                "expected at most {expected_args_len} argument(s) in call to '{name}', got {actual_args_len}"
+            ));
+        }
+    }
+
+    if !func.variadic && expected_args_len != actual_args_len {
+        return Err(format!(
+            "expected {expected_args_len} argument(s) in call to '{name}', got {actual_args_len}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// check the type of the argument at `idx`, and that `idx` does not exceed
+/// the maximum arguments of the function.
+fn check_call_arg(func: &Function, idx: usize, actual_arg: &Expr) -> Result<(), String> {
+    let expected_args_len = func.arg_types.len();
+    let name = func.name;
+
+    let exceeded = if func.variadic {
+        idx >= expected_args_len && func.id() != Some(FunctionId::LabelJoin)
+    } else {
+        idx >= expected_args_len
+    };
+    if exceeded {
+        return check_call_args_len(func, idx + 1);
+    }
+
+    // special cases from https://prometheus.io/docs/prometheus/latest/querying/functions
+    if idx == 0 {
+        if let Some(val) = actual_arg.scalar_value() {
+            match func.id() {
+                Some(FunctionId::Exp) if val.is_nan() || val.is_infinite() => return Ok(()),
+                Some(FunctionId::Ln | FunctionId::Log2 | FunctionId::Log10)
+                    if val.is_nan() || val.is_infinite() || val <= 0.0 =>
+                {
+                    return Ok(())
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // the destination and the source labels of label_join must be valid label names
+    if func.id() == Some(FunctionId::LabelJoin) && (idx == 1 || idx >= 3) {
+        if let Expr::StringLiteral(label) = actual_arg {
+            if !is_label(&label.val) {
+                let kind = if idx == 1 { "destination" } else { "source" };
+                return Err(format!(
+                    "invalid {kind} label name in label_join(): '{}'",
+                    label.val
+                ));
+            }
+        }
+    }
+
+    // this only happens when function args are variadic
+    let idx = idx.min(expected_args_len - 1);
+
+    expect_type(
+        func.arg_types[idx],
+        Some(actual_arg.value_type()),
+        &format!("call to function '{name}'"),
+    )?;
+    Ok(())
+}
+
+fn check_ast_for_unary(ex: UnaryExpr) -> Result<Expr, String> {
+    let value_type = ex.expr.value_type();
+    if value_type != ValueType::Scalar && value_type != ValueType::Vector {
+        return Err(format!(
+            "unary expression only allowed on expressions of type scalar or vector, got {value_type}"
+        ));
+    }
+
+    Ok(Expr::Unary(ex))
+}
+
+fn check_ast_for_subquery(ex: SubqueryExpr) -> Result<Expr, String> {
+    let value_type = ex.expr.value_type();
+    if value_type != ValueType::Vector {
+        return Err(format!(
+            "subquery is only allowed on vector, got {value_type} instead"
+        ));
+    }
+
+    Ok(Expr::Subquery(ex))
+}
+
+fn check_ast_for_vector_selector(ex: VectorSelector) -> Result<Expr, String> {
+    match ex.name {
+        Some(ref name) => match ex.matchers.find_matcher_value(METRIC_NAME) {
+            Some(val) => Err(format!(
+                "metric name must not be set twice: '{}' or '{}'",
+                name, val
+            )),
+            None => Ok(Expr::VectorSelector(ex)),
+        },
+        None if ex.matchers.is_empty_matchers() => {
+            // When name is None, a vector selector must contain at least one non-empty matcher
+            // to prevent implicit selection of all metrics (e.g. by a typo).
+            Err(errors::EMPTY_VECTOR_SELECTOR.into())
+        }
+        _ => Ok(Expr::VectorSelector(ex)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label::{MatchOp, Matcher};
+
+    #[test]
+    fn test_valid_at_modifier() {
+        let cases = vec![
+            // tuple: (seconds, elapsed milliseconds before or after UNIX_EPOCH)
+            (0.0, 0),
+            (1000.3, 1000300),    // after UNIX_EPOCH
+            (1000.9, 1000900),    // after UNIX_EPOCH
+            (1000.9991, 1000999), // after UNIX_EPOCH
+            (1000.9999, 1001000), // after UNIX_EPOCH
+            (-1000.3, 1000300),   // before UNIX_EPOCH
+            (-1000.9, 1000900),   // before UNIX_EPOCH
+        ];
+
+        for (secs, elapsed) in cases {
+            match AtModifier::try_from(secs).unwrap() {
+                AtModifier::At(st) => {
+                    if secs.is_sign_positive() || secs == 0.0 {
+                        assert_eq!(
+                            elapsed,
+                            st.duration_since(SystemTime::UNIX_EPOCH)
+                                .unwrap()
+                                .as_millis()
+                        )
+                    } else if secs.is_sign_negative() {
+                        assert_eq!(
+                            elapsed,
+                            SystemTime::UNIX_EPOCH
+                                .duration_since(st)
+                                .unwrap()
+                                .as_millis()
+                        )
+                    }
+                }
+                _ => panic!(),
+            }
+        }
+
+        assert_eq!(
+            AtModifier::try_from(Expr::from(1.0)),
+            AtModifier::try_from(1.0),
+        );
+    }
+
+    #[test]
+    fn test_invalid_at_modifier() {
+        let cases = vec![
+            f64::MAX,
+            f64::MIN,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+
+        for secs in cases {
+            assert!(AtModifier::try_from(secs).is_err())
+        }
+
+        assert_eq!(
+            AtModifier::try_from(token::T_ADD),
+            Err("invalid @ modifier preprocessor '+', START or END is valid.".into())
+        );
+
+        assert_eq!(
+            AtModifier::try_from(Expr::from("string literal")),
+            Err("invalid float value after @ modifier".into())
+        );
+    }
+
+    #[test]
+    fn test_at_modifier_rounding() {
+        let at = |secs: f64| AtModifier::try_from(secs).unwrap();
+
+        // equal once rounded to the same millisecond
+        assert_eq!(at(3.3335), at(3.334));
+        assert_eq!(at(3.3336), at(3.334));
+        assert_eq!(at(-3.3335), at(-3.334));
+        assert_eq!(at(100.0004), at(100.0));
+        assert_eq!("@ 3.334", at(3.3335).to_string());
+
+        // but not within a different millisecond
+        assert_ne!(at(3.3334), at(3.334));
+        assert_ne!(at(3.3345), at(3.334));
+
+        let lhs = crate::parser::parse("foo @ 3.3335").unwrap();
+        let rhs = crate::parser::parse("foo @ 3.334").unwrap();
+        assert_eq!(lhs, rhs);
+        assert_eq!(lhs.to_string(), rhs.to_string());
+    }
+
+    #[test]
+    fn test_binary_operator_spacing() {
+        let arithmetic = ["+", "-", "*", "/", "%", "^", "atan2"];
+        let comparison = ["==", "!=", ">", "<", ">=", "<="];
+        let set = ["and", "or", "unless"];
+        let matchings = ["", "on (x)", "on (x, z)", "on ()", "ignoring (x)"];
+        let groupings = ["", "group_left (y)", "group_right (y)", "group_left ()"];
+
+        let ops = arithmetic.iter().chain(&comparison).chain(&set);
+        for op in ops {
+            let bools: &[&str] = if comparison.contains(op) {
+                &["", "bool"]
+            } else {
+                &[""]
+            };
+            let op_groupings: &[&str] = if set.contains(op) { &[""] } else { &groupings };
+            for bool_str in bools {
+                for matching in matchings {
+                    for grouping in op_groupings {
+                        if !grouping.is_empty() && matching.is_empty() {
+                            continue;
+                        }
+                        let parts = [*op, *bool_str, matching, *grouping];
+                        let parts: Vec<&str> =
+                            parts.into_iter().filter(|p| !p.is_empty()).collect();
+                        let expected = format!("a {} b", parts.join(" "));
+
+                        let expr = crate::parser::parse(&expected).unwrap();
+                        assert_eq!(expected, expr.to_string());
+
+                        // the spacing of the input does not matter
+                        let input = format!("a  {}  b", parts.join("   "));
+                        let expr = crate::parser::parse(&input).unwrap();
+                        assert_eq!(expected, expr.to_string(), "{input}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_binary_labels() {
+        assert_eq!(
+            &Labels::new(vec!["foo", "bar"]),
+            LabelModifier::Include(Labels::new(vec!["foo", "bar"])).labels()
+        );
+
+        assert_eq!(
+            &Labels::new(vec!["foo", "bar"]),
+            LabelModifier::Exclude(Labels::new(vec!["foo", "bar"])).labels()
+        );
+
+        assert_eq!(
+            &Labels::new(vec!["foo", "bar"]),
+            VectorMatchCardinality::OneToMany(Labels::new(vec!["foo", "bar"]))
+                .labels()
+                .unwrap()
+        );
+
+        assert_eq!(
+            &Labels::new(vec!["foo", "bar"]),
+            VectorMatchCardinality::ManyToOne(Labels::new(vec!["foo", "bar"]))
+                .labels()
+                .unwrap()
+        );
+
+        assert_eq!(VectorMatchCardinality::OneToOne.labels(), None);
+        assert_eq!(VectorMatchCardinality::ManyToMany.labels(), None);
+    }
+
+    #[test]
+    fn test_label_modifier_set() {
+        let by = LabelModifier::include(vec!["a", "b"]);
+        let set = by.as_set();
+        assert_eq!(HashSet::from(["a", "b"]), set);
+        assert_eq!(by, LabelModifier::from_include_set(&set));
+        assert_eq!(
+            LabelModifier::exclude(vec!["a", "b"]),
+            LabelModifier::from_exclude_set(&set)
+        );
+
+        let set = HashSet::from([String::from("z"), String::from("x"), String::from("y")]);
+        assert_eq!(
+            LabelModifier::include(vec!["x", "y", "z"]),
+            LabelModifier::from_include_set(&set)
+        );
+        assert!(LabelModifier::exclude(vec![]).as_set().is_empty());
+    }
+
+    #[test]
+    fn test_neg() {
+        assert_eq!(
+            -VectorSelector::from("foo"),
+            UnaryExpr {
+                expr: Box::new(Expr::from(VectorSelector::from("foo")))
+            }
+        )
+    }
+
+    #[test]
+    fn test_scalar_value() {
+        assert_eq!(Some(1.0), Expr::from(1.0).scalar_value());
+        assert_eq!(None, Expr::from("1.0").scalar_value());
+    }
+
+    #[test]
+    fn test_at_expr() {
+        assert_eq!(
+            "@ <timestamp> may not be set multiple times",
+            Expr::from(VectorSelector::from("foo"))
+                .at_expr(AtModifier::try_from(1.0).unwrap())
+                .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+                .unwrap_err()
+        );
+
+        assert_eq!(
+            "@ <timestamp> may not be set multiple times",
+            Expr::new_matrix_selector(
+                Expr::from(VectorSelector::from("foo")),
+                Duration::from_secs(1),
+            )
+            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+            .unwrap_err()
+        );
+
+        assert_eq!(
+            "@ <timestamp> may not be set multiple times",
+            Expr::new_subquery_expr(
+                Expr::from(VectorSelector::from("foo")),
+                Duration::from_secs(1),
+                None,
+            )
+            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+            .and_then(|ex| ex.at_expr(AtModifier::try_from(1.0).unwrap()))
+            .unwrap_err()
+        )
+    }
+
+    #[test]
+    fn test_offset_expr() {
+        assert_eq!(
+            "offset may not be set multiple times",
+            Expr::from(VectorSelector::from("foo"))
+                .offset_expr(Offset::Pos(Duration::from_secs(1000)))
+                .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+                .unwrap_err()
+        );
+
+        assert_eq!(
+            "offset may not be set multiple times",
+            Expr::new_matrix_selector(
+                Expr::from(VectorSelector::from("foo")),
+                Duration::from_secs(1),
+            )
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+            .unwrap_err()
+        );
+
+        assert_eq!(
+            "offset may not be set multiple times",
+            Expr::new_subquery_expr(
+                Expr::from(VectorSelector::from("foo")),
+                Duration::from_secs(1),
+                None,
+            )
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(1000))))
+            .unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_number_format_idempotent() {
+        let cases = vec![
+            ("1", "1"),
+            ("- 1", "-1"),
+            ("+ 1", "1"),
+            ("Inf", "Inf"),
+            ("inf", "Inf"),
+            ("+Inf", "Inf"),
+            ("- Inf", "-Inf"),
+            (".5", "0.5"),
+            ("5.", "5"),
+            ("123.4567", "123.4567"),
+            ("5e-3", "0.005"),
+            ("5e3", "5000"),
+            ("0xc", "12"),
+            ("0755", "493"),
+            ("08", "8"),
+            ("+5.5e-3", "0.0055"),
+            ("-0755", "-493"),
+            ("NaN", "NaN"),
+            ("NAN", "NaN"),
+            ("0.1", "0.1"),
+            ("1e21", "1000000000000000000000"),
+            ("1.5e-10", "0.00000000015"),
+            ("- 0", "0"),
+        ];
+
+        for (input, expected) in cases {
+            let mut expr = crate::parser::parse(input).unwrap();
+            expr.normalize_number_format();
+            let rendered = expr.to_string();
+            assert_eq!(expected, rendered, "{input}");
+
+            let mut reparsed = crate::parser::parse(&rendered).unwrap();
+            reparsed.normalize_number_format();
+            assert_eq!(expr, reparsed, "{input}");
+            assert_eq!(rendered, reparsed.to_string(), "{input}");
+        }
+
+        // extreme values never switch to the exponent form, and still parse back
+        for val in [f64::MAX, f64::MIN_POSITIVE, 5e-324, 1e300] {
+            let rendered = Expr::from(val).to_string();
+            assert!(!rendered.contains('e'), "{rendered}");
+            assert_eq!(Expr::from(val), crate::parser::parse(&rendered).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_expr_to_string() {
+        let mut cases = vec![
+            ("1", "1"),
+            ("- 1", "-1"),
+            ("+ 1", "1"),
+            ("Inf", "Inf"),
+            ("inf", "Inf"),
+            ("+Inf", "Inf"),
+            ("- Inf", "-Inf"),
+            (".5", "0.5"),
+            ("5.", "5"),
             ("123.4567", "123.4567"),
             ("5e-3", "0.005"),
             ("5e3", "5000"),
@@ -1872,829 +4139,2546 @@ mod tests {
             ("foo*sum", "foo * sum"),
             ("foo * on(test,blub) bar", "foo * on (test, blub) bar"),
             (
-                r#"up{job="hi", instance="in"} offset 5m @ 100"#,
-                r#"up{instance="in",job="hi"} @ 100.000 offset 5m"#,
+                r#"up{job="hi", instance="in"} offset 5m @ 100"#,
+                r#"up{instance="in",job="hi"} @ 100.000 offset 5m"#,
+            ),
+            (
+                r#"up{job="hi", instance="in"}"#,
+                r#"up{instance="in",job="hi"}"#,
+            ),
+            ("sum (up) by (job,instance)", "sum by (job, instance) (up)"),
+            (
+                "foo / on(test,blub) group_left(bar) bar",
+                "foo / on (test, blub) group_left (bar) bar",
+            ),
+            (
+                "foo / on(test,blub) group_right(bar) bar",
+                "foo / on (test, blub) group_right (bar) bar",
+            ),
+            (
+                r#"foo{a="b",foo!="bar",test=~"test",bar!~"baz"}"#,
+                r#"foo{a="b",bar!~"baz",foo!="bar",test=~"test"}"#,
+            ),
+            (
+                r#"{__name__=~"foo.+",__name__=~".*bar"}"#,
+                r#"{__name__=~".*bar",__name__=~"foo.+"}"#,
+            ),
+            (
+                r#"test{a="b"}[5y] OFFSET 3d"#,
+                r#"test{a="b"}[5y] offset 3d"#,
+            ),
+            (
+                r#"{a="b"}[5y] OFFSET 3d"#,
+                r#"{a="b"}[5y] offset 3d"#,
+            ),
+            (
+                "sum(some_metric) without(and, by, avg, count, alert, annotations)",
+                "sum without (and, by, avg, count, alert, annotations) (some_metric)",
+            ),
+            (
+                r#"floor(some_metric{foo!="bar"})"#,
+                r#"floor(some_metric{foo!="bar"})"#,
+            ),
+            (
+                "sum(rate(http_request_duration_seconds[10m])) / count(rate(http_request_duration_seconds[10m]))",
+                "sum(rate(http_request_duration_seconds[10m])) / count(rate(http_request_duration_seconds[10m]))",
+            ),
+            ("rate(some_metric[5m])", "rate(some_metric[5m])"),
+            ("round(some_metric,5)", "round(some_metric, 5)"),
+            (
+                r#"absent(sum(nonexistent{job="myjob"}))"#,
+                r#"absent(sum(nonexistent{job="myjob"}))"#,
+            ),
+            (
+                "histogram_quantile(0.9,rate(http_request_duration_seconds_bucket[10m]))",
+                "histogram_quantile(0.9, rate(http_request_duration_seconds_bucket[10m]))",
+            ),
+            (
+                "histogram_quantile(0.9,sum(rate(http_request_duration_seconds_bucket[10m])) by(job,le))",
+                "histogram_quantile(0.9, sum by (job, le) (rate(http_request_duration_seconds_bucket[10m])))",
+            ),
+            (
+                r#"label_join(up{job="api-server",src1="a",src2="b",src3="c"}, "foo", ",", "src1", "src2", "src3")"#,
+                r#"label_join(up{job="api-server",src1="a",src2="b",src3="c"}, "foo", ",", "src1", "src2", "src3")"#,
+            ),
+            (
+                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:])[4m:3s] @ 100"#,
+                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:])[4m:3s] @ 100.000"#,
+            ),
+            (
+                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:])[4m:3s]"#,
+                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:])[4m:3s]"#,
+            ),
+            (
+                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:] offset 4m)[4m:3s]"#,
+                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:] offset 4m)[4m:3s]"#,
+            ),
+            ("some_metric OFFSET 1m [10m:5s]", "some_metric offset 1m[10m:5s]"),
+            ("some_metric @123 [10m:5s]", "some_metric @ 123.000[10m:5s]")
+        ];
+
+        // the following cases are from https://github.com/prometheus/prometheus/blob/main/promql/parser/printer_test.go
+        let mut cases1 = vec![
+            (
+                r#"sum by() (task:errors:rate10s{job="s"})"#,
+                r#"sum(task:errors:rate10s{job="s"})"#,
+            ),
+            (
+                r#"sum by(code) (task:errors:rate10s{job="s"})"#,
+                r#"sum by (code) (task:errors:rate10s{job="s"})"#,
+            ),
+            (
+                r#"sum without() (task:errors:rate10s{job="s"})"#,
+                r#"sum without () (task:errors:rate10s{job="s"})"#,
+            ),
+            (
+                r#"sum without(instance) (task:errors:rate10s{job="s"})"#,
+                r#"sum without (instance) (task:errors:rate10s{job="s"})"#,
+            ),
+            (
+                r#"topk(5, task:errors:rate10s{job="s"})"#,
+                r#"topk(5, task:errors:rate10s{job="s"})"#,
+            ),
+            (
+                r#"count_values("value", task:errors:rate10s{job="s"})"#,
+                r#"count_values("value", task:errors:rate10s{job="s"})"#,
+            ),
+            ("a - on() c", "a - on () c"),
+            ("a - on(b) c", "a - on (b) c"),
+            ("a - on(b) group_left(x) c", "a - on (b) group_left (x) c"),
+            (
+                "a - on(b) group_left(x, y) c",
+                "a - on (b) group_left (x, y) c",
+            ),
+            ("a - on(b) group_left c", "a - on (b) group_left () c"),
+            ("a - ignoring(b) c", "a - ignoring (b) c"),
+            ("a - ignoring() c", "a - c"),
+            ("up > bool 0", "up > bool 0"),
+            ("a offset 1m", "a offset 1m"),
+            ("a offset -7m", "a offset -7m"),
+            (r#"a{c="d"}[5m] offset 1m"#, r#"a{c="d"}[5m] offset 1m"#),
+            ("a[5m] offset 1m", "a[5m] offset 1m"),
+            ("a[12m] offset -3m", "a[12m] offset -3m"),
+            ("a[1h:5m] offset 1m", "a[1h:5m] offset 1m"),
+            (r#"{__name__="a"}"#, r#"{__name__="a"}"#),
+            (r#"a{b!="c"}[1m]"#, r#"a{b!="c"}[1m]"#),
+            (r#"a{b=~"c"}[1m]"#, r#"a{b=~"c"}[1m]"#),
+            (r#"a{b!~"c"}[1m]"#, r#"a{b!~"c"}[1m]"#),
+            ("a @ 10", "a @ 10.000"),
+            ("a[1m] @ 10", "a[1m] @ 10.000"),
+            ("a @ start()", "a @ start()"),
+            ("a @ end()", "a @ end()"),
+            ("a[1m] @ start()", "a[1m] @ start()"),
+            ("a[1m] @ end()", "a[1m] @ end()"),
+        ];
+
+        cases.append(&mut cases1);
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string())
+        }
+    }
+
+    #[test]
+    fn test_subquery_negative_zero_offset() {
+        // the parser rejects zero durations, so `foo[5m:] offset -0s` can only
+        // be built programmatically
+        let vs = Expr::new_vector_selector(Some(String::from("foo")), Matchers::empty()).unwrap();
+        let sq = Expr::new_subquery_expr(vs, Duration::from_secs(300), None)
+            .and_then(|ex| ex.offset_expr(Offset::Neg(Duration::ZERO)))
+            .unwrap();
+        assert_eq!("foo[5m:]", sq.to_string());
+        match &sq {
+            Expr::Subquery(ex) => assert_eq!("[5m:]", ex.get_time_suffix_string()),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            crate::parser::parse("foo[5m:]").unwrap().to_string(),
+            sq.to_string()
+        );
+        assert_eq!("0s", Offset::Neg(Duration::ZERO).to_string());
+        assert_eq!("-1m", Offset::Neg(Duration::from_secs(60)).to_string());
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let expr = crate::parser::parse("a + b").unwrap();
+        let BinaryExpr { lhs, op, rhs, .. } = expr.into_binary().unwrap();
+        assert_eq!(T_ADD, op.id());
+        assert_eq!("a", lhs.to_string());
+        assert_eq!("b", rhs.to_string());
+
+        let expr = crate::parser::parse("rate(foo[5m])").unwrap();
+        let expr = expr.into_binary().unwrap_err();
+        assert_eq!("rate(foo[5m])", expr.to_string());
+        let call = expr.into_call().unwrap();
+        assert_eq!("rate", call.func.name);
+        let ms = call.args.args[0].clone().into_matrix_selector().unwrap();
+        assert_eq!(Some(String::from("foo")), ms.vs.name);
+
+        let expr = crate::parser::parse("-1").unwrap();
+        assert_eq!(-1.0, expr.into_number_literal().unwrap().val);
+    }
+
+    #[test]
+    fn test_subquery_zero_step() {
+        let vs = Expr::new_vector_selector(Some(String::from("foo")), Matchers::empty()).unwrap();
+        let sq = Expr::new_subquery_expr(vs, Duration::from_secs(600), Some(Duration::ZERO))
+            .and_then(|ex| ex.offset_expr(Offset::Pos(Duration::from_secs(300))))
+            .unwrap();
+        assert_eq!("foo[10m:] offset 5m", sq.to_string());
+        assert_eq!("foo[10m:] offset 5m", sq.prettify());
+    }
+
+    #[test]
+    fn test_offset_millis_round_trip() {
+        let cases = vec![
+            ("foo offset 500ms", "foo offset 500ms"),
+            ("foo OFFSET 1h30ms", "foo offset 1h30ms"),
+            ("foo offset -1h30ms", "foo offset -1h30ms"),
+            ("foo offset 1m1s1ms", "foo offset 1m1s1ms"),
+            ("foo offset 90000ms", "foo offset 1m30s"),
+            ("foo offset 1d500ms", "foo offset 1d500ms"),
+            ("foo[5m] offset 500ms", "foo[5m] offset 500ms"),
+            ("foo[500ms] offset -2s5ms", "foo[500ms] offset -2s5ms"),
+            (
+                "rate(foo[5m])[1h:500ms] offset 1s1ms",
+                "rate(foo[5m])[1h:500ms] offset 1s1ms",
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string());
+            assert_eq!(expr, crate::parser::parse(&expr.to_string()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_vector_selector_to_string() {
+        let cases = vec![
+            (VectorSelector::default(), ""),
+            (VectorSelector::from("foobar"), "foobar"),
+            (
+                {
+                    let name = Some(String::from("foobar"));
+                    let matchers = Matchers::one(Matcher::new(MatchOp::Equal, "a", "x"));
+                    VectorSelector::new(name, matchers)
+                },
+                r#"foobar{a="x"}"#,
+            ),
+            (
+                {
+                    let matchers = Matchers::new(vec![
+                        Matcher::new(MatchOp::Equal, "a", "x"),
+                        Matcher::new(MatchOp::Equal, "b", "y"),
+                    ]);
+                    VectorSelector::new(None, matchers)
+                },
+                r#"{a="x",b="y"}"#,
+            ),
+            (
+                {
+                    let matchers =
+                        Matchers::one(Matcher::new(MatchOp::Equal, METRIC_NAME, "foobar"));
+                    VectorSelector::new(None, matchers)
+                },
+                r#"{__name__="foobar"}"#,
+            ),
+        ];
+
+        for (vs, expect) in cases {
+            assert_eq!(expect, vs.to_string())
+        }
+    }
+
+    #[test]
+    fn test_colon_metric_names_round_trip() {
+        let cases = vec![
+            ":bc",
+            "foo:bar:baz",
+            ":foo:bar:",
+            r#":bc{a="x"}"#,
+            r#"foo:bar:baz{a="x"}[5m]"#,
+            "rate(foo:bar:baz[5m] offset 1m)",
+            "sum by (job) (:bc) / foo:bar:baz",
+        ];
+
+        for input in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(input, expr.to_string());
+        }
+
+        let expr = crate::parser::parse(":bc").unwrap();
+        let vs = expr.as_vector_selector().unwrap();
+        assert_eq!(Some(":bc"), vs.name.as_deref());
+    }
+
+    #[test]
+    fn test_aggregate_param_round_trip() {
+        let cases = vec![
+            ("topk(5, m)", "topk(5, m)"),
+            ("topk(5,m)", "topk(5, m)"),
+            ("bottomk( 5 ,m )", "bottomk(5, m)"),
+            ("quantile(0.5, m)", "quantile(0.5, m)"),
+            ("quantile(0.5,rate(m[5m]))", "quantile(0.5, rate(m[5m]))"),
+            (r#"count_values("value", m)"#, r#"count_values("value", m)"#),
+            (r#"count_values('value',m)"#, r#"count_values("value", m)"#),
+            ("topk by (job) (5, m)", "topk by (job) (5, m)"),
+            ("topk(5, m) by (job)", "topk by (job) (5, m)"),
+            ("bottomk without (job)(5,m)", "bottomk without (job) (5, m)"),
+            (
+                r#"count_values without (a) ("value", m)"#,
+                r#"count_values without (a) ("value", m)"#,
+            ),
+            ("topk(scalar(foo), m)", "topk(scalar(foo), m)"),
+            ("topk(1 + 2, m)", "topk(1 + 2, m)"),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+            assert_eq!(expr, crate::parser::parse(expected).unwrap(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_aggregate_expr_pretty() {
+        let cases = vec![
+            ("sum(foo)", "sum(foo)"),
+            (
+                r#"sum by() (task:errors:rate10s{job="s"})"#,
+                r#"sum(
+  task:errors:rate10s{job="s"}
+)"#,
+            ),
+            (
+                r#"sum without(job,foo) (task:errors:rate10s{job="s"})"#,
+                r#"sum without (job, foo) (
+  task:errors:rate10s{job="s"}
+)"#,
+            ),
+            (
+                r#"sum(task:errors:rate10s{job="s"}) without(job,foo)"#,
+                r#"sum without (job, foo) (
+  task:errors:rate10s{job="s"}
+)"#,
+            ),
+            (
+                r#"sum by(job,foo) (task:errors:rate10s{job="s"})"#,
+                r#"sum by (job, foo) (
+  task:errors:rate10s{job="s"}
+)"#,
+            ),
+            (
+                r#"sum (task:errors:rate10s{job="s"}) by(job,foo)"#,
+                r#"sum by (job, foo) (
+  task:errors:rate10s{job="s"}
+)"#,
+            ),
+            (
+                r#"topk(10, ask:errors:rate10s{job="s"})"#,
+                r#"topk(
+  10,
+  ask:errors:rate10s{job="s"}
+)"#,
+            ),
+            (
+                r#"sum by(job,foo) (sum by(job,foo) (task:errors:rate10s{job="s"}))"#,
+                r#"sum by (job, foo) (
+  sum by (job, foo) (
+    task:errors:rate10s{job="s"}
+  )
+)"#,
+            ),
+            (
+                r#"sum by(job,foo) (sum by(job,foo) (sum by(job,foo) (task:errors:rate10s{job="s"})))"#,
+                r#"sum by (job, foo) (
+  sum by (job, foo) (
+    sum by (job, foo) (
+      task:errors:rate10s{job="s"}
+    )
+  )
+)"#,
+            ),
+            (
+                r#"sum by(job,foo)
+(sum by(job,foo) (task:errors:rate10s{job="s"}))"#,
+                r#"sum by (job, foo) (
+  sum by (job, foo) (
+    task:errors:rate10s{job="s"}
+  )
+)"#,
+            ),
+            (
+                r#"sum by(job,foo)
+(sum(task:errors:rate10s{job="s"}) without(job,foo))"#,
+                r#"sum by (job, foo) (
+  sum without (job, foo) (
+    task:errors:rate10s{job="s"}
+  )
+)"#,
+            ),
+            (
+                r#"sum by(job,foo) # Comment 1.
+(sum by(job,foo) ( # Comment 2.
+task:errors:rate10s{job="s"}))"#,
+                r#"sum by (job, foo) (
+  sum by (job, foo) (
+    task:errors:rate10s{job="s"}
+  )
+)"#,
+            ),
+        ];
+
+        for (input, expect) in cases {
+            let expr = crate::parser::parse(input);
+            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        }
+    }
+
+    #[test]
+    fn test_binary_expr_pretty() {
+        let cases = vec![
+            ("a+b", "a + b"),
+            (
+                "a == bool 1",
+                "  a
+== bool
+  1",
+            ),
+            (
+                "a == 1024000",
+                "  a
+==
+  1024000",
+            ),
+            (
+                "a + ignoring(job) b",
+                "  a
++ ignoring (job)
+  b",
+            ),
+            (
+                "foo_1 + foo_2",
+                "  foo_1
++
+  foo_2",
+            ),
+            (
+                "foo_1 + foo_2 + foo_3",
+                "    foo_1
+  +
+    foo_2
++
+  foo_3",
+            ),
+            (
+                "foo + baar + foo_3",
+                "  foo + baar
++
+  foo_3",
+            ),
+            (
+                "foo_1 + foo_2 + foo_3 + foo_4",
+                "      foo_1
+    +
+      foo_2
+  +
+    foo_3
++
+  foo_4",
+            ),
+            (
+                "foo_1 + ignoring(foo) foo_2 + ignoring(job) group_left foo_3 + on(instance) group_right foo_4",
+
+                 "      foo_1
+    + ignoring (foo)
+      foo_2
+  + ignoring (job) group_left ()
+    foo_3
++ on (instance) group_right ()
+  foo_4",
+            ),
+        ];
+
+        for (input, expect) in cases {
+            let expr = crate::parser::parse(input);
+            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        }
+    }
+
+    #[test]
+    fn test_call_expr_pretty() {
+        let cases = vec![
+            (
+                "rate(foo[1m])",
+                "rate(
+  foo[1m]
+)",
+            ),
+            (
+                "sum_over_time(foo[1m])",
+                "sum_over_time(
+  foo[1m]
+)",
+            ),
+            (
+                "rate(long_vector_selector[10m:1m] @ start() offset 1m)",
+                "rate(
+  long_vector_selector[10m:1m] @ start() offset 1m
+)",
+            ),
+            (
+                "histogram_quantile(0.9, rate(foo[1m]))",
+                "histogram_quantile(
+  0.9,
+  rate(
+    foo[1m]
+  )
+)",
+            ),
+            (
+                "histogram_quantile(0.9, rate(foo[1m] @ start()))",
+                "histogram_quantile(
+  0.9,
+  rate(
+    foo[1m] @ start()
+  )
+)",
+            ),
+            (
+                "max_over_time(rate(demo_api_request_duration_seconds_count[1m])[1m:] @ start() offset 1m)",
+                "max_over_time(
+  rate(
+    demo_api_request_duration_seconds_count[1m]
+  )[1m:] @ start() offset 1m
+)",
+            ),
+            (
+                r#"label_replace(up{job="api-server",service="a:c"}, "foo", "$1", "service", "(.*):.*")"#,
+                r#"label_replace(
+  up{job="api-server",service="a:c"},
+  "foo",
+  "$1",
+  "service",
+  "(.*):.*"
+)"#,
+            ),
+            (
+                r#"label_replace(label_replace(up{job="api-server",service="a:c"}, "foo", "$1", "service", "(.*):.*"), "foo", "$1", "service", "(.*):.*")"#,
+                r#"label_replace(
+  label_replace(
+    up{job="api-server",service="a:c"},
+    "foo",
+    "$1",
+    "service",
+    "(.*):.*"
+  ),
+  "foo",
+  "$1",
+  "service",
+  "(.*):.*"
+)"#,
+            ),
+        ];
+
+        for (input, expect) in cases {
+            let expr = crate::parser::parse(input);
+            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        }
+    }
+
+    #[test]
+    fn test_paren_expr_pretty() {
+        let cases = vec![
+            ("(foo)", "(foo)"),
+            (
+                "(_foo_long_)",
+                "(
+  _foo_long_
+)",
+            ),
+            (
+                "((foo_long))",
+                "(
+  (foo_long)
+)",
+            ),
+            (
+                "((_foo_long_))",
+                "(
+  (
+    _foo_long_
+  )
+)",
+            ),
+            (
+                "(((foo_long)))",
+                "(
+  (
+    (foo_long)
+  )
+)",
+            ),
+            ("(1 + 2)", "(1 + 2)"),
+            (
+                "(foo + bar)",
+                "(
+  foo + bar
+)",
+            ),
+            (
+                "(foo_long + bar_long)",
+                "(
+    foo_long
+  +
+    bar_long
+)",
+            ),
+            (
+                "(foo_long + bar_long + bar_2_long)",
+                "(
+      foo_long
+    +
+      bar_long
+  +
+    bar_2_long
+)",
+            ),
+            (
+                "((foo_long + bar_long) + bar_2_long)",
+                "(
+    (
+        foo_long
+      +
+        bar_long
+    )
+  +
+    bar_2_long
+)",
+            ),
+            (
+                "(1111 + 2222)",
+                "(
+    1111
+  +
+    2222
+)",
+            ),
+            (
+                "(sum_over_time(foo[1m]))",
+                "(
+  sum_over_time(
+    foo[1m]
+  )
+)",
+            ),
+            (
+                r#"(label_replace(up{job="api-server",service="a:c"}, "foo", "$1", "service", "(.*):.*"))"#,
+                r#"(
+  label_replace(
+    up{job="api-server",service="a:c"},
+    "foo",
+    "$1",
+    "service",
+    "(.*):.*"
+  )
+)"#,
+            ),
+            (
+                r#"(label_replace(label_replace(up{job="api-server",service="a:c"}, "foo", "$1", "service", "(.*):.*"), "foo", "$1", "service", "(.*):.*"))"#,
+                r#"(
+  label_replace(
+    label_replace(
+      up{job="api-server",service="a:c"},
+      "foo",
+      "$1",
+      "service",
+      "(.*):.*"
+    ),
+    "foo",
+    "$1",
+    "service",
+    "(.*):.*"
+  )
+)"#,
+            ),
+            (
+                r#"(label_replace(label_replace((up{job="api-server",service="a:c"}), "foo", "$1", "service", "(.*):.*"), "foo", "$1", "service", "(.*):.*"))"#,
+                r#"(
+  label_replace(
+    label_replace(
+      (
+        up{job="api-server",service="a:c"}
+      ),
+      "foo",
+      "$1",
+      "service",
+      "(.*):.*"
+    ),
+    "foo",
+    "$1",
+    "service",
+    "(.*):.*"
+  )
+)"#,
+            ),
+        ];
+
+        for (input, expect) in cases {
+            let expr = crate::parser::parse(input);
+            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        }
+    }
+
+    #[test]
+    fn test_unary_expr_pretty() {
+        let cases = vec![
+            ("-1", "-1"),
+            ("-vector_selector", "-vector_selector"),
+            (
+                "(-vector_selector)",
+                "(
+  -vector_selector
+)",
             ),
             (
-                r#"up{job="hi", instance="in"}"#,
-                r#"up{instance="in",job="hi"}"#,
+                "-histogram_quantile(0.9,rate(foo[1m]))",
+                "-histogram_quantile(
+  0.9,
+  rate(
+    foo[1m]
+  )
+)",
             ),
-            ("sum (up) by (job,instance)", "sum by (job, instance) (up)"),
             (
-                "foo / on(test,blub) group_left(bar) bar",
-                "foo / on (test, blub) group_left (bar) bar",
+                "-histogram_quantile(0.99, sum by (le) (rate(foo[1m])))",
+                "-histogram_quantile(
+  0.99,
+  sum by (le) (
+    rate(
+      foo[1m]
+    )
+  )
+)",
             ),
             (
-                "foo / on(test,blub) group_right(bar) bar",
-                "foo / on (test, blub) group_right (bar) bar",
+                "-histogram_quantile(0.9, -rate(foo[1m] @ start()))",
+                "-histogram_quantile(
+  0.9,
+  -rate(
+    foo[1m] @ start()
+  )
+)",
             ),
             (
-                r#"foo{a="b",foo!="bar",test=~"test",bar!~"baz"}"#,
-                r#"foo{a="b",bar!~"baz",foo!="bar",test=~"test"}"#,
+                "(-histogram_quantile(0.9, -rate(foo[1m] @ start())))",
+                "(
+  -histogram_quantile(
+    0.9,
+    -rate(
+      foo[1m] @ start()
+    )
+  )
+)",
             ),
+        ];
+
+        for (input, expect) in cases {
+            let expr = crate::parser::parse(input);
+            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        }
+    }
+
+    #[test]
+    fn test_expr_pretty() {
+        // Following queries have been taken from https://monitoring.mixins.dev/
+        let cases = vec![
             (
-                r#"{__name__=~"foo.+",__name__=~".*bar"}"#,
-                r#"{__name__=~".*bar",__name__=~"foo.+"}"#,
+                r#"(node_filesystem_avail_bytes{job="node",fstype!=""} / node_filesystem_size_bytes{job="node",fstype!=""} * 100 < 40 and predict_linear(node_filesystem_avail_bytes{job="node",fstype!=""}[6h], 24*60*60) < 0 and node_filesystem_readonly{job="node",fstype!=""} == 0)"#,
+                r#"(
+            node_filesystem_avail_bytes{fstype!="",job="node"}
+          /
+            node_filesystem_size_bytes{fstype!="",job="node"}
+        *
+          100
+      <
+        40
+    and
+        predict_linear(
+          node_filesystem_avail_bytes{fstype!="",job="node"}[6h],
+            24 * 60
+          *
+            60
+        )
+      <
+        0
+  and
+      node_filesystem_readonly{fstype!="",job="node"}
+    ==
+      0
+)"#,
             ),
             (
-                r#"test{a="b"}[5y] OFFSET 3d"#,
-                r#"test{a="b"}[5y] offset 3d"#,
+                r#"(node_filesystem_avail_bytes{job="node",fstype!=""} / node_filesystem_size_bytes{job="node",fstype!=""} * 100 < 20 and predict_linear(node_filesystem_avail_bytes{job="node",fstype!=""}[6h], 4*60*60) < 0 and node_filesystem_readonly{job="node",fstype!=""} == 0)"#,
+                r#"(
+            node_filesystem_avail_bytes{fstype!="",job="node"}
+          /
+            node_filesystem_size_bytes{fstype!="",job="node"}
+        *
+          100
+      <
+        20
+    and
+        predict_linear(
+          node_filesystem_avail_bytes{fstype!="",job="node"}[6h],
+            4 * 60
+          *
+            60
+        )
+      <
+        0
+  and
+      node_filesystem_readonly{fstype!="",job="node"}
+    ==
+      0
+)"#,
             ),
             (
-                r#"{a="b"}[5y] OFFSET 3d"#,
-                r#"{a="b"}[5y] offset 3d"#,
+                r#"(node_timex_offset_seconds > 0.05 and deriv(node_timex_offset_seconds[5m]) >= 0) or (node_timex_offset_seconds < -0.05 and deriv(node_timex_offset_seconds[5m]) <= 0)"#,
+                r#"  (
+        node_timex_offset_seconds
+      >
+        0.05
+    and
+        deriv(
+          node_timex_offset_seconds[5m]
+        )
+      >=
+        0
+  )
+or
+  (
+        node_timex_offset_seconds
+      <
+        -0.05
+    and
+        deriv(
+          node_timex_offset_seconds[5m]
+        )
+      <=
+        0
+  )"#,
             ),
             (
-                "sum(some_metric) without(and, by, avg, count, alert, annotations)",
-                "sum without (and, by, avg, count, alert, annotations) (some_metric)",
+                r#"1 - ((node_memory_MemAvailable_bytes{job="node"} or (node_memory_Buffers_bytes{job="node"} + node_memory_Cached_bytes{job="node"} + node_memory_MemFree_bytes{job="node"} + node_memory_Slab_bytes{job="node"}) ) / node_memory_MemTotal_bytes{job="node"})"#,
+                r#"  1
+-
+  (
+      (
+          node_memory_MemAvailable_bytes{job="node"}
+        or
+          (
+                  node_memory_Buffers_bytes{job="node"}
+                +
+                  node_memory_Cached_bytes{job="node"}
+              +
+                node_memory_MemFree_bytes{job="node"}
+            +
+              node_memory_Slab_bytes{job="node"}
+          )
+      )
+    /
+      node_memory_MemTotal_bytes{job="node"}
+  )"#,
             ),
             (
-                r#"floor(some_metric{foo!="bar"})"#,
-                r#"floor(some_metric{foo!="bar"})"#,
+                r#"min by (job, integration) (rate(alertmanager_notifications_failed_total{job="alertmanager", integration=~".*"}[5m]) / rate(alertmanager_notifications_total{job="alertmanager", integration="~.*"}[5m])) > 0.01"#,
+                r#"  min by (job, integration) (
+      rate(
+        alertmanager_notifications_failed_total{integration=~".*",job="alertmanager"}[5m]
+      )
+    /
+      rate(
+        alertmanager_notifications_total{integration="~.*",job="alertmanager"}[5m]
+      )
+  )
+>
+  0.01"#,
+            ),
+            (
+                r#"(count by (job) (changes(process_start_time_seconds{job="alertmanager"}[10m]) > 4) / count by (job) (up{job="alertmanager"})) >= 0.5"#,
+                r#"  (
+      count by (job) (
+          changes(
+            process_start_time_seconds{job="alertmanager"}[10m]
+          )
+        >
+          4
+      )
+    /
+      count by (job) (
+        up{job="alertmanager"}
+      )
+  )
+>=
+  0.5"#,
+            ),
+        ];
+
+        for (input, expect) in cases {
+            let expr = crate::parser::parse(input);
+            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        }
+    }
+
+    #[test]
+    fn test_prettify_operator_column() {
+        let config = PrettyConfig {
+            style: PrettyStyle::OperatorColumn,
+        };
+        let cases = vec![
+            (
+                "http_requests_total_service_a + http_requests_total_service_b + http_requests_total_service_c + http_requests_total_service_d",
+                "  http_requests_total_service_a
++ http_requests_total_service_b
++ http_requests_total_service_c
++ http_requests_total_service_d",
+            ),
+            (
+                "http_requests_total_service_a + http_requests_total_service_b - ignoring (job) http_requests_total_service_c",
+                "                 http_requests_total_service_a
+               + http_requests_total_service_b
+- ignoring (job) http_requests_total_service_c",
+            ),
+            (
+                "(http_requests_total_service_aaa + http_requests_total_service_bbb + http_requests_total_service_ccc) / scalar(foo)",
+                "  (
+      http_requests_total_service_aaa
+    + http_requests_total_service_bbb
+    + http_requests_total_service_ccc
+  )
+/ scalar(foo)",
             ),
+            ("a + b", "a + b"),
+        ];
+
+        for (input, expect) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expect, expr.prettify_with(&config), "{input}");
+        }
+
+        let expr = crate::parser::parse("foo + bar").unwrap();
+        assert_eq!(
+            expr.prettify(),
+            expr.prettify_with(&PrettyConfig::default())
+        );
+    }
+
+    #[test]
+    fn test_step_invariant_pretty() {
+        let cases = vec![
+            ("a @ 1", "a @ 1.000"),
+            ("a @ start()", "a @ start()"),
+            ("vector_selector @ start()", "vector_selector @ start()"),
+        ];
+
+        for (input, expect) in cases {
+            let expr = crate::parser::parse(input);
+            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        }
+    }
+
+    #[test]
+    fn test_prettify() {
+        let cases = vec![
+            ("vector_selector", "vector_selector"),
             (
-                "sum(rate(http_request_duration_seconds[10m])) / count(rate(http_request_duration_seconds[10m]))",
-                "sum(rate(http_request_duration_seconds[10m])) / count(rate(http_request_duration_seconds[10m]))",
+                r#"vector_selector{fooooooooooooooooo="barrrrrrrrrrrrrrrrrrr",barrrrrrrrrrrrrrrrrrr="fooooooooooooooooo",process_name="alertmanager"}"#,
+                r#"vector_selector{barrrrrrrrrrrrrrrrrrr="fooooooooooooooooo",fooooooooooooooooo="barrrrrrrrrrrrrrrrrrr",process_name="alertmanager"}"#,
             ),
-            ("rate(some_metric[5m])", "rate(some_metric[5m])"),
-            ("round(some_metric,5)", "round(some_metric, 5)"),
             (
-                r#"absent(sum(nonexistent{job="myjob"}))"#,
-                r#"absent(sum(nonexistent{job="myjob"}))"#,
+                r#"matrix_selector{fooooooooooooooooo="barrrrrrrrrrrrrrrrrrr",barrrrrrrrrrrrrrrrrrr="fooooooooooooooooo",process_name="alertmanager"}[1y2w3d]"#,
+                r#"matrix_selector{barrrrrrrrrrrrrrrrrrr="fooooooooooooooooo",fooooooooooooooooo="barrrrrrrrrrrrrrrrrrr",process_name="alertmanager"}[382d]"#,
             ),
+        ];
+
+        for (input, expect) in cases {
+            assert_eq!(expect, crate::parser::parse(input).unwrap().prettify());
+        }
+    }
+
+    #[test]
+    fn test_eval_stmt_try_new_range() {
+        let expr = || crate::parser::parse("rate(foo[5m])").unwrap();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let end = start + Duration::from_secs(3600);
+        let interval = Duration::from_secs(60);
+        let lookback = Duration::from_secs(300);
+
+        let stmt = EvalStmt::try_new_range(expr(), start, end, interval, lookback).unwrap();
+        assert_eq!(expr(), stmt.expr);
+        assert_eq!((start, end), (stmt.start, stmt.end));
+        assert_eq!((interval, lookback), (stmt.interval, stmt.lookback_delta));
+
+        // an instant query is a range of a single instant
+        assert!(EvalStmt::try_new_range(expr(), start, start, interval, lookback).is_ok());
+
+        let cases = vec![
             (
-                "histogram_quantile(0.9,rate(http_request_duration_seconds_bucket[10m]))",
-                "histogram_quantile(0.9, rate(http_request_duration_seconds_bucket[10m]))",
+                expr(),
+                end,
+                start,
+                interval,
+                lookback,
+                errors::END_BEFORE_START,
             ),
             (
-                "histogram_quantile(0.9,sum(rate(http_request_duration_seconds_bucket[10m])) by(job,le))",
-                "histogram_quantile(0.9, sum by (job, le) (rate(http_request_duration_seconds_bucket[10m])))",
+                expr(),
+                start,
+                end,
+                Duration::ZERO,
+                lookback,
+                errors::ZERO_STEP,
             ),
             (
-                r#"label_join(up{job="api-server",src1="a",src2="b",src3="c"}, "foo", ",", "src1", "src2", "src3")"#,
-                r#"label_join(up{job="api-server",src1="a",src2="b",src3="c"}, "foo", ",", "src1", "src2", "src3")"#,
+                expr(),
+                start,
+                end,
+                interval,
+                Duration::ZERO,
+                errors::ZERO_LOOKBACK_DELTA,
             ),
             (
-                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:])[4m:3s] @ 100"#,
-                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:])[4m:3s] @ 100.000"#,
+                crate::parser::parse("foo[5m]").unwrap(),
+                start,
+                end,
+                interval,
+                lookback,
+                "invalid expression type 'matrix' for range query, must be scalar or vector",
             ),
+        ];
+
+        for (expr, start, end, interval, lookback, expected) in cases {
+            assert_eq!(
+                Some(String::from(expected)),
+                EvalStmt::try_new_range(expr, start, end, interval, lookback).err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_stmt_to_string() {
+        let query = r#"http_requests_total{job="apiserver", handler="/api/comments"}[5m]"#;
+        let start = "2024-10-08T07:15:00.022978+00:00";
+        let end = "2024-10-08T07:15:30.012978+00:00";
+        let expect = r#"[http_requests_total{handler="/api/comments",job="apiserver"}[5m]] @ [2024-10-08T07:15:00.022978+00:00, 2024-10-08T07:15:30.012978+00:00, 1m, 5m]"#;
+
+        let stmt = EvalStmt {
+            expr: crate::parser::parse(query).unwrap(),
+            start: DateTime::parse_from_rfc3339(start)
+                .unwrap()
+                .with_timezone(&Utc)
+                .into(),
+            end: DateTime::parse_from_rfc3339(end)
+                .unwrap()
+                .with_timezone(&Utc)
+                .into(),
+            interval: Duration::from_secs(60),
+            lookback_delta: Duration::from_secs(300),
+        };
+
+        assert_eq!(expect, stmt.to_string());
+    }
+
+    #[test]
+    fn test_vector_selector_matchers_eq() {
+        let selector = |input| match crate::parser::parse(input).unwrap() {
+            Expr::VectorSelector(vs) => vs,
+            Expr::MatrixSelector(ms) => ms.vs,
+            _ => unreachable!(),
+        };
+
+        let cases = vec![
+            ("foo offset 5m", "foo offset 10m", true),
+            ("foo @ 100", "foo offset 1m", true),
+            (r#"foo{a="b"} @ start()"#, r#"foo{a="b"}"#, true),
             (
-                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:])[4m:3s]"#,
-                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:])[4m:3s]"#,
+                r#"foo{a="b" or c="d"}"#,
+                r#"foo{a="b" or c="d"} offset 1h"#,
+                true,
             ),
+            ("foo[5m] offset 1m", "foo", true),
+            ("foo", "bar", false),
+            (r#"foo{a="b"}"#, r#"foo{a="c"}"#, false),
+            (r#"foo{a="b"}"#, r#"foo{a!="b"}"#, false),
+            (r#"{__name__="foo"}"#, r#"{__name__="foo",a="b"}"#, false),
+            (r#"foo{a="1",b="2"}"#, r#"foo{b="2",a="1"}"#, true),
             (
-                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:] offset 4m)[4m:3s]"#,
-                r#"min_over_time(rate(foo{bar="baz"}[2s])[5m:] offset 4m)[4m:3s]"#,
+                r#"foo{a="1",a="1",b="2"}"#,
+                r#"foo{a="1",b="2",b="2"}"#,
+                false,
             ),
-            ("some_metric OFFSET 1m [10m:5s]", "some_metric offset 1m[10m:5s]"),
-            ("some_metric @123 [10m:5s]", "some_metric @ 123.000[10m:5s]")
+            (r#"foo{a="1" or b="2"}"#, r#"foo{b="2" or a="1"}"#, false),
         ];
 
-        // the following cases are from https://github.com/prometheus/prometheus/blob/main/promql/parser/printer_test.go
-        let mut cases1 = vec![
-            (
-                r#"sum by() (task:errors:rate10s{job="s"})"#,
-                r#"sum(task:errors:rate10s{job="s"})"#,
-            ),
+        for (lhs, rhs, expected) in cases {
+            assert_eq!(
+                expected,
+                selector(lhs).matchers_eq(&selector(rhs)),
+                "{lhs} vs {rhs}"
+            );
+        }
+        assert_ne!(selector("foo offset 5m"), selector("foo offset 10m"));
+    }
+
+    #[test]
+    fn test_eq_ignoring_matcher_order() {
+        let cases = vec![
+            (r#"foo{a="1",b="2"}"#, r#"foo{b="2",a="1"}"#, true),
             (
-                r#"sum by(code) (task:errors:rate10s{job="s"})"#,
-                r#"sum by (code) (task:errors:rate10s{job="s"})"#,
+                r#"rate(foo{a="1",b="2"}[5m]) + on (job) sum(bar{c="3",d="4"})"#,
+                r#"rate(foo{b="2",a="1"}[5m]) + on (job) sum(bar{d="4",c="3"})"#,
+                true,
             ),
             (
-                r#"sum without() (task:errors:rate10s{job="s"})"#,
-                r#"sum without () (task:errors:rate10s{job="s"})"#,
+                r#"max_over_time(foo{a="1",b="2"}[5m:1m])"#,
+                r#"max_over_time(foo{b="2",a="1"}[5m:1m])"#,
+                true,
             ),
+            (r#"foo{a="1"} offset 5m"#, r#"foo{a="1"}"#, false),
+            (r#"foo{a="1"}[5m]"#, r#"foo{a="1"}[1m]"#, false),
+            (r#"foo{a="1"} - bar"#, r#"bar - foo{a="1"}"#, false),
+            ("topk(3, foo)", "topk(5, foo)", false),
+            ("(foo)", "foo", false),
+            ("1", "1", true),
+        ];
+
+        for (lhs, rhs, expected) in cases {
+            let lhs = crate::parser::parse(lhs).unwrap();
+            let rhs = crate::parser::parse(rhs).unwrap();
+            assert_eq!(
+                expected,
+                lhs.eq_ignoring_matcher_order(&rhs),
+                "{lhs} vs {rhs}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_vector_selector_to_series_query() {
+        let selector = |input| match crate::parser::parse(input).unwrap() {
+            Expr::VectorSelector(vs) => vs,
+            Expr::MatrixSelector(ms) => ms.vs,
+            _ => unreachable!(),
+        };
+
+        let cases = vec![
+            ("foo", "foo", "match[]=foo"),
             (
-                r#"sum without(instance) (task:errors:rate10s{job="s"})"#,
-                r#"sum without (instance) (task:errors:rate10s{job="s"})"#,
+                r#"foo{job="a b"}"#,
+                r#"foo{job="a b"}"#,
+                "match[]=foo%7Bjob%3D%22a%20b%22%7D",
             ),
             (
-                r#"topk(5, task:errors:rate10s{job="s"})"#,
-                r#"topk(5, task:errors:rate10s{job="s"})"#,
+                r#"foo{job=~"api.*"} offset 5m @ 100"#,
+                r#"foo{job=~"api.*"}"#,
+                "match[]=foo%7Bjob%3D~%22api.%2A%22%7D",
             ),
             (
-                r#"count_values("value", task:errors:rate10s{job="s"})"#,
-                r#"count_values("value", task:errors:rate10s{job="s"})"#,
+                r#"{__name__="foo",a!="ä"}[5m]"#,
+                r#"{__name__="foo",a!="ä"}"#,
+                "match[]=%7B__name__%3D%22foo%22%2Ca%21%3D%22%C3%A4%22%7D",
             ),
-            ("a - on() c", "a - on () c"),
-            ("a - on(b) c", "a - on (b) c"),
-            ("a - on(b) group_left(x) c", "a - on (b) group_left (x) c"),
             (
-                "a - on(b) group_left(x, y) c",
-                "a - on (b) group_left (x, y) c",
+                r#"foo:bar{a="1" or b="2"}"#,
+                r#"foo:bar{a="1" or b="2"}"#,
+                "match[]=foo%3Abar%7Ba%3D%221%22%20or%20b%3D%222%22%7D",
             ),
-            ("a - on(b) group_left c", "a - on (b) group_left () c"),
-            ("a - ignoring(b) c", "a - ignoring (b) c"),
-            ("a - ignoring() c", "a - c"),
-            ("up > bool 0", "up > bool 0"),
-            ("a offset 1m", "a offset 1m"),
-            ("a offset -7m", "a offset -7m"),
-            (r#"a{c="d"}[5m] offset 1m"#, r#"a{c="d"}[5m] offset 1m"#),
-            ("a[5m] offset 1m", "a[5m] offset 1m"),
-            ("a[12m] offset -3m", "a[12m] offset -3m"),
-            ("a[1h:5m] offset 1m", "a[1h:5m] offset 1m"),
-            (r#"{__name__="a"}"#, r#"{__name__="a"}"#),
-            (r#"a{b!="c"}[1m]"#, r#"a{b!="c"}[1m]"#),
-            (r#"a{b=~"c"}[1m]"#, r#"a{b=~"c"}[1m]"#),
-            (r#"a{b!~"c"}[1m]"#, r#"a{b!~"c"}[1m]"#),
-            ("a @ 10", "a @ 10.000"),
-            ("a[1m] @ 10", "a[1m] @ 10.000"),
-            ("a @ start()", "a @ start()"),
-            ("a @ end()", "a @ end()"),
-            ("a[1m] @ start()", "a[1m] @ start()"),
-            ("a[1m] @ end()", "a[1m] @ end()"),
         ];
 
-        cases.append(&mut cases1);
-        for (input, expected) in cases {
+        for (input, match_arg, query) in cases {
+            let vs = selector(input);
+            assert_eq!(match_arg, vs.to_prometheus_match_arg());
+            assert_eq!(query, vs.to_series_query());
+        }
+    }
+
+    #[test]
+    fn test_operator_and_function_call_count() {
+        let cases = vec![
+            ("rate(foo[5m]) + 1", 1, 1),
+            ("foo", 0, 0),
+            ("-foo", 1, 0),
+            ("-(a + b) * on(job) c / d", 4, 0),
+            ("sum(rate(foo[5m])) by (job) > bool 0", 1, 1),
+            ("topk(scalar(a), abs(b))", 0, 2),
+            ("time() - timestamp(foo offset 1m)", 1, 2),
+            ("round(-max_over_time(rate(foo[5m])[10m:1m] @ 100))", 1, 3),
+            ("max_over_time((a and b)[5m:])", 1, 1),
+        ];
+
+        for (input, operators, calls) in cases {
             let expr = crate::parser::parse(input).unwrap();
-            assert_eq!(expected, expr.to_string())
+            assert_eq!(operators, expr.operator_count(), "{input}");
+            assert_eq!(calls, expr.function_call_count(), "{input}");
         }
     }
 
     #[test]
-    fn test_vector_selector_to_string() {
+    fn test_to_string_sorted_everything() {
         let cases = vec![
-            (VectorSelector::default(), ""),
-            (VectorSelector::from("foobar"), "foobar"),
+            ("b + a", "a + b"),
+            ("a + b", "a + b"),
+            ("(b) * ((a))", "a * b"),
+            ("(c + b) + a", "a + (b + c)"),
+            ("a - (b - c)", "a - (b - c)"),
+            ("(a - b) - c", "a - b - c"),
+            ("(a * b) + c", "a * b + c"),
+            ("(a + b) * c", "(a + b) * c"),
+            ("(a ^ b) ^ c", "(a ^ b) ^ c"),
+            ("a ^ (b ^ c)", "a ^ b ^ c"),
+            ("(-a) ^ b", "(-a) ^ b"),
+            ("-(a + b)", "-(a + b)"),
+            ("b - a", "b - a"),
+            ("b / a", "b / a"),
+            ("b + on (job) a", "b + on (job) a"),
+            ("b * ignoring (job) a", "b * ignoring (job) a"),
+            ("a - on(a,a,b) b", "a - on (a, b) b"),
+            ("a - ignoring(b, a, b) b", "a - ignoring (a, b) b"),
             (
-                {
-                    let name = Some(String::from("foobar"));
-                    let matchers = Matchers::one(Matcher::new(MatchOp::Equal, "a", "x"));
-                    VectorSelector::new(name, matchers)
-                },
-                r#"foobar{a="x"}"#,
+                "a / on(x) group_left(y, y) b",
+                "a / on (x) group_left (y) b",
             ),
+            ("sum by (job, job) (a)", "sum by (job) (a)"),
+            ("b + ignoring () a", "a + b"),
+            ("b == bool a", "b == bool a"),
             (
-                {
-                    let matchers = Matchers::new(vec![
-                        Matcher::new(MatchOp::Equal, "a", "x"),
-                        Matcher::new(MatchOp::Equal, "b", "y"),
-                    ]);
-                    VectorSelector::new(None, matchers)
-                },
-                r#"{a="x",b="y"}"#,
+                "sum by (job, env) (b{z=\"1\", a=\"2\"})",
+                "sum by (env, job) (b{a=\"2\",z=\"1\"})",
             ),
             (
-                {
-                    let matchers =
-                        Matchers::one(Matcher::new(MatchOp::Equal, METRIC_NAME, "foobar"));
-                    VectorSelector::new(None, matchers)
-                },
-                r#"{__name__="foobar"}"#,
+                "b / on (z, a) group_left (y, x) a",
+                "b / on (a, z) group_left (x, y) a",
             ),
+            ("rate((b + a)[5m:])", "rate((a + b)[5m:])"),
+            ("(((foo)))", "foo"),
         ];
 
-        for (vs, expect) in cases {
-            assert_eq!(expect, vs.to_string())
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            let sorted = expr.to_string_sorted_everything();
+            assert_eq!(expected, sorted, "{input}");
+            // the rendering is stable and parses back
+            let reparsed = crate::parser::parse(&sorted).unwrap();
+            assert_eq!(sorted, reparsed.to_string_sorted_everything(), "{input}");
         }
+
+        let a_b = crate::parser::parse("sum(x) + rate(y[5m])").unwrap();
+        let b_a = crate::parser::parse("(rate(y[5m]) + sum(x))").unwrap();
+        assert_eq!(
+            a_b.to_string_sorted_everything(),
+            b_a.to_string_sorted_everything()
+        );
     }
 
     #[test]
-    fn test_aggregate_expr_pretty() {
+    fn test_matcher_count() {
         let cases = vec![
-            ("sum(foo)", "sum(foo)"),
-            (
-                r#"sum by() (task:errors:rate10s{job="s"})"#,
-                r#"sum(
-  task:errors:rate10s{job="s"}
-)"#,
-            ),
-            (
-                r#"sum without(job,foo) (task:errors:rate10s{job="s"})"#,
-                r#"sum without (job, foo) (
-  task:errors:rate10s{job="s"}
-)"#,
-            ),
-            (
-                r#"sum(task:errors:rate10s{job="s"}) without(job,foo)"#,
-                r#"sum without (job, foo) (
-  task:errors:rate10s{job="s"}
-)"#,
-            ),
-            (
-                r#"sum by(job,foo) (task:errors:rate10s{job="s"})"#,
-                r#"sum by (job, foo) (
-  task:errors:rate10s{job="s"}
-)"#,
-            ),
-            (
-                r#"sum (task:errors:rate10s{job="s"}) by(job,foo)"#,
-                r#"sum by (job, foo) (
-  task:errors:rate10s{job="s"}
-)"#,
-            ),
+            (r#"up{a="1",b="2"} + down{c="3"}"#, 3),
+            ("foo", 0),
+            (r#"{__name__="foo"}"#, 1),
+            (r#"rate(foo{a="1"}[5m])"#, 1),
+            (r#"foo{a="1" or b="2", c="3"}"#, 3),
+            ("1 + 2", 0),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.matcher_count(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_nameless_selectors() {
+        let cases = vec![
+            (r#"{job="api"} + up"#, vec![r#"{job="api"}"#]),
             (
-                r#"topk(10, ask:errors:rate10s{job="s"})"#,
-                r#"topk(
-  10,
-  ask:errors:rate10s{job="s"}
-)"#,
+                r#"rate({job="api"}[5m]) / on (job) {env="prod", job=~"a.*"} offset 5m"#,
+                vec![r#"{job="api"}"#, r#"{env="prod",job=~"a.*"} offset 5m"#],
             ),
+            (r#"{job="a" or env="b"}"#, vec![r#"{job="a" or env="b"}"#]),
+            (r#"{__name__="up", job="api"}"#, vec![]),
             (
-                r#"sum by(job,foo) (sum by(job,foo) (task:errors:rate10s{job="s"}))"#,
-                r#"sum by (job, foo) (
-  sum by (job, foo) (
-    task:errors:rate10s{job="s"}
-  )
-)"#,
+                r#"{__name__=~"up|down"} + {__name__!="up", job="a"}"#,
+                vec![],
             ),
+            (r#"{job="a" or __name__="up"}"#, vec![]),
+            (r#"up{job="api"} + foo"#, vec![]),
+            ("1 + 2", vec![]),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            let actual: Vec<String> = expr
+                .nameless_selectors()
+                .iter()
+                .map(|vs| vs.to_string())
+                .collect();
+            assert_eq!(expected, actual, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_references_name() {
+        let cases = vec![
+            ("rate(foo:bar[5m])", true),
+            ("sum(foo:bar) by (job)", true),
+            ("foo + foo:bar offset 5m", true),
+            (r#"{__name__="foo:bar", job="a"}"#, true),
+            (r#"{__name__=~"foo:.*"}"#, true),
+            (r#"{job="a" or __name__="foo:bar"}"#, true),
+            ("foo:bar_total", false),
+            ("rate(foo[5m])", false),
+            (r#"{__name__!="foo:bar", job="a"}"#, false),
+            (r#"{__name__=~"baz"}"#, false),
+            // like the other regex matchers of the crate, the pattern is not anchored
+            (r#"{__name__=~"foo"}"#, true),
+            (r#"{__name__=~"foo|foo:bar"}"#, true),
+            (r#""foo:bar""#, false),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.references_name("foo:bar"), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_selectivity_score() {
+        let cases = vec![
+            ("foo", 4),
+            (r#"foo{a="1",b="2"}"#, 12),
+            (r#"foo{a=~".*"}"#, 6),
+            (r#"foo{a!="1",b!~"2"}"#, 6),
+            (r#"{a="1"}"#, 4),
+            (r#"{__name__="foo",a="1"}"#, 8),
+            (r#"foo{a="1" or b=~"2"}"#, 6),
+            (r#"foo{a="1",b="2" or c="3"}"#, 8),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            let vs = expr.as_vector_selector().unwrap();
+            assert_eq!(expected, vs.selectivity_score(), "{input}");
+        }
+
+        let score = |input| {
+            let expr = crate::parser::parse(input).unwrap();
+            expr.as_vector_selector().unwrap().selectivity_score()
+        };
+        assert!(score(r#"foo{a="1",b="2"}"#) > score(r#"foo{a=~".*"}"#));
+    }
+
+    #[test]
+    fn test_cost_estimate() {
+        let cases = vec![
+            ("foo", 1),
+            ("1 + 2", 3),
+            (r#"foo{a="1"}"#, 3),
+            (r#"foo{a=~"1.*"}"#, 13),
+            ("rate(foo[5m])", 7),
+            ("max_over_time(foo[10m:1m])", 33),
+            ("max_over_time(foo[10m:])", 33),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.cost_estimate(), "{input}");
+        }
+
+        let simple = crate::parser::parse("foo").unwrap();
+        let complex = crate::parser::parse(
+            r#"sum by (job) (rate(foo{job=~"api.*", env="prod"}[1h])) / max_over_time(bar[1d:5m])"#,
+        )
+        .unwrap();
+        assert!(complex.cost_estimate() > simple.cost_estimate());
+
+        let mut sq = crate::parser::parse("foo[1s:1m]").unwrap();
+        if let Expr::Subquery(ex) = &mut sq {
+            ex.step = Some(Duration::from_micros(500));
+        }
+        assert_eq!(1022, sq.cost_estimate());
+    }
+
+    #[test]
+    fn test_flatten_associative() {
+        let cases = vec![
+            ("a+b+c+d", Some(("+", vec!["a", "b", "c", "d"]))),
+            ("a * (b * c) * d", Some(("*", vec!["a", "(b * c)", "d"]))),
+            ("a * b * c + d", Some(("+", vec!["a * b * c", "d"]))),
+            ("a + b * c + d", Some(("+", vec!["a", "b * c", "d"]))),
+            ("a and b and c", Some(("and", vec!["a", "b", "c"]))),
+            ("a or b or c and d", Some(("or", vec!["a", "b", "c and d"]))),
             (
-                r#"sum by(job,foo) (sum by(job,foo) (sum by(job,foo) (task:errors:rate10s{job="s"})))"#,
-                r#"sum by (job, foo) (
-  sum by (job, foo) (
-    sum by (job, foo) (
-      task:errors:rate10s{job="s"}
-    )
-  )
-)"#,
+                "a + b + on (job) c + on (job) d",
+                Some(("+", vec!["a + b", "c", "d"])),
             ),
             (
-                r#"sum by(job,foo)
-(sum by(job,foo) (task:errors:rate10s{job="s"}))"#,
-                r#"sum by (job, foo) (
-  sum by (job, foo) (
-    task:errors:rate10s{job="s"}
-  )
-)"#,
+                "a and on (job) b and on (job) c and d",
+                Some(("and", vec!["a and on (job) b and on (job) c", "d"])),
             ),
+            ("a - b - c", None),
+            ("a / b", None),
+            ("(a + b)", None),
+            ("a", None),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            let actual = expr.flatten_associative().map(|(op, operands)| {
+                let operands: Vec<String> = operands.iter().map(|e| e.to_string()).collect();
+                (op.to_string(), operands)
+            });
+            let expected = expected.map(|(op, operands)| {
+                let operands: Vec<String> = operands.into_iter().map(String::from).collect();
+                (op.to_string(), operands)
+            });
+            assert_eq!(expected, actual, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_max_binary_chain_length() {
+        let cases = vec![
+            ("a", 0),
+            ("a + b", 2),
+            ("a+b+c+d", 4),
+            ("a + b - c * d", 3),
+            ("a * b * c + d", 3),
+            ("(a + b) + (c + d)", 2),
+            ("a ^ b ^ c", 3),
+            ("a or b and c unless d or e", 3),
+            ("sum(a + b + c) / count(a)", 3),
+            ("rate(foo[5m])", 0),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.max_binary_chain_length(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_function_names() {
+        let cases = vec![
             (
-                r#"sum by(job,foo)
-(sum(task:errors:rate10s{job="s"}) without(job,foo))"#,
-                r#"sum by (job, foo) (
-  sum without (job, foo) (
-    task:errors:rate10s{job="s"}
-  )
-)"#,
+                "histogram_quantile(0.9, rate(x[5m]))",
+                vec!["rate", "histogram_quantile"],
             ),
+            ("rate(x[5m]) / rate(y[5m])", vec!["rate", "rate"]),
             (
-                r#"sum by(job,foo) # Comment 1.
-(sum by(job,foo) ( # Comment 2.
-task:errors:rate10s{job="s"}))"#,
-                r#"sum by (job, foo) (
-  sum by (job, foo) (
-    task:errors:rate10s{job="s"}
-  )
-)"#,
+                "sum(abs(foo)) + max_over_time(vector(time())[5m:])",
+                vec!["abs", "time", "vector", "max_over_time"],
             ),
+            ("sum(foo)", vec![]),
         ];
 
-        for (input, expect) in cases {
-            let expr = crate::parser::parse(input);
-            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.function_names(), "{input}");
         }
     }
 
     #[test]
-    fn test_binary_expr_pretty() {
+    fn test_subqueries() {
+        let expr = crate::parser::parse("min_over_time(rate(foo[2s])[5m:])[4m:3s]").unwrap();
+        let subqueries = expr.subqueries();
+        assert_eq!(2, subqueries.len());
+        assert_eq!(Duration::from_secs(240), subqueries[0].range);
+        assert_eq!(Some(Duration::from_secs(3)), subqueries[0].step);
+        assert_eq!(Duration::from_secs(300), subqueries[1].range);
+        assert_eq!(None, subqueries[1].step);
+
+        let expr = crate::parser::parse("rate(foo[5m]) + bar").unwrap();
+        assert!(expr.subqueries().is_empty());
+    }
+
+    #[test]
+    fn test_collect_durations() {
+        let m = |n: u64| Duration::from_secs(60 * n);
         let cases = vec![
-            ("a+b", "a + b"),
+            ("rate(foo[5m:1m])", vec![m(5), m(1)]),
+            ("rate(foo[5m:1m] offset 10m)", vec![m(5), m(1), m(10)]),
+            ("rate(foo[5m:])", vec![m(5)]),
+            ("foo offset -2m", vec![m(2)]),
+            ("rate(foo[5m] offset 1m)", vec![m(5), m(1)]),
             (
-                "a == bool 1",
-                "  a
-== bool
-  1",
+                "max_over_time(rate(foo[2m])[10m:1m]) + bar offset 3m",
+                vec![m(10), m(1), m(2), m(3)],
             ),
+            ("foo + 1", vec![]),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.collect_durations(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_number_literal_format() {
+        let cases = vec![
+            (0.005, NumberFormat::Fixed(6), "0.005000"),
+            (0.005, NumberFormat::Default, "0.005"),
+            (0.005, NumberFormat::Exponent, "5e-3"),
+            (1500.0, NumberFormat::Exponent, "1.5e3"),
+            (1.0, NumberFormat::Fixed(0), "1"),
+            (-2.5, NumberFormat::Fixed(2), "-2.50"),
+            (f64::INFINITY, NumberFormat::Fixed(2), "Inf"),
+            (f64::NEG_INFINITY, NumberFormat::Exponent, "-Inf"),
+            (f64::NAN, NumberFormat::Fixed(2), "NaN"),
+        ];
+
+        for (val, format, expect) in cases {
+            assert_eq!(expect, NumberLiteral::new(val).format(format));
+        }
+    }
+
+    #[test]
+    fn test_number_literal_ieee_eq() {
+        let cases = vec![
+            (1.0, 1.0, true, true),
+            (1.0, 2.0, false, false),
+            (0.0, -0.0, true, true),
+            (f64::INFINITY, f64::INFINITY, true, true),
+            (f64::NAN, f64::NAN, true, false),
+            (f64::NAN, 1.0, false, false),
+        ];
+
+        for (lhs, rhs, eq, ieee_eq) in cases {
+            let (lhs, rhs) = (NumberLiteral::new(lhs), NumberLiteral::new(rhs));
+            assert_eq!(eq, lhs == rhs, "{lhs} == {rhs}");
+            assert_eq!(ieee_eq, lhs.ieee_eq(&rhs), "{lhs} ieee_eq {rhs}");
+        }
+
+        let lhs = crate::parser::parse("NaN").unwrap();
+        let rhs = crate::parser::parse("NaN").unwrap();
+        assert_eq!(lhs, rhs);
+        assert!(!lhs
+            .as_number_literal()
+            .unwrap()
+            .ieee_eq(rhs.as_number_literal().unwrap()));
+    }
+
+    #[test]
+    fn test_effective_at() {
+        let at = |secs: f64| AtModifier::try_from(secs).unwrap();
+        let cases = vec![
+            ("(foo + bar)[5m:] @ 100", Some(at(100.0))),
+            ("((foo + bar)[5m:] @ 100)", Some(at(100.0))),
+            ("(((foo @ 50)))", Some(at(50.0))),
+            ("(foo @ 50)[5m:] @ 100", Some(at(100.0))),
+            ("foo[5m] @ end()", Some(AtModifier::End)),
+            ("(foo[5m:1m] @ start())", Some(AtModifier::Start)),
+            ("(foo @ 50)[5m:]", None),
+            ("foo", None),
+            ("(foo + bar @ 10)", None),
+            ("rate(foo[5m] @ 10)", None),
+            ("1", None),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected.as_ref(), expr.effective_at(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_as_variants() {
+        let mut expr = crate::parser::parse("rate(foo[5m])").unwrap();
+        assert!(expr.as_binary().is_none());
+        assert!(expr.as_binary_mut().is_none());
+        assert_eq!("rate", expr.as_call().unwrap().func.name);
+
+        let call = expr.as_call_mut().unwrap();
+        call.func = crate::parser::function::get_function("irate").unwrap();
+        let ms = call.args.args[0].as_matrix_selector_mut().unwrap();
+        ms.range = Duration::from_secs(60);
+        assert_eq!("irate(foo[1m])", expr.to_string());
+
+        let mut expr = crate::parser::parse("a + -b").unwrap();
+        let binary = expr.as_binary_mut().unwrap();
+        binary.lhs.as_vector_selector_mut().unwrap().name = Some("c".into());
+        let unary = binary.rhs.as_unary_mut().unwrap();
+        assert!(unary.expr.as_vector_selector().is_some());
+        *unary.expr = Expr::from(1.0);
+        assert_eq!("c + -1", expr.to_string());
+
+        let expr = crate::parser::parse(r#"("a")"#).unwrap();
+        let paren = expr.as_paren().unwrap();
+        assert_eq!("a", paren.expr.as_string_literal().unwrap().val);
+        assert!(paren.expr.as_number_literal().is_none());
+
+        let mut expr = crate::parser::parse("max_over_time(foo[5m:1m])").unwrap();
+        assert!(expr.as_aggregate().is_none());
+        let call = expr.as_call_mut().unwrap();
+        let subquery = call.args.args[0].as_subquery_mut().unwrap();
+        subquery.step = None;
+        assert_eq!("max_over_time(foo[5m:])", expr.to_string());
+
+        let mut expr = crate::parser::parse("sum by (job) (foo)").unwrap();
+        let aggregate = expr.as_aggregate_mut().unwrap();
+        aggregate.modifier = Some(LabelModifier::exclude(vec!["job"]));
+        assert_eq!("sum without (job) (foo)", expr.to_string());
+        assert!(expr.as_extension().is_none());
+    }
+
+    #[test]
+    fn test_find() {
+        let expr = crate::parser::parse("a + rate(b[5m])").unwrap();
+        let found = expr.find(|e| matches!(e, Expr::Call(_))).unwrap();
+        assert_eq!("rate(b[5m])", found.to_string());
+
+        // pre-order, the outer call comes first
+        let expr = crate::parser::parse("abs(a) + sum(ceil(floor(b)))").unwrap();
+        let found = expr.find(|e| matches!(e, Expr::Call(_))).unwrap();
+        assert_eq!("abs(a)", found.to_string());
+        let found = expr
+            .find(|e| matches!(e, Expr::Call(call) if call.func.name != "abs"))
+            .unwrap();
+        assert_eq!("ceil(floor(b))", found.to_string());
+
+        // the aggregation param is searched as well
+        let expr = crate::parser::parse("topk(scalar(a), b)").unwrap();
+        let found = expr.find(|e| matches!(e, Expr::VectorSelector(_))).unwrap();
+        assert_eq!("a", found.to_string());
+
+        // the expression itself
+        let expr = crate::parser::parse("a + b").unwrap();
+        assert_eq!(Some(&expr), expr.find(|e| matches!(e, Expr::Binary(_))));
+
+        let expr = crate::parser::parse("a + b").unwrap();
+        assert_eq!(None, expr.find(|e| matches!(e, Expr::Call(_))));
+    }
+
+    #[test]
+    fn test_strip_at_modifiers_and_offsets() {
+        let cases = vec![
+            ("foo @ 100 offset 5m", "foo offset 5m", "foo @ 100.000"),
             (
-                "a == 1024000",
-                "  a
-==
-  1024000",
+                "foo[5m] @ end() offset -1m",
+                "foo[5m] offset -1m",
+                "foo[5m] @ end()",
             ),
             (
-                "a + ignoring(job) b",
-                "  a
-+ ignoring (job)
-  b",
+                "rate(foo[5m] offset 1h)[30m:1m] @ start() offset 10m",
+                "rate(foo[5m] offset 1h)[30m:1m] offset 10m",
+                "rate(foo[5m])[30m:1m] @ start()",
             ),
             (
-                "foo_1 + foo_2",
-                "  foo_1
-+
-  foo_2",
+                "sum(foo @ 1 offset 1m) / bar @ 2",
+                "sum(foo offset 1m) / bar",
+                "sum(foo @ 1.000) / bar @ 2.000",
             ),
+            ("foo + 1", "foo + 1", "foo + 1"),
+        ];
+
+        for (input, without_at, without_offset) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+
+            let mut stripped = expr.clone();
+            stripped.strip_at_modifiers();
+            assert_eq!(without_at, stripped.to_string());
+
+            let mut stripped = expr;
+            stripped.strip_offsets();
+            assert_eq!(without_offset, stripped.to_string());
+        }
+    }
+
+    #[test]
+    fn test_fold_post() {
+        let expr = crate::parser::parse("(1 + 2) * (3 - 1) + foo").unwrap();
+        let mut visited = vec![];
+        let folded = expr
+            .fold_post(|expr| {
+                if let Expr::Binary(ex) = &expr {
+                    // the operands are already folded when the parent is visited
+                    if ex.op.id() == T_MUL {
+                        assert_eq!(Some(3.0), ex.lhs.scalar_value());
+                        assert_eq!(Some(2.0), ex.rhs.scalar_value());
+                    }
+                }
+                visited.push(expr.to_string());
+                expr.fold_constants()
+            })
+            .unwrap();
+
+        assert_eq!("6 + foo", folded.to_string());
+        assert_eq!(
+            vec!["1", "2", "1 + 2", "(3)", "3", "1", "3 - 1", "(2)", "3 * 2", "foo", "6 + foo"],
+            visited
+        );
+
+        let err = crate::parser::parse("foo + 1")
+            .unwrap()
+            .fold_post(|expr| match expr {
+                Expr::NumberLiteral(_) => Err(String::from("no numbers")),
+                expr => Ok(expr),
+            });
+        assert_eq!(Err(String::from("no numbers")), err);
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let cases = vec![
+            ("1 + 2", "3"),
+            ("foo * (2 * 60)", "foo * 120"),
+            ("2 ^ 3 ^ 2", "512"),
+            ("-(1 + 2)", "-3"),
+            ("7 % 4 - 1", "2"),
+            ("1 < bool 2", "1"),
+            ("1 == bool 2", "0"),
             (
-                "foo_1 + foo_2 + foo_3",
-                "    foo_1
-  +
-    foo_2
-+
-  foo_3",
+                "sum(rate(foo[5m])) > (0.5 + 0.25)",
+                "sum(rate(foo[5m])) > 0.75",
             ),
+            ("topk(2 + 1, foo)", "topk(3, foo)"),
+            ("rate(foo[5m])[1h:]", "rate(foo[5m])[1h:]"),
+            ("1 / 0", "Inf"),
+            ("foo + bar", "foo + bar"),
+            ("(foo)", "(foo)"),
+            ("(-2) ^ foo", "(-2) ^ foo"),
+            ("(2 - 4) ^ foo", "(-2) ^ foo"),
+            ("-2 ^ foo", "-2 ^ foo"),
+            ("foo ^ (2 - 4)", "foo ^ -2"),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(
+                expected,
+                expr.fold_constants().unwrap().to_string(),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rewrite_offsets() {
+        let hour = Duration::from_secs(3600);
+        let shift = |offset: &Offset| match offset {
+            Offset::Pos(dur) => Offset::Pos(*dur + hour),
+            Offset::Neg(dur) if *dur > hour => Offset::Neg(*dur - hour),
+            Offset::Neg(dur) => Offset::Pos(hour - *dur),
+        };
+
+        let cases = vec![
             (
-                "foo + baar + foo_3",
-                "  foo + baar
-+
-  foo_3",
+                "a offset 5m + b offset 10m",
+                "a offset 1h5m + b offset 1h10m",
             ),
             (
-                "foo_1 + foo_2 + foo_3 + foo_4",
-                "      foo_1
-    +
-      foo_2
-  +
-    foo_3
-+
-  foo_4",
+                "rate(foo[5m] offset -30m)[1h:1m] offset 2h",
+                "rate(foo[5m] offset 30m)[1h:1m] offset 3h",
             ),
             (
-                "foo_1 + ignoring(foo) foo_2 + ignoring(job) group_left foo_3 + on(instance) group_right foo_4",
-
-                 "      foo_1
-    + ignoring (foo)
-      foo_2
-  + ignoring (job) group_left ()
-    foo_3
-+ on (instance) group_right ()
-  foo_4",
+                "foo + sum_over_time(bar[5m:])",
+                "foo + sum_over_time(bar[5m:])",
             ),
         ];
 
-        for (input, expect) in cases {
-            let expr = crate::parser::parse(input);
-            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        for (input, expected) in cases {
+            let mut expr = crate::parser::parse(input).unwrap();
+            expr.rewrite_offsets(shift);
+            assert_eq!(expected, expr.to_string());
         }
     }
 
     #[test]
-    fn test_call_expr_pretty() {
+    fn test_clamp_ranges() {
         let cases = vec![
+            ("rate(foo[10s])", "rate(foo[1m])"),
+            ("rate(foo[5m])", "rate(foo[5m])"),
+            ("rate(foo[1m])", "rate(foo[1m])"),
             (
-                "rate(foo[1m])",
-                "rate(
-  foo[1m]
-)",
-            ),
-            (
-                "sum_over_time(foo[1m])",
-                "sum_over_time(
-  foo[1m]
-)",
-            ),
-            (
-                "rate(long_vector_selector[10m:1m] @ start() offset 1m)",
-                "rate(
-  long_vector_selector[10m:1m] @ start() offset 1m
-)",
+                "max_over_time(rate(foo[30s])[50s:10s] offset 20s)",
+                "max_over_time(rate(foo[1m])[1m:10s] offset 20s)",
             ),
             (
-                "histogram_quantile(0.9, rate(foo[1m]))",
-                "histogram_quantile(
-  0.9,
-  rate(
-    foo[1m]
-  )
-)",
+                "sum(rate(foo[10s] @ 100)) / rate(bar[2m])",
+                "sum(rate(foo[1m] @ 100.000)) / rate(bar[2m])",
             ),
+            ("foo offset 10s", "foo offset 10s"),
+        ];
+
+        for (input, expected) in cases {
+            let mut expr = crate::parser::parse(input).unwrap();
+            expr.clamp_ranges(Duration::from_secs(60));
+            assert_eq!(expected, expr.to_string(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_map_string_literals() {
+        let cases = vec![
             (
-                "histogram_quantile(0.9, rate(foo[1m] @ start()))",
-                "histogram_quantile(
-  0.9,
-  rate(
-    foo[1m] @ start()
-  )
-)",
+                r#"label_replace(up, "cluster", "$cluster", "instance", "(.*)")"#,
+                r#"label_replace(up, "cluster", "prod", "instance", "(.*)")"#,
             ),
             (
-                "max_over_time(rate(demo_api_request_duration_seconds_count[1m])[1m:] @ start() offset 1m)",
-                "max_over_time(
-  rate(
-    demo_api_request_duration_seconds_count[1m]
-  )[1m:] @ start() offset 1m
-)",
+                r#"count_values("$cluster", up{cluster="$cluster"})"#,
+                r#"count_values("prod", up{cluster="$cluster"})"#,
             ),
             (
-                r#"label_replace(up{job="api-server",service="a:c"}, "foo", "$1", "service", "(.*):.*")"#,
-                r#"label_replace(
-  up{job="api-server",service="a:c"},
-  "foo",
-  "$1",
-  "service",
-  "(.*):.*"
-)"#,
+                r#"sum(label_join(up, "dst", "$cluster", "src", "job")) + rate(foo[5m])"#,
+                r#"sum(label_join(up, "dst", "prod", "src", "job")) + rate(foo[5m])"#,
             ),
-            (
-                r#"label_replace(label_replace(up{job="api-server",service="a:c"}, "foo", "$1", "service", "(.*):.*"), "foo", "$1", "service", "(.*):.*")"#,
-                r#"label_replace(
-  label_replace(
-    up{job="api-server",service="a:c"},
-    "foo",
-    "$1",
-    "service",
-    "(.*):.*"
-  ),
-  "foo",
-  "$1",
-  "service",
-  "(.*):.*"
-)"#,
+            ("rate(foo[5m])", "rate(foo[5m])"),
+        ];
+
+        for (input, expected) in cases {
+            let mut expr = crate::parser::parse(input).unwrap();
+            expr.map_string_literals(|s| s.replace("$cluster", "prod"));
+            assert_eq!(expected, expr.to_string(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_replace_subquery_steps() {
+        let cases = vec![
+            (
+                "min_over_time(rate(foo[2s])[5m:])[4m:3s]",
+                "min_over_time(rate(foo[2s])[5m:10s])[4m:10s]",
             ),
+            (
+                "max_over_time(foo[5m:1m] @ 100 offset 1m)",
+                "max_over_time(foo[5m:10s] @ 100.000 offset 1m)",
+            ),
+            ("rate(foo[5m])", "rate(foo[5m])"),
         ];
 
-        for (input, expect) in cases {
-            let expr = crate::parser::parse(input);
-            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        for (input, expected) in cases {
+            let mut expr = crate::parser::parse(input).unwrap();
+            expr.replace_subquery_steps(Duration::from_secs(10));
+            assert_eq!(expected, expr.to_string());
         }
     }
 
     #[test]
-    fn test_paren_expr_pretty() {
+    fn test_labels_required_by_grouping() {
+        let by = |ls: &[&str]| GroupingResult::By(ls.iter().map(|l| l.to_string()).collect());
+        let without =
+            |ls: &[&str]| GroupingResult::Without(ls.iter().map(|l| l.to_string()).collect());
+
         let cases = vec![
-            ("(foo)", "(foo)"),
+            ("sum by (a, b) (x)", by(&["a", "b"])),
+            ("(sum(x) by (a))", by(&["a"])),
+            ("sum(x)", by(&[])),
+            ("sum by () (x)", by(&[])),
+            ("avg without (c) (x)", without(&["c"])),
+            ("quantile without (c, d) (0.9, x)", without(&["c", "d"])),
+            (r#"count_values by (a) ("v", x)"#, by(&["a", "v"])),
+            (r#"count_values("v", x)"#, by(&["v"])),
+            (r#"count_values without (v, c) ("v", x)"#, without(&["c"])),
+            ("topk by (a) (3, x)", GroupingResult::All),
+            ("x", GroupingResult::All),
+            ("rate(x[5m])", GroupingResult::All),
+            ("sum by (a) (x) / 2", GroupingResult::All),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.labels_required_by_grouping(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_rewrite_name_matchers() {
+        let rename = |m: &Matcher| {
+            let rest = m.value.strip_prefix("old_")?;
+            Some(Matcher::new(m.op.clone(), &m.name, &format!("new_{rest}")))
+        };
+        let cases = vec![
+            (r#"{__name__=~"old_.*"}"#, r#"{__name__=~"new_.*"}"#),
             (
-                "(_foo_long_)",
-                "(
-  _foo_long_
-)",
+                r#"rate({__name__=~"old_.*",job="a"}[5m]) + old_foo"#,
+                r#"rate({__name__=~"new_.*",job="a"}[5m]) + old_foo"#,
             ),
             (
-                "((foo_long))",
-                "(
-  (foo_long)
-)",
+                r#"{__name__="old_foo" or __name__!~"old_bar|baz",job="a"}"#,
+                r#"{__name__="new_foo" or __name__!~"new_bar|baz", job="a"}"#,
             ),
             (
-                "((_foo_long_))",
-                "(
-  (
-    _foo_long_
-  )
-)",
+                r#"sum({__name__=~"other_.*",old_label="old_x"})"#,
+                r#"sum({__name__=~"other_.*",old_label="old_x"})"#,
             ),
+        ];
+
+        for (input, expected) in cases {
+            let mut expr = crate::parser::parse(input).unwrap();
+            expr.rewrite_name_matchers(rename).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+            assert_eq!(crate::parser::parse(expected).unwrap(), expr, "{input}");
+        }
+
+        // the regex is compiled again from the new value
+        let mut expr = crate::parser::parse(r#"{__name__=~"old_.*"}"#).unwrap();
+        expr.rewrite_name_matchers(rename).unwrap();
+        let m = &expr.as_vector_selector().unwrap().matchers.matchers[0];
+        assert!(m.is_match("new_foo"));
+        assert!(!m.is_match("old_foo"));
+
+        // invalid regex
+        let mut expr = crate::parser::parse(r#"{__name__=~"old_.*"} + {__name__=~"a"}"#).unwrap();
+        let original = expr.clone();
+        let result = expr.rewrite_name_matchers(|m| {
+            let value = if m.value == "a" { "b(" } else { "new_.*" };
+            Some(Matcher::new(m.op.clone(), &m.name, value))
+        });
+        assert_eq!(Err(String::from("illegal regex for b(")), result);
+        assert_eq!(original, expr);
+    }
+
+    #[test]
+    fn test_rename_functions() {
+        let map = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(from, to)| (from.to_string(), to.to_string()))
+                .collect()
+        };
+
+        let mut expr = crate::parser::parse("holt_winters(x[5m], 0.5, 0.5)").unwrap();
+        expr.rename_functions(&map(&[("holt_winters", "double_exponential_smoothing")]))
+            .unwrap();
+        assert_eq!(
+            "double_exponential_smoothing(x[5m], 0.5, 0.5)",
+            expr.to_string()
+        );
+        assert_eq!(
+            expr,
+            crate::parser::parse("double_exponential_smoothing(x[5m], 0.5, 0.5)").unwrap()
+        );
+
+        let mut expr = crate::parser::parse(
+            "sum(holt_winters(x[5m], 0.5, 0.5)) / (floor(holt_winters(y[1h], 0.1, 0.9)) + rate(z[5m]))",
+        )
+        .unwrap();
+        expr.rename_functions(&map(&[
+            ("holt_winters", "double_exponential_smoothing"),
+            ("floor", "ceil"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            "sum(double_exponential_smoothing(x[5m], 0.5, 0.5)) / (ceil(double_exponential_smoothing(y[1h], 0.1, 0.9)) + rate(z[5m]))",
+            expr.to_string()
+        );
+
+        // unknown replacement
+        let mut expr = crate::parser::parse("holt_winters(x[5m], 0.5, 0.5)").unwrap();
+        assert_eq!(
+            expr.rename_functions(&map(&[("holt_winters", "no_such_function")])),
+            Err("unknown function with name 'no_such_function'".into())
+        );
+
+        // incompatible replacement leaves the expression untouched
+        let input = "floor(x) + rate(y[5m])";
+        let mut expr = crate::parser::parse(input).unwrap();
+        assert_eq!(
+            expr.rename_functions(&map(&[("floor", "ceil"), ("rate", "abs")])),
+            Err("expected type vector in call to function 'abs', got matrix".into())
+        );
+        assert_eq!(input, expr.to_string());
+
+        // so does a return type which doesn't fit where the call is used
+        let cases = vec![
             (
-                "(((foo_long)))",
-                "(
-  (
-    (foo_long)
-  )
-)",
+                "sum(abs(foo))",
+                "expected type vector in aggregation expression, got scalar",
             ),
-            ("(1 + 2)", "(1 + 2)"),
+            ("abs(foo) > 1", errors::SCALAR_COMPARISON_WITHOUT_BOOL),
+        ];
+        for (input, expected) in cases {
+            let mut expr = crate::parser::parse(input).unwrap();
+            assert_eq!(
+                Err(String::from(expected)),
+                expr.rename_functions(&map(&[("abs", "scalar")])),
+                "{input}"
+            );
+            assert_eq!(input, expr.to_string());
+        }
+    }
+
+    #[test]
+    fn test_replace_at_with_absolute() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let end = SystemTime::UNIX_EPOCH + Duration::from_secs(200);
+
+        let mut expr = crate::parser::parse("foo @ start()").unwrap();
+        assert!(!expr.is_absolute_time());
+        expr.replace_at_with_absolute(start, end);
+        assert_eq!(
+            Some(&AtModifier::At(start)),
+            expr.as_vector_selector().unwrap().at.as_ref()
+        );
+        assert!(expr.is_absolute_time());
+
+        let cases = vec![
+            ("foo[5m] @ end()", "foo[5m] @ 200.000"),
             (
-                "(foo + bar)",
-                "(
-  foo + bar
-)",
+                "max_over_time(rate(foo[5m] @ start())[10m:1m] @ end()) + bar @ 50",
+                "max_over_time(rate(foo[5m] @ 100.000)[10m:1m] @ 200.000) + bar @ 50.000",
             ),
+            ("foo + bar @ start()", "foo + bar @ 100.000"),
+            ("foo offset 5m", "foo offset 5m"),
+        ];
+
+        for (input, expected) in cases {
+            let mut expr = crate::parser::parse(input).unwrap();
+            expr.replace_at_with_absolute(start, end);
+            assert_eq!(expected, expr.to_string(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_is_absolute_time() {
+        let cases = vec![
+            ("foo @ 100", true),
+            ("foo[5m] @ 100", true),
+            ("rate(foo[5m] @ 100) + bar @ 200 offset 1m", true),
+            ("sum by (job) (foo @ 100) * 2", true),
+            ("rate(foo[5m])[10m:1m] @ 100", true),
+            ("1 + 2", true),
+            (r#""foo""#, true),
+            ("foo @ start()", false),
+            ("foo[5m] @ end()", false),
+            ("foo @ 100 + bar @ end()", false),
+            ("rate(foo[5m] @ start())[10m:1m] @ 100", false),
+            ("foo", false),
+            ("foo @ 100 + bar", false),
+            ("rate(foo[5m])[10m:1m]", false),
+            ("time()", false),
+            ("timestamp(foo @ 100)", false),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.is_absolute_time(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_call_builder() {
+        let func = |name| crate::parser::function::get_function(name).unwrap();
+        let vs = || Expr::from(VectorSelector::from("foo"));
+
+        let call = Call::builder(func("rate"))
+            .arg(Expr::new_matrix_selector(vs(), Duration::from_secs(300)).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!("rate(foo[5m])", call.to_string());
+
+        let call = Call::builder(func("round")).arg(vs()).build().unwrap();
+        assert_eq!("round(foo)", call.to_string());
+        let call = Call::builder(func("round"))
+            .arg(vs())
+            .arg(Expr::from(2.0))
+            .build()
+            .unwrap();
+        assert_eq!("round(foo, 2)", call.to_string());
+
+        let call = Call::builder(func("label_join"))
+            .arg(vs())
+            .arg(Expr::from("dst"))
+            .arg(Expr::from(","))
+            .arg(Expr::from("a"))
+            .arg(Expr::from("b"))
+            .build()
+            .unwrap();
+        assert_eq!(r#"label_join(foo, "dst", ",", "a", "b")"#, call.to_string());
+
+        let call = Call::builder(func("time")).build().unwrap();
+        assert_eq!("time()", call.to_string());
+
+        let cases = vec![
             (
-                "(foo_long + bar_long)",
-                "(
-    foo_long
-  +
-    bar_long
-)",
+                Call::builder(func("abs")).arg(Expr::from(1.0)),
+                "expected type vector in call to function 'abs', got scalar",
             ),
             (
-                "(foo_long + bar_long + bar_2_long)",
-                "(
-      foo_long
-    +
-      bar_long
-  +
-    bar_2_long
-)",
+                Call::builder(func("abs"))
+                    .arg(Expr::from(1.0))
+                    .arg(Expr::from("a")),
+                "expected type vector in call to function 'abs', got scalar",
             ),
             (
-                "((foo_long + bar_long) + bar_2_long)",
-                "(
-    (
-        foo_long
-      +
-        bar_long
-    )
-  +
-    bar_2_long
-)",
+                Call::builder(func("abs")).arg(vs()).arg(vs()),
+                "expected 1 argument(s) in call to 'abs', got 2",
             ),
             (
-                "(1111 + 2222)",
-                "(
-    1111
-  +
-    2222
-)",
+                Call::builder(func("abs")),
+                "expected 1 argument(s) in call to 'abs', got 0",
             ),
             (
-                "(sum_over_time(foo[1m]))",
-                "(
-  sum_over_time(
-    foo[1m]
-  )
-)",
+                Call::builder(func("round")).arg(vs()).arg(vs()),
+                "expected type scalar in call to function 'round', got vector",
             ),
             (
-                r#"(label_replace(up{job="api-server",service="a:c"}, "foo", "$1", "service", "(.*):.*"))"#,
-                r#"(
-  label_replace(
-    up{job="api-server",service="a:c"},
-    "foo",
-    "$1",
-    "service",
-    "(.*):.*"
-  )
-)"#,
+                Call::builder(func("round"))
+                    .arg(vs())
+                    .arg(Expr::from(1.0))
+                    .arg(Expr::from(1.0)),
+                "expected at most 2 argument(s) in call to 'round', got 3",
             ),
             (
-                r#"(label_replace(label_replace(up{job="api-server",service="a:c"}, "foo", "$1", "service", "(.*):.*"), "foo", "$1", "service", "(.*):.*"))"#,
-                r#"(
-  label_replace(
-    label_replace(
-      up{job="api-server",service="a:c"},
-      "foo",
-      "$1",
-      "service",
-      "(.*):.*"
-    ),
-    "foo",
-    "$1",
-    "service",
-    "(.*):.*"
-  )
-)"#,
+                Call::builder(func("label_join")).arg(vs()),
+                "expected at least 3 argument(s) in call to 'label_join', got 1",
             ),
             (
-                r#"(label_replace(label_replace((up{job="api-server",service="a:c"}), "foo", "$1", "service", "(.*):.*"), "foo", "$1", "service", "(.*):.*"))"#,
-                r#"(
-  label_replace(
-    label_replace(
-      (
-        up{job="api-server",service="a:c"}
-      ),
-      "foo",
-      "$1",
-      "service",
-      "(.*):.*"
-    ),
-    "foo",
-    "$1",
-    "service",
-    "(.*):.*"
-  )
-)"#,
+                Call::builder(func("rate")).arg(vs()),
+                "expected type matrix in call to function 'rate', got vector",
+            ),
+            (
+                Call::builder(func("time")).arg(vs()),
+                "expected 0 argument(s) in call to 'time', got 1",
             ),
         ];
 
-        for (input, expect) in cases {
-            let expr = crate::parser::parse(input);
-            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        for (builder, err) in cases {
+            assert_eq!(builder.build().unwrap_err(), err);
         }
     }
 
     #[test]
-    fn test_unary_expr_pretty() {
+    fn test_at_and_offset_spacing() {
         let cases = vec![
-            ("-1", "-1"),
-            ("-vector_selector", "-vector_selector"),
-            (
-                "(-vector_selector)",
-                "(
-  -vector_selector
-)",
-            ),
-            (
-                "-histogram_quantile(0.9,rate(foo[1m]))",
-                "-histogram_quantile(
-  0.9,
-  rate(
-    foo[1m]
-  )
-)",
-            ),
+            ("foo @ 100 offset 5m", "foo"),
+            ("foo[1m] @ 100 offset 5m", "foo[1m]"),
+            ("foo[1m:] @ 100 offset 5m", "foo[1m:]"),
+            ("foo[1m:10s] @ 100 offset 5m", "foo[1m:10s]"),
+            ("(foo + bar)[1m:] @ 100 offset 5m", "(foo + bar)[1m:]"),
+        ];
+
+        for (input, prefix) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            let s = expr.to_string();
+            assert_eq!(
+                Some(" @ 100.000 offset 5m"),
+                s.strip_prefix(prefix),
+                "{input}"
+            );
+
+            let s = expr.to_string_with_at_precision(0);
+            assert_eq!(Some(" @ 100 offset 5m"), s.strip_prefix(prefix), "{input}");
+        }
+
+        let cases = vec![
+            ("foo offset -5m", "foo offset -5m"),
+            ("foo[1m] offset -5m", "foo[1m] offset -5m"),
+            ("foo[1m:] offset -5m", "foo[1m:] offset -5m"),
+            ("foo @ end()", "foo @ end()"),
+            ("foo[1m] @ end()", "foo[1m] @ end()"),
+            ("foo[1m:] @ end()", "foo[1m:] @ end()"),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_to_string_with_at_precision() {
+        let cases = vec![
+            ("foo @ 100", 0, "foo @ 100"),
+            ("foo @ 100", 6, "foo @ 100.000000"),
+            ("foo @ 100", 3, "foo @ 100.000"),
+            ("foo @ 100.5", 1, "foo @ 100.5"),
+            ("foo @ 100.5", 6, "foo @ 100.500000"),
+            ("foo @ start()", 0, "foo @ start()"),
             (
-                "-histogram_quantile(0.99, sum by (le) (rate(foo[1m])))",
-                "-histogram_quantile(
-  0.99,
-  sum by (le) (
-    rate(
-      foo[1m]
-    )
-  )
-)",
+                "sum(rate(foo[5m] @ 100)) / bar @ 10 + 1.25",
+                0,
+                "sum(rate(foo[5m] @ 100)) / bar @ 10 + 1.25",
             ),
             (
-                "-histogram_quantile(0.9, -rate(foo[1m] @ start()))",
-                "-histogram_quantile(
-  0.9,
-  -rate(
-    foo[1m] @ start()
-  )
-)",
+                r#"label_replace(foo @ 100, "a", "b", "c", "d")"#,
+                1,
+                r#"label_replace(foo @ 100.0, "a", "b", "c", "d")"#,
             ),
             (
-                "(-histogram_quantile(0.9, -rate(foo[1m] @ start())))",
-                "(
-  -histogram_quantile(
-    0.9,
-    -rate(
-      foo[1m] @ start()
-    )
-  )
-)",
+                "-max_over_time(rate(foo[5m])[10m:1m] @ 100 offset 1m)",
+                2,
+                "-max_over_time(rate(foo[5m])[10m:1m] @ 100.00 offset 1m)",
             ),
+            ("rate(foo[5m:] @ 100)", 0, "rate(foo[5m:] @ 100)"),
+            ("foo[5m:] @ 100", 1, "foo[5m:] @ 100.0"),
+        ];
+
+        for (input, n, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string_with_at_precision(n));
+        }
+
+        let expr = crate::parser::parse("foo @ 100.5").unwrap();
+        assert_eq!("foo @ 100.500", expr.to_string());
+        let expr = crate::parser::parse("a @ 1.5").unwrap();
+        assert_eq!(expr, crate::parser::parse(&expr.to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_has_regex_matcher() {
+        let cases = vec![
+            (r#"foo{a=~"x"}"#, true),
+            (r#"foo{a="x"}"#, false),
+            (r#"foo{a!~"x"}"#, true),
+            ("foo", false),
+            (r#"rate(foo{a=~"x"}[5m])"#, true),
+            (r#"sum(foo) / on (job) bar{b="y" or c=~"z"}"#, true),
+            (r#"{__name__=~"foo.*"}[5m:]"#, true),
+            (r#"label_replace(foo, "a", "b", "c", "d.*")"#, false),
         ];
 
-        for (input, expect) in cases {
-            let expr = crate::parser::parse(input);
-            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.has_regex_matcher(), "{input}");
         }
     }
 
     #[test]
-    fn test_expr_pretty() {
-        // Following queries have been taken from https://monitoring.mixins.dev/
+    fn test_is_pure() {
         let cases = vec![
+            ("time()", false),
+            ("foo", false),
+            ("foo @ 100", true),
+            ("rate(foo[5m] @ 100)", true),
+            ("foo @ 100 + bar", false),
+            ("foo @ 100 + bar @ 200 offset 5m", true),
+            ("foo @ start()", false),
+            ("foo @ end() - 1", false),
+            ("1 + 2", true),
+            (r#""foo""#, true),
+            ("day_of_week()", false),
+            ("day_of_week(foo @ 100)", true),
+            ("year(foo @ 100) - year()", false),
+            ("max_over_time(year()[5m:1m] @ 100)", true),
+            ("timestamp(foo @ 100)", false),
+            ("predict_linear(foo[5m] @ 100, 60)", false),
+            ("max_over_time(timestamp(foo)[5m:1m] @ 100)", true),
+            ("time() + foo @ 100", false),
+            ("max_over_time((time() - foo)[5m:1m] @ 100)", true),
+            ("max_over_time(foo[5m:1m])", false),
+            ("max_over_time(foo[5m:1m] @ start())", false),
+            ("max_over_time((foo @ end())[5m:1m] @ 100)", false),
+            ("sum by (job) (foo @ 100)", true),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.is_pure(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_comparison_threshold() {
+        let cases = vec![
+            ("error_rate > 0.9", true, Some(0.9)),
+            ("0.9 <= error_rate", true, Some(0.9)),
+            ("up == bool 0", true, Some(0.0)),
+            ("foo != -1", true, Some(-1.0)),
+            ("foo > bar", true, None),
+            ("foo > (0.9)", true, None),
+            ("1 < bool 2", true, Some(2.0)),
+            ("error_rate * 0.9", false, None),
+            ("(error_rate > 0.9)", false, None),
+            ("foo", false, None),
+        ];
+
+        for (input, is_comparison, threshold) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(is_comparison, expr.is_binary_comparison(), "{input}");
+            assert_eq!(threshold, expr.comparison_threshold(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_expects_vector_result() {
+        let cases = vec![
+            ("up", true),
+            ("sum(rate(foo[5m]))", true),
+            ("vector(1)", true),
+            ("up > 1", true),
+            ("scalar(up)", false),
+            ("1", false),
+            ("1 + scalar(up)", false),
+            ("foo[5m]", false),
+            (r#""literal""#, false),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.expects_vector_result(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_check_instant_query() {
+        let cases = vec![
+            ("rate(foo[5m])", Ok(())),
+            ("foo", Ok(())),
+            ("1 + 2", Ok(())),
             (
-                r#"(node_filesystem_avail_bytes{job="node",fstype!=""} / node_filesystem_size_bytes{job="node",fstype!=""} * 100 < 40 and predict_linear(node_filesystem_avail_bytes{job="node",fstype!=""}[6h], 24*60*60) < 0 and node_filesystem_readonly{job="node",fstype!=""} == 0)"#,
-                r#"(
-            node_filesystem_avail_bytes{fstype!="",job="node"}
-          /
-            node_filesystem_size_bytes{fstype!="",job="node"}
-        *
-          100
-      <
-        40
-    and
-        predict_linear(
-          node_filesystem_avail_bytes{fstype!="",job="node"}[6h],
-            24 * 60
-          *
-            60
-        )
-      <
-        0
-  and
-      node_filesystem_readonly{fstype!="",job="node"}
-    ==
-      0
-)"#,
-            ),
-            (
-                r#"(node_filesystem_avail_bytes{job="node",fstype!=""} / node_filesystem_size_bytes{job="node",fstype!=""} * 100 < 20 and predict_linear(node_filesystem_avail_bytes{job="node",fstype!=""}[6h], 4*60*60) < 0 and node_filesystem_readonly{job="node",fstype!=""} == 0)"#,
-                r#"(
-            node_filesystem_avail_bytes{fstype!="",job="node"}
-          /
-            node_filesystem_size_bytes{fstype!="",job="node"}
-        *
-          100
-      <
-        20
-    and
-        predict_linear(
-          node_filesystem_avail_bytes{fstype!="",job="node"}[6h],
-            4 * 60
-          *
-            60
-        )
-      <
-        0
-  and
-      node_filesystem_readonly{fstype!="",job="node"}
-    ==
-      0
-)"#,
-            ),
-            (
-                r#"(node_timex_offset_seconds > 0.05 and deriv(node_timex_offset_seconds[5m]) >= 0) or (node_timex_offset_seconds < -0.05 and deriv(node_timex_offset_seconds[5m]) <= 0)"#,
-                r#"  (
-        node_timex_offset_seconds
-      >
-        0.05
-    and
-        deriv(
-          node_timex_offset_seconds[5m]
-        )
-      >=
-        0
-  )
-or
-  (
-        node_timex_offset_seconds
-      <
-        -0.05
-    and
-        deriv(
-          node_timex_offset_seconds[5m]
-        )
-      <=
-        0
-  )"#,
-            ),
-            (
-                r#"1 - ((node_memory_MemAvailable_bytes{job="node"} or (node_memory_Buffers_bytes{job="node"} + node_memory_Cached_bytes{job="node"} + node_memory_MemFree_bytes{job="node"} + node_memory_Slab_bytes{job="node"}) ) / node_memory_MemTotal_bytes{job="node"})"#,
-                r#"  1
--
-  (
-      (
-          node_memory_MemAvailable_bytes{job="node"}
-        or
-          (
-                  node_memory_Buffers_bytes{job="node"}
-                +
-                  node_memory_Cached_bytes{job="node"}
-              +
-                node_memory_MemFree_bytes{job="node"}
-            +
-              node_memory_Slab_bytes{job="node"}
-          )
-      )
-    /
-      node_memory_MemTotal_bytes{job="node"}
-  )"#,
+                "foo[5m]",
+                Err("invalid expression type 'matrix' for instant query, must be scalar or vector"),
             ),
             (
-                r#"min by (job, integration) (rate(alertmanager_notifications_failed_total{job="alertmanager", integration=~".*"}[5m]) / rate(alertmanager_notifications_total{job="alertmanager", integration="~.*"}[5m])) > 0.01"#,
-                r#"  min by (job, integration) (
-      rate(
-        alertmanager_notifications_failed_total{integration=~".*",job="alertmanager"}[5m]
-      )
-    /
-      rate(
-        alertmanager_notifications_total{integration="~.*",job="alertmanager"}[5m]
-      )
-  )
->
-  0.01"#,
+                "rate(foo[5m])[10m:]",
+                Err("invalid expression type 'matrix' for instant query, must be scalar or vector"),
             ),
             (
-                r#"(count by (job) (changes(process_start_time_seconds{job="alertmanager"}[10m]) > 4) / count by (job) (up{job="alertmanager"})) >= 0.5"#,
-                r#"  (
-      count by (job) (
-          changes(
-            process_start_time_seconds{job="alertmanager"}[10m]
-          )
-        >
-          4
-      )
-    /
-      count by (job) (
-        up{job="alertmanager"}
-      )
-  )
->=
-  0.5"#,
+                r#""literal""#,
+                Err("invalid expression type 'string' for instant query, must be scalar or vector"),
             ),
         ];
 
-        for (input, expect) in cases {
-            let expr = crate::parser::parse(input);
-            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(
+                expected.map_err(String::from),
+                expr.check_instant_query(),
+                "{input}"
+            );
         }
     }
 
     #[test]
-    fn test_step_invariant_pretty() {
+    fn test_is_step_invariant() {
         let cases = vec![
-            ("a @ 1", "a @ 1.000"),
-            ("a @ start()", "a @ start()"),
-            ("vector_selector @ start()", "vector_selector @ start()"),
+            ("foo @ 100", true),
+            ("foo", false),
+            ("foo[5m] @ start()", true),
+            ("foo[5m]", false),
+            ("1 + 2", true),
+            (r#""foo""#, true),
+            ("pi()", true),
+            ("time()", false),
+            ("foo @ 100 + bar", false),
+            ("foo @ 100 + bar @ end()", true),
+            ("-(foo @ 100)", true),
+            ("sum by (job) (rate(foo[5m] @ 100))", true),
+            ("topk(5, rate(foo[5m] @ 100))", true),
+            ("timestamp(foo @ 100)", false),
+            ("day_of_week()", false),
+            ("rate(foo[5m])[10m:] @ 100", true),
+            ("rate(foo[5m] @ 100)[10m:]", false),
         ];
 
-        for (input, expect) in cases {
-            let expr = crate::parser::parse(input);
-            assert_eq!(expect, expr.unwrap().pretty(0, 10));
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.is_step_invariant(), "{input}");
         }
     }
 
     #[test]
-    fn test_prettify() {
+    fn test_to_dot() {
+        let expr = crate::parser::parse("a + rate(b[5m])").unwrap();
+        let dot = expr.to_dot();
+
+        assert!(dot.starts_with("digraph promql {\n"));
+        assert!(dot.ends_with('}'));
+        for label in [
+            "n0 [label=\"Binary: +\"];",
+            "n1 [label=\"VectorSelector: a\"];",
+            "n2 [label=\"Call: rate\"];",
+            "n3 [label=\"MatrixSelector: b[5m]\"];",
+        ] {
+            assert!(dot.contains(label), "{label} not found in {dot}");
+        }
+        for edge in ["n0 -> n1;", "n0 -> n2;", "n2 -> n3;"] {
+            assert!(dot.contains(edge), "{edge} not found in {dot}");
+        }
+
+        let expr = crate::parser::parse(r#"sum by (job) (foo{a="b"})"#).unwrap();
+        let dot = expr.to_dot();
+        assert!(dot.contains(r#"n0 [label="Aggregate: sum by (job)"];"#));
+        assert!(dot.contains(r#"n1 [label="VectorSelector: foo{a=\"b\"}"];"#));
+    }
+
+    #[test]
+    #[cfg(feature = "comment-extension")]
+    fn test_comment_extension() {
+        let inner = crate::parser::parse(r#"foo{a="b"}"#).unwrap();
+        let expr = inner.clone().with_comment("from dashboard");
+
+        let ext = expr.as_extension().unwrap();
+        assert_eq!("comment", ext.expr.name());
+        let comment = ext
+            .expr
+            .as_any()
+            .downcast_ref::<CommentExtension>()
+            .unwrap();
+        assert_eq!("from dashboard", comment.comment);
+
+        assert_eq!(vec![&inner], expr.children());
+        assert_eq!(ValueType::Vector, expr.value_type());
+
+        let expr = crate::parser::parse("1 + 2").unwrap().with_comment("");
+        assert_eq!(ValueType::Scalar, expr.value_type());
+    }
+
+    #[test]
+    fn test_query_view() {
+        let expr = crate::parser::parse("foo + bar").unwrap();
+        let view = expr.view();
+        let names: Vec<&str> = view.metric_names().collect();
+        assert_eq!(vec!["foo", "bar"], names);
+        // the names are borrowed from the AST
+        let lhs_name = match &expr {
+            Expr::Binary(ex) => ex.lhs.as_vector_selector().unwrap().name.as_deref(),
+            _ => unreachable!(),
+        };
+        assert!(std::ptr::eq(names[0], lhs_name.unwrap()));
+
+        let expr = crate::parser::parse(
+            r#"sum(rate({__name__="foo", job="a"}[5m])) / on(env) count_over_time(bar{env=~"p.*"}[1h])"#,
+        )
+        .unwrap();
+        let view = expr.view();
+        assert!(std::ptr::eq(&expr, view.expr()));
+        assert_eq!(2, view.selectors().count());
+        assert_eq!(vec!["foo", "bar"], view.metric_names().collect::<Vec<_>>());
+        assert_eq!(vec!["job", "env"], view.label_names().collect::<Vec<_>>());
+        assert_eq!(
+            vec!["rate", "count_over_time"],
+            view.function_names().collect::<Vec<_>>()
+        );
+
+        // the calls are listed in the same order as Expr::function_names
+        let expr = crate::parser::parse("histogram_quantile(0.9, rate(x[5m]))").unwrap();
+        assert_eq!(
+            vec!["rate", "histogram_quantile"],
+            expr.view().function_names().collect::<Vec<_>>()
+        );
+        assert_eq!(expr.function_names(), vec!["rate", "histogram_quantile"]);
+    }
+
+    #[test]
+    fn test_hash_canonical() {
+        use std::collections::hash_map::DefaultHasher;
+
+        let hash = |input: &str| {
+            let mut hasher = DefaultHasher::new();
+            crate::parser::parse(input)
+                .unwrap()
+                .hash_canonical(&mut hasher);
+            hasher.finish()
+        };
+
         let cases = vec![
-            ("vector_selector", "vector_selector"),
+            ("b + a", "(a) + b"),
             (
-                r#"vector_selector{fooooooooooooooooo="barrrrrrrrrrrrrrrrrrr",barrrrrrrrrrrrrrrrrrr="fooooooooooooooooo",process_name="alertmanager"}"#,
-                r#"vector_selector{barrrrrrrrrrrrrrrrrrr="fooooooooooooooooo",fooooooooooooooooo="barrrrrrrrrrrrrrrrrrr",process_name="alertmanager"}"#,
+                r#"sum by (job, env) (foo{b="2", a="1"}) * bar"#,
+                r#"bar * (sum by (env, job) (foo{a="1",b="2"}))"#,
             ),
             (
-                r#"matrix_selector{fooooooooooooooooo="barrrrrrrrrrrrrrrrrrr",barrrrrrrrrrrrrrrrrrr="fooooooooooooooooo",process_name="alertmanager"}[1y2w3d]"#,
-                r#"matrix_selector{barrrrrrrrrrrrrrrrrrr="fooooooooooooooooo",fooooooooooooooooo="barrrrrrrrrrrrrrrrrrr",process_name="alertmanager"}[382d]"#,
+                "foo / on (b, a) group_left (d, c) bar",
+                "foo / on (a, b) group_left (c, d) bar",
             ),
+            ("rate(foo[5m])", "(rate(foo[5m]))"),
+            ("(c + b) + a", "a + (b + c)"),
+            ("b + ignoring () a", "a + b"),
+            ("sum by (job, job) (a)", "sum by (job) (a)"),
+            ("sum by () (a)", "sum(a)"),
+            ("rate((b + a)[5m:])", "rate((a + b)[5m:])"),
         ];
+        for (lhs, rhs) in cases {
+            assert_eq!(hash(lhs), hash(rhs), "{lhs} and {rhs}");
+        }
 
-        for (input, expect) in cases {
-            assert_eq!(expect, crate::parser::parse(input).unwrap().prettify());
+        let cases = vec![
+            ("a - b", "b - a"),
+            ("foo", "foo offset 5m"),
+            (r#"foo{a="1"}"#, r#"foo{a="2"}"#),
+            ("a + b", "a * b"),
+            ("b + on (job) a", "a + on (job) b"),
+            ("sum by (a) (foo)", "sum without (a) (foo)"),
+            ("rate(foo[5m:])", "rate(foo[5m:] offset 1m)"),
+            ("foo[5m:1m]", "foo[5m:]"),
+        ];
+        for (lhs, rhs) in cases {
+            assert_ne!(hash(lhs), hash(rhs), "{lhs} and {rhs}");
         }
+
+        // long chains of commutative operators must not blow up
+        let terms: Vec<String> = (0..200).map(|i| format!("t{i}")).collect();
+        let chain = terms.join(" + ");
+        let swapped = format!("t199 + ({})", terms[..199].join(" + "));
+        assert_eq!(hash(&chain), hash(&swapped));
     }
 
     #[test]
-    fn test_eval_stmt_to_string() {
-        let query = r#"http_requests_total{job="apiserver", handler="/api/comments"}[5m]"#;
-        let start = "2024-10-08T07:15:00.022978+00:00";
-        let end = "2024-10-08T07:15:30.012978+00:00";
-        let expect = r#"[http_requests_total{handler="/api/comments",job="apiserver"}[5m]] @ [2024-10-08T07:15:00.022978+00:00, 2024-10-08T07:15:30.012978+00:00, 1m, 5m]"#;
+    fn test_shape_signature() {
+        let cases = vec![
+            (r#"foo{job="a"} + 1"#, "foo{job=?} + ?"),
+            (
+                r#"sum by (job) (rate(foo{job=~"a.*", env!="dev"}[5m] offset 1h)) > 0.5"#,
+                "sum by (job) (rate(foo{env!=?,job=~?}[5m] offset 1h)) > ?",
+            ),
+            (
+                r#"topk(5, {__name__="foo", a="1" or b="2"})"#,
+                "topk(?, {__name__=?, a=? or b=?})",
+            ),
+            (
+                r#"label_replace(foo, "dst", "$1", "src", "(.*)")"#,
+                r#"label_replace(foo, "dst", "$1", "src", "(.*)")"#,
+            ),
+            ("-foo * -2", "-foo * ?"),
+        ];
 
-        let stmt = EvalStmt {
-            expr: crate::parser::parse(query).unwrap(),
-            start: DateTime::parse_from_rfc3339(start)
-                .unwrap()
-                .with_timezone(&Utc)
-                .into(),
-            end: DateTime::parse_from_rfc3339(end)
-                .unwrap()
-                .with_timezone(&Utc)
-                .into(),
-            interval: Duration::from_secs(60),
-            lookback_delta: Duration::from_secs(300),
-        };
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.shape_signature(), "{input}");
+        }
 
-        assert_eq!(expect, stmt.to_string());
+        let signature = |input: &str| crate::parser::parse(input).unwrap().shape_signature();
+        assert_eq!(
+            signature(r#"foo{job="a"} + 1"#),
+            signature(r#"foo{job="b"} + 2"#)
+        );
+        assert_ne!(
+            signature(r#"foo{job="a"} + 1"#),
+            signature(r#"foo{env="a"} + 1"#)
+        );
+        assert_ne!(
+            signature(r#"rate(foo[5m])"#),
+            signature(r#"rate(foo[10m])"#)
+        );
+    }
+
+    #[test]
+    fn test_to_sexpr() {
+        let cases = vec![
+            ("foo + 1", "(binary + (selector foo) (num 1))"),
+            (
+                r#"-sum by (job) (rate(foo{a="b"}[5m] offset 1m))"#,
+                r#"(unary - (aggregate sum by (job) (call rate (matrix foo {a="b"} 5m (offset 1m)))))"#,
+            ),
+            (
+                r#"topk(3, {__name__="bar"} @ 100) > bool on (job) (baz)"#,
+                r#"(binary > bool on (job) (aggregate topk (num 3) (selector {__name__="bar"} (@ 100.000))) (paren (selector baz)))"#,
+            ),
+            (
+                r#"label_replace(quantile_over_time(0.9, foo[1h:1m] @ end()), "a", "b", "c", "d")"#,
+                r#"(call label_replace (call quantile_over_time (num 0.9) (subquery (selector foo) 1h 1m (@ end()))) (str "a") (str "b") (str "c") (str "d"))"#,
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_sexpr(), "{input}");
+        }
     }
 }
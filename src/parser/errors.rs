@@ -0,0 +1,100 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The catalog of the static error messages returned by the parser, so that they
+//! can be compared against the ones of Prometheus in one place. Messages carrying
+//! the offending input are formatted by the functions at the end.
+
+// FIXME: show more helpful error message to some invalid promql queries.
+pub const INVALID_QUERY: &str = "invalid promql query";
+pub const NO_EXPRESSION_FOUND: &str = "no expression found in input";
+
+// lexer
+pub const LEXER_END_STATE: &str = "End state can not shift forward.";
+pub const UNCLOSED_LEFT_PAREN: &str = "unclosed left parenthesis";
+pub const TOO_MANY_LEFT_PARENS: &str = "too many left parentheses";
+pub const UNEXPECTED_RIGHT_PAREN: &str = "unexpected right parenthesis ')'";
+pub const UNEXPECTED_RIGHT_BRACE: &str = "unexpected right brace '}'";
+pub const UNEXPECTED_RIGHT_BRACKET: &str = "unexpected right bracket ']'";
+pub const UNEXPECTED_TILDE_AFTER_EQL: &str = "unexpected character after '=': '~'";
+pub const BANG_AT_END: &str = "'!' can not be at the end";
+pub const UNEXPECTED_DOT: &str = "unexpected character: '.'";
+pub const ESCAPE_NOT_TERMINATED: &str = "escape sequence not terminated";
+pub const LEFT_BRACE_INSIDE_BRACES: &str = "unexpected left brace '{' inside braces";
+pub const END_INSIDE_BRACES: &str = "unexpected end of input inside braces";
+pub const SECOND_COLON_IN_BRACKETS: &str = "unexpected second colon(:) in brackets";
+pub const COLON_WITHOUT_DURATION: &str = "expect duration before first colon(:) in brackets";
+pub const LEFT_BRACKET_INSIDE_BRACKETS: &str = "unexpected left brace '[' inside brackets";
+pub const END_INSIDE_BRACKETS: &str = "unexpected end of input inside brackets";
+
+// grammar
+pub const UNEXPECTED_GROUP_LEFT: &str = "unexpected <group_left>";
+pub const UNEXPECTED_GROUP_RIGHT: &str = "unexpected <group_right>";
+pub const TRAILING_COMMA_IN_CALL: &str = "trailing commas not allowed in function call args";
+pub const TRAILING_COMMA_IN_AGGREGATION: &str = "trailing commas not allowed in aggregation args";
+pub const END_IN_OFFSET: &str = "unexpected end of input in offset, expected duration";
+pub const END_IN_AT: &str = "unexpected end of input in @, expected timestamp";
+pub const MISSING_DURATION_UNIT: &str = "missing unit character in duration";
+pub const COMMA_IN_LABEL_MATCHING: &str =
+    "unexpected ',' in label matching, expected identifier or right_brace";
+pub const KEEP_COMMON_UNSUPPORTED: &str = "keep_common is no longer supported";
+pub const BOOL_AFTER_MATCHING: &str = "bool modifier must come before on/ignoring";
+
+// ast
+pub const INVALID_AT_FLOAT: &str = "invalid float value after @ modifier";
+pub const UNARY_ON_STRING: &str =
+    "unary expression only allowed on expressions of type scalar or vector, got: string";
+pub const UNARY_ON_MATRIX: &str =
+    "unary expression only allowed on expressions of type scalar or vector, got: matrix";
+pub const OFFSET_BEFORE_RANGE: &str = "no offset modifiers allowed before range";
+pub const AT_BEFORE_RANGE: &str = "no @ modifiers allowed before range";
+pub const RANGE_ON_NON_SELECTOR: &str = "ranges only allowed for vector selectors";
+pub const AT_SET_TWICE: &str = "@ <timestamp> may not be set multiple times";
+pub const AT_ON_NON_SELECTOR: &str =
+    "@ modifier must be preceded by a vector selector or matrix selector or a subquery";
+pub const OFFSET_SET_TWICE: &str = "offset may not be set multiple times";
+pub const OFFSET_ON_NON_SELECTOR: &str =
+    "offset modifier must be preceded by a vector selector or matrix selector or a subquery";
+pub const AGGREGATE_WITHOUT_EXPR: &str =
+    "aggregate operation needs a single instant vector parameter, but found none";
+pub const BOOL_ON_NON_COMPARISON: &str = "bool modifier can only be used on comparison operators";
+pub const SCALAR_COMPARISON_WITHOUT_BOOL: &str =
+    "comparisons between scalars must use BOOL modifier";
+pub const BINARY_INVALID_OPERANDS: &str =
+    "binary expression must contain only scalar and instant vector types";
+pub const VECTOR_MATCHING_ON_NON_VECTORS: &str = "vector matching only allowed between vectors";
+pub const EMPTY_VECTOR_SELECTOR: &str =
+    "vector selector must contain at least one non-empty matcher";
+
+// duration
+pub const EMPTY_DURATION: &str = "empty duration string";
+pub const ZERO_DURATION: &str = "duration must be greater than 0";
+pub const DURATION_OVERFLOW: &str = "duration overflowed";
+
+// eval statement
+pub const END_BEFORE_START: &str = "end timestamp must not be before start time";
+pub const ZERO_STEP: &str = "zero query resolution step widths are not accepted";
+pub const ZERO_LOOKBACK_DELTA: &str = "zero lookback delta is not accepted";
+
+pub fn invalid_duration(ds: &str) -> String {
+    format!("not a valid duration string: {ds}")
+}
+
+pub fn set_operator_on_scalar(op: impl std::fmt::Display) -> String {
+    format!("set operator '{op}' not allowed in binary scalar expression")
+}
+
+pub fn metric_name_set_twice(first: &str, second: &str) -> String {
+    format!("metric name must not be set twice: {first:?} or {second:?}")
+}
@@ -19,7 +19,6 @@ use lazy_static::lazy_static;
 
 use crate::parser::value::ValueType;
 use crate::parser::{Expr, Prettier};
-use crate::util::join_vector;
 
 /// called by func in Call
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -63,7 +62,14 @@ impl FunctionArgs {
 
 impl fmt::Display for FunctionArgs {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", join_vector(&self.args, ", ", false))
+        // pass the formatter down instead of join_vector to keep its precision
+        for (idx, arg) in self.args.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            fmt::Display::fmt(arg, f)?;
+        }
+        Ok(())
     }
 }
 
@@ -107,6 +113,42 @@ impl Function {
         }
     }
 
+    /// whether the result of the function depends on the evaluation timestamp itself,
+    /// like `time()` or `day_of_week()`. Such functions can not be evaluated only once
+    /// even if all the selectors in the arguments are fixed by `@` modifiers. These are
+    /// the [date functions](Function::is_date_time), `timestamp()` and `predict_linear()`.
+    pub fn is_at_modifier_unsafe(&self) -> bool {
+        self.is_date_time()
+            || matches!(
+                self.id(),
+                Some(FunctionId::PredictLinear | FunctionId::Timestamp)
+            )
+    }
+
+    /// the identifier of the function, only None for functions which aren't built-in.
+    pub fn id(&self) -> Option<FunctionId> {
+        FunctionId::from_name(self.name)
+    }
+
+    /// whether the function is `time()` or a date function like `day_of_week()`, which
+    /// read the evaluation timestamp if no argument is given.
+    pub fn is_date_time(&self) -> bool {
+        matches!(
+            self.id(),
+            Some(
+                FunctionId::DaysInMonth
+                    | FunctionId::DayOfMonth
+                    | FunctionId::DayOfWeek
+                    | FunctionId::DayOfYear
+                    | FunctionId::Hour
+                    | FunctionId::Minute
+                    | FunctionId::Month
+                    | FunctionId::Year
+                    | FunctionId::Time
+            )
+        )
+    }
+
     #[cfg(feature = "ser")]
     pub(crate) fn serialize_variadic<S>(variadic: &bool, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -120,6 +162,109 @@ impl Function {
     }
 }
 
+/// declare [FunctionId] with the names of the functions.
+macro_rules! function_ids {
+    ($($id:ident => $name:literal,)*) => {
+        /// The identifier of every built-in function, which is cheaper and less error-prone
+        /// to compare than the names, see [`Function::id`].
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum FunctionId {
+            $($id,)*
+        }
+
+        impl FunctionId {
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $($name => Some(Self::$id),)*
+                    _ => None,
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$id => $name,)*
+                }
+            }
+        }
+    };
+}
+
+function_ids!(
+    Abs => "abs",
+    Absent => "absent",
+    AbsentOverTime => "absent_over_time",
+    Acos => "acos",
+    Acosh => "acosh",
+    Asin => "asin",
+    Asinh => "asinh",
+    Atan => "atan",
+    Atanh => "atanh",
+    AvgOverTime => "avg_over_time",
+    Ceil => "ceil",
+    Changes => "changes",
+    Clamp => "clamp",
+    ClampMax => "clamp_max",
+    ClampMin => "clamp_min",
+    Cos => "cos",
+    Cosh => "cosh",
+    CountOverTime => "count_over_time",
+    DayOfMonth => "day_of_month",
+    DayOfWeek => "day_of_week",
+    DayOfYear => "day_of_year",
+    DaysInMonth => "days_in_month",
+    Deg => "deg",
+    Delta => "delta",
+    Deriv => "deriv",
+    DoubleExponentialSmoothing => "double_exponential_smoothing",
+    Exp => "exp",
+    Floor => "floor",
+    HistogramCount => "histogram_count",
+    HistogramFraction => "histogram_fraction",
+    HistogramQuantile => "histogram_quantile",
+    HistogramStddev => "histogram_stddev",
+    HistogramStdvar => "histogram_stdvar",
+    HistogramSum => "histogram_sum",
+    HoltWinters => "holt_winters",
+    Hour => "hour",
+    Idelta => "idelta",
+    Increase => "increase",
+    Irate => "irate",
+    LabelJoin => "label_join",
+    LabelReplace => "label_replace",
+    LastOverTime => "last_over_time",
+    Ln => "ln",
+    Log10 => "log10",
+    Log2 => "log2",
+    MaxOverTime => "max_over_time",
+    MinOverTime => "min_over_time",
+    Minute => "minute",
+    Month => "month",
+    Pi => "pi",
+    PredictLinear => "predict_linear",
+    PresentOverTime => "present_over_time",
+    QuantileOverTime => "quantile_over_time",
+    Rad => "rad",
+    Rate => "rate",
+    Resets => "resets",
+    Round => "round",
+    Scalar => "scalar",
+    Sgn => "sgn",
+    Sin => "sin",
+    Sinh => "sinh",
+    Sort => "sort",
+    SortDesc => "sort_desc",
+    Sqrt => "sqrt",
+    StddevOverTime => "stddev_over_time",
+    StdvarOverTime => "stdvar_over_time",
+    SumOverTime => "sum_over_time",
+    Tan => "tan",
+    Tanh => "tanh",
+    Time => "time",
+    Timestamp => "timestamp",
+    Vector => "vector",
+    Year => "year",
+);
+
 macro_rules! map {
     // if variadic args, then the last is the variadic one
     ($(($name:literal, $arg:expr, $ret:expr)),*) => (
@@ -194,6 +339,11 @@ lazy_static! {
         ("deg", vec![ValueType::Vector], ValueType::Vector),
         ("delta", vec![ValueType::Matrix], ValueType::Vector),
         ("deriv", vec![ValueType::Matrix], ValueType::Vector),
+        (
+            "double_exponential_smoothing",
+            vec![ValueType::Matrix, ValueType::Scalar, ValueType::Scalar],
+            ValueType::Vector
+        ),
         ("exp", vec![ValueType::Vector], ValueType::Vector),
         ("floor", vec![ValueType::Vector], ValueType::Vector),
         (
@@ -212,6 +362,16 @@ lazy_static! {
             vec![ValueType::Scalar, ValueType::Vector],
             ValueType::Vector
         ),
+        (
+            "histogram_stddev",
+            vec![ValueType::Vector],
+            ValueType::Vector
+        ),
+        (
+            "histogram_stdvar",
+            vec![ValueType::Vector],
+            ValueType::Vector
+        ),
         (
             "holt_winters",
             vec![ValueType::Matrix, ValueType::Scalar, ValueType::Scalar],
@@ -302,6 +462,9 @@ lazy_static! {
 }
 
 /// get_function returns a predefined Function object for the given name.
+///
+/// NOTE: the lookup is case-sensitive as in Prometheus, so `RATE` is not `rate`,
+/// while keywords like aggregators and set operators are case-insensitive.
 pub(crate) fn get_function(name: &str) -> Option<Function> {
     FUNCTIONS.get(name).cloned()
 }
@@ -318,6 +481,79 @@ mod tests {
         assert_eq!(get_function(func), get_function(func));
     }
 
+    #[test]
+    fn test_function_name_case_sensitive() {
+        assert!(get_function("rate").is_some());
+        assert!(get_function("RATE").is_none());
+        assert!(get_function("Rate").is_none());
+    }
+
+    #[test]
+    fn test_call_return_types() {
+        let cases = vec![
+            ("scalar(foo)", ValueType::Scalar),
+            ("scalar(sum(foo))", ValueType::Scalar),
+            ("vector(1)", ValueType::Vector),
+            ("vector(time())", ValueType::Vector),
+            ("absent(foo)", ValueType::Vector),
+            ("absent_over_time(foo[5m])", ValueType::Vector),
+            ("time()", ValueType::Scalar),
+            ("rate(foo[5m])", ValueType::Vector),
+        ];
+
+        // the built-in functions have a fixed return type, whatever the arguments
+        for (input, expected) in cases {
+            let expr = crate::parser::parse(input).unwrap();
+            assert_eq!(
+                expected,
+                expr.as_call().unwrap().func.return_type,
+                "{input}"
+            );
+            assert_eq!(expected, expr.value_type(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_function_id() {
+        assert_eq!(Some(FunctionId::Rate), get_function("rate").unwrap().id());
+        assert_eq!(Some(FunctionId::Log10), get_function("log10").unwrap().id());
+        assert_eq!(
+            Some(FunctionId::SortDesc),
+            get_function("sort_desc").unwrap().id()
+        );
+        assert_eq!(None, FunctionId::from_name("RATE"));
+        assert_ne!(
+            get_function("rate").unwrap().id(),
+            get_function("irate").unwrap().id()
+        );
+
+        for (name, func) in FUNCTIONS.iter() {
+            assert_eq!(Some(*name), func.id().map(|id| id.name()));
+        }
+    }
+
+    #[test]
+    fn test_is_date_time() {
+        for name in ["time", "day_of_week", "year", "days_in_month"] {
+            assert!(get_function(name).unwrap().is_date_time(), "{name}");
+        }
+        for name in ["timestamp", "rate", "abs", "pi"] {
+            assert!(!get_function(name).unwrap().is_date_time(), "{name}");
+        }
+        for name in ["time", "day_of_week", "timestamp", "predict_linear"] {
+            assert!(
+                get_function(name).unwrap().is_at_modifier_unsafe(),
+                "{name}"
+            );
+        }
+        for name in ["rate", "abs", "pi"] {
+            assert!(
+                !get_function(name).unwrap().is_at_modifier_unsafe(),
+                "{name}"
+            );
+        }
+    }
+
     #[test]
     fn test_function_args_equality() {
         assert_eq!(FunctionArgs::empty_args(), FunctionArgs::empty_args());
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::parser::errors;
 use crate::parser::token::*;
 use cfgrammar::NewlineCache;
 use lrlex::{DefaultLexeme, DefaultLexerTypes, LRNonStreamingLexer};
@@ -23,19 +24,111 @@ const STRING_SYMBOLS: &str = r#"'"`"#;
 
 pub(crate) type LexemeType = DefaultLexeme<TokenId>;
 
-pub fn lexer(s: &str) -> Result<LRNonStreamingLexer<DefaultLexerTypes<TokenId>>, String> {
+pub fn lexer(s: &str) -> Result<LRNonStreamingLexer<'_, '_, DefaultLexerTypes<TokenId>>, String> {
+    let lexemes = lexemes(s)?;
+    Ok(new_lexer(s, lexemes))
+}
+
+/// same as [lexer], but the offset modifier placed before the range of a matrix selector
+/// is moved behind the range, e.g. `foo offset 1m[5m]` is lexed as `foo[5m] offset 1m`.
+/// With the `bare-zero-arg-functions` feature, `time` is also lexed as `time()`.
+pub(crate) fn lenient_lexer(
+    s: &str,
+) -> Result<LRNonStreamingLexer<'_, '_, DefaultLexerTypes<TokenId>>, String> {
+    let mut lexemes = lexemes(s)?;
+    reorder_offset_before_range(&mut lexemes);
+    #[cfg(feature = "bare-zero-arg-functions")]
+    call_bare_zero_arg_functions(s, &mut lexemes);
+    Ok(new_lexer(s, lexemes))
+}
+
+/// lex the whole input, the last lexeme is always EOF if no error happens.
+pub(crate) fn lexemes(s: &str) -> Result<Vec<LexemeType>, String> {
     let lexemes: Vec<Result<LexemeType, String>> = Lexer::new(s).collect();
     match lexemes.last() {
         Some(Err(info)) => Err(info.into()),
-        Some(Ok(_)) => {
-            // TODO: use better error mechanism, instead of filtering the err.
-            let lexemes = lexemes.into_iter().filter_map(|l| l.ok()).map(Ok).collect();
-            Ok(LRNonStreamingLexer::new(s, lexemes, NewlineCache::new()))
-        }
+        // TODO: use better error mechanism, instead of filtering the err.
+        Some(Ok(_)) => Ok(lexemes.into_iter().filter_map(|l| l.ok()).collect()),
         None => Err(format!("no expression found in input: '{s}'")),
     }
 }
 
+fn new_lexer(
+    s: &str,
+    lexemes: Vec<LexemeType>,
+) -> LRNonStreamingLexer<'_, '_, DefaultLexerTypes<TokenId>> {
+    let lexemes = lexemes.into_iter().map(Ok).collect();
+    LRNonStreamingLexer::new(s, lexemes, NewlineCache::new())
+}
+
+/// rotate `OFFSET [+|-] DURATION [ DURATION ]` into `[ DURATION ] OFFSET [+|-] DURATION`.
+/// Lexemes keep their spans, so only the order the parser sees them in changes.
+fn reorder_offset_before_range(lexemes: &mut [LexemeType]) {
+    let tok_id = |lexemes: &[LexemeType], idx: usize| lexemes.get(idx).map(|l| l.tok_id());
+
+    for i in 0..lexemes.len() {
+        if tok_id(lexemes, i) != Some(T_OFFSET) {
+            continue;
+        }
+        let mut range_start = i + 1;
+        if matches!(tok_id(lexemes, range_start), Some(T_ADD | T_SUB)) {
+            range_start += 1;
+        }
+        // the offset duration itself
+        if tok_id(lexemes, range_start) != Some(T_DURATION) {
+            continue;
+        }
+        range_start += 1;
+
+        let is_range = tok_id(lexemes, range_start) == Some(T_LEFT_BRACKET)
+            && tok_id(lexemes, range_start + 1) == Some(T_DURATION)
+            && tok_id(lexemes, range_start + 2) == Some(T_RIGHT_BRACKET);
+        if is_range {
+            lexemes[i..range_start + 3].rotate_left(range_start - i);
+        }
+    }
+}
+
+/// insert empty parentheses after the name of a function without arguments, like `time`
+/// or `pi`, unless it is followed by `(`, `{` or `[`, or it is a label name inside braces
+/// or grouping labels. The inserted lexemes have empty spans at the end of the name.
+#[cfg(feature = "bare-zero-arg-functions")]
+fn call_bare_zero_arg_functions(s: &str, lexemes: &mut Vec<LexemeType>) {
+    let mut inside_braces = false;
+    let mut inside_grouping = false;
+    let mut i = 0;
+    while i < lexemes.len() {
+        let next = lexemes.get(i + 1).map(|l| l.tok_id());
+        match lexemes[i].tok_id() {
+            T_LEFT_BRACE => inside_braces = true,
+            T_RIGHT_BRACE => inside_braces = false,
+            T_BY | T_WITHOUT | T_ON | T_IGNORING | T_GROUP_LEFT | T_GROUP_RIGHT
+                if next == Some(T_LEFT_PAREN) =>
+            {
+                inside_grouping = true
+            }
+            T_RIGHT_PAREN => inside_grouping = false,
+            T_IDENTIFIER
+                if !inside_braces
+                    && !inside_grouping
+                    && !matches!(next, Some(T_LEFT_PAREN | T_LEFT_BRACE | T_LEFT_BRACKET)) =>
+            {
+                let span = lexemes[i].span();
+                let name = &s[span.start()..span.end()];
+                let is_zero_arg = crate::parser::function::get_function(name)
+                    .is_some_and(|func| func.arg_types.is_empty());
+                if is_zero_arg {
+                    lexemes.insert(i + 1, LexemeType::new(T_LEFT_PAREN, span.end(), 0));
+                    lexemes.insert(i + 2, LexemeType::new(T_RIGHT_PAREN, span.end(), 0));
+                    i += 2;
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+}
+
 #[derive(Debug)]
 enum State {
     Start,
@@ -54,8 +147,9 @@ enum State {
 }
 
 #[derive(Debug)]
-struct Context {
+struct Context<'a> {
     // TODO: use &str instead of Vec<char> for better performance.
+    input: &'a str,
     chars: Vec<char>,
     idx: usize,   // Current position in the Vec, increment by 1.
     start: usize, // Start position of one Token, increment by char.len_utf8.
@@ -68,9 +162,10 @@ struct Context {
     eof: bool,          // Whether we got end of file
 }
 
-impl Context {
-    fn new(input: &str) -> Context {
+impl<'a> Context<'a> {
+    fn new(input: &'a str) -> Context<'a> {
         Self {
+            input,
             chars: input.chars().collect(),
             idx: 0,
             start: 0,
@@ -108,6 +203,14 @@ impl Context {
         self.chars.get(self.idx).copied()
     }
 
+    /// get the first char from the pos which isn't an ASCII whitespace, this won't consume it.
+    fn peek_past_spaces(&self) -> Option<char> {
+        self.chars[self.idx..]
+            .iter()
+            .find(|ch| !ch.is_ascii_whitespace())
+            .copied()
+    }
+
     /// string lexeme SHOULD trim the surrounding string symbols, ' or " or `
     fn lexeme(&mut self, token_id: TokenId) -> LexemeType {
         let mut start = self.start;
@@ -125,34 +228,25 @@ impl Context {
     }
 
     // TODO: refactor needed, details in Issues/15.
+    /// the text between start and pos, sliced from the input so that extracting
+    /// long lexemes stays linear.
     fn lexeme_string(&self) -> String {
-        let mut s = String::from("");
-        if self.idx == 0 {
-            return s;
-        }
-
-        let mut pos = self.pos;
-        let mut idx = self.idx;
-        while pos > self.start {
-            if let Some(&ch) = self.chars.get(idx - 1) {
-                pos -= ch.len_utf8();
-                idx -= 1;
-                s.push(ch);
-            };
-        }
-        s.chars().rev().collect()
+        self.input
+            .get(self.start..self.pos)
+            .expect("the span of a lexeme is always on char boundaries of the input")
+            .to_string()
     }
 }
 
 #[derive(Debug)]
-struct Lexer {
+struct Lexer<'a> {
     state: State,
-    ctx: Context,
+    ctx: Context<'a>,
 }
 
 /// block for context operations.
-impl Lexer {
-    fn new(input: &str) -> Self {
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
         let ctx = Context::new(input);
         let state = State::Start;
         Self { state, ctx }
@@ -228,6 +322,10 @@ impl Lexer {
         self.ctx.peek()
     }
 
+    fn peek_past_spaces(&self) -> Option<char> {
+        self.ctx.peek_past_spaces()
+    }
+
     /// lexeme() consumes the Span, which means consecutive lexeme() call
     /// will get wrong Span unless Lexer shifts its State.
     fn lexeme(&mut self, token_id: TokenId) -> LexemeType {
@@ -254,13 +352,13 @@ impl Lexer {
 }
 
 /// block for state operations.
-impl Lexer {
+impl Lexer<'_> {
     fn shift(&mut self) {
         // NOTE: the design of the match arms's order is of no importance.
         // If different orders result in different states, then it has to be fixed.
         self.state = match self.state {
             State::Start => self.start(),
-            State::End => State::Err("End state can not shift forward.".into()),
+            State::End => State::Err(errors::LEXER_END_STATE.into()),
             State::Lexeme(_) => State::Start,
             State::String(ch) => self.accept_string(ch),
             State::KeywordOrIdentifier => self.accept_keyword_or_identifier(),
@@ -287,7 +385,7 @@ impl Lexer {
         let c = match self.pop() {
             None => {
                 if !self.is_paren_balanced() {
-                    return State::Err("unclosed left parenthesis".into());
+                    return State::Err(errors::UNCLOSED_LEFT_PAREN.into());
                 }
 
                 if !self.is_eof() {
@@ -318,13 +416,13 @@ impl Lexer {
                     State::Lexeme(T_EQLC)
                 }
                 // =~ (label matcher) MUST be in brace
-                Some('~') => State::Err("unexpected character after '=': '~'".into()),
+                Some('~') => State::Err(errors::UNEXPECTED_TILDE_AFTER_EQL.into()),
                 _ => State::Lexeme(T_EQL),
             },
             '!' => match self.pop() {
                 Some('=') => State::Lexeme(T_NEQ),
                 Some(ch) => State::Err(format!("unexpected character after '!': '{ch}'")),
-                None => State::Err("'!' can not be at the end".into()),
+                None => State::Err(errors::BANG_AT_END.into()),
             },
             '<' => match self.peek() {
                 Some('=') => {
@@ -345,7 +443,7 @@ impl Lexer {
             '.' => match self.peek() {
                 Some(ch) if ch.is_ascii_digit() => State::NumberOrDuration,
                 Some(ch) => State::Err(format!("unexpected character after '.': '{ch}'")),
-                None => State::Err("unexpected character: '.'".into()),
+                None => State::Err(errors::UNEXPECTED_DOT.into()),
             },
             ch if is_alpha(ch) || ch == ':' => State::KeywordOrIdentifier,
             ch if STRING_SYMBOLS.contains(ch) => State::String(ch),
@@ -353,30 +451,30 @@ impl Lexer {
                 if self.inc_paren_depth() {
                     return State::Lexeme(T_LEFT_PAREN);
                 }
-                State::Err("too many left parentheses".into())
+                State::Err(errors::TOO_MANY_LEFT_PARENS.into())
             }
             ')' => {
                 if self.is_paren_balanced() {
-                    return State::Err("unexpected right parenthesis ')'".into());
+                    return State::Err(errors::UNEXPECTED_RIGHT_PAREN.into());
                 }
                 if self.dec_paren_depth() {
                     return State::Lexeme(T_RIGHT_PAREN);
                 }
-                State::Err("unexpected right parenthesis ')'".into())
+                State::Err(errors::UNEXPECTED_RIGHT_PAREN.into())
             }
             '{' => {
                 self.dive_into_braces();
                 State::Lexeme(T_LEFT_BRACE)
             }
             // the matched } has been consumed inside braces
-            '}' => State::Err("unexpected right brace '}'".into()),
+            '}' => State::Err(errors::UNEXPECTED_RIGHT_BRACE.into()),
             '[' => {
                 self.reset_colon_scanned();
                 self.dive_into_brackets();
                 State::Lexeme(T_LEFT_BRACKET)
             }
             // the matched ] has been consumed inside brackets
-            ']' => State::Err("unexpected right bracket ']'".into()),
+            ']' => State::Err(errors::UNEXPECTED_RIGHT_BRACKET.into()),
             ch => State::Err(format!("unexpected character: {ch:?}")),
         }
     }
@@ -385,10 +483,14 @@ impl Lexer {
     fn accept_duration(&mut self) -> State {
         self.backup();
         self.scan_number();
+        let number = self.lexeme_string();
         if !self.accept_remaining_duration() {
             self.pop(); // this is to include the bad syntax
             return State::Err(format!("bad duration syntax: {}", self.lexeme_string()));
         }
+        if !is_integer(&number) {
+            return State::Err(format!("bad duration syntax: {}", self.lexeme_string()));
+        }
         State::Lexeme(T_DURATION)
     }
 
@@ -400,7 +502,12 @@ impl Lexer {
         }
 
         // Next two chars must be a valid unit and a non-alphanumeric.
+        let number = self.lexeme_string();
         if self.accept_remaining_duration() {
+            // durations are made of integers, like `5m`, not `1.5m` or `5e3s`
+            if !is_integer(&number) {
+                return State::Err(format!("bad duration syntax: {}", self.lexeme_string()));
+            }
             return State::Lexeme(T_DURATION);
         }
 
@@ -540,7 +647,7 @@ impl Lexer {
         match self.pop() {
             Some(ch) if ch == symbol || ESCAPE_SYMBOLS.contains(ch) => State::String(symbol),
             Some(ch) => State::Err(format!("unknown escape sequence '{ch}'")),
-            None => State::Err("escape sequence not terminated".into()),
+            None => State::Err(errors::ESCAPE_NOT_TERMINATED.into()),
         }
     }
 
@@ -568,7 +675,10 @@ impl Lexer {
             Some('o') | Some('O') => {
                 if let Some('r') | Some('R') = self.peek() {
                     self.pop();
-                    if let Some(' ') = self.peek() {
+                    // `or` followed by a matching operator is a label, like `{or = "a"}`
+                    if matches!(self.peek(), Some(ch) if ch.is_ascii_whitespace())
+                        && !matches!(self.peek_past_spaces(), Some('=') | Some('!'))
+                    {
                         State::Lexeme(T_LOR)
                     } else {
                         State::Identifier
@@ -593,15 +703,15 @@ impl Lexer {
                 Some(ch) => State::Err(format!(
                     "unexpected character after '!' inside braces: '{ch}'"
                 )),
-                None => State::Err("'!' can not be at the end".into()),
+                None => State::Err(errors::BANG_AT_END.into()),
             },
-            Some('{') => State::Err("unexpected left brace '{' inside braces".into()),
+            Some('{') => State::Err(errors::LEFT_BRACE_INSIDE_BRACES.into()),
             Some('}') => {
                 self.jump_outof_braces();
                 State::Lexeme(T_RIGHT_BRACE)
             }
             Some(ch) => State::Err(format!("unexpected character inside braces: '{ch}'")),
-            None => State::Err("unexpected end of input inside braces".into()),
+            None => State::Err(errors::END_INSIDE_BRACES.into()),
         }
     }
 
@@ -634,11 +744,11 @@ impl Lexer {
             Some(ch) if ch.is_ascii_whitespace() => State::Space,
             Some(':') => {
                 if self.is_colon_scanned() {
-                    return State::Err("unexpected second colon(:) in brackets".into());
+                    return State::Err(errors::SECOND_COLON_IN_BRACKETS.into());
                 }
 
                 if self.is_colon_the_first_char_in_brackets() {
-                    return State::Err("expect duration before first colon(:) in brackets".into());
+                    return State::Err(errors::COLON_WITHOUT_DURATION.into());
                 }
 
                 self.set_colon_scanned();
@@ -650,9 +760,9 @@ impl Lexer {
                 self.reset_colon_scanned();
                 State::Lexeme(T_RIGHT_BRACKET)
             }
-            Some('[') => State::Err("unexpected left brace '[' inside brackets".into()),
+            Some('[') => State::Err(errors::LEFT_BRACKET_INSIDE_BRACKETS.into()),
             Some(ch) => State::Err(format!("unexpected character inside brackets: '{ch}'")),
-            None => State::Err("unexpected end of input inside brackets".into()),
+            None => State::Err(errors::END_INSIDE_BRACKETS.into()),
         }
     }
 
@@ -665,7 +775,7 @@ impl Lexer {
 }
 
 // TODO: reference iterator
-impl Iterator for Lexer {
+impl Iterator for Lexer<'_> {
     type Item = Result<LexemeType, String>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -679,6 +789,10 @@ impl Iterator for Lexer {
     }
 }
 
+fn is_integer(s: &str) -> bool {
+    s.chars().all(|ch| ch.is_ascii_digit())
+}
+
 fn is_alpha_numeric(ch: char) -> bool {
     is_alpha(ch) || ch.is_ascii_digit()
 }
@@ -687,6 +801,47 @@ fn is_alpha(ch: char) -> bool {
     ch == '_' || ch.is_ascii_alphabetic()
 }
 
+/// whether the metric name can be written without quotes, like `foo:bar`,
+/// while names like `http.requests` must be quoted, i.e. `{"http.requests"}`.
+/// Keywords the `metric_identifier` rule of the grammar doesn't take back as names,
+/// and `inf`/`nan` in any case, must be quoted as well, e.g. `{"bool"}` or `{"NaN"}`,
+/// while aggregators like `sum` are fine.
+pub(crate) fn is_metric_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    let is_identifier = match chars.next() {
+        Some(ch) if is_alpha(ch) || ch == ':' => chars.all(|ch| is_alpha_numeric(ch) || ch == ':'),
+        _ => false,
+    };
+    is_identifier
+        && match get_keyword_token(&s.to_lowercase()) {
+            None => true,
+            Some(id) => {
+                TokenType::new(id).is_aggregator()
+                    || matches!(
+                        id,
+                        T_LAND | T_LOR | T_LUNLESS | T_BY | T_WITHOUT | T_OFFSET | T_START | T_END
+                    )
+            }
+        }
+}
+
+/// whether the text, escapes included, can be put between the quote symbols as is,
+/// i.e. it has neither a bare quote symbol nor an escape sequence unknown to the lexer.
+pub(crate) fn is_valid_in_quotes(s: &str, symbol: char) -> bool {
+    let mut chars = s.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.next() {
+                Some(ch) if ch == symbol || ESCAPE_SYMBOLS.contains(ch) => (),
+                _ => return false,
+            },
+            ch if ch == symbol => return false,
+            _ => (),
+        }
+    }
+    true
+}
+
 pub(crate) fn is_label(s: &str) -> bool {
     if s.is_empty() {
         return false;
@@ -871,6 +1026,58 @@ mod tests {
             ("1h", vec![(T_DURATION, 0, 2)], None),
             ("3w", vec![(T_DURATION, 0, 2)], None),
             ("1y", vec![(T_DURATION, 0, 2)], None),
+            ("5e3s", vec![], Some("bad duration syntax: 5e3s")),
+            ("1.5e2m", vec![], Some("bad duration syntax: 1.5e2m")),
+            ("1.5m", vec![], Some("bad duration syntax: 1.5m")),
+            ("0x1fs", vec![], Some("bad duration syntax: 0x1fs")),
+            ("5e3", vec![(T_NUMBER, 0, 3)], None),
+        ];
+        assert_matches(cases);
+    }
+
+    #[test]
+    fn test_signed_offsets() {
+        let cases = vec![
+            (
+                "foo offset -7m",
+                vec![
+                    (T_IDENTIFIER, 0, 3),
+                    (T_OFFSET, 4, 6),
+                    (T_SUB, 11, 1),
+                    (T_DURATION, 12, 2),
+                ],
+                None,
+            ),
+            (
+                "foo offset - 7m",
+                vec![
+                    (T_IDENTIFIER, 0, 3),
+                    (T_OFFSET, 4, 6),
+                    (T_SUB, 11, 1),
+                    (T_DURATION, 13, 2),
+                ],
+                None,
+            ),
+            (
+                "foo offset +7m",
+                vec![
+                    (T_IDENTIFIER, 0, 3),
+                    (T_OFFSET, 4, 6),
+                    (T_ADD, 11, 1),
+                    (T_DURATION, 12, 2),
+                ],
+                None,
+            ),
+            (
+                "foo offset + 7m",
+                vec![
+                    (T_IDENTIFIER, 0, 3),
+                    (T_OFFSET, 4, 6),
+                    (T_ADD, 11, 1),
+                    (T_DURATION, 13, 2),
+                ],
+                None,
+            ),
         ];
         assert_matches(cases);
     }
@@ -1054,6 +1261,65 @@ mod tests {
         assert_matches(cases);
     }
 
+    #[test]
+    fn test_whitespace_inside_braces() {
+        let cases = vec![
+            (
+                "{\tfoo\n=\r'bar'\t}",
+                vec![
+                    (T_LEFT_BRACE, 0, 1),
+                    (T_IDENTIFIER, 2, 3),
+                    (T_EQL, 6, 1),
+                    (T_STRING, 9, 3),
+                    (T_RIGHT_BRACE, 14, 1),
+                ],
+                None,
+            ),
+            (
+                "{a='b' or\tc='d'}",
+                vec![
+                    (T_LEFT_BRACE, 0, 1),
+                    (T_IDENTIFIER, 1, 1),
+                    (T_EQL, 2, 1),
+                    (T_STRING, 4, 1),
+                    (T_LOR, 7, 2),
+                    (T_IDENTIFIER, 10, 1),
+                    (T_EQL, 11, 1),
+                    (T_STRING, 13, 1),
+                    (T_RIGHT_BRACE, 15, 1),
+                ],
+                None,
+            ),
+            (
+                "{or = 'b'}",
+                vec![
+                    (T_LEFT_BRACE, 0, 1),
+                    (T_IDENTIFIER, 1, 2),
+                    (T_EQL, 4, 1),
+                    (T_STRING, 7, 1),
+                    (T_RIGHT_BRACE, 9, 1),
+                ],
+                None,
+            ),
+            // like at the top level, only ASCII whitespace is skipped
+            (
+                "{foo\u{a0}='bar'}",
+                vec![(T_LEFT_BRACE, 0, 1), (T_IDENTIFIER, 1, 3)],
+                Some("unexpected character inside braces: '\u{a0}'"),
+            ),
+            (
+                "{\u{2003}foo='bar'}",
+                vec![(T_LEFT_BRACE, 0, 1)],
+                Some("unexpected character inside braces: '\u{2003}'"),
+            ),
+        ];
+        assert_matches(cases);
+
+        for input in ["foo\u{a0}", "\u{2003}foo", "foo\u{a0}{a='b'}"] {
+            assert!(Lexer::new(input).any(|r| r.is_err()), "\n<input>: {input}");
+        }
+    }
+
     #[test]
     fn test_common_errors() {
         let cases = vec![
@@ -1402,4 +1668,36 @@ mod tests {
         assert!(!is_label("0up"));
         assert!(!is_label("0_up"));
     }
+
+    #[test]
+    fn test_is_valid_in_quotes() {
+        assert!(is_valid_in_quotes("abc", '"'));
+        assert!(is_valid_in_quotes(r#"a\"b\n"#, '"'));
+        assert!(is_valid_in_quotes(r#"a"b"#, '\''));
+        assert!(is_valid_in_quotes(r"it\'s", '\''));
+
+        assert!(!is_valid_in_quotes(r#"a"b"#, '"'));
+        assert!(!is_valid_in_quotes(r"it\'s", '"'));
+        assert!(!is_valid_in_quotes(r"a\q", '"'));
+        assert!(!is_valid_in_quotes(r"a\", '"'));
+    }
+
+    #[test]
+    fn test_is_metric_name() {
+        assert!(is_metric_name("up"));
+        assert!(is_metric_name(":bc"));
+        assert!(is_metric_name("foo:bar_1"));
+
+        assert!(!is_metric_name(""));
+        assert!(!is_metric_name("1up"));
+        assert!(!is_metric_name("http.requests"));
+        assert!(!is_metric_name("a-b"));
+        assert!(!is_metric_name("inf"));
+        assert!(!is_metric_name("NaN"));
+        assert!(!is_metric_name("bool"));
+        assert!(!is_metric_name("group_left"));
+        assert!(!is_metric_name("atan2"));
+        assert!(is_metric_name("sum"));
+        assert!(is_metric_name("offset"));
+    }
 }
@@ -0,0 +1,321 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Heuristic checks of valid expressions which are likely to be mistakes,
+//! see [`Expr::lints`].
+
+use crate::label::METRIC_NAME;
+use crate::parser::token::{T_BOTTOMK, T_COUNT_VALUES, T_TOPK};
+use crate::parser::{BinaryExpr, Call, Expr, FunctionId, LabelModifier, VectorMatchCardinality};
+use std::fmt;
+
+/// A warning about a valid expression, which is likely not what the author means.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// the message of the warning
+    pub message: String,
+    /// the offending expression, rendered as string
+    pub expr: String,
+}
+
+impl Lint {
+    fn new(message: String, expr: &Expr) -> Self {
+        Self {
+            message,
+            expr: expr.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in '{}'", self.message, self.expr)
+    }
+}
+
+/// run all the lint rules against every node of the expression.
+pub(crate) fn lint(expr: &Expr) -> Vec<Lint> {
+    let mut lints = vec![];
+    expr.for_each_node(&mut |node| {
+        if let Expr::Binary(ex) = node {
+            lint_matching_labels(ex, node, &mut lints);
+            lint_implicit_many_to_many(ex, node, &mut lints);
+            lint_grouping_labels(ex, node, &mut lints);
+        }
+        if let Expr::Call(call) = node {
+            lint_histogram_quantile(call, node, &mut lints);
+        }
+    });
+    lints
+}
+
+/// run only the histogram lint rules, see [`Expr::validate_native_histogram_usage`].
+pub(crate) fn lint_histograms(expr: &Expr) -> Vec<Lint> {
+    let mut lints = vec![];
+    expr.for_each_node(&mut |node| {
+        if let Expr::Call(call) = node {
+            lint_histogram_quantile(call, node, &mut lints);
+        }
+    });
+    lints
+}
+
+/// `on()` labels which can't be present on the operands.
+fn lint_matching_labels(ex: &BinaryExpr, node: &Expr, lints: &mut Vec<Lint>) {
+    let labels = match ex.modifier.as_ref().and_then(|m| m.matching.as_ref()) {
+        Some(LabelModifier::Include(labels)) => labels,
+        _ => return,
+    };
+
+    for label in &labels.labels {
+        // the metric name only pairs series of the same metric, which makes
+        // sense for filtering with set operators, but hardly for arithmetic.
+        if label == METRIC_NAME && !ex.op.is_set_operator() {
+            lints.push(Lint::new(
+                format!("on({METRIC_NAME}) only matches series with the same metric name"),
+                node,
+            ));
+            continue;
+        }
+
+        for (side, operand) in [("left", &ex.lhs), ("right", &ex.rhs)] {
+            if drops_label(operand, label) {
+                lints.push(Lint::new(
+                    format!("label '{label}' in on() is never present on the {side} side"),
+                    node,
+                ));
+            }
+        }
+    }
+}
+
+/// set operators between vectors are silently upgraded to many-to-many matching,
+/// which can't be written explicitly, so relying on it is worth a warning.
+fn lint_implicit_many_to_many(ex: &BinaryExpr, node: &Expr, lints: &mut Vec<Lint>) {
+    let card = ex.modifier.as_ref().map(|m| &m.card);
+    if ex.op.is_set_operator() && card == Some(&VectorMatchCardinality::ManyToMany) {
+        lints.push(Lint::new(
+            format!("set operator '{}' implicitly matches many-to-many", ex.op),
+            node,
+        ));
+    }
+}
+
+/// `group_left()`/`group_right()` labels which are also `ignoring()` labels. The
+/// overlap with `on()` labels is rejected by the parser already.
+fn lint_grouping_labels(ex: &BinaryExpr, node: &Expr, lints: &mut Vec<Lint>) {
+    let modifier = match &ex.modifier {
+        Some(modifier) => modifier,
+        None => return,
+    };
+    let (ignoring, grouping) = match (&modifier.matching, modifier.card.labels()) {
+        (Some(LabelModifier::Exclude(ignoring)), Some(grouping)) => (ignoring, grouping),
+        _ => return,
+    };
+    let group = match modifier.card {
+        VectorMatchCardinality::OneToMany(_) => "group_right",
+        _ => "group_left",
+    };
+
+    for label in &grouping.labels {
+        if ignoring.labels.contains(label) {
+            lints.push(Lint::new(
+                format!("label '{label}' in {group}() is also in ignoring()"),
+                node,
+            ));
+        }
+    }
+}
+
+/// `histogram_quantile()` over the `_bucket` series of a classic histogram, with an
+/// aggregation dropping the `le` label the quantile is computed from. Native
+/// histograms have no `le` label, so any aggregation of them is fine.
+fn lint_histogram_quantile(call: &Call, node: &Expr, lints: &mut Vec<Lint>) {
+    if call.func.id() != Some(FunctionId::HistogramQuantile) {
+        return;
+    }
+    let buckets = match call.args.args.get(1) {
+        Some(buckets) => buckets,
+        None => return,
+    };
+
+    let mut classic = false;
+    buckets.for_each_selector(&mut |vs| {
+        classic |= matches!(&vs.name, Some(name) if name.ends_with("_bucket"));
+    });
+    if classic && drops_label(buckets, "le") {
+        lints.push(Lint::new(
+            String::from(
+                "histogram_quantile() over classic histogram buckets must keep the 'le' label",
+            ),
+            node,
+        ));
+    }
+}
+
+/// whether the result of the expression can never have the label,
+/// which is only known for the output of aggregations.
+fn drops_label(expr: &Expr, label: &str) -> bool {
+    match expr {
+        Expr::Paren(ex) => drops_label(&ex.expr, label),
+        // topk and bottomk keep the labels of the input series
+        Expr::Aggregate(ex) if !matches!(ex.op.id(), T_TOPK | T_BOTTOMK) => {
+            let kept = match &ex.modifier {
+                Some(LabelModifier::Include(labels)) => labels.labels.iter().any(|l| l == label),
+                Some(LabelModifier::Exclude(labels)) => {
+                    label != METRIC_NAME && labels.labels.iter().all(|l| l != label)
+                }
+                None => false,
+            };
+            let counted = ex.op.id() == T_COUNT_VALUES
+                && matches!(ex.param.as_deref(), Some(Expr::StringLiteral(s)) if s.val == label);
+            !kept && !counted
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser;
+
+    #[test]
+    fn test_lint_matching_labels() {
+        let cases = vec![
+            (
+                "a + on(__name__) b",
+                vec!["on(__name__) only matches series with the same metric name in 'a + on (__name__) b'"],
+            ),
+            (
+                "a > on(job, __name__) group_left () b",
+                vec!["on(__name__) only matches series with the same metric name in 'a > on (job, __name__) group_left () b'"],
+            ),
+            (
+                "sum without (job) (a) / on(job) b",
+                vec!["label 'job' in on() is never present on the left side in 'sum without (job) (a) / on (job) b'"],
+            ),
+            (
+                "a - on(job) (sum by (instance) (b))",
+                vec!["label 'job' in on() is never present on the right side in 'a - on (job) (sum by (instance) (b))'"],
+            ),
+            (
+                "sum(a) * on(job) count(b)",
+                vec![
+                    "label 'job' in on() is never present on the left side in 'sum(a) * on (job) count(b)'",
+                    "label 'job' in on() is never present on the right side in 'sum(a) * on (job) count(b)'",
+                ],
+            ),
+            (
+                "a and on(__name__) b",
+                vec!["set operator 'and' implicitly matches many-to-many in 'a and on (__name__) b'"],
+            ),
+            ("a + on(job) b", vec![]),
+            ("a + ignoring(__name__) b", vec![]),
+            ("sum by (job) (a) / on(job) b", vec![]),
+            ("sum without (instance) (a) / on(job) b", vec![]),
+            ("topk(3, a) / on(job) b", vec![]),
+            (r#"count_values by (job) ("value", a) / on(value) b"#, vec![]),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            let lints: Vec<String> = expr.lints().iter().map(|l| l.to_string()).collect();
+            assert_eq!(expected, lints, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_lint_implicit_many_to_many() {
+        let cases = vec![
+            (
+                "foo and bar",
+                vec!["set operator 'and' implicitly matches many-to-many in 'foo and bar'"],
+            ),
+            (
+                "foo or ignoring (job) bar",
+                vec!["set operator 'or' implicitly matches many-to-many in 'foo or ignoring (job) bar'"],
+            ),
+            (
+                "foo unless on (job) bar",
+                vec!["set operator 'unless' implicitly matches many-to-many in 'foo unless on (job) bar'"],
+            ),
+            ("foo + bar", vec![]),
+            ("foo == on (job) group_left () bar", vec![]),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            let lints: Vec<String> = expr.lints().iter().map(|l| l.to_string()).collect();
+            assert_eq!(expected, lints, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_lint_grouping_labels() {
+        let cases = vec![
+            (
+                "foo * ignoring (job, env) group_left (env) bar",
+                vec!["label 'env' in group_left() is also in ignoring() in 'foo * ignoring (job, env) group_left (env) bar'"],
+            ),
+            (
+                "foo / ignoring (job) group_right (job, instance) bar",
+                vec!["label 'job' in group_right() is also in ignoring() in 'foo / ignoring (job) group_right (job, instance) bar'"],
+            ),
+            ("foo * ignoring (job) group_left (env) bar", vec![]),
+            ("foo * on (job) group_left (env) bar", vec![]),
+            ("foo * ignoring (job) bar", vec![]),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            let lints: Vec<String> = expr.lints().iter().map(|l| l.to_string()).collect();
+            assert_eq!(expected, lints, "{input}");
+        }
+    }
+
+    #[test]
+    fn test_lint_histogram_quantile() {
+        let cases = vec![
+            (
+                "histogram_quantile(0.9, sum(rate(foo_bucket[5m])))",
+                vec!["histogram_quantile() over classic histogram buckets must keep the 'le' label in 'histogram_quantile(0.9, sum(rate(foo_bucket[5m])))'"],
+            ),
+            (
+                "histogram_quantile(0.9, sum by (job) (rate(foo_bucket[5m])))",
+                vec!["histogram_quantile() over classic histogram buckets must keep the 'le' label in 'histogram_quantile(0.9, sum by (job) (rate(foo_bucket[5m])))'"],
+            ),
+            (
+                "histogram_quantile(0.9, (sum without (le) (rate(foo_bucket[5m]))))",
+                vec!["histogram_quantile() over classic histogram buckets must keep the 'le' label in 'histogram_quantile(0.9, (sum without (le) (rate(foo_bucket[5m]))))'"],
+            ),
+            ("histogram_quantile(0.9, sum by (job, le) (rate(foo_bucket[5m])))", vec![]),
+            ("histogram_quantile(0.9, sum without (job) (rate(foo_bucket[5m])))", vec![]),
+            ("histogram_quantile(0.9, rate(foo_bucket[5m]))", vec![]),
+            ("histogram_quantile(0.9, sum(rate(foo[5m])))", vec![]),
+            ("histogram_quantile(0.9, sum by (job) (rate(foo[5m])))", vec![]),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            let lints: Vec<String> = expr.lints().iter().map(|l| l.to_string()).collect();
+            assert_eq!(expected, lints, "{input}");
+            let lints: Vec<String> = expr
+                .validate_native_histogram_usage()
+                .iter()
+                .map(|l| l.to_string())
+                .collect();
+            assert_eq!(expected, lints, "{input}");
+        }
+    }
+}
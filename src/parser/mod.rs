@@ -20,25 +20,34 @@
 //! Notes that in PromQL the parsed [`Expr`] is only a part of an query. It would also needs other
 //! parameters like "start"/"end" time or "step" time etc, which is included in [`EvalStmt`].
 
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
 pub mod ast;
+pub mod errors;
 pub mod function;
 pub mod lex;
+pub mod lint;
 pub mod parse;
 pub(crate) mod production;
 pub mod token;
 pub mod value;
 
+#[cfg(feature = "comment-extension")]
+pub use ast::CommentExtension;
 pub use ast::{
-    AggregateExpr, AtModifier, BinModifier, BinaryExpr, Call, EvalStmt, Expr, Extension,
-    LabelModifier, MatrixSelector, NumberLiteral, Offset, ParenExpr, StringLiteral, SubqueryExpr,
-    UnaryExpr, VectorMatchCardinality, VectorSelector,
+    AggregateExpr, AtModifier, BinModifier, BinaryExpr, Call, CallBuilder, EvalStmt, Expr,
+    Extension, GroupingResult, LabelModifier, MatrixSelector, NumberFormat, NumberLiteral, Offset,
+    ParenExpr, QueryView, StringLiteral, SubqueryExpr, UnaryExpr, VectorMatchCardinality,
+    VectorSelector,
 };
-pub use function::{Function, FunctionArgs};
+pub use function::{Function, FunctionArgs, FunctionId};
 pub use lex::lexer;
-pub use parse::parse;
+pub use lint::Lint;
+pub use parse::{
+    canonicalize, parse, parse_reader, parse_rule, parse_with_options, parse_with_passes,
+    ParseOptions, PostParsePass,
+};
 
-// FIXME: show more helpful error message to some invalid promql queries.
-const INVALID_QUERY_INFO: &str = "invalid promql query";
 const INDENT_STR: &str = "  ";
 const MAX_CHARACTERS_PER_LINE: usize = 100;
 
@@ -85,6 +94,23 @@ pub trait Prettier: std::fmt::Display {
     }
 }
 
+/// Options of [`Expr::prettify_with`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrettyConfig {
+    pub style: PrettyStyle,
+}
+
+/// The layout of the prettified expression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PrettyStyle {
+    /// the layout of [`Expr::prettify`], operators sit between the indented operands
+    #[default]
+    Default,
+    /// chains of binary operators are put one operand per line, with the operators
+    /// right-aligned in a column before the operands
+    OperatorColumn,
+}
+
 fn indent(n: usize) -> String {
     INDENT_STR.repeat(n)
 }
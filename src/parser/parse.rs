@@ -12,20 +12,249 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::parser::{lex, Expr, INVALID_QUERY_INFO};
+use crate::parser::errors::INVALID_QUERY;
+use crate::parser::token::{
+    TokenType, T_ADD, T_DURATION, T_IDENTIFIER, T_LEFT_BRACE, T_RIGHT_BRACE, T_SUB,
+};
+use crate::parser::{lex, Expr};
+use crate::util::{parse_duration, parse_str_radix};
+use lrpar::Lexeme;
+use std::borrow::Cow;
+use std::fmt::{self, Write};
+use std::io::BufRead;
+use std::time::Duration;
 
 /// Parse the given query literal to an AST (which is [`Expr`] in this crate).
 pub fn parse(input: &str) -> Result<Expr, String> {
-    match lex::lexer(input) {
-        Err(e) => Err(e),
-        Ok(lexer) => {
-            // NOTE: the errs is ignored so far.
-            let (res, _errs) = crate::promql_y::parse(&lexer);
-            res.ok_or_else(|| String::from(INVALID_QUERY_INFO))?
+    parse_with_options(input, &ParseOptions::default())
+}
+
+/// Parse a rule definition in the form of `<expr> [for <duration>]`, like the expression
+/// and the pending duration of an alerting rule, e.g. `up == 0 for 5m`.
+pub fn parse_rule(input: &str) -> Result<(Expr, Option<Duration>), String> {
+    let input = strip_bom(input);
+    let lexemes = lex::lexemes(input)?;
+    if let [.., for_lexeme, duration_lexeme, _eof] = lexemes.as_slice() {
+        let for_span = for_lexeme.span();
+        if for_lexeme.tok_id() == T_IDENTIFIER
+            && duration_lexeme.tok_id() == T_DURATION
+            && input[for_span.start()..for_span.end()].eq_ignore_ascii_case("for")
+        {
+            let duration_span = duration_lexeme.span();
+            let duration = parse_duration(&input[duration_span.start()..duration_span.end()])?;
+            let expr = parse(&input[..for_span.start()])?;
+            return Ok((expr, Some(duration)));
+        }
+    }
+
+    parse(input).map(|expr| (expr, None))
+}
+
+/// Parse the queries of a reader, one per line, without loading the whole input.
+/// Blank lines and comment-only lines starting with `#` are skipped. Errors, either
+/// from reading or from parsing, are prefixed with the 1-based line number.
+pub fn parse_reader<R: BufRead>(reader: R) -> impl Iterator<Item = Result<Expr, String>> {
+    reader.lines().enumerate().filter_map(|(idx, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(format!("line {}: {e}", idx + 1))),
+        };
+        let query = line.trim();
+        if query.is_empty() || query.starts_with('#') {
+            return None;
         }
+        Some(parse(query).map_err(|e| format!("line {}: {e}", idx + 1)))
+    })
+}
+
+/// Parse the query and render it back in the canonical form, which is the same as
+/// `parse(input)?.to_string()`. The input is returned as is if it is already canonical,
+/// so no allocation is needed for the result in that case.
+pub fn canonicalize(input: &str) -> Result<Cow<'_, str>, String> {
+    /// compares the rendering with the input as it is written, failing on the first
+    /// difference, so that nothing is allocated for canonical input.
+    struct Compare<'a>(&'a str);
+
+    impl fmt::Write for Compare<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 = self.0.strip_prefix(s).ok_or(fmt::Error)?;
+            Ok(())
+        }
+    }
+
+    let expr = parse(input)?;
+    let mut rest = Compare(input);
+    if write!(rest, "{expr}").is_ok() && rest.0.is_empty() {
+        Ok(Cow::Borrowed(input))
+    } else {
+        Ok(Cow::Owned(expr.to_string()))
     }
 }
 
+/// Options to tweak the behavior of [`parse_with_options`]. The default options
+/// behave exactly like [`parse`], which follows Prometheus.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Accept some queries Prometheus rejects and normalize them, e.g.
+    /// `foo offset 1m[5m]` is parsed as `foo[5m] offset 1m`.
+    pub lenient: bool,
+    /// Reject the query if any vector selector has more matchers than the limit,
+    /// counting the ones of all the `or` groups, which guards against untrusted
+    /// queries. None means no limit.
+    pub max_matchers_per_selector: Option<usize>,
+}
+
+impl ParseOptions {
+    pub fn with_lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    pub fn with_max_matchers_per_selector(mut self, max: Option<usize>) -> Self {
+        self.max_matchers_per_selector = max;
+        self
+    }
+}
+
+/// Parse the given query literal to an AST with the provided [`ParseOptions`].
+pub fn parse_with_options(input: &str, options: &ParseOptions) -> Result<Expr, String> {
+    let input = strip_bom(input);
+    if let Some(expr) = parse_literal(input) {
+        return Ok(expr);
+    }
+
+    let lexer = if options.lenient {
+        lex::lenient_lexer(input)?
+    } else {
+        lex::lexer(input)?
+    };
+
+    // NOTE: the errs is ignored so far.
+    let (res, _errs) = crate::promql_y::parse(&lexer);
+    let expr = match res {
+        Some(res) => res?,
+        None => {
+            check_operator_sequence(input)?;
+            return Err(String::from(INVALID_QUERY));
+        }
+    };
+
+    if let Some(max) = options.max_matchers_per_selector {
+        check_max_matchers(&expr, max)?;
+    }
+    Ok(expr)
+}
+
+/// remove the leading UTF-8 byte order mark, which editors may put in front of
+/// the pasted queries. Positions in errors are relative to the stripped input.
+fn strip_bom(input: &str) -> &str {
+    input.strip_prefix('\u{FEFF}').unwrap_or(input)
+}
+
+/// report the first binary operator which directly follows another one, like `<`
+/// in `1 +-<`, only `+` and `-` being allowed there as unary operators. Label
+/// matchers, where `or` separates the matcher groups, are not checked.
+fn check_operator_sequence(input: &str) -> Result<(), String> {
+    let lexemes = match lex::lexemes(input) {
+        Ok(lexemes) => lexemes,
+        Err(_) => return Ok(()),
+    };
+
+    let mut run_start = None;
+    let mut in_braces = false;
+    for lexeme in lexemes {
+        let span = lexeme.span();
+        let id = lexeme.tok_id();
+        match id {
+            T_LEFT_BRACE => in_braces = true,
+            T_RIGHT_BRACE => in_braces = false,
+            _ => (),
+        }
+        if in_braces || TokenType::new(id).op_class().is_none() {
+            run_start = None;
+            continue;
+        }
+        match run_start {
+            Some(start) if id != T_ADD && id != T_SUB => {
+                return Err(format!(
+                    "unexpected operator sequence '{}' at position {}",
+                    &input[start..span.end()],
+                    span.start()
+                ));
+            }
+            Some(_) => (),
+            None => run_start = Some(span.start()),
+        }
+    }
+    Ok(())
+}
+
+fn check_max_matchers(expr: &Expr, max: usize) -> Result<(), String> {
+    let mut checked = Ok(());
+    expr.for_each_selector(&mut |vs| {
+        let matchers = &vs.matchers;
+        let count =
+            matchers.matchers.len() + matchers.or_matchers.iter().map(Vec::len).sum::<usize>();
+        if count > max && checked.is_ok() {
+            checked = Err(format!(
+                "vector selector has {count} matchers, exceeding the limit of {max}"
+            ));
+        }
+    });
+    checked
+}
+
+/// Fast path for the input which is a single number or string literal, like `1`,
+/// `Inf` or `"foo"`, skipping the lexer and the parser. The [`Expr`] is built the
+/// same way as the grammar does, and None is returned for anything else, including
+/// the inputs which are not obviously valid, so they get the errors of the general path.
+fn parse_literal(input: &str) -> Option<Expr> {
+    let s = input.trim_matches(|c: char| c.is_ascii_whitespace());
+
+    let is_number = if s.eq_ignore_ascii_case("inf") || s.eq_ignore_ascii_case("nan") {
+        true
+    } else if let Some(hex) = s.strip_prefix("0x") {
+        !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit())
+    } else {
+        // digits with an optional fraction, exponents are left to the lexer
+        let (int, frac) = s.split_once('.').unwrap_or((s, "0"));
+        !int.is_empty()
+            && int.chars().all(|c| c.is_ascii_digit())
+            && !frac.is_empty()
+            && frac.chars().all(|c| c.is_ascii_digit())
+    };
+    if is_number {
+        return parse_str_radix(s).ok().map(Expr::from);
+    }
+
+    // quoted string without escapes, the quotes are excluded from the value
+    let quote = s.chars().next()?;
+    if matches!(quote, '"' | '\'' | '`') && s.len() >= 2 && s.ends_with(quote) {
+        let val = &s[1..s.len() - 1];
+        if !val.contains([quote, '\\']) {
+            return Some(Expr::from(val));
+        }
+    }
+    None
+}
+
+/// A transformation of the [`Expr`] run by [`parse_with_passes`] after the query
+/// is parsed and checked, which lets downstream crates plug in their own
+/// normalizations or experimental syntax, e.g. constant folding.
+pub trait PostParsePass {
+    fn run(&self, expr: &mut Expr) -> Result<(), String>;
+}
+
+/// Same as [`parse`], then run the passes in order against the parsed [`Expr`].
+/// The first error of the passes is returned.
+pub fn parse_with_passes(input: &str, passes: &[Box<dyn PostParsePass>]) -> Result<Expr, String> {
+    let mut expr = parse(input)?;
+    for pass in passes {
+        pass.run(&mut expr)?;
+    }
+    Ok(expr)
+}
+
 /// cases in original prometheus is a huge slices which are constructed more than 3000 lines,
 /// and it is hard to split them based on the original order. So here is the Note:
 ///
@@ -38,12 +267,14 @@ mod tests {
 
     use crate::label::{Labels, MatchOp, Matcher, Matchers, METRIC_NAME};
     use crate::parser;
+    use crate::parser::errors::{self, INVALID_QUERY, KEEP_COMMON_UNSUPPORTED};
     use crate::parser::function::get_function;
     use crate::parser::{
         token, AtModifier as At, BinModifier, Expr, FunctionArgs, LabelModifier, Offset,
-        VectorMatchCardinality, VectorSelector, INVALID_QUERY_INFO,
+        VectorMatchCardinality, VectorSelector,
     };
     use crate::util::duration;
+    use std::borrow::Cow;
     use std::time::Duration;
     use std::vec;
 
@@ -316,6 +547,32 @@ mod tests {
                     Expr::from(1.0),
                 ),
             ),
+            (
+                "foo == bool on(bar) baz",
+                Expr::new_binary_expr(
+                    Expr::from(VectorSelector::from("foo")),
+                    token::T_EQLC,
+                    Some(
+                        BinModifier::default()
+                            .with_return_bool(true)
+                            .with_matching(Some(LabelModifier::include(vec!["bar"]))),
+                    ),
+                    Expr::from(VectorSelector::from("baz")),
+                ),
+            ),
+            (
+                "foo != bool ignoring(bar) baz",
+                Expr::new_binary_expr(
+                    Expr::from(VectorSelector::from("foo")),
+                    token::T_NEQ,
+                    Some(
+                        BinModifier::default()
+                            .with_return_bool(true)
+                            .with_matching(Some(LabelModifier::exclude(vec!["bar"]))),
+                    ),
+                    Expr::from(VectorSelector::from("baz")),
+                ),
+            ),
             (
                 "2.5 / bar",
                 Expr::new_binary_expr(
@@ -705,6 +962,8 @@ mod tests {
                 "foo and bool 10",
                 "bool modifier can only be used on comparison operators",
             ),
+            ("foo == on(bar) bool baz", errors::BOOL_AFTER_MATCHING),
+            ("foo > ignoring(bar) bool baz", errors::BOOL_AFTER_MATCHING),
             (
                 "1 and 1",
                 "set operator 'and' not allowed in binary scalar expression",
@@ -740,9 +999,42 @@ mod tests {
                 " +some_metric",
                 Expr::from(VectorSelector::from("some_metric")),
             ),
+            ("++1", Expr::from(1.0)),
+            ("+-1", Expr::from(-1.0)),
+            ("-+1", Expr::from(-1.0)),
+            ("--1", Expr::from(1.0)),
+            (
+                "+ +some_metric",
+                Expr::from(VectorSelector::from("some_metric")),
+            ),
+            (
+                "-+some_metric",
+                Expr::new_unary_expr(Expr::from(VectorSelector::from("some_metric"))).unwrap(),
+            ),
+            (
+                "- -some_metric",
+                Expr::new_unary_expr(Expr::from(VectorSelector::from("some_metric")))
+                    .and_then(Expr::new_unary_expr)
+                    .unwrap(),
+            ),
         ];
         assert_cases(Case::new_expr_cases(cases));
 
+        // stacked unary operators render back to an equivalent expression
+        let cases = vec![
+            ("++1", "1"),
+            ("+-1", "-1"),
+            ("--1", "1"),
+            ("+ +some_metric", "some_metric"),
+            ("- -some_metric", "--some_metric"),
+            ("-+-some_metric", "--some_metric"),
+        ];
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+            assert_eq!(expr, parser::parse(expected).unwrap(), "{input}");
+        }
+
         let cases = vec![
             (r#"-"string""#, "unary expression only allowed on expressions of type scalar or vector, got: string"),
             ("-test[5m]", "unary expression only allowed on expressions of type scalar or vector, got: matrix"),
@@ -756,6 +1048,11 @@ mod tests {
         let cases = vec![
             ("foo", Ok(Expr::from(VectorSelector::from("foo")))),
             ("min", Ok(Expr::from(VectorSelector::from("min")))),
+            (":bc", Ok(Expr::from(VectorSelector::from(":bc")))),
+            (
+                "foo:bar:baz",
+                Ok(Expr::from(VectorSelector::from("foo:bar:baz"))),
+            ),
             (
                 "foo offset 5m",
                 Expr::from(VectorSelector::from("foo"))
@@ -766,6 +1063,21 @@ mod tests {
                 Expr::from(VectorSelector::from("foo"))
                     .offset_expr(Offset::Neg(Duration::from_secs(60 * 7))),
             ),
+            (
+                "foo offset - 7m",
+                Expr::from(VectorSelector::from("foo"))
+                    .offset_expr(Offset::Neg(Duration::from_secs(60 * 7))),
+            ),
+            (
+                "foo offset +7m",
+                Expr::from(VectorSelector::from("foo"))
+                    .offset_expr(Offset::Pos(Duration::from_secs(60 * 7))),
+            ),
+            (
+                "foo offset + 7m",
+                Expr::from(VectorSelector::from("foo"))
+                    .offset_expr(Offset::Pos(Duration::from_secs(60 * 7))),
+            ),
             (
                 "foo OFFSET 1h30m",
                 Expr::from(VectorSelector::from("foo"))
@@ -983,7 +1295,7 @@ mod tests {
             (
                 r#"foo{__name__="bar" lol}"#,
                 // "invalid label matcher, expected label matching operator after 'lol'",
-                INVALID_QUERY_INFO,
+                INVALID_QUERY,
             ),
         ];
         assert_cases(Case::new_fail_cases(fail_cases));
@@ -1053,6 +1365,14 @@ mod tests {
                 )
                 .and_then(|ex| ex.offset_expr(Offset::Pos(duration::WEEK_DURATION * 2))),
             ),
+            (
+                "test[5w] offset - 2w",
+                Expr::new_matrix_selector(
+                    Expr::from(VectorSelector::from("test")),
+                    duration::WEEK_DURATION * 5,
+                )
+                .and_then(|ex| ex.offset_expr(Offset::Neg(duration::WEEK_DURATION * 2))),
+            ),
             (r#"test{a="b"}[5y] OFFSET 3d"#, {
                 Expr::new_vector_selector(
                     Some(String::from("test")),
@@ -1073,13 +1393,17 @@ mod tests {
 
         assert_cases(Case::new_result_cases(cases));
 
-        let fail_cases = vec![
+        let mut fail_cases = vec![
             ("foo[5mm]", "bad duration syntax: 5mm"),
             ("foo[5m1]", "bad duration syntax: 5m1]"),
             ("foo[5m:1m1]", "bad duration syntax: 1m1]"),
             ("foo[5y1hs]", "not a valid duration string: 5y1hs"),
             ("foo[5m1h]", "not a valid duration string: 5m1h"),
             ("foo[5m1m]", "not a valid duration string: 5m1m"),
+            ("foo[5e3s]", "bad duration syntax: 5e3s"),
+            ("foo[1.5e2m]", "bad duration syntax: 1.5e2m"),
+            ("foo[5m:1.5m]", "bad duration syntax: 1.5m"),
+            ("foo offset 5e3s", "bad duration syntax: 5e3s"),
             ("foo[0m]", "duration must be greater than 0"),
             (
                 r#"foo["5m"]"#,
@@ -1103,10 +1427,6 @@ mod tests {
                 "some_metric OFFSET 1m[5m]",
                 "no offset modifiers allowed before range",
             ),
-            (
-                "some_metric[5m] @ 1m",
-                "unexpected duration '1m' in @, expected timestamp",
-            ),
             (
                 "some_metric[5m] @",
                 "unexpected end of input in @, expected timestamp",
@@ -1120,9 +1440,33 @@ mod tests {
                 "ranges only allowed for vector selectors",
             ),
         ];
+        if !cfg!(feature = "at-unit-suffix") {
+            fail_cases.push((
+                "some_metric[5m] @ 1m",
+                "unexpected duration '1m' in @, expected timestamp",
+            ));
+        }
         assert_cases(Case::new_fail_cases(fail_cases));
     }
 
+    #[test]
+    #[cfg(feature = "at-unit-suffix")]
+    fn test_at_unit_suffix() {
+        let cases = vec![
+            ("foo @ 100s", "foo @ 100"),
+            ("foo @ 500ms", "foo @ 0.5"),
+            ("foo[5m] @ 1m", "foo[5m] @ 60"),
+            ("foo[5m:] @ 1h30m", "foo[5m:] @ 5400"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parser::parse(input), parser::parse(expected), "{input}");
+        }
+
+        let expr = parser::parse("foo @ 500ms").unwrap();
+        let expected = At::At(std::time::SystemTime::UNIX_EPOCH + Duration::from_millis(500));
+        assert_eq!(Some(&expected), expr.effective_at());
+    }
+
     #[test]
     fn test_aggregation_expr() {
         let cases = vec![
@@ -1218,27 +1562,25 @@ mod tests {
         assert_cases(Case::new_result_cases(cases));
 
         let fail_cases = vec![
-            ("sum without(==)(some_metric)", INVALID_QUERY_INFO),
-            ("sum without(,)(some_metric)", INVALID_QUERY_INFO),
-            ("sum without(foo,,)(some_metric)", INVALID_QUERY_INFO),
-            ("sum some_metric by (test)", INVALID_QUERY_INFO),
-            ("sum (some_metric) by test", INVALID_QUERY_INFO),
+            ("sum without(==)(some_metric)", INVALID_QUERY),
+            ("sum without(,)(some_metric)", INVALID_QUERY),
+            ("sum without(foo,,)(some_metric)", INVALID_QUERY),
+            ("sum some_metric by (test)", INVALID_QUERY),
+            ("sum (some_metric) by test", INVALID_QUERY),
             (
                 "sum () by (test)",
                 "no arguments for aggregate expression 'sum' provided",
             ),
-            ("MIN keep_common (some_metric)", INVALID_QUERY_INFO),
-            ("MIN (some_metric) keep_common", INVALID_QUERY_INFO),
-            ("sum (some_metric) without (test) by (test)", INVALID_QUERY_INFO),
-            ("sum without (test) (some_metric) by (test)", INVALID_QUERY_INFO),
+            ("MIN keep_common (some_metric)", KEEP_COMMON_UNSUPPORTED),
+            ("MIN (some_metric) keep_common", KEEP_COMMON_UNSUPPORTED),
+            ("sum other (some_metric)", INVALID_QUERY),
+            ("sum (some_metric) without (test) by (test)", INVALID_QUERY),
+            ("sum without (test) (some_metric) by (test)", INVALID_QUERY),
             (
                 "topk(some_metric)",
                 "wrong number of arguments for aggregate expression provided, expected 2, got 1",
             ),
-            (
-                "topk(some_metric,)",
-                "trailing commas not allowed in function call args",
-            ),
+            ("topk(some_metric,)", errors::TRAILING_COMMA_IN_AGGREGATION),
             (
                 "topk(some_metric, other_metric)",
                 "expected type scalar in aggregation expression, got vector",
@@ -1249,7 +1591,7 @@ mod tests {
             ),
             (
                 "rate(some_metric[5m]) @ 1234",
-                "@ modifier must be preceded by an vector selector or matrix selector or a subquery"
+                "@ modifier must be preceded by a vector selector or matrix selector or a subquery",
             ),
         ];
         assert_cases(Case::new_fail_cases(fail_cases));
@@ -1452,6 +1794,38 @@ mod tests {
                     )
                 }),
             ),
+            (
+                r#"histogram_stddev(rate(nh[5m]))"#,
+                Expr::new_matrix_selector(
+                    Expr::from(VectorSelector::from("nh")),
+                    duration::MINUTE_DURATION * 5,
+                )
+                .and_then(|ex| {
+                    Expr::new_call(get_function("rate").unwrap(), FunctionArgs::new_args(ex))
+                })
+                .and_then(|ex| {
+                    Expr::new_call(
+                        get_function("histogram_stddev").unwrap(),
+                        FunctionArgs::new_args(ex),
+                    )
+                }),
+            ),
+            (
+                r#"histogram_stdvar(rate(nh[5m]))"#,
+                Expr::new_matrix_selector(
+                    Expr::from(VectorSelector::from("nh")),
+                    duration::MINUTE_DURATION * 5,
+                )
+                .and_then(|ex| {
+                    Expr::new_call(get_function("rate").unwrap(), FunctionArgs::new_args(ex))
+                })
+                .and_then(|ex| {
+                    Expr::new_call(
+                        get_function("histogram_stdvar").unwrap(),
+                        FunctionArgs::new_args(ex),
+                    )
+                }),
+            ),
             (r#"increase(http_requests_total{job="api-server"}[5m])"#, {
                 let name = String::from("http_requests_total");
                 let matchers = Matchers::one(Matcher::new(MatchOp::Equal, "job", "api-server"));
@@ -1516,6 +1890,29 @@ mod tests {
                     })
                 },
             ),
+            // an empty replacement removes the destination label
+            (
+                r#"label_replace(up, "foo", "", "bar", ".*")"#,
+                Expr::new_call(
+                    get_function("label_replace").unwrap(),
+                    FunctionArgs::new_args(Expr::from(VectorSelector::from("up")))
+                        .append_args(Expr::from("foo"))
+                        .append_args(Expr::from(""))
+                        .append_args(Expr::from("bar"))
+                        .append_args(Expr::from(".*")),
+                ),
+            ),
+            (
+                r#"label_replace(up, "", "", "", "")"#,
+                Expr::new_call(
+                    get_function("label_replace").unwrap(),
+                    FunctionArgs::new_args(Expr::from(VectorSelector::from("up")))
+                        .append_args(Expr::from(""))
+                        .append_args(Expr::from(""))
+                        .append_args(Expr::from(""))
+                        .append_args(Expr::from("")),
+                ),
+            ),
             // special cases
             (
                 r#"exp(+Inf)"#,
@@ -1648,6 +2045,9 @@ mod tests {
                 "non_existent_function_far_bar()",
                 "unknown function with name 'non_existent_function_far_bar'",
             ),
+            // function names are case-sensitive like Prometheus, unlike keywords
+            ("RATE(foo[5m])", "unknown function with name 'RATE'"),
+            ("Rate(foo[5m])", "unknown function with name 'Rate'"),
             (
                 "rate(some_metric)",
                 "expected type matrix in call to function 'rate', got vector",
@@ -1666,6 +2066,18 @@ mod tests {
                 "label_join()",
                 "expected at least 3 argument(s) in call to 'label_join', got 0",
             ),
+            (
+                r#"label_join(up, "dst", ",", "bad label")"#,
+                "invalid source label name in label_join(): 'bad label'",
+            ),
+            (
+                r#"label_join(up, "dst", ",", "src1", "")"#,
+                "invalid source label name in label_join(): ''",
+            ),
+            (
+                r#"label_join(up, "1dst", ",", "src1")"#,
+                "invalid destination label name in label_join(): '1dst'",
+            ),
             // (r#"label_replace(a, `b`, `c\xff`, `d`, `.*`)"#, ""),
         ];
         assert_cases(Case::new_fail_cases(fail_cases));
@@ -1919,6 +2331,16 @@ mod tests {
                 .and_then(|ex| ex.at_expr(At::try_from(123_f64).unwrap()))
                 .and_then(|ex| ex.offset_expr(Offset::Pos(duration::MINUTE_DURATION))),
             ),
+            (
+                "some_metric[10m:5s] @ 123 offset 1m",
+                Expr::new_subquery_expr(
+                    Expr::from(VectorSelector::from("some_metric")),
+                    duration::MINUTE_DURATION * 10,
+                    Some(Duration::from_secs(5)),
+                )
+                .and_then(|ex| ex.at_expr(At::try_from(123_f64).unwrap()))
+                .and_then(|ex| ex.offset_expr(Offset::Pos(duration::MINUTE_DURATION))),
+            ),
             (r#"(foo + bar{nm="val"})[5m:]"#, {
                 let matchers = Matchers::one(Matcher::new(MatchOp::Equal, "nm", "val"));
                 Expr::new_binary_expr(
@@ -2056,11 +2478,284 @@ mod tests {
         ];
         assert_cases(Case::new_result_cases(cases));
 
+        let cases = vec![("start()", INVALID_QUERY), ("end()", INVALID_QUERY)];
+        assert_cases(Case::new_fail_cases(cases));
+    }
+
+    #[test]
+    fn test_max_matchers_per_selector() {
+        let options = parser::ParseOptions::default().with_max_matchers_per_selector(Some(3));
+
         let cases = vec![
-            ("start()", INVALID_QUERY_INFO),
-            ("end()", INVALID_QUERY_INFO),
+            r#"foo{a="1",b="2",c="3"}"#,
+            r#"{a="1",b="2",c="3"}"#,
+            r#"sum(rate(foo{a="1",b="2"}[5m])) / bar{c="3",d="4",e="5"}"#,
+            r#"foo{a="1" or b="2" or c="3"}"#,
+            r#"foo{a="1",b="2",c="3"}[5m:1m] offset 1m"#,
+            "1",
+        ];
+        for input in cases {
+            assert_eq!(
+                parser::parse_with_options(input, &options),
+                parser::parse(input)
+            );
+        }
+
+        let cases = vec![
+            (
+                r#"foo{a="1",b="2",c="3",d="4"}"#,
+                "vector selector has 4 matchers, exceeding the limit of 3",
+            ),
+            (
+                r#"bar + rate(foo{a="1",b="2",c="3",d="4",e="5"}[5m])"#,
+                "vector selector has 5 matchers, exceeding the limit of 3",
+            ),
+            (
+                r#"foo{a="1",b="2" or c="3",d="4"}"#,
+                "vector selector has 4 matchers, exceeding the limit of 3",
+            ),
+        ];
+        for (input, err) in cases {
+            assert_eq!(
+                parser::parse_with_options(input, &options),
+                Err(err.to_string())
+            );
+            assert!(parser::parse(input).is_ok());
+        }
+
+        // any count is accepted by default
+        let input = (0..1000)
+            .map(|i| format!(r#"l{i}="v""#))
+            .collect::<Vec<_>>()
+            .join(",");
+        let input = format!("foo{{{input}}}");
+        assert!(parser::parse_with_options(&input, &parser::ParseOptions::default()).is_ok());
+        assert_eq!(
+            parser::parse_with_options(&input, &options),
+            Err("vector selector has 1000 matchers, exceeding the limit of 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_literal_fast_path() {
+        let cases = vec![
+            "1",
+            "  1\n",
+            "08",
+            "017",
+            "0xc",
+            "0xC",
+            "1.5",
+            "123.456",
+            "Inf",
+            "inf",
+            "NaN",
+            "nAN",
+            r#""x""#,
+            r#""""#,
+            "'x y'",
+            "`x`",
+            "`a\nb`",
+            r#""🦀""#,
+        ];
+        for input in cases {
+            let expr = super::parse_literal(input).unwrap_or_else(|| panic!("{input}"));
+            let lexer = crate::parser::lex::lexer(input).unwrap();
+            let (general, _) = crate::promql_y::parse(&lexer);
+            assert_eq!(Ok(expr), general.unwrap(), "{input}");
+        }
+
+        // left to the general path
+        let cases = vec![
+            "",
+            " ",
+            "1e5",
+            "1.",
+            ".5",
+            "0x",
+            "1d",
+            "5m",
+            "-1",
+            "+Inf",
+            "Infinity",
+            "NaN123",
+            "1 + 1",
+            "foo",
+            r#""x\"y""#,
+            r#""x\ty""#,
+            r#""x"#,
+            r#"""#,
+            r#""x"y""#,
+            "'x\"",
+            "0777777777777777777777777",
+        ];
+        for input in cases {
+            assert_eq!(None, super::parse_literal(input), "{input}");
+        }
+
+        assert_eq!(parser::parse("1"), Ok(Expr::from(1.0)));
+        assert_eq!(parser::parse("0xc"), Ok(Expr::from(12.0)));
+        assert_eq!(parser::parse("08"), Ok(Expr::from(8.0)));
+        assert_eq!(parser::parse(r#""x""#), Ok(Expr::from("x")));
+        assert_eq!(parser::parse("Inf"), Ok(Expr::from(f64::INFINITY)));
+    }
+
+    #[test]
+    fn test_error_catalog() {
+        let cases = vec![
+            ("1 offset 1d", errors::OFFSET_ON_NON_SELECTOR),
+            ("sum(foo) offset 1d", errors::OFFSET_ON_NON_SELECTOR),
+            ("1 @ 1", errors::AT_ON_NON_SELECTOR),
+            ("foo offset 1s offset 2s", errors::OFFSET_SET_TWICE),
+            ("foo @ 1 @ 2", errors::AT_SET_TWICE),
+            ("foo offset 1m[5m]", errors::OFFSET_BEFORE_RANGE),
+            ("foo @ 1 [5m]", errors::AT_BEFORE_RANGE),
+            ("(foo + bar)[5m]", errors::RANGE_ON_NON_SELECTOR),
+            ("foo offset", errors::END_IN_OFFSET),
+            ("foo @", errors::END_IN_AT),
+            ("rate(foo[5m],)", errors::TRAILING_COMMA_IN_CALL),
+            ("{}", errors::EMPTY_VECTOR_SELECTOR),
+            ("", errors::NO_EXPRESSION_FOUND),
+            ("1 +", INVALID_QUERY),
         ];
         assert_cases(Case::new_fail_cases(cases));
+
+        assert!(errors::OFFSET_ON_NON_SELECTOR.contains("preceded by a vector selector"));
+        assert!(errors::AT_ON_NON_SELECTOR.contains("preceded by a vector selector"));
+    }
+
+    #[test]
+    fn test_at_modifier_on_non_selectors() {
+        let err =
+            "@ modifier must be preceded by a vector selector or matrix selector or a subquery";
+        let exprs = [
+            "rate(some_metric[5m])",
+            "time()",
+            "vector(1)",
+            "(foo + bar)",
+            "(foo)",
+            "(-foo)",
+            "(foo[5m])",
+            "sum(foo)",
+            "sum by (job) (foo)",
+            "topk(3, foo)",
+            "foo + 1",
+            "foo > bool 1",
+            "-1",
+            "1",
+            r#""foo""#,
+        ];
+        // the preprocessors must be rejected the same way as timestamps
+        let ats = ["@ 1234", "@ -1234", "@ start()", "@ end()"];
+
+        let inputs: Vec<String> = exprs
+            .iter()
+            .flat_map(|expr| ats.iter().map(move |at| format!("{expr} {at}")))
+            .collect();
+        let cases = inputs.iter().map(|input| (input.as_str(), err)).collect();
+        assert_cases(Case::new_fail_cases(cases));
+    }
+
+    #[test]
+    fn test_at_modifier_on_selectors() {
+        let cases = vec![
+            ("foo @ 1234", "foo @ 1234.000"),
+            ("foo[5m] @ 1234", "foo[5m] @ 1234.000"),
+            ("foo[5m:1m] @ 1234", "foo[5m:1m] @ 1234.000"),
+            ("(foo + bar)[5m:] @ start()", "(foo + bar)[5m:] @ start()"),
+            ("rate(foo[5m] @ end())", "rate(foo[5m] @ end())"),
+            ("sum(foo @ 1234)", "sum(foo @ 1234.000)"),
+            ("foo + bar @ 1234", "foo + bar @ 1234.000"),
+            // the `@` binds tighter than the unary operator
+            ("-foo @ 1234", "-foo @ 1234.000"),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+        }
+
+        let expr = parser::parse("-foo @ 1234").unwrap();
+        let unary = expr.as_unary().unwrap();
+        assert!(unary.expr.as_vector_selector().unwrap().at.is_some());
+    }
+
+    #[test]
+    fn test_group_modifiers_without_labels() {
+        let cases = vec![
+            ("a - on(b) group_left c", "a - on (b) group_left () c"),
+            ("a - on(b) group_left() c", "a - on (b) group_left () c"),
+            ("a - on(b) group_left ( ) c", "a - on (b) group_left () c"),
+            ("a - on(b) group_right c", "a - on (b) group_right () c"),
+            ("a - on(b) group_right() c", "a - on (b) group_right () c"),
+            (
+                "a - ignoring(b) group_left c",
+                "a - ignoring (b) group_left () c",
+            ),
+            (
+                "a - ignoring(b) group_right() c",
+                "a - ignoring (b) group_right () c",
+            ),
+            (
+                "a > bool on() group_left c",
+                "a > bool on () group_left () c",
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+            assert_eq!(expr, parser::parse(expected).unwrap(), "{input}");
+        }
+
+        let card = |input| {
+            let expr = parser::parse(input).unwrap();
+            let modifier = expr.as_binary().unwrap().modifier.clone().unwrap();
+            modifier.card
+        };
+        let empty = Labels::new(vec![]);
+        for input in ["a - on(b) group_left c", "a - on(b) group_left() c"] {
+            assert_eq!(
+                VectorMatchCardinality::ManyToOne(empty.clone()),
+                card(input),
+                "{input}"
+            );
+        }
+        for input in ["a - on(b) group_right c", "a - on(b) group_right() c"] {
+            assert_eq!(
+                VectorMatchCardinality::OneToMany(empty.clone()),
+                card(input),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_bom_and_surrounding_whitespace() {
+        let cases = vec![
+            ("\u{FEFF}up", "up"),
+            ("\u{FEFF}1", "1"),
+            ("\u{FEFF}  sum(rate(foo[5m]))\n", "sum(rate(foo[5m]))"),
+            ("\n\nup\n\n", "up"),
+            ("\r\n\tup{job=\"a\"} \r\n", r#"up{job="a"}"#),
+            ("# comment\nup\n", "up"),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input:?}");
+        }
+
+        let (expr, duration) = parser::parse_rule("\u{FEFF}up == 0 for 5m\n").unwrap();
+        assert_eq!("up == 0", expr.to_string());
+        assert_eq!(Some(Duration::from_secs(300)), duration);
+
+        // only a leading BOM is stripped
+        assert_eq!(
+            Err(String::from("no expression found in input")),
+            parser::parse("\u{FEFF}")
+        );
+        assert!(parser::parse("up\u{FEFF}").is_err());
     }
 
     #[test]
@@ -2071,33 +2766,43 @@ mod tests {
                 "# just a comment\n\n",
                 "no expression found in input",
             ),
-            ("1+", INVALID_QUERY_INFO),
+            ("1+", INVALID_QUERY),
             (".", "unexpected character: '.'"),
             ("2.5.", "bad number or duration syntax: 2.5."),
             ("100..4", "bad number or duration syntax: 100.."),
             ("0deadbeef", "bad number or duration syntax: 0de"),
-            ("1 /", INVALID_QUERY_INFO),
-            ("*1", INVALID_QUERY_INFO),
+            ("1 /", INVALID_QUERY),
+            ("*1", INVALID_QUERY),
             ("(1))", "unexpected right parenthesis ')'"),
             ("((1)", "unclosed left parenthesis"),
             ("(", "unclosed left parenthesis"),
             ("1 !~ 1", "unexpected character after '!': '~'"),
             ("1 =~ 1", "unexpected character after '=': '~'"),
-            ("*test", INVALID_QUERY_INFO),
+            ("*test", INVALID_QUERY),
             (
                 "1 offset 1d",
-                "offset modifier must be preceded by an vector selector or matrix selector or a subquery"
+                "offset modifier must be preceded by a vector selector or matrix selector or a subquery"
             ),
             (
                 "foo offset 1s offset 2s",
                 "offset may not be set multiple times"
             ),
-            ("a - on(b) ignoring(c) d", INVALID_QUERY_INFO),
+            ("a - on(b) ignoring(c) d", INVALID_QUERY),
+            ("foo + * bar", "unexpected operator sequence '+ *' at position 6"),
+            ("foo == and bar", "unexpected operator sequence '== and' at position 7"),
+            ("1 +-/ 2", "unexpected operator sequence '+-/' at position 4"),
+            (
+                r#"foo{a="1" or or b="2"} and * bar"#,
+                "unexpected operator sequence 'and *' at position 27"
+            ),
 
             // Fuzzing regression tests.
-            ("-=", INVALID_QUERY_INFO),
-            ("++-++-+-+-<", INVALID_QUERY_INFO),
-            ("e-+=/(0)", INVALID_QUERY_INFO),
+            ("-=", INVALID_QUERY),
+            (
+                "++-++-+-+-<",
+                "unexpected operator sequence '++-++-+-+-<' at position 10"
+            ),
+            ("e-+=/(0)", INVALID_QUERY),
             ("a>b()", "unknown function with name 'b'"),
             (
                 "rate(avg)",
@@ -2118,6 +2823,144 @@ mod tests {
         assert_cases(fail_cases);
     }
 
+    #[test]
+    fn test_long_lexemes() {
+        // megabytes long lexemes are extracted by slicing the input, this would
+        // time out if each of them was rebuilt char by char.
+        let value = "v".repeat(1 << 20);
+        let name = "m".repeat(1 << 20);
+        let input = format!(r#"foo{{a="{value}", b!="{value}"}} + {name}"#);
+
+        let expr = parser::parse(&input).unwrap();
+        let ex = expr.as_binary().unwrap();
+        let vs = ex.lhs.as_vector_selector().unwrap();
+        assert_eq!(value, vs.matchers.matchers[0].value);
+        assert_eq!(value, vs.matchers.matchers[1].value);
+        let vs = ex.rhs.as_vector_selector().unwrap();
+        assert_eq!(Some(name), vs.name);
+    }
+
+    #[test]
+    fn test_matcher_value_quotes_round_trip() {
+        let cases = vec![
+            (r#"{a="x\"y"}"#, r#"{a="x\"y"}"#),
+            (r#"{a='x"y'}"#, r#"{a='x"y'}"#),
+            (r"{a='it\'s'}", r"{a='it\'s'}"),
+            (r#"{a=`x"y'z`}"#, r#"{a=`x"y'z`}"#),
+            (r#"{a="x\\"}"#, r#"{a="x\\"}"#),
+            (r#"foo{a!~'"', b="c"}"#, r#"foo{a!~'"',b="c"}"#),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+            assert_eq!(expr, parser::parse(&expr.to_string()).unwrap(), "{input}");
+        }
+    }
+
+    #[test]
+    fn test_trailing_commas() {
+        // trailing commas are fine in lists of labels, like in Prometheus
+        let cases = vec![
+            ("sum by (foo,) (some_metric)", "sum by (foo) (some_metric)"),
+            (
+                "sum without (foo, bar,) (some_metric)",
+                "sum without (foo, bar) (some_metric)",
+            ),
+            ("sum (some_metric) by (foo,)", "sum by (foo) (some_metric)"),
+            ("foo * on (a,) bar", "foo * on (a) bar"),
+            (
+                "foo * ignoring (a,) group_left (b,) bar",
+                "foo * ignoring (a) group_left (b) bar",
+            ),
+            (r#"foo{a="b",}"#, r#"foo{a="b"}"#),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parser::parse(expected), parser::parse(input), "{input}");
+        }
+
+        // but not in the arguments of function calls and aggregations, which report
+        // the innermost list with the trailing comma
+        let fail_cases = vec![
+            ("rate(foo[5m],)", errors::TRAILING_COMMA_IN_CALL),
+            ("round(foo, 5,)", errors::TRAILING_COMMA_IN_CALL),
+            ("sum(foo,)", errors::TRAILING_COMMA_IN_AGGREGATION),
+            ("topk(5, foo,)", errors::TRAILING_COMMA_IN_AGGREGATION),
+            ("sum by (job) (foo,)", errors::TRAILING_COMMA_IN_AGGREGATION),
+            ("sum(rate(foo[5m],))", errors::TRAILING_COMMA_IN_CALL),
+            ("abs(sum(foo,))", errors::TRAILING_COMMA_IN_AGGREGATION),
+        ];
+        assert_cases(Case::new_fail_cases(fail_cases));
+    }
+
+    #[test]
+    fn test_inf_nan_context() {
+        // quoted, Inf and NaN are the strings of matcher values or string literals
+        let cases = vec![
+            (r#"{threshold="Inf"}"#, "threshold", "Inf"),
+            (r#"foo{threshold="+Inf"}"#, "threshold", "+Inf"),
+            (r#"foo{x='NaN'}"#, "x", "NaN"),
+            ("foo{x=`nan`}", "x", "nan"),
+            (r#"{Inf="1"}"#, "Inf", "1"),
+        ];
+        for (input, name, value) in cases {
+            let expr = parser::parse(input).unwrap();
+            let vs = expr.as_vector_selector().unwrap();
+            assert_eq!(
+                Some(Matcher::new(MatchOp::Equal, name, value)),
+                vs.matchers.matchers.first().cloned(),
+                "{input}"
+            );
+        }
+        assert_eq!(parser::parse(r#""Inf""#), Ok(Expr::from("Inf")));
+
+        // unquoted, they are the floats wherever a number is expected
+        let expr = parser::parse("foo > Inf").unwrap();
+        let ex = expr.as_binary().unwrap();
+        assert_eq!(Some(f64::INFINITY), ex.rhs.scalar_value());
+
+        let expr = parser::parse("foo > -inf").unwrap();
+        let ex = expr.as_binary().unwrap();
+        assert_eq!(Some(f64::NEG_INFINITY), ex.rhs.scalar_value());
+
+        let expr = parser::parse("topk(NaN, foo)").unwrap();
+        let param = expr.as_aggregate().unwrap().param.as_ref().unwrap();
+        assert!(param.scalar_value().unwrap().is_nan());
+
+        // but out of bounds as timestamps, and no label values
+        let fail_cases = vec![
+            ("foo @ NaN", "timestamp out of bounds for @ modifier: NaN"),
+            ("foo @ Inf", "timestamp out of bounds for @ modifier: inf"),
+            (
+                "foo{threshold=Inf}",
+                "unexpected identifier 'Inf' in label matching, expected string",
+            ),
+        ];
+        assert_cases(Case::new_fail_cases(fail_cases));
+    }
+
+    #[test]
+    fn test_matcher_op_whitespace() {
+        let cases = vec![
+            (r#"{a = "b"}"#, r#"{a="b"}"#),
+            (r#"{a=~ "b"}"#, r#"{a=~"b"}"#),
+            (r#"foo{a !~ "b"}"#, r#"foo{a!~"b"}"#),
+            (r#"foo{a	!=	"b"}"#, r#"foo{a!="b"}"#),
+            (r#"foo{ a = "b" , c =~ "d" }"#, r#"foo{a="b",c=~"d"}"#),
+            (r#"{a = "b" or c != "d"}"#, r#"{a="b" or c!="d"}"#),
+            (r#"{or = "b"}"#, r#"{or="b"}"#),
+            (r#"{a = "b" or or !~ "c"}"#, r#"{a="b" or or!~"c"}"#),
+        ];
+
+        for (spaced, compact) in cases {
+            assert_eq!(
+                parser::parse(compact).unwrap(),
+                parser::parse(spaced).unwrap(),
+                "{spaced}"
+            );
+        }
+    }
+
     #[test]
     fn test_or_filters() {
         let cases = vec![
@@ -2229,10 +3072,330 @@ mod tests {
                 r#"foo{or}"#,
                 r#"invalid label matcher, expected label matching operator after 'or'"#,
             ),
-            (r#"foo{label1="1" or}"#, INVALID_QUERY_INFO),
-            (r#"foo{or label1="1"}"#, INVALID_QUERY_INFO),
-            (r#"foo{label1="1" or or label2="2"}"#, INVALID_QUERY_INFO),
+            (r#"foo{label1="1" or}"#, INVALID_QUERY),
+            (r#"foo{or label1="1"}"#, INVALID_QUERY),
+            (r#"foo{label1="1" or or label2="2"}"#, INVALID_QUERY),
+        ];
+        assert_cases(Case::new_fail_cases(fail_cases));
+    }
+
+    #[test]
+    fn test_lenient_offset_before_range() {
+        let options = parser::ParseOptions::default().with_lenient(true);
+        let cases = vec![
+            ("foo offset 1m[5m]", "foo[5m] offset 1m"),
+            ("foo OFFSET -1m [5m]", "foo[5m] offset -1m"),
+            (
+                r#"rate(foo{a="b"} offset 1h[5m])"#,
+                r#"rate(foo{a="b"}[5m] offset 1h)"#,
+            ),
+            ("foo[5m] offset 1m", "foo[5m] offset 1m"),
+            ("foo offset 1m[10m:5s]", "foo offset 1m[10m:5s]"),
+        ];
+        for (input, expected) in cases {
+            let expr = parser::parse_with_options(input, &options).unwrap();
+            assert_eq!(parser::parse(expected).unwrap(), expr);
+            assert_eq!(expected, expr.to_string());
+        }
+
+        assert_eq!(
+            parser::parse("foo offset 1m[5m]"),
+            Err("no offset modifiers allowed before range".into())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "bare-zero-arg-functions")]
+    fn test_lenient_bare_zero_arg_functions() {
+        let options = parser::ParseOptions::default().with_lenient(true);
+        let cases = vec![
+            ("time", "time()"),
+            ("time()", "time()"),
+            ("time - foo", "time() - foo"),
+            ("vector(pi * 2)", "vector(pi() * 2)"),
+            ("time[5m]", "time[5m]"),
+            ("time{job=\"a\"}", "time{job=\"a\"}"),
+            ("sum by (time) (foo)", "sum by (time) (foo)"),
+            (
+                "foo / on (time) group_left (pi) bar",
+                "foo / on (time) group_left (pi) bar",
+            ),
+            ("rate(foo[5m])", "rate(foo[5m])"),
+        ];
+        for (input, expected) in cases {
+            let expr = parser::parse_with_options(input, &options).unwrap();
+            assert_eq!(parser::parse(expected).unwrap(), expr, "{input}");
+            assert_eq!(expected, expr.to_string());
+        }
+
+        // the default behavior is unchanged
+        let expr = parser::parse("time").unwrap();
+        assert_eq!(Expr::from(VectorSelector::from("time")), expr);
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let canonical = r#"sum by (job) (rate(foo{a="b"}[5m]))"#;
+        assert!(matches!(
+            parser::canonicalize(canonical),
+            Ok(Cow::Borrowed(s)) if s == canonical
+        ));
+
+        let input = r#"sum(rate(foo{a="b"}[5m])) by (job)"#;
+        assert!(matches!(
+            parser::canonicalize(input),
+            Ok(Cow::Owned(s)) if s == canonical
+        ));
+
+        // the rendering is a prefix of the input, but it is not canonical
+        assert!(matches!(
+            parser::canonicalize("foo "),
+            Ok(Cow::Owned(s)) if s == "foo"
+        ));
+
+        assert_eq!(
+            parser::canonicalize("foo{"),
+            Err(String::from("unexpected end of input inside braces"))
+        );
+    }
+
+    #[test]
+    fn test_quoted_metric_names() {
+        let cases = vec![
+            (r#"{"a.b", job="x"}"#, r#"{"a.b",job="x"}"#),
+            (r#"{"a.b",job="x",}"#, r#"{"a.b",job="x"}"#),
+            (r#"{"http.requests"}"#, r#"{"http.requests"}"#),
+            (
+                r#"rate({"http.requests", env="prod"}[5m] offset 1m)"#,
+                r#"rate({"http.requests",env="prod"}[5m] offset 1m)"#,
+            ),
+            // valid identifiers don't need the quotes
+            (r#"{"foo:bar", job="x"}"#, r#"foo:bar{job="x"}"#),
+            // the quotes of the name are picked like the ones of the label values
+            (r#"{'a"b', job="x"}"#, r#"{'a"b',job="x"}"#),
+            (r#"{"a\"b"}"#, r#"{"a\"b"}"#),
+            // names lexed as keywords or numbers keep the quotes
+            (r#"{"inf"}"#, r#"{"inf"}"#),
+            (r#"{"NaN"}"#, r#"{"NaN"}"#),
+            (r#"{"bool"}"#, r#"{"bool"}"#),
+            (r#"{"Inf"}"#, r#"{"Inf"}"#),
+            (r#"{"on"}"#, r#"{"on"}"#),
+            (r#"{"group_left", job="x"}"#, r#"{"group_left",job="x"}"#),
+            (r#"{"atan2"}"#, r#"{"atan2"}"#),
+            // but the grammar takes aggregators and a few keywords back as names
+            (r#"{"sum", job="x"}"#, r#"sum{job="x"}"#),
+            (r#"{"offset"}"#, "offset"),
+            // the name can be at any position
+            (r#"{job="x", "a.b"}"#, r#"{"a.b",job="x"}"#),
+            (
+                r#"{env="y", "a.b", job="x",}"#,
+                r#"{"a.b",env="y",job="x"}"#,
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            assert_eq!(expected, expr.to_string(), "{input}");
+            assert_eq!(expr, parser::parse(expected).unwrap(), "{input}");
+        }
+
+        // within `or`, the name only applies to its group
+        let cases = vec![
+            (r#"{"a.b" or x="1"}"#, r#"{__name__="a.b" or x="1"}"#),
+            (
+                r#"{x="1", "a.b" or y="2"}"#,
+                r#"{x="1", __name__="a.b" or y="2"}"#,
+            ),
+            (r#"{x="1" or "a.b"}"#, r#"{x="1" or __name__="a.b"}"#),
+            (
+                r#"{x="1" or "a.b", y="2"}"#,
+                r#"{x="1" or __name__="a.b", y="2"}"#,
+            ),
+        ];
+        for (input, expected) in cases {
+            let expr = parser::parse(input).unwrap();
+            assert_eq!(parser::parse(expected).unwrap(), expr, "{input}");
+            assert_eq!(expected, expr.to_string(), "{input}");
+        }
+
+        // a name given to every group is rendered in every group
+        let matchers = Matchers::empty()
+            .append(Matcher::new(MatchOp::Equal, "x", "1"))
+            .append_or(Matcher::new(MatchOp::Equal, "y", "2"));
+        let expr = Expr::new_vector_selector(Some(String::from("a.b")), matchers).unwrap();
+        assert_eq!(r#"{"a.b", x="1" or "a.b", y="2"}"#, expr.to_string());
+
+        let fail_cases = vec![
+            (
+                r#"{"a.b", "c.d"}"#,
+                r#"metric name must not be set twice: "a.b" or "c.d""#,
+            ),
+            (
+                r#"foo{"a.b"}"#,
+                r#"metric name must not be set twice: "foo" or "a.b""#,
+            ),
+        ];
+        for (input, expected) in fail_cases {
+            assert_eq!(Err(expected.to_string()), parser::parse(input), "{input}");
+        }
+
+        let expr = parser::parse(r#"{"a.b", job="x"}"#).unwrap();
+        let matchers = Matchers::one(Matcher::new(MatchOp::Equal, "job", "x"));
+        assert_eq!(
+            expr,
+            Expr::new_vector_selector(Some(String::from("a.b")), matchers).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_subquery_empty_step() {
+        let cases = vec![
+            "foo[10m:]",
+            "foo[10m:] offset 5m",
+            "foo[10m:] @ 100.000",
+            "rate(foo[5m])[10m:]",
+            "max_over_time(foo[10m:])[1h:]",
+        ];
+
+        for input in cases {
+            let expr = parser::parse(input).unwrap();
+            assert_eq!(input, expr.to_string(), "{input}");
+            assert_eq!(expr, parser::parse(&expr.to_string()).unwrap(), "{input}");
+            assert_eq!(None, expr.as_subquery().unwrap().step, "{input}");
+        }
+
+        let fail_cases = vec![
+            ("foo[10m:0s]", "duration must be greater than 0"),
+            ("foo[10m:0m] offset 5m", "duration must be greater than 0"),
         ];
         assert_cases(Case::new_fail_cases(fail_cases));
     }
+
+    #[test]
+    fn test_subquery_at_and_offset_order() {
+        let cases = vec![
+            (
+                "some_metric[10m:5s] offset 1m @ 123",
+                "some_metric[10m:5s] @ 123 offset 1m",
+                "some_metric[10m:5s] @ 123.000 offset 1m",
+            ),
+            (
+                "rate(foo[1m])[1h:] offset -5m @ start()",
+                "rate(foo[1m])[1h:] @ start() offset -5m",
+                "rate(foo[1m])[1h:] @ start() offset -5m",
+            ),
+        ];
+
+        for (offset_first, at_first, rendered) in cases {
+            let expr = parser::parse(offset_first).unwrap();
+            assert_eq!(expr, parser::parse(at_first).unwrap());
+            assert_eq!(rendered, expr.to_string());
+            assert_eq!(expr, parser::parse(rendered).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parse_reader() {
+        let input = "foo\n\n  # a comment\nsum(rate(bar[5m]))\nfoo{\n  1 + 2  \n";
+        let results: Vec<_> = parser::parse_reader(input.as_bytes()).collect();
+        assert_eq!(
+            results,
+            vec![
+                parser::parse("foo"),
+                parser::parse("sum(rate(bar[5m]))"),
+                Err(String::from(
+                    "line 5: unexpected end of input inside braces"
+                )),
+                parser::parse("1 + 2"),
+            ]
+        );
+
+        assert_eq!(0, parser::parse_reader(&b""[..]).count());
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            parser::parse_rule("up == 0 for 5m"),
+            Ok((
+                parser::parse("up == 0").unwrap(),
+                Some(duration::MINUTE_DURATION * 5)
+            ))
+        );
+        assert_eq!(
+            parser::parse_rule(r#"sum by (job) (rate(foo{a="for"}[5m])) > 1 FOR 1h30m"#),
+            Ok((
+                parser::parse(r#"sum by (job) (rate(foo{a="for"}[5m])) > 1"#).unwrap(),
+                Some(duration::HOUR_DURATION + duration::MINUTE_DURATION * 30)
+            ))
+        );
+        assert_eq!(
+            parser::parse_rule("up == 0"),
+            Ok((parser::parse("up == 0").unwrap(), None))
+        );
+        assert_eq!(
+            parser::parse_rule("for 5m"),
+            Err(String::from("no expression found in input"))
+        );
+        assert_eq!(
+            parser::parse_rule("up == 0 for"),
+            Err(String::from(INVALID_QUERY))
+        );
+    }
+
+    struct IncrementNumbers;
+
+    impl parser::PostParsePass for IncrementNumbers {
+        fn run(&self, expr: &mut Expr) -> Result<(), String> {
+            expr.for_each_node_mut(&mut |node| {
+                if let Expr::NumberLiteral(lit) = node {
+                    lit.val += 1.0;
+                }
+            });
+            Ok(())
+        }
+    }
+
+    struct RejectStrings;
+
+    impl parser::PostParsePass for RejectStrings {
+        fn run(&self, expr: &mut Expr) -> Result<(), String> {
+            match expr {
+                Expr::StringLiteral(_) => Err(String::from("string literals are not allowed")),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_with_passes() {
+        let passes: Vec<Box<dyn parser::PostParsePass>> = vec![Box::new(IncrementNumbers)];
+        assert_eq!(
+            parser::parse_with_passes("topk(3, foo) * 2 + 1", &passes)
+                .unwrap()
+                .to_string(),
+            "topk(4, foo) * 3 + 2"
+        );
+
+        let passes: Vec<Box<dyn parser::PostParsePass>> =
+            vec![Box::new(IncrementNumbers), Box::new(IncrementNumbers)];
+        assert_eq!(
+            parser::parse_with_passes("foo offset 1m > 1", &passes),
+            parser::parse("foo offset 1m > 3")
+        );
+
+        assert_eq!(parser::parse_with_passes("foo", &[]), parser::parse("foo"));
+
+        let passes: Vec<Box<dyn parser::PostParsePass>> =
+            vec![Box::new(RejectStrings), Box::new(IncrementNumbers)];
+        assert_eq!(
+            parser::parse_with_passes(r#""foo""#, &passes),
+            Err(String::from("string literals are not allowed"))
+        );
+        // errors of the parser come first
+        assert_eq!(
+            parser::parse_with_passes("foo +", &passes),
+            parser::parse("foo +")
+        );
+    }
 }
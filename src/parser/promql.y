@@ -137,7 +137,7 @@ START_METRIC_SELECTOR
 start -> Result<Expr, String>:
                 expr { $1 }
         |       expr EOF { $1 }
-        |       EOF { Err("no expression found in input".into()) }
+        |       EOF { Err(errors::NO_EXPRESSION_FOUND.into()) }
 ;
 
 expr -> Result<Expr, String>:
@@ -160,23 +160,45 @@ expr -> Result<Expr, String>:
  * Aggregations.
  */
 aggregate_expr -> Result<Expr, String>:
-                aggregate_op aggregate_modifier function_call_body
+                aggregate_op aggregate_modifier aggregate_body
                 {
                         Expr::new_aggregate_expr($1?.id(), Some($2?), $3?)
                 }
-        |       aggregate_op function_call_body aggregate_modifier
+        |       aggregate_op aggregate_body aggregate_modifier
                 {
                         Expr::new_aggregate_expr($1?.id(), Some($3?), $2?)
                 }
-        |       aggregate_op function_call_body
+        |       aggregate_op aggregate_body
                 {
                         Expr::new_aggregate_expr($1?.id(), None, $2?)
                 }
 ;
 
+/* same as function_call_body, but reports trailing commas as aggregation ones */
+aggregate_body -> Result<FunctionArgs, String>:
+                LEFT_PAREN aggregate_args RIGHT_PAREN { $2 }
+        |       LEFT_PAREN RIGHT_PAREN { Ok(FunctionArgs::empty_args()) }
+;
+
+aggregate_args -> Result<FunctionArgs, String>:
+                aggregate_args COMMA expr { Ok($1?.append_args($3?)) }
+        |       expr { Ok(FunctionArgs::new_args($1?)) }
+        |       aggregate_args COMMA { Err(errors::TRAILING_COMMA_IN_AGGREGATION.into()) }
+;
+
 aggregate_modifier -> Result<LabelModifier, String>:
                 BY grouping_labels { Ok(LabelModifier::Include($2?)) }
         |       WITHOUT grouping_labels { Ok(LabelModifier::Exclude($2?)) }
+        /* keep_common was removed from Prometheus, report it instead of a generic error */
+        |       IDENTIFIER
+                {
+                        let id = lexeme_to_string($lexer, &$1)?;
+                        if id == "keep_common" {
+                                Err(errors::KEEP_COMMON_UNSUPPORTED.into())
+                        } else {
+                                Err(errors::INVALID_QUERY.into())
+                        }
+                }
 ;
 
 /*
@@ -247,8 +269,9 @@ group_modifiers -> Result<Option<BinModifier>, String>:
                 {
                         Ok(update_optional_card($1?, VectorMatchCardinality::OneToMany(Labels::new(vec![]))))
                 }
-        |       GROUP_LEFT grouping_labels { Err("unexpected <group_left>".into()) }
-        |       GROUP_RIGHT grouping_labels { Err("unexpected <group_right>".into()) }
+        |       on_or_ignoring BOOL { Err(errors::BOOL_AFTER_MATCHING.into()) }
+        |       GROUP_LEFT grouping_labels { Err(errors::UNEXPECTED_GROUP_LEFT.into()) }
+        |       GROUP_RIGHT grouping_labels { Err(errors::UNEXPECTED_GROUP_RIGHT.into()) }
 ;
 
 grouping_labels -> Result<Labels, String>:
@@ -297,7 +320,7 @@ function_call_body -> Result<FunctionArgs, String>:
 function_call_args -> Result<FunctionArgs, String>:
                 function_call_args COMMA expr { Ok($1?.append_args($3?)) }
         |       expr { Ok(FunctionArgs::new_args($1?)) }
-        |       function_call_args COMMA { Err("trailing commas not allowed in function call args".into()) }
+        |       function_call_args COMMA { Err(errors::TRAILING_COMMA_IN_CALL.into()) }
 ;
 
 /*
@@ -309,6 +332,9 @@ paren_expr -> Result<Expr, String>:
 
 /*
  * Offset modifiers.
+ *
+ * The sign is lexed as a separate ADD/SUB token before the DURATION, so the
+ * whitespace in between does not matter, `offset -7m` equals `offset - 7m`.
  */
 offset_expr -> Result<Expr, String>:
                 expr OFFSET duration { $1?.offset_expr(Offset::Pos($3?)) }
@@ -319,7 +345,7 @@ offset_expr -> Result<Expr, String>:
                         let num = parse_str_radix(&lexeme_to_string($lexer, &$3)?)?;
                         Err(format!("unexpected number '{num}' in offset, expected duration"))
                 }
-        |       expr OFFSET EOF { Err("unexpected end of input in offset, expected duration".into()) }
+        |       expr OFFSET EOF { Err(errors::END_IN_OFFSET.into()) }
 ;
 
 /*
@@ -343,11 +369,17 @@ at_expr -> Result<Expr, String>:
         |       expr AT DURATION
                 {
                         let du = lexeme_to_string($lexer, &$3)?;
-                        Err(format!("unexpected duration '{du}' in @, expected timestamp"))
+                        if cfg!(feature = "at-unit-suffix") {
+                                // the duration since the unix epoch, e.g. `@ 500ms`
+                                let secs = parse_duration(&du)?.as_secs_f64();
+                                $1?.at_expr(AtModifier::try_from(secs)?)
+                        } else {
+                                Err(format!("unexpected duration '{du}' in @, expected timestamp"))
+                        }
                 }
         |       expr AT EOF
                 {
-                        Err("unexpected end of input in @, expected timestamp".into())
+                        Err(errors::END_IN_AT.into())
                 }
 ;
 
@@ -366,7 +398,7 @@ matrix_selector -> Result<Expr, String>:
                 }
         |       expr LEFT_BRACKET RIGHT_BRACKET
                 {
-                        Err("missing unit character in duration".into())
+                        Err(errors::MISSING_DURATION_UNIT.into())
                 }
 ;
 
@@ -391,7 +423,11 @@ unary_expr -> Result<Expr, String>:
 vector_selector -> Result<Expr, String>:
                 metric_identifier label_matchers
                 {
-                        Expr::new_vector_selector(Some($1?.val), $2?)
+                        let name = $1?.val;
+                        match $2? {
+                                (Some(quoted), _) => Err(errors::metric_name_set_twice(&name, &quoted)),
+                                (None, matchers) => Expr::new_vector_selector(Some(name), matchers),
+                        }
                 }
         |       metric_identifier
                 {
@@ -399,22 +435,44 @@ vector_selector -> Result<Expr, String>:
                 }
         |       label_matchers
                 {
-                        Expr::new_vector_selector(None, $1?)
+                        let (name, matchers) = $1?;
+                        Expr::new_vector_selector(name, matchers)
                 }
 ;
 
-label_matchers -> Result<Matchers, String>:
+// the quoted metric name, like `"a.b"` in `{job="x", "a.b"}`, is kept apart from
+// the matchers, unless it is part of an `or` group, see `append_quoted_name`.
+label_matchers -> Result<(Option<String>, Matchers), String>:
                 LEFT_BRACE label_match_list RIGHT_BRACE { $2 }
         |       LEFT_BRACE label_match_list COMMA RIGHT_BRACE { $2 }
-        |       LEFT_BRACE RIGHT_BRACE { Ok(Matchers::empty()) }
+        |       LEFT_BRACE RIGHT_BRACE { Ok((None, Matchers::empty())) }
         |       LEFT_BRACE COMMA RIGHT_BRACE
-                { Err("unexpected ',' in label matching, expected identifier or right_brace".into()) }
+                { Err(errors::COMMA_IN_LABEL_MATCHING.into()) }
 ;
 
-label_match_list -> Result<Matchers, String>:
-                label_match_list COMMA label_matcher { Ok($1?.append($3?)) }
-        |       label_match_list LOR label_matcher { Ok($1?.append_or($3?)) }
-        |       label_matcher { Ok(Matchers::empty().append($1?)) }
+label_match_list -> Result<(Option<String>, Matchers), String>:
+                label_match_list COMMA label_matcher
+                {
+                        let (name, matchers) = $1?;
+                        Ok((name, matchers.append($3?)))
+                }
+        |       label_match_list LOR label_matcher { Ok(append_or_matcher($1?, $3?)) }
+        |       label_match_list COMMA STRING
+                {
+                        let name = lexeme_to_string($lexer, &$3)?;
+                        append_quoted_name($1?, name)
+                }
+        |       label_match_list LOR STRING
+                {
+                        let name = lexeme_to_string($lexer, &$3)?;
+                        Ok(append_or_matcher($1?, Matcher::new(MatchOp::Equal, METRIC_NAME, &name)))
+                }
+        |       label_matcher { Ok((None, Matchers::empty().append($1?))) }
+        |       STRING
+                {
+                        let name = lexeme_to_string($lexer, &$1)?;
+                        Ok((Some(name), Matchers::empty()))
+                }
 ;
 
 label_matcher -> Result<Matcher, String>:
@@ -574,9 +632,10 @@ maybe_duration -> Result<Option<Duration>, String>:
 %%
 
 use std::time::Duration;
-use crate::label::{Labels, Matcher, Matchers};
+use crate::label::{Labels, MatchOp, Matcher, Matchers, METRIC_NAME};
 use crate::parser::{AtModifier, BinModifier, Expr, FunctionArgs, LabelModifier, Offset, VectorMatchCardinality};
 use crate::parser::ast::check_ast;
+use crate::parser::errors;
 use crate::parser::function::get_function;
 use crate::parser::lex::is_label;
 use crate::parser::production::{lexeme_to_string, lexeme_to_token, span_to_string};
@@ -598,3 +657,31 @@ fn update_optional_card(
     let modifier = modifier.unwrap_or_default();
     Some(modifier.with_card(card))
 }
+
+/// add the quoted metric name of a selector, like `"a.b"` in `{job="x", "a.b"}`.
+/// Once there is an `or`, it only applies to its group, so it is a `__name__` matcher.
+fn append_quoted_name(
+    (prev, matchers): (Option<String>, Matchers),
+    name: String,
+) -> Result<(Option<String>, Matchers), String> {
+    if !matchers.or_matchers.is_empty() {
+        let matcher = Matcher::new(MatchOp::Equal, METRIC_NAME, &name);
+        return Ok((None, matchers.append(matcher)));
+    }
+    match prev {
+        Some(prev) => Err(errors::metric_name_set_twice(&prev, &name)),
+        None => Ok((Some(name), matchers)),
+    }
+}
+
+/// start a new `or` group with the matcher. A quoted metric name given before only
+/// applies to the first group, so it becomes a `__name__` matcher of that group.
+fn append_or_matcher(
+    (name, mut matchers): (Option<String>, Matchers),
+    matcher: Matcher,
+) -> (Option<String>, Matchers) {
+    if let Some(name) = name {
+        matchers = matchers.append(Matcher::new(MatchOp::Equal, METRIC_NAME, &name));
+    }
+    (None, matchers.append_or(matcher))
+}
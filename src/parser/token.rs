@@ -24,6 +24,19 @@ pub type TokenId = u8;
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct TokenType(TokenId);
 
+/// The class of a binary operator, see [`TokenType::op_class`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OpClass {
+    /// `+`, `-`, `*`, `/`, `%` and `^`
+    Arithmetic,
+    /// `==`, `!=`, `<=`, `<`, `>=` and `>`
+    Comparison,
+    /// `and`, `or` and `unless`
+    SetOp,
+    /// `atan2`, which is arithmetic but spelled like a keyword
+    Atan2,
+}
+
 #[cfg(feature = "ser")]
 impl serde::Serialize for TokenType {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -208,6 +221,59 @@ impl TokenType {
         self.0
     }
 
+    /// every operator, aggregator, keyword and preprocessor token, like for
+    /// building a highlighter. The other tokens, like `{ID}`, have no fixed text.
+    pub fn all() -> &'static [TokenType] {
+        const ALL: &[TokenType] = &[
+            // Operators.
+            TokenType(T_ADD),
+            TokenType(T_DIV),
+            TokenType(T_EQLC),
+            TokenType(T_EQL_REGEX),
+            TokenType(T_GTE),
+            TokenType(T_GTR),
+            TokenType(T_LAND),
+            TokenType(T_LOR),
+            TokenType(T_LSS),
+            TokenType(T_LTE),
+            TokenType(T_LUNLESS),
+            TokenType(T_MOD),
+            TokenType(T_MUL),
+            TokenType(T_NEQ),
+            TokenType(T_NEQ_REGEX),
+            TokenType(T_POW),
+            TokenType(T_SUB),
+            TokenType(T_AT),
+            TokenType(T_ATAN2),
+            // Aggregators.
+            TokenType(T_AVG),
+            TokenType(T_BOTTOMK),
+            TokenType(T_COUNT),
+            TokenType(T_COUNT_VALUES),
+            TokenType(T_GROUP),
+            TokenType(T_MAX),
+            TokenType(T_MIN),
+            TokenType(T_QUANTILE),
+            TokenType(T_STDDEV),
+            TokenType(T_STDVAR),
+            TokenType(T_SUM),
+            TokenType(T_TOPK),
+            // Keywords.
+            TokenType(T_BOOL),
+            TokenType(T_BY),
+            TokenType(T_GROUP_LEFT),
+            TokenType(T_GROUP_RIGHT),
+            TokenType(T_IGNORING),
+            TokenType(T_OFFSET),
+            TokenType(T_ON),
+            TokenType(T_WITHOUT),
+            // Preprocessors.
+            TokenType(T_START),
+            TokenType(T_END),
+        ];
+        ALL
+    }
+
     pub fn is_aggregator(&self) -> bool {
         self.0 > T_AGGREGATORS_START && self.0 < T_AGGREGATORS_END
     }
@@ -227,6 +293,32 @@ impl TokenType {
     pub fn is_operator(&self) -> bool {
         self.0 > T_OPERATORS_START && self.0 < T_OPERATORS_END
     }
+
+    /// the class of the binary operator, None if the token is not a binary operator,
+    /// including the operator tokens `=~`, `!~` and `@`.
+    pub fn op_class(&self) -> Option<OpClass> {
+        match self.0 {
+            T_ADD | T_SUB | T_MUL | T_DIV | T_MOD | T_POW => Some(OpClass::Arithmetic),
+            T_EQLC | T_NEQ | T_LTE | T_LSS | T_GTE | T_GTR => Some(OpClass::Comparison),
+            T_LAND | T_LOR | T_LUNLESS => Some(OpClass::SetOp),
+            T_ATAN2 => Some(OpClass::Atan2),
+            _ => None,
+        }
+    }
+
+    /// the precedence of the binary operator as declared in the grammar, the higher
+    /// the tighter it binds. Only `^` is right associative.
+    pub(crate) fn precedence(&self) -> Option<u8> {
+        match self.0 {
+            T_LOR => Some(1),
+            T_LAND | T_LUNLESS => Some(2),
+            T_EQLC | T_GTE | T_GTR | T_LSS | T_LTE | T_NEQ => Some(3),
+            T_ADD | T_SUB => Some(4),
+            T_MUL | T_DIV | T_MOD | T_ATAN2 => Some(5),
+            T_POW => Some(6),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for TokenType {
@@ -239,6 +331,37 @@ impl fmt::Display for TokenType {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_all() {
+        let all = TokenType::all();
+        assert_eq!(41, all.len());
+
+        for token in all {
+            let display = token_display(token.id());
+            assert!(!display.is_empty(), "{token:?}");
+            assert_ne!("unknown token", display, "{token:?}");
+            assert_ne!("not used", display, "{token:?}");
+            // the tokens spelled as words are the keywords of the lexer
+            if display
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+            {
+                assert_eq!(Some(token.id()), get_keyword_token(display), "{token:?}");
+            }
+        }
+
+        for id in 0..=TokenId::MAX {
+            assert!(!token_display(id).is_empty(), "{id}");
+
+            let token = TokenType::new(id);
+            let listed = token.is_operator()
+                || token.is_aggregator()
+                || (id > T_KEYWORDS_START && id < T_KEYWORDS_END)
+                || (id > T_PREPROCESSOR_START && id < T_PREPROCESSOR_END);
+            assert_eq!(listed, all.contains(&token), "{}", token_display(id));
+        }
+    }
+
     #[test]
     fn test_token_display() {
         assert_eq!(token_display(T_EQL), "=");
@@ -430,6 +553,29 @@ mod tests {
         assert!(!TokenType(T_OPERATORS_END).is_operator());
     }
 
+    #[test]
+    fn test_op_class() {
+        for id in [T_ADD, T_SUB, T_MUL, T_DIV, T_MOD, T_POW] {
+            assert_eq!(TokenType(id).op_class(), Some(OpClass::Arithmetic));
+        }
+        for id in [T_EQLC, T_NEQ, T_LTE, T_LSS, T_GTE, T_GTR] {
+            assert_eq!(TokenType(id).op_class(), Some(OpClass::Comparison));
+            assert!(TokenType(id).is_comparison_operator());
+        }
+        for id in [T_LAND, T_LOR, T_LUNLESS] {
+            assert_eq!(TokenType(id).op_class(), Some(OpClass::SetOp));
+            assert!(TokenType(id).is_set_operator());
+        }
+        assert_eq!(TokenType(T_ATAN2).op_class(), Some(OpClass::Atan2));
+
+        assert_eq!(TokenType(T_EQL_REGEX).op_class(), None);
+        assert_eq!(TokenType(T_NEQ_REGEX).op_class(), None);
+        assert_eq!(TokenType(T_AT).op_class(), None);
+        assert_eq!(TokenType(T_EQL).op_class(), None);
+        assert_eq!(TokenType(T_SUM).op_class(), None);
+        assert_eq!(TokenType(T_OFFSET).op_class(), None);
+    }
+
     #[test]
     fn test_is_aggregator() {
         assert!(TokenType(T_AVG).is_aggregator());
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::parser::errors;
+use crate::parser::token::{TokenId, TokenType};
 use std::fmt::{self, Display};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -35,6 +37,26 @@ impl Display for ValueType {
     }
 }
 
+impl ValueType {
+    /// the type of the result of the binary operator `op` between operands of the given
+    /// types, which is only valid between scalars and vectors, and set operators are
+    /// only valid between vectors. Modifiers, like `bool`, are not taken into account.
+    pub fn binary_result(lhs: ValueType, rhs: ValueType, op: TokenId) -> Result<ValueType, String> {
+        let op = TokenType::new(op);
+        if op.is_set_operator() && (lhs == ValueType::Scalar || rhs == ValueType::Scalar) {
+            return Err(errors::set_operator_on_scalar(op));
+        }
+
+        match (lhs, rhs) {
+            (ValueType::Scalar, ValueType::Scalar) => Ok(ValueType::Scalar),
+            (ValueType::Scalar | ValueType::Vector, ValueType::Scalar | ValueType::Vector) => {
+                Ok(ValueType::Vector)
+            }
+            _ => Err(errors::BINARY_INVALID_OPERANDS.into()),
+        }
+    }
+}
+
 pub trait Value {
     fn vtype(&self) -> ValueType;
 }
@@ -50,4 +72,58 @@ mod tests {
         assert_eq!(ValueType::Vector.to_string(), "vector");
         assert_eq!(ValueType::Matrix.to_string(), "matrix");
     }
+
+    #[test]
+    fn test_binary_result() {
+        use crate::parser::token::{T_ADD, T_EQLC, T_LAND, T_MUL};
+
+        let cases = vec![
+            (
+                ValueType::Vector,
+                ValueType::Scalar,
+                T_MUL,
+                Ok(ValueType::Vector),
+            ),
+            (
+                ValueType::Scalar,
+                ValueType::Vector,
+                T_EQLC,
+                Ok(ValueType::Vector),
+            ),
+            (
+                ValueType::Vector,
+                ValueType::Vector,
+                T_LAND,
+                Ok(ValueType::Vector),
+            ),
+            (
+                ValueType::Scalar,
+                ValueType::Scalar,
+                T_ADD,
+                Ok(ValueType::Scalar),
+            ),
+            (
+                ValueType::String,
+                ValueType::Vector,
+                T_ADD,
+                Err(errors::BINARY_INVALID_OPERANDS.to_string()),
+            ),
+            (
+                ValueType::Vector,
+                ValueType::Matrix,
+                T_ADD,
+                Err(errors::BINARY_INVALID_OPERANDS.to_string()),
+            ),
+            (
+                ValueType::Scalar,
+                ValueType::Vector,
+                T_LAND,
+                Err("set operator 'and' not allowed in binary scalar expression".to_string()),
+            ),
+        ];
+
+        for (lhs, rhs, op, expected) in cases {
+            assert_eq!(expected, ValueType::binary_result(lhs, rhs, op));
+        }
+    }
 }
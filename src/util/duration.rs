@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::parser::errors;
 use lazy_static::lazy_static;
 use regex::Regex;
 use std::fmt::Write;
@@ -68,15 +69,15 @@ const ALL_CAPS: [(&str, Duration); 7] = [
 /// ```
 pub fn parse_duration(ds: &str) -> Result<Duration, String> {
     if ds.is_empty() {
-        return Err("empty duration string".into());
+        return Err(errors::EMPTY_DURATION.into());
     }
 
     if ds == "0" {
-        return Err("duration must be greater than 0".into());
+        return Err(errors::ZERO_DURATION.into());
     }
 
     if !DURATION_RE.is_match(ds) {
-        return Err(format!("not a valid duration string: {ds}"));
+        return Err(errors::invalid_duration(ds));
     }
 
     let caps = DURATION_RE.captures(ds).unwrap();
@@ -91,11 +92,11 @@ pub fn parse_duration(ds: &str) -> Result<Duration, String> {
         })
         .try_fold(Duration::ZERO, |acc, x| {
             acc.checked_add(x.unwrap_or(Duration::ZERO))
-                .ok_or_else(|| "duration overflowed".into())
+                .ok_or_else(|| errors::DURATION_OVERFLOW.into())
         });
 
     if matches!(dur, Ok(d) if d == Duration::ZERO) {
-        Err("duration must be greater than 0".into())
+        Err(errors::ZERO_DURATION.into())
     } else {
         dur
     }
@@ -110,7 +111,7 @@ pub fn display_duration(duration: &Duration) -> String {
     let mut ss = String::new();
 
     let mut f = |unit: &str, mult: u128, exact: bool| {
-        if exact && ms % mult != 0 {
+        if exact && !ms.is_multiple_of(mult) {
             return;
         }
 
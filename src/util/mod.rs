@@ -20,7 +20,7 @@ mod visitor;
 
 pub use duration::{display_duration, parse_duration};
 pub use number::parse_str_radix;
-pub use visitor::{walk_expr, ExprVisitor};
+pub use visitor::{walk_expr, walk_expr_with_path, ExprPathVisitor, ExprVisitor, PathSegment};
 
 pub(crate) fn join_vector<T: std::fmt::Display>(v: &[T], sep: &str, sort: bool) -> String {
     let mut vs = v.iter().map(|x| x.to_string()).collect::<Vec<String>>();
@@ -29,3 +29,17 @@ pub(crate) fn join_vector<T: std::fmt::Display>(v: &[T], sep: &str, sort: bool)
     }
     vs.join(sep)
 }
+
+/// percent-encode all the characters except the unreserved ones of RFC 3986,
+/// so that the string can be used in the query of an URL.
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
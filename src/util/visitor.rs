@@ -84,6 +84,103 @@ pub fn walk_expr<V: ExprVisitor>(visitor: &mut V, expr: &Expr) -> Result<bool, V
     Ok(true)
 }
 
+/// Describes how a child [Expr] is reached from its parent. A slice of segments
+/// from the root is the path of an [Expr] in the AST.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    AggregateParam,
+    AggregateExpr,
+    UnaryExpr,
+    BinaryLhs,
+    BinaryRhs,
+    ParenExpr,
+    SubqueryExpr,
+    /// the index of the argument in the function call
+    CallArg(usize),
+    /// the index of the child in [`ExtensionExpr::children`](crate::parser::ast::ExtensionExpr::children)
+    ExtensionChild(usize),
+}
+
+impl PathSegment {
+    /// the children of an [Expr] along with the segment leading to each of them,
+    /// in the order of [`Expr::children`].
+    pub(crate) fn children(expr: &Expr) -> Vec<(PathSegment, &Expr)> {
+        expr.children()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, child)| (PathSegment::of_child(expr, idx), child))
+            .collect()
+    }
+
+    /// the segment leading to the child at the index of [`Expr::children`].
+    fn of_child(expr: &Expr, idx: usize) -> PathSegment {
+        match expr {
+            Expr::Aggregate(AggregateExpr { param, .. }) if param.is_some() && idx == 0 => {
+                PathSegment::AggregateParam
+            }
+            Expr::Aggregate(_) => PathSegment::AggregateExpr,
+            Expr::Unary(_) => PathSegment::UnaryExpr,
+            Expr::Binary(_) if idx == 0 => PathSegment::BinaryLhs,
+            Expr::Binary(_) => PathSegment::BinaryRhs,
+            Expr::Paren(_) => PathSegment::ParenExpr,
+            Expr::Subquery(_) => PathSegment::SubqueryExpr,
+            Expr::Call(_) => PathSegment::CallArg(idx),
+            Expr::Extension(_) => PathSegment::ExtensionChild(idx),
+            Expr::NumberLiteral(_)
+            | Expr::StringLiteral(_)
+            | Expr::VectorSelector(_)
+            | Expr::MatrixSelector(_) => unreachable!("leaves have no children"),
+        }
+    }
+}
+
+/// Variant of [ExprVisitor] which also receives the path from the root to the visited node.
+/// The path is empty for the root itself.
+pub trait ExprPathVisitor {
+    type Error;
+
+    /// Called before any children are visited. Return `Ok(false)` to cut short the recursion
+    /// (skip traversing and return).
+    fn pre_visit(&mut self, expr: &Expr, path: &[PathSegment]) -> Result<bool, Self::Error>;
+
+    /// Called after all children are visited. Return `Ok(false)` to cut short the recursion
+    /// (skip traversing and return).
+    fn post_visit(&mut self, _expr: &Expr, _path: &[PathSegment]) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+/// Same as [walk_expr], but keeps track of the path of each node, see [ExprPathVisitor].
+/// Unlike [walk_expr], the param of an aggregation is visited as well.
+pub fn walk_expr_with_path<V: ExprPathVisitor>(
+    visitor: &mut V,
+    expr: &Expr,
+) -> Result<bool, V::Error> {
+    let mut path = vec![];
+    walk_path(visitor, expr, &mut path)
+}
+
+fn walk_path<V: ExprPathVisitor>(
+    visitor: &mut V,
+    expr: &Expr,
+    path: &mut Vec<PathSegment>,
+) -> Result<bool, V::Error> {
+    if !visitor.pre_visit(expr, path)? {
+        return Ok(false);
+    }
+
+    for (segment, child) in PathSegment::children(expr) {
+        path.push(segment);
+        let recurse = walk_path(visitor, child, path)?;
+        path.pop();
+        if !recurse {
+            return Ok(false);
+        }
+    }
+
+    visitor.post_visit(expr, path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -224,4 +321,90 @@ mod tests {
         let ast = parser::parse("pg_stat_activity_count{namespace=\"sample\"} ^ pg_stat_activity_count{namespace=\"sample\"}").unwrap();
         assert!(walk_expr(&mut visitor, &ast).unwrap());
     }
+
+    struct PathCollector {
+        paths: Vec<(String, Vec<PathSegment>)>,
+    }
+
+    impl ExprPathVisitor for PathCollector {
+        type Error = ();
+
+        fn pre_visit(&mut self, expr: &Expr, path: &[PathSegment]) -> Result<bool, Self::Error> {
+            if matches!(expr, Expr::VectorSelector(_) | Expr::MatrixSelector(_)) {
+                self.paths.push((expr.to_string(), path.to_vec()));
+            }
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_walk_expr_with_path() {
+        let ast = parser::parse(
+            "a / (topk(scalar(b), sum by (job) (label_replace(rate(c[5m]), \"x\", \"y\", \"z\", \"w\"))))",
+        )
+        .unwrap();
+        let mut visitor = PathCollector { paths: vec![] };
+        assert!(ast.walk_paths(&mut visitor).unwrap());
+
+        assert_eq!(
+            visitor.paths,
+            vec![
+                (String::from("a"), vec![PathSegment::BinaryLhs]),
+                (
+                    String::from("b"),
+                    vec![
+                        PathSegment::BinaryRhs,
+                        PathSegment::ParenExpr,
+                        PathSegment::AggregateParam,
+                        PathSegment::CallArg(0)
+                    ]
+                ),
+                (
+                    String::from("c[5m]"),
+                    vec![
+                        PathSegment::BinaryRhs,
+                        PathSegment::ParenExpr,
+                        PathSegment::AggregateExpr,
+                        PathSegment::AggregateExpr,
+                        PathSegment::CallArg(0),
+                        PathSegment::CallArg(0)
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subtree_at_path() {
+        let ast = parser::parse("rate(foo[5m]) + 1").unwrap();
+        let cases = vec![
+            (vec![], Some("rate(foo[5m]) + 1")),
+            (vec![PathSegment::BinaryLhs], Some("rate(foo[5m])")),
+            (
+                vec![PathSegment::BinaryLhs, PathSegment::CallArg(0)],
+                Some("foo[5m]"),
+            ),
+            (vec![PathSegment::BinaryRhs], Some("1")),
+            (vec![PathSegment::BinaryLhs, PathSegment::CallArg(1)], None),
+            (vec![PathSegment::ParenExpr], None),
+            (vec![PathSegment::BinaryRhs, PathSegment::UnaryExpr], None),
+        ];
+
+        for (path, expected) in cases {
+            let actual = ast.subtree_at_path(&path).map(|expr| expr.to_string());
+            assert_eq!(expected.map(String::from), actual, "{path:?}");
+        }
+
+        // every path recorded by a traversal leads back to its node
+        let ast = parser::parse(
+            "a / (topk(scalar(b), sum by (job) (label_replace(rate(c[5m]), \"x\", \"y\", \"z\", \"w\"))))",
+        )
+        .unwrap();
+        let mut visitor = PathCollector { paths: vec![] };
+        assert!(ast.walk_paths(&mut visitor).unwrap());
+        for (expr, path) in visitor.paths {
+            let subtree = ast.subtree_at_path(&path).map(|expr| expr.to_string());
+            assert_eq!(Some(expr), subtree, "{path:?}");
+        }
+    }
 }
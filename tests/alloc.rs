@@ -0,0 +1,67 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Guards the number of allocations of parsing against regressions. The counting
+//! allocator is global to this test binary, so it holds a single test.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use promql_parser::parser::parse;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// the allocations budget of parsing [QUERY] once, reallocations included. It is
+/// about 1.3x the current count of around 750, so small changes pass, but a real
+/// regression, like doubling the allocations, makes the test fail.
+const BUDGET: usize = 1_000;
+
+const QUERY: &str = r#"sum by (job) (rate(http_requests_total{job="api", code!="500"}[5m] offset 1h)) / on (job) group_left () sum by (job) (up @ end())"#;
+
+#[test]
+fn test_parse_allocations() {
+    // the first parse also initializes the lazy statics, like the duration regex
+    parse(QUERY).unwrap();
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let expr = parse(QUERY).unwrap();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    drop(expr);
+
+    assert!(
+        allocations <= BUDGET,
+        "parsing allocated {allocations} times, over the budget of {BUDGET}"
+    );
+}
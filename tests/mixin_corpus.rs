@@ -0,0 +1,44 @@
+// Copyright 2023 Greptime Team
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Every query of a corpus taken from public monitoring mixins must parse and
+//! round-trip through its string form to the same AST.
+
+use promql_parser::parser::parse;
+
+const CORPUS: &str = include_str!("fixtures/mixin_queries.promql");
+
+fn queries() -> impl Iterator<Item = (usize, &'static str)> {
+    CORPUS
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+#[test]
+fn test_mixin_corpus_round_trip() {
+    assert!(queries().count() >= 100);
+
+    for (line, query) in queries() {
+        let expr = parse(query).unwrap_or_else(|e| panic!("line {line}: {query}: {e}"));
+        let rendered = expr.to_string();
+        let reparsed = parse(&rendered).unwrap_or_else(|e| panic!("line {line}: {rendered}: {e}"));
+        // the matchers are rendered sorted, which is the only difference allowed
+        assert!(
+            expr.eq_ignoring_matcher_order(&reparsed),
+            "line {line}: {query}\n{expr:?}\n{reparsed:?}"
+        );
+    }
+}
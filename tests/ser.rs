@@ -555,3 +555,36 @@ fn test_serialize() {
         "without": true
     });
 }
+
+#[test]
+fn test_serialize_matcher() {
+    let cases = vec![
+        (
+            r#"foo{job="api"}"#,
+            r#"{"name":"job","type":"=","value":"api"}"#,
+        ),
+        (
+            r#"foo{job!="api"}"#,
+            r#"{"name":"job","type":"!=","value":"api"}"#,
+        ),
+        (
+            r#"foo{job=~"api|web"}"#,
+            r#"{"name":"job","type":"=~","value":"api|web"}"#,
+        ),
+        (
+            r#"foo{job!~"api.*"}"#,
+            r#"{"name":"job","type":"!~","value":"api.*"}"#,
+        ),
+    ];
+
+    for (input, expected) in cases {
+        let ast = parse(input).expect("Failed to parse");
+        let vs = ast.as_vector_selector().expect("Not a vector selector");
+        let matcher = vs.matchers.matchers.first().expect("No matcher");
+        assert_eq!(
+            expected,
+            serde_json::to_string(matcher).expect("Failed to serialize"),
+            "{input}"
+        );
+    }
+}